@@ -0,0 +1,171 @@
+use error::*;
+use std::fmt;
+
+/// How the temperature of the blocked Gibbs sampler goes down over the epochs, see `Sampler::set_temperature`. The epochs are counted from 1, and the temperature never drops below 1. `from_spec` only accepts schedules that reach 1 after finitely many epochs, so that the sampler ends up at the true posterior.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AnnealingSchedule {
+    /// Always samples at temperature 1, i.e. no annealing.
+    Constant,
+    /// Goes down linearly from `start` in the first epoch to 1 after `num_epochs` epochs.
+    Linear { start: f64, num_epochs: usize },
+    /// Starts at `start` and is multiplied by `rate` after every epoch, until it reaches 1.
+    Exponential { start: f64, rate: f64 },
+    /// Each pair gives the epoch from which on the temperature is used. Sorted by the epoch, and the last temperature is 1. Before the first one, the temperature is 1.
+    Steps(Vec<(usize, f64)>),
+}
+
+fn parse_number<T: ::std::str::FromStr>(value: &str, spec: &str) -> Result<T> {
+    value.trim().parse().map_err(|_| {
+        Error::InvalidConfig(format!(
+            "Invalid number {:?} in the annealing schedule {:?}",
+            value, spec
+        ))
+    })
+}
+
+fn check_temperature(temperature: f64, spec: &str) -> Result<f64> {
+    if temperature.is_finite() && temperature >= 1.0 {
+        Ok(temperature)
+    } else {
+        Err(Error::InvalidConfig(format!(
+            "The temperatures in the annealing schedule {:?} must be at least 1",
+            spec
+        )))
+    }
+}
+
+impl AnnealingSchedule {
+    /// Parses one of
+    /// - `none`: no annealing.
+    /// - `linear:START:EPOCHS`, e.g. `linear:3:50`.
+    /// - `exp:START:RATE`, e.g. `exp:3:0.95`.
+    /// - `steps:EPOCH=T,EPOCH=T,...`, e.g. `steps:1=3,20=2,40=1`.
+    pub fn from_spec(spec: &str) -> Result<Self> {
+        let fields: Vec<&str> = spec.split(':').collect();
+        let schedule = match fields.as_slice() {
+            ["none"] => AnnealingSchedule::Constant,
+            ["linear", start, num_epochs] => {
+                let num_epochs = parse_number(num_epochs, spec)?;
+                if num_epochs == 0 {
+                    return Err(Error::InvalidConfig(format!(
+                        "The annealing schedule {:?} must last at least one epoch",
+                        spec
+                    )));
+                }
+                AnnealingSchedule::Linear {
+                    start: check_temperature(parse_number(start, spec)?, spec)?,
+                    num_epochs,
+                }
+            }
+            ["exp", start, rate] => {
+                let rate: f64 = parse_number(rate, spec)?;
+                let start = check_temperature(parse_number(start, spec)?, spec)?;
+                // With a rate of 1 a start above 1 would be kept forever.
+                if !(rate > 0.0 && (rate < 1.0 || rate == 1.0 && start == 1.0)) {
+                    return Err(Error::InvalidConfig(format!(
+                        "The rate of the annealing schedule {:?} must lie in (0, 1)",
+                        spec
+                    )));
+                }
+                AnnealingSchedule::Exponential { start, rate }
+            }
+            ["steps", steps] => {
+                let mut parsed_steps = Vec::new();
+                for step in steps.split(',') {
+                    let mut parts = step.splitn(2, '=');
+                    match (parts.next(), parts.next()) {
+                        (Some(epoch), Some(temperature)) => parsed_steps.push((
+                            parse_number(epoch, spec)?,
+                            check_temperature(parse_number(temperature, spec)?, spec)?,
+                        )),
+                        _ => {
+                            return Err(Error::InvalidConfig(format!(
+                                "Expected EPOCH=TEMPERATURE instead of {:?} in the annealing schedule {:?}",
+                                step, spec
+                            )))
+                        }
+                    }
+                }
+                parsed_steps.sort_by_key(|&(epoch, _)| epoch);
+                if parsed_steps.last().map(|&(_, temperature)| temperature) != Some(1.0) {
+                    return Err(Error::InvalidConfig(format!(
+                        "The last step of the annealing schedule {:?} must go down to temperature 1",
+                        spec
+                    )));
+                }
+                AnnealingSchedule::Steps(parsed_steps)
+            }
+            _ => {
+                return Err(Error::InvalidConfig(format!(
+                    "Unknown annealing schedule {:?}, expected none, linear:START:EPOCHS, exp:START:RATE or steps:EPOCH=T,...",
+                    spec
+                )))
+            }
+        };
+        Ok(schedule)
+    }
+
+    /// The temperature at which the given epoch is sampled.
+    pub fn get_temperature(&self, epoch: usize) -> f64 {
+        let num_finished_epochs = epoch.max(1) - 1;
+        match self {
+            AnnealingSchedule::Constant => 1.0,
+            AnnealingSchedule::Linear { start, num_epochs } => {
+                let progress = num_finished_epochs.min(*num_epochs) as f64 / *num_epochs as f64;
+                start + (1.0 - start) * progress
+            }
+            AnnealingSchedule::Exponential { start, rate } => {
+                (start * rate.powf(num_finished_epochs as f64)).max(1.0)
+            }
+            AnnealingSchedule::Steps(steps) => steps
+                .iter()
+                .take_while(|&&(first_epoch, _)| first_epoch <= epoch)
+                .last()
+                .map_or(1.0, |&(_, temperature)| temperature),
+        }
+    }
+}
+
+impl fmt::Display for AnnealingSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AnnealingSchedule::Constant => write!(f, "none"),
+            AnnealingSchedule::Linear { start, num_epochs } => {
+                write!(f, "linear:{}:{}", start, num_epochs)
+            }
+            AnnealingSchedule::Exponential { start, rate } => write!(f, "exp:{}:{}", start, rate),
+            AnnealingSchedule::Steps(steps) => {
+                write!(f, "steps:")?;
+                for (i, (epoch, temperature)) in steps.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}={}", epoch, temperature)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedules_that_never_reach_temperature_1_are_rejected() {
+        assert!(AnnealingSchedule::from_spec("steps:1=3,20=2").is_err());
+        assert!(AnnealingSchedule::from_spec("exp:3:1").is_err());
+        assert!(AnnealingSchedule::from_spec("exp:1:1").is_ok());
+        assert!(AnnealingSchedule::from_spec("steps:20=1,1=3").is_ok());
+    }
+
+    #[test]
+    fn test_late_epochs_are_sampled_at_temperature_1() {
+        for spec in &["none", "linear:3:50", "exp:3:0.95", "steps:1=3,20=2,40=1"] {
+            let schedule = AnnealingSchedule::from_spec(spec).unwrap();
+            assert!(schedule.get_temperature(1) >= 1.0);
+            assert_eq!(schedule.get_temperature(1000), 1.0, "{}", spec);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+extern crate getopts;
+extern crate rust_nhpylm;
+
+use std::collections::HashSet;
+use std::env::args;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+
+use getopts::Options;
+use std::process;
+
+use rust_nhpylm::evaluation::*;
+
+fn read_segmented_lines(path: &str) -> io::Result<Vec<Vec<String>>> {
+    let file = File::open(path)?;
+    let mut lines = Vec::new();
+    for line in BufReader::new(file).lines() {
+        lines.push(split_segmented_line(&line?));
+    }
+    Ok(lines)
+}
+
+fn main() {
+    let args: Vec<String> = args().collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help menu");
+    opts.optopt(
+        "d",
+        "dictionary",
+        "Word list of the training data, used to tell in-vocabulary from OOV words",
+        "FILENAME",
+    );
+    opts.optopt("g", "gold", "Path to the gold segmentation", "FILENAME");
+    opts.optopt("t", "test", "Path to the segmentation to score", "FILENAME");
+
+    let matches = opts.parse(&args[1..]).unwrap_or_else(|e| {
+        println!("Error: {}", e);
+        process::exit(1);
+    });
+
+    if matches.opt_present("h") {
+        print!(
+            "{}",
+            opts.usage(&format!("Usage: {} -g GOLD -t TEST [options]", args[0]))
+        );
+        return;
+    }
+
+    if !matches.opts_present(&["g".to_owned()]) || !matches.opts_present(&["t".to_owned()]) {
+        println!("Please specify both the gold file with -g and the test file with -t!");
+        process::exit(1);
+    }
+
+    let dictionary = match matches.opt_get::<String>("d").unwrap() {
+        None => HashSet::new(),
+        Some(dictionary_file) => read_dictionary(Path::new(&dictionary_file)).unwrap_or_else(|e| {
+            println!(
+                "Error: failed to read the dictionary {}: {}",
+                dictionary_file, e
+            );
+            process::exit(1);
+        }),
+    };
+
+    let gold_file: String = matches.opt_get("g").unwrap().unwrap();
+    let test_file: String = matches.opt_get("t").unwrap().unwrap();
+    let gold_lines = read_segmented_lines(&gold_file).unwrap_or_else(|e| {
+        println!("Error: failed to read {}: {}", gold_file, e);
+        process::exit(1);
+    });
+    let test_lines = read_segmented_lines(&test_file).unwrap_or_else(|e| {
+        println!("Error: failed to read {}: {}", test_file, e);
+        process::exit(1);
+    });
+
+    if gold_lines.len() != test_lines.len() {
+        println!(
+            "Warning: the gold file has {} lines but the test file has {}. Only the first {} lines are scored.",
+            gold_lines.len(),
+            test_lines.len(),
+            gold_lines.len().min(test_lines.len())
+        );
+    }
+
+    let mut evaluator = Evaluator::new(&dictionary);
+    for (gold, test) in gold_lines.iter().zip(test_lines.iter()) {
+        evaluator.add_sentence(gold, test);
+    }
+    println!("{}", evaluator.get_scores());
+}
@@ -0,0 +1,132 @@
+extern crate getopts;
+extern crate rust_nhpylm;
+
+use std::env::args;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use getopts::Options;
+use std::process;
+
+use rust_nhpylm::Model;
+
+enum OutputFormat {
+    Plain,
+    Segmented,
+    Json,
+}
+
+fn main() {
+    let args: Vec<String> = args().collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help menu");
+    opts.optopt("m", "model", "Path to the trained model", "FILENAME");
+    opts.optopt(
+        "n",
+        "num-sentences",
+        "The number of sentences to generate",
+        "10",
+    );
+    opts.optopt(
+        "",
+        "seed",
+        "Seed for the random number generator, so that the same seed gives the same sentences",
+        "0",
+    );
+    opts.optopt(
+        "",
+        "temperature",
+        "Below 1, the sentences stick to the most probable words, and above 1 they become more varied",
+        "1.0",
+    );
+    opts.optopt(
+        "",
+        "max-words",
+        "Cut a sentence off after this many words if the model hasn't ended it by then",
+        "100",
+    );
+    opts.optopt(
+        "",
+        "format",
+        "plain for the text of each sentence, segmented for its words separated by two spaces, or json for one line of JSON per sentence",
+        "plain",
+    );
+
+    let matches = opts.parse(&args[1..]).unwrap_or_else(|e| {
+        println!("Error: {}", e);
+        process::exit(1);
+    });
+
+    if matches.opt_present("h") {
+        print!(
+            "{}",
+            opts.usage(&format!("Usage: {} -m MODEL [options]", args[0]))
+        );
+        return;
+    }
+
+    if !matches.opt_present("m") {
+        println!("Please specify the trained model with -m!");
+        process::exit(1);
+    }
+
+    let model_file: String = matches.opt_get("m").unwrap().unwrap();
+    let num_sentences: usize = matches.opt_get_default("n", 10).unwrap_or_else(|e| {
+        eprintln!("Error: invalid --num-sentences: {}", e);
+        process::exit(1);
+    });
+    let seed: u64 = matches.opt_get_default("seed", 0).unwrap_or_else(|e| {
+        eprintln!("Error: invalid --seed: {}", e);
+        process::exit(1);
+    });
+    let temperature: f64 = matches
+        .opt_get_default("temperature", 1.0)
+        .unwrap_or_else(|e| {
+            eprintln!("Error: invalid --temperature: {}", e);
+            process::exit(1);
+        });
+    let max_words: usize = matches
+        .opt_get_default("max-words", 100)
+        .unwrap_or_else(|e| {
+            eprintln!("Error: invalid --max-words: {}", e);
+            process::exit(1);
+        });
+    let format = match matches.opt_str("format").as_ref().map(|f| f.as_str()) {
+        None | Some("plain") => OutputFormat::Plain,
+        Some("segmented") => OutputFormat::Segmented,
+        Some("json") => OutputFormat::Json,
+        Some(format) => {
+            eprintln!(
+                "Error: unknown --format {:?}, expected plain, segmented or json",
+                format
+            );
+            process::exit(1);
+        }
+    };
+
+    let mut model = Model::load(Path::new(&model_file)).unwrap_or_else(|e| {
+        eprintln!("Error: failed to load the model from {}: {}", model_file, e);
+        process::exit(1);
+    });
+    model.reseed(seed);
+
+    let stdout = io::stdout();
+    let mut writer = io::BufWriter::new(stdout.lock());
+    for _ in 0..num_sentences {
+        let sentence = model
+            .generate_sentence(temperature, max_words)
+            .unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            });
+        let line = match format {
+            OutputFormat::Plain => sentence.get_text(),
+            OutputFormat::Segmented => sentence.get_segmented_text(),
+            OutputFormat::Json => sentence.to_json(),
+        };
+        writeln!(writer, "{}", line).unwrap();
+    }
+    writer.flush().unwrap();
+}
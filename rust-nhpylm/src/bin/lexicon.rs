@@ -0,0 +1,116 @@
+extern crate getopts;
+extern crate rust_nhpylm;
+
+use std::env::args;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use getopts::Options;
+use std::process;
+
+use rust_nhpylm::{LexiconOrder, Model};
+
+fn main() {
+    let args: Vec<String> = args().collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help menu");
+    opts.optopt("m", "model", "Path to the trained model", "FILENAME");
+    opts.optopt(
+        "o",
+        "output",
+        "Path to the file to write the lexicon to. Writes to stdout if not given",
+        "FILENAME",
+    );
+    opts.optopt(
+        "",
+        "format",
+        "tsv for a header and one tab separated line per word, or json for one line of JSON per word",
+        "tsv",
+    );
+    opts.optopt(
+        "",
+        "sort",
+        "Sort the words by count, tables, probability, base-probability or length, from the highest down, or alphabetically by word",
+        "count",
+    );
+    opts.optopt(
+        "",
+        "min-count",
+        "Only list the words that occur at least this many times in the training segmentation",
+        "1",
+    );
+
+    let matches = opts.parse(&args[1..]).unwrap_or_else(|e| {
+        println!("Error: {}", e);
+        process::exit(1);
+    });
+
+    if matches.opt_present("h") {
+        print!(
+            "{}",
+            opts.usage(&format!("Usage: {} -m MODEL [options]", args[0]))
+        );
+        return;
+    }
+
+    if !matches.opt_present("m") {
+        println!("Please specify the trained model with -m!");
+        process::exit(1);
+    }
+
+    let model_file: String = matches.opt_get("m").unwrap().unwrap();
+    let json = match matches.opt_str("format").as_ref().map(|f| f.as_str()) {
+        None | Some("tsv") => false,
+        Some("json") => true,
+        Some(format) => {
+            eprintln!("Error: unknown --format {:?}, expected tsv or json", format);
+            process::exit(1);
+        }
+    };
+    let order =
+        LexiconOrder::from_spec(&matches.opt_get_default("sort", "count".to_owned()).unwrap())
+            .unwrap_or_else(|e| {
+                eprintln!("Error: invalid --sort: {}", e);
+                process::exit(1);
+            });
+    let min_count: usize = matches.opt_get_default("min-count", 1).unwrap_or_else(|e| {
+        eprintln!("Error: invalid --min-count: {}", e);
+        process::exit(1);
+    });
+
+    let mut model = Model::load(Path::new(&model_file)).unwrap_or_else(|e| {
+        eprintln!("Error: failed to load the model from {}: {}", model_file, e);
+        process::exit(1);
+    });
+
+    let mut lexicon = model.lexicon().unwrap_or_else(|e| {
+        eprintln!("Error: failed to build the lexicon: {}", e);
+        process::exit(1);
+    });
+    lexicon
+        .entries
+        .retain(|entry| entry.token_count >= min_count);
+    lexicon.sort(order);
+
+    let mut writer: Box<dyn Write> = match matches.opt_str("o") {
+        Some(output_file) => Box::new(io::BufWriter::new(
+            File::create(&output_file).unwrap_or_else(|e| {
+                eprintln!("Error: failed to create {}: {}", output_file, e);
+                process::exit(1);
+            }),
+        )),
+        None => Box::new(io::BufWriter::new(io::stdout())),
+    };
+    let result = if json {
+        lexicon.write_json(&mut writer)
+    } else {
+        lexicon.write_tsv(&mut writer)
+    };
+    if let Err(e) = result.and_then(|_| writer.flush().map_err(|e| e.into())) {
+        eprintln!("Error: failed to write the lexicon: {}", e);
+        process::exit(1);
+    }
+}
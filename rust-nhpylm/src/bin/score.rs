@@ -0,0 +1,156 @@
+extern crate getopts;
+extern crate rust_nhpylm;
+
+use std::env::args;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+
+use getopts::Options;
+use std::process;
+
+use rust_nhpylm::Model;
+
+struct Totals {
+    num_lines: usize,
+    num_characters: usize,
+    num_unknown_characters: usize,
+    log_likelihood: f64,
+}
+
+fn score_lines<R: BufRead, W: Write>(
+    model: &mut Model,
+    reader: R,
+    writer: &mut W,
+    json: bool,
+    totals: &mut Totals,
+) -> io::Result<()> {
+    if !json {
+        writeln!(
+            writer,
+            "num_characters\tnum_unknown_characters\tlog_likelihood\tcross_entropy\tviterbi_log_probability\tsegmentation"
+        )?;
+    }
+    for line in reader.lines() {
+        let (sentence_chars, constraints) = model.read_line(&line?, None);
+        let score = model
+            .score_sentence_with_constraints(sentence_chars, constraints)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        totals.num_lines += 1;
+        totals.num_characters += score.num_characters;
+        totals.num_unknown_characters += score.num_unknown_characters;
+        totals.log_likelihood += score.log_likelihood;
+        if json {
+            writeln!(writer, "{}", score.to_json())?;
+        } else {
+            writeln!(
+                writer,
+                "{}\t{}\t{:.4}\t{:.4}\t{:.4}\t{}",
+                score.num_characters,
+                score.num_unknown_characters,
+                score.log_likelihood,
+                score.cross_entropy,
+                score.viterbi_log_probability,
+                score.words.join("  ")
+            )?;
+        }
+    }
+    writer.flush()
+}
+
+fn main() {
+    let args: Vec<String> = args().collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help menu");
+    opts.optopt("m", "model", "Path to the trained model", "FILENAME");
+    opts.optopt(
+        "f",
+        "file",
+        "Path to the file to score. Reads from stdin if not given",
+        "FILENAME",
+    );
+    opts.optopt(
+        "",
+        "format",
+        "tsv for a header and one tab separated line per input line, or json for one line of JSON per input line",
+        "tsv",
+    );
+
+    let matches = opts.parse(&args[1..]).unwrap_or_else(|e| {
+        println!("Error: {}", e);
+        process::exit(1);
+    });
+
+    if matches.opt_present("h") {
+        print!(
+            "{}",
+            opts.usage(&format!("Usage: {} -m MODEL [options]", args[0]))
+        );
+        return;
+    }
+
+    if !matches.opt_present("m") {
+        println!("Please specify the trained model with -m!");
+        process::exit(1);
+    }
+
+    let model_file: String = matches.opt_get("m").unwrap().unwrap();
+    let json = match matches.opt_str("format").as_ref().map(|f| f.as_str()) {
+        None | Some("tsv") => false,
+        Some("json") => true,
+        Some(format) => {
+            eprintln!("Error: unknown --format {:?}, expected tsv or json", format);
+            process::exit(1);
+        }
+    };
+
+    let mut model = Model::load(Path::new(&model_file)).unwrap_or_else(|e| {
+        eprintln!("Error: failed to load the model from {}: {}", model_file, e);
+        process::exit(1);
+    });
+
+    let stdout = io::stdout();
+    let mut writer = io::BufWriter::new(stdout.lock());
+    let mut totals = Totals {
+        num_lines: 0,
+        num_characters: 0,
+        num_unknown_characters: 0,
+        log_likelihood: 0.0,
+    };
+
+    let result = if matches.opt_present("f") {
+        let input_file: String = matches.opt_get("f").unwrap().unwrap();
+        match File::open(&input_file) {
+            Ok(file) => score_lines(
+                &mut model,
+                BufReader::new(file),
+                &mut writer,
+                json,
+                &mut totals,
+            ),
+            Err(e) => Err(e),
+        }
+    } else {
+        let stdin = io::stdin();
+        let reader = stdin.lock();
+        score_lines(&mut model, reader, &mut writer, json, &mut totals)
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+
+    // The summary goes to stderr, so that the output stays aligned with the input.
+    eprintln!(
+        "Scored {} lines with {} characters, {} of them unknown. Log-likelihood {:.3}, cross-entropy {:.4} bits per character",
+        totals.num_lines,
+        totals.num_characters,
+        totals.num_unknown_characters,
+        totals.log_likelihood,
+        -totals.log_likelihood / totals.num_characters.max(1) as f64 / ::std::f64::consts::LN_2
+    );
+}
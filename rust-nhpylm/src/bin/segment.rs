@@ -0,0 +1,264 @@
+extern crate getopts;
+extern crate rust_nhpylm;
+
+use std::env::args;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+
+use getopts::Options;
+use std::process;
+
+use rust_nhpylm::{Dictionary, Model, Normalizer, SegmentationAnalysis, SegmentationConstraints};
+
+struct OutputOptions {
+    separator: String,
+    // With either of these, each line is written as a `SegmentationAnalysis` in JSON.
+    nbest: Option<usize>,
+    boundary_probabilities: bool,
+    // The number of samples for the minimum Bayes risk segmentation, which then replaces the Viterbi segmentation.
+    mbr_samples: Option<usize>,
+}
+
+fn best_segmentation(
+    model: &mut Model,
+    sentence_chars: Vec<char>,
+    constraints: SegmentationConstraints,
+    output: &OutputOptions,
+) -> io::Result<Vec<String>> {
+    match output.mbr_samples {
+        Some(num_samples) => {
+            model.mbr_segment_sentence_with_constraints(sentence_chars, constraints, num_samples)
+        }
+        None => model.segment_sentence_with_constraints(sentence_chars, constraints),
+    }
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
+
+fn segment_lines<R: BufRead, W: Write>(
+    model: &mut Model,
+    reader: R,
+    writer: &mut W,
+    output: &OutputOptions,
+    dictionary: Option<&Dictionary>,
+) -> io::Result<()> {
+    for line in reader.lines() {
+        // The same as in `Corpus::read_corpus`, the line is normalized and the model never sees any whitespace, only the hard boundaries it may stand for.
+        let (sentence_chars, constraints) = model.read_line(&line?, dictionary);
+        if output.nbest.is_some() || output.boundary_probabilities {
+            let analysis = SegmentationAnalysis {
+                words: best_segmentation(
+                    model,
+                    sentence_chars.clone(),
+                    constraints.clone(),
+                    output,
+                )?,
+                nbest: match output.nbest {
+                    Some(n) => Some(
+                        model
+                            .nbest_segmentations(sentence_chars.clone(), constraints.clone(), n)
+                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?,
+                    ),
+                    None => None,
+                },
+                boundary_probabilities: if output.boundary_probabilities {
+                    Some(
+                        model
+                            .boundary_probabilities(sentence_chars, constraints)
+                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?,
+                    )
+                } else {
+                    None
+                },
+            };
+            writeln!(writer, "{}", analysis.to_json())?;
+            continue;
+        }
+
+        let words = best_segmentation(model, sentence_chars, constraints, output)?;
+        // Still output the empty line so that the output stays aligned with the input.
+        if words.is_empty() {
+            writeln!(writer)?;
+            continue;
+        }
+        writeln!(writer, "{}", words.join(&output.separator))?;
+    }
+    writer.flush()
+}
+
+fn main() {
+    let args: Vec<String> = args().collect();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help menu");
+    opts.optopt("m", "model", "Path to the trained model", "FILENAME");
+    opts.optopt(
+        "f",
+        "file",
+        "Path to the file to segment. Reads from stdin if not given",
+        "FILENAME",
+    );
+    opts.optopt(
+        "s",
+        "separator",
+        "The string put between two words. Defaults to two spaces, as in the PKU and MSR gold files",
+        "SEPARATOR",
+    );
+
+    opts.optopt(
+        "",
+        "normalize",
+        "Comma separated normalization steps (nfkc, halfwidth, strip-control) applied to the input instead of the ones the model was trained with",
+        "STEPS",
+    );
+    opts.optmulti(
+        "",
+        "mapping",
+        "Path to a table of character replacements applied after the --normalize steps. Only used together with --normalize",
+        "FILENAME",
+    );
+    opts.optopt(
+        "",
+        "dictionary",
+        "Path to a word list whose entries are never split: one word per line, !WORD for a word that must not be produced, and WO|RD for a boundary that must be kept",
+        "FILENAME",
+    );
+    opts.optopt(
+        "",
+        "nbest",
+        "Also find the N most probable segmentations of each line with their log probabilities. Writes each line as a line of JSON",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "boundary-probabilities",
+        "Also compute the probability that a word ends after each character of a line, summed over all its segmentations. Writes each line as a line of JSON",
+    );
+    opts.optopt(
+        "",
+        "mbr",
+        "Instead of the single most probable segmentation, output the one with the minimum Bayes risk under the boundary F-measure, estimated from N segmentations sampled from the model",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "seed",
+        "Seed of the random number generator that draws the samples of --mbr",
+        "0",
+    );
+    opts.optflag(
+        "",
+        "print-normalizer",
+        "Print the normalization, the hard boundaries and the word types the model was trained with and exit",
+    );
+
+    let matches = opts.parse(&args[1..]).unwrap_or_else(|e| {
+        println!("Error: {}", e);
+        process::exit(1);
+    });
+
+    if matches.opt_present("h") {
+        print!(
+            "{}",
+            opts.usage(&format!("Usage: {} -m MODEL [options]", args[0]))
+        );
+        return;
+    }
+
+    if !matches.opt_present("m") {
+        println!("Please specify the trained model with -m!");
+        process::exit(1);
+    }
+
+    let model_file: String = matches.opt_get("m").unwrap().unwrap();
+    let output = OutputOptions {
+        separator: matches.opt_get_default("s", "  ".to_owned()).unwrap(),
+        nbest: matches.opt_get("nbest").unwrap_or_else(|e| {
+            eprintln!("Error: invalid --nbest: {}", e);
+            process::exit(1);
+        }),
+        boundary_probabilities: matches.opt_present("boundary-probabilities"),
+        mbr_samples: matches.opt_get("mbr").unwrap_or_else(|e| {
+            eprintln!("Error: invalid --mbr: {}", e);
+            process::exit(1);
+        }),
+    };
+    if output.mbr_samples == Some(0) {
+        eprintln!("Error: --mbr needs at least one sample");
+        process::exit(1);
+    }
+    let seed: u64 = matches.opt_get_default("seed", 0).unwrap_or_else(|e| {
+        eprintln!("Error: invalid --seed: {}", e);
+        process::exit(1);
+    });
+
+    let mut model = Model::load(Path::new(&model_file)).unwrap_or_else(|e| {
+        eprintln!("Error: failed to load the model from {}: {}", model_file, e);
+        process::exit(1);
+    });
+    model.reseed(seed);
+
+    if matches.opt_present("print-normalizer") {
+        println!("{}", model.normalizer);
+        println!("Hard boundaries: {}", model.boundary_rules);
+        println!("Word types: {}", model.get_word_type_scheme().get_name());
+        return;
+    }
+
+    if let Some(spec) = matches.opt_str("normalize") {
+        let normalizer = Normalizer::from_spec(&spec).and_then(|mut normalizer| {
+            for mapping_file in matches.opt_strs("mapping") {
+                normalizer.add_mapping_table(Path::new(&mapping_file))?;
+            }
+            Ok(normalizer)
+        });
+        model.normalizer = normalizer.unwrap_or_else(|e| {
+            eprintln!("Error: failed to set up the normalization: {}", e);
+            process::exit(1);
+        });
+    }
+
+    let dictionary = matches.opt_str("dictionary").map(|dictionary_file| {
+        Dictionary::read(Path::new(&dictionary_file), &model.normalizer).unwrap_or_else(|e| {
+            eprintln!(
+                "Error: failed to read the dictionary {}: {}",
+                dictionary_file, e
+            );
+            process::exit(1);
+        })
+    });
+
+    let stdout = io::stdout();
+    let mut writer = io::BufWriter::new(stdout.lock());
+
+    let result = if matches.opt_present("f") {
+        let input_file: String = matches.opt_get("f").unwrap().unwrap();
+        match File::open(&input_file) {
+            Ok(file) => segment_lines(
+                &mut model,
+                BufReader::new(file),
+                &mut writer,
+                &output,
+                dictionary.as_ref(),
+            ),
+            Err(e) => Err(e),
+        }
+    } else {
+        let stdin = io::stdin();
+        let reader = stdin.lock();
+        segment_lines(
+            &mut model,
+            reader,
+            &mut writer,
+            &output,
+            dictionary.as_ref(),
+        )
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
@@ -4,33 +4,213 @@ extern crate rust_nhpylm;
 
 use either::*;
 use std::env::args;
+use std::fmt::Display;
 use std::fs;
-use std::path::Path;
-use std::time::SystemTime;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
 
-use getopts::Options;
+use getopts::{Matches, Options};
+use std::collections::HashSet;
 use std::process;
 
-use rust_nhpylm::def::*;
-use rust_nhpylm::{Corpus, Dataset, Model, Trainer};
+use rust_nhpylm::evaluation::read_dictionary;
+use rust_nhpylm::{
+    get_word_type_scheme, AnnealingSchedule, BoundaryRules, Corpus, Dataset, DatasetSplit,
+    Dictionary, Model, Normalizer, Result, Trainer, TrainingConfig, WordLengthCorrection,
+    WORD_TYPE_SCHEME_NAMES,
+};
+
+fn exit_with_error<E: Display>(message: &str, e: E) -> ! {
+    println!("Error: {}: {}", message, e);
+    process::exit(1);
+}
+
+/// Replaces `value` with the option if it was given on the command line.
+fn override_option<T>(matches: &Matches, name: &str, value: &mut T)
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let option = matches
+        .opt_get(name)
+        .unwrap_or_else(|e| exit_with_error(&format!("invalid value for option {}", name), e));
+    if let Some(option) = option {
+        *value = option;
+    }
+}
+
+fn override_optional_option(matches: &Matches, name: &str, value: &mut Option<String>) {
+    if let Some(option) = matches.opt_str(name) {
+        *value = Some(option);
+    }
+}
+
+fn override_flag(matches: &Matches, name: &str, value: &mut bool) {
+    if matches.opt_present(name) {
+        *value = true;
+    }
+}
+
+fn override_multi_option(matches: &Matches, name: &str, values: &mut Vec<String>) {
+    let options = matches.opt_strs(name);
+    if !options.is_empty() {
+        *values = options;
+    }
+}
+
+/// The options given on the command line take precedence over the configuration file.
+fn override_config(matches: &Matches, config: &mut TrainingConfig) {
+    override_optional_option(matches, "load-model", &mut config.load_model);
+    override_optional_option(matches, "resume", &mut config.resume);
+
+    let corpus = &mut config.corpus;
+    override_optional_option(matches, "file", &mut corpus.file);
+    override_optional_option(matches, "dir", &mut corpus.dir);
+    override_flag(matches, "gold", &mut corpus.gold);
+    override_flag(matches, "stream", &mut corpus.stream);
+    override_option(matches, "train-dev-split", &mut corpus.train_dev_split);
+    override_multi_option(matches, "supervised", &mut corpus.supervised);
+    override_option(
+        matches,
+        "supervised-proportion",
+        &mut corpus.supervised_proportion,
+    );
+    override_option(matches, "normalize", &mut corpus.normalize);
+    override_multi_option(matches, "mapping", &mut corpus.mapping);
+    override_flag(
+        matches,
+        "whitespace-boundaries",
+        &mut corpus.whitespace_boundaries,
+    );
+    override_optional_option(matches, "boundary-symbols", &mut corpus.boundary_symbols);
+    override_optional_option(matches, "dictionary", &mut corpus.dictionary);
+
+    let model = &mut config.model;
+    override_option(matches, "order", &mut model.order);
+    override_option(matches, "max-word-length", &mut model.max_word_length);
+    override_option(matches, "word-types", &mut model.word_types);
+    override_option(matches, "lambda-a", &mut model.lambda_a);
+    override_option(matches, "lambda-b", &mut model.lambda_b);
+    override_option(matches, "beta-stop", &mut model.beta_stop);
+    override_option(matches, "beta-pass", &mut model.beta_pass);
+
+    let sampler = &mut config.sampler;
+    override_option(matches, "seed", &mut sampler.seed);
+    override_option(matches, "threads", &mut sampler.threads);
+    override_option(matches, "batch-size", &mut sampler.batch_size);
+    override_flag(
+        matches,
+        "always-accept-new-segmentation",
+        &mut sampler.always_accept_new_segmentation,
+    );
+    override_option(matches, "annealing", &mut sampler.annealing);
+    override_option(
+        matches,
+        "length-correction-start",
+        &mut sampler.length_correction_start,
+    );
+    override_option(
+        matches,
+        "length-correction-samples",
+        &mut sampler.length_correction_samples,
+    );
+    override_option(
+        matches,
+        "length-correction-early-stop",
+        &mut sampler.length_correction_early_stop,
+    );
+    override_flag(
+        matches,
+        "exact-length-correction",
+        &mut sampler.exact_length_correction,
+    );
+
+    let schedule = &mut config.schedule;
+    override_option(matches, "epochs", &mut schedule.epochs);
+    override_option(matches, "target-directory", &mut schedule.target_directory);
+    override_option(
+        matches,
+        "checkpoint-interval",
+        &mut schedule.checkpoint_interval,
+    );
+    override_option(
+        matches,
+        "checkpoint-minutes",
+        &mut schedule.checkpoint_minutes,
+    );
+    override_option(matches, "keep-checkpoints", &mut schedule.keep_checkpoints);
+    override_option(matches, "mbr-epochs", &mut schedule.mbr_epochs);
+    override_option(matches, "eval-interval", &mut schedule.eval_interval);
+    override_optional_option(matches, "eval-dictionary", &mut schedule.eval_dictionary);
+    override_option(matches, "report-interval", &mut schedule.report_interval);
+    override_option(matches, "report-samples", &mut schedule.report_samples);
+    override_optional_option(matches, "report-log", &mut schedule.report_log);
+}
+
+fn read_file_into_corpus(
+    path: &Path,
+    corpus: &mut Corpus,
+    with_gold_segmentation: bool,
+) -> Result<()> {
+    if with_gold_segmentation {
+        corpus.read_corpus_with_gold_segmentation(path)
+    } else {
+        corpus.read_corpus(path)
+    }
+}
 
 // Either Left(file) or Right(dir).
-fn build_corpus(path: Either<&str, &str>) -> Corpus {
-    // fn build_corpus(input_file_name: &str) -> Corpus {
-    let mut corpus = Corpus::new();
+fn list_corpus_files(path: Either<&str, &str>) -> Result<Vec<PathBuf>> {
     match path {
-        Left(input_file_name) => {
-            let path = Path::new(input_file_name).to_path_buf();
-            corpus.read_corpus(&path);
-        }
+        Left(input_file_name) => Ok(vec![Path::new(input_file_name).to_path_buf()]),
         Right(input_dir_name) => {
-            for file in fs::read_dir(input_dir_name).unwrap() {
-                let file = file.unwrap().path();
-                corpus.read_corpus(&file);
+            let mut files = Vec::new();
+            for file in fs::read_dir(input_dir_name)? {
+                files.push(file?.path());
             }
+            Ok(files)
+        }
+    }
+}
+
+/// Writes one sentence per line, with the words separated by two spaces as in the PKU and MSR gold files.
+fn write_segmentations(path: &Path, segmentations: &[Vec<String>]) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(File::create(path)?);
+    for words in segmentations {
+        writeln!(writer, "{}", words.join("  "))?;
+    }
+    writer.flush()
+}
+
+fn checkpoint_path(directory: &Path, epoch: usize) -> PathBuf {
+    directory.join(format!("checkpoint-{:08}.ckpt", epoch))
+}
+
+/// Deletes all but the `num_to_keep` latest checkpoints in `directory`.
+fn rotate_checkpoints(directory: &Path, num_to_keep: usize) -> io::Result<()> {
+    let mut checkpoints: Vec<(usize, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let epoch = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("checkpoint-"))
+            .and_then(|name| name.strip_suffix(".ckpt"))
+            .and_then(|epoch| epoch.parse::<usize>().ok());
+        if let Some(epoch) = epoch {
+            checkpoints.push((epoch, path));
         }
     }
-    corpus
+    checkpoints.sort();
+    let num_to_remove = checkpoints.len().saturating_sub(num_to_keep);
+    for (_, path) in &checkpoints[..num_to_remove] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
 }
 
 // fn read_file_into_corpus(path: &str, corpus: &mut Corpus) {
@@ -51,23 +231,189 @@ fn main() {
 
     let mut opts = Options::new();
     opts.optflag("h", "help", "Print this help menu");
+    opts.optopt(
+        "",
+        "config",
+        "Path to a TOML file (or a JSON file ending in .json) with the settings of the training, grouped into the sections corpus, model, sampler and schedule, with the same names as the options. The options given on the command line take precedence",
+        "FILENAME",
+    );
     opts.optopt("f", "file", "Path to the training file", "FILENAME");
     opts.optopt("d", "dir", "Path to the training directory", "DIRNAME");
+    opts.optopt(
+        "",
+        "normalize",
+        "Comma separated normalization steps applied to the corpus before training: nfkc, halfwidth (full-width to half-width ASCII) and strip-control (control and zero-width characters). The model remembers them for the segmentation. Ignored when continuing from a saved model",
+        "none",
+    );
+    opts.optmulti(
+        "",
+        "mapping",
+        "Path to a table of character replacements, one character, a tab and its replacement per line, applied after the --normalize steps. Can be given multiple times",
+        "FILENAME",
+    );
+    opts.optflag(
+        "",
+        "whitespace-boundaries",
+        "Treat the whitespace in the corpus as word boundaries that are never crossed, instead of ignoring it. The model applies the same rule when segmenting. Can't be combined with -g, whose whitespace is the gold segmentation",
+    );
+    opts.optopt(
+        "",
+        "boundary-symbols",
+        "Punctuation that always forms a word on its own, e.g. ，。！？, or all for every symbol. The model applies the same rule when segmenting",
+        "SYMBOLS",
+    );
+    opts.optopt(
+        "",
+        "dictionary",
+        "Path to a word list that constrains the segmentation of the training sentences: one word per line that is never split, !WORD for a word that must not be produced, and WO|RD for a boundary that must be kept. Pass it to segment as well. Ignored when continuing from a saved model",
+        "FILENAME",
+    );
+    opts.optflag(
+        "",
+        "stream",
+        "Read the corpus given with -f or -d line by line while building the training sentences, instead of loading it into memory first. The train/dev split is then only approximately --train-dev-split",
+    );
+    opts.optopt(
+        "m",
+        "load-model",
+        "Path to a saved model to continue training from",
+        "FILENAME",
+    );
+    opts.optmulti(
+        "",
+        "supervised",
+        "Path to an already segmented file whose sentences are used as supervised training data, e.g. icwb2-data/gold/pku_training_words.utf8. Can be given multiple times",
+        "FILENAME",
+    );
+    opts.optopt(
+        "",
+        "resume",
+        "Path to a checkpoint to continue an interrupted training from. The corpus and model options are then ignored",
+        "FILENAME",
+    );
+    opts.optopt(
+        "",
+        "checkpoint-interval",
+        "Write a checkpoint to the target directory every N epochs. 0 disables it",
+        "0",
+    );
+    opts.optopt(
+        "",
+        "checkpoint-minutes",
+        "Write a checkpoint to the target directory whenever M minutes have passed since the last one. 0 disables it",
+        "0",
+    );
+    opts.optopt(
+        "",
+        "keep-checkpoints",
+        "Number of the latest checkpoints to keep. 0 keeps all of them",
+        "3",
+    );
+    opts.optopt(
+        "",
+        "mbr-epochs",
+        "Collect the segmentations of the training sentences over the last K epochs, and write the segmentation with the minimum Bayes risk under the boundary F-measure of each sentence to train.mbr.txt in the target directory. 0 disables it",
+        "0",
+    );
+    opts.optopt(
+        "",
+        "annealing",
+        "Temperature schedule of the blocked Gibbs sampling, which flattens the segmentation posterior early in the training: none, linear:START:EPOCHS (from START down to 1 over EPOCHS epochs), exp:START:RATE (multiplied by RATE after every epoch) or steps:EPOCH=T,... (T from EPOCH on, the last T must be 1)",
+        "none",
+    );
+    opts.optopt(
+        "",
+        "length-correction-start",
+        "First epoch after which the distribution of the word lengths of the character model is re-estimated, to be replaced by a Poisson distribution. 0 keeps it uniform",
+        "4",
+    );
+    opts.optopt(
+        "",
+        "length-correction-samples",
+        "Number of words sampled from the character model to estimate the distribution of the word lengths",
+        "20000",
+    );
+    opts.optopt(
+        "",
+        "length-correction-early-stop",
+        "Stop sampling words for the distribution of the word lengths once every length was sampled this many times",
+        "10",
+    );
+    opts.optflag(
+        "",
+        "exact-length-correction",
+        "Compute the distribution of the word lengths of the character model exactly instead of sampling words",
+    );
+    opts.optopt(
+        "",
+        "supervised-proportion",
+        "The largest share of supervised sentences among all the training sentences. 1.0 uses all supervised sentences",
+        "1.0",
+    );
 
     opts.optflag(
         "a",
         "always-accept-new-segmentation",
         "Always accept new segmentation",
     );
+    opts.optflag(
+        "g",
+        "gold",
+        "The corpus is already segmented with whitespace. The model is still trained on the unsegmented text, but its segmentation of the dev sentences is evaluated against the one in the corpus",
+    );
+    opts.optopt(
+        "",
+        "eval-interval",
+        "Evaluate on the dev sentences every N epochs. Requires --gold. 0 disables the evaluation",
+        "10",
+    );
+    opts.optopt(
+        "",
+        "eval-dictionary",
+        "Word list used to tell in-vocabulary from OOV words in the evaluation, e.g. icwb2-data/gold/pku_training_words.utf8",
+        "FILENAME",
+    );
+
+    opts.optopt(
+        "",
+        "report-interval",
+        "Report the perplexity, log-likelihood, acceptance rate and model size every N epochs. 0 disables the reports",
+        "10",
+    );
+    opts.optopt(
+        "",
+        "report-samples",
+        "Number of dev sentences whose segmentation is shown in each report",
+        "5",
+    );
+    opts.optopt(
+        "",
+        "report-log",
+        "Also append each report as a line of JSON to this file",
+        "FILENAME",
+    );
+
+    opts.optopt(
+        "t",
+        "threads",
+        "Number of threads for the blocked Gibbs sampling. With more than 1, the sentences are segmented in parallel in batches",
+        "1",
+    );
+    opts.optopt(
+        "",
+        "batch-size",
+        "Number of sentences segmented in parallel against the same snapshot of the model. Only used with --threads",
+        "256",
+    );
 
     opts.optopt("s", "seed", "Seed for the training", "1");
     opts.optopt("e", "epochs", "Total epochs of training", "100000");
-    // opts.optopt(
-    //     "",
-    //     "target-directory",
-    //     "Directory to save the trained model to",
-    //     "out",
-    // );
+    opts.optopt(
+        "",
+        "target-directory",
+        "Directory to save the trained model to",
+        "out",
+    );
     opts.optopt(
         "p",
         "train-dev-split",
@@ -89,95 +435,375 @@ fn main() {
         "The beta-pass parameter for character-level HPYLM",
         "0.85",
     );
+    opts.optopt(
+        "",
+        "word-types",
+        &format!(
+            "The word types with their own word length distributions, one of {}. Ignored when continuing from a saved model",
+            WORD_TYPE_SCHEME_NAMES.join(", ")
+        ),
+        "japanese",
+    );
     opts.optopt(
         "l",
         "max-word-length",
         "Maximum allowed length of a word",
         "16",
     );
+    opts.optopt(
+        "n",
+        "order",
+        "Order of the word n-gram model, 2 for bigram or 3 for trigram. Ignored when continuing from a saved model",
+        "3",
+    );
 
     let matches = opts.parse(&args[1..]).unwrap_or_else(|e| {
         println!("Error: {}", e);
         process::exit(1);
     });
 
-    if !matches.opts_present(&["f".to_owned(), "d".to_owned()]) {
-        println!("Please specify either the corpus file with -f or the corpus directory with -d!");
+    if matches.opt_present("h") {
+        print!(
+            "{}",
+            opts.usage(&format!("Usage: {} -f FILE [options]", args[0]))
+        );
+        return;
+    }
+
+    let mut config = match matches.opt_str("config") {
+        Some(config_file) => TrainingConfig::load(Path::new(&config_file)).unwrap_or_else(|e| {
+            exit_with_error(
+                &format!("failed to read the configuration {}", config_file),
+                e,
+            )
+        }),
+        None => TrainingConfig::default(),
+    };
+    override_config(&matches, &mut config);
+
+    if config.corpus.file.is_none()
+        && config.corpus.dir.is_none()
+        && config.corpus.supervised.is_empty()
+        && config.load_model.is_none()
+        && config.resume.is_none()
+    {
+        println!("Please specify either the corpus file with -f, the corpus directory with -d or supervised files with --supervised, a saved model with -m, or a checkpoint with --resume!");
         process::exit(1);
     }
 
-    if matches.opt_present("f") && matches.opt_present("d") {
+    if config.corpus.file.is_some() && config.corpus.dir.is_some() {
         println!("Please specify either the corpus file or directory, but not both!");
         process::exit(1);
     }
 
-    // let target_directory = matches.opt_get_default("target-directory", "out".to_owned());
-
-    let seed = matches.opt_get_default("s", 1).unwrap();
-    let epoches = matches.opt_get_default("e", 100000).unwrap();
-    let split = matches.opt_get_default("p", 0.9).unwrap();
-    let lambda_a = matches
-        .opt_get_default("lambda-a", INITIAL_LAMBDA_A)
-        .unwrap();
-    let lambda_b = matches
-        .opt_get_default("lambda-b", INITIAL_LAMBDA_B)
-        .unwrap();
-    let beta_stop = matches
-        .opt_get_default("beta-stop", CHPYLM_BETA_STOP)
-        .unwrap();
-    let beta_pass = matches
-        .opt_get_default("beta-stop", CHPYLM_BETA_PASS)
-        .unwrap();
-    let max_word_length = matches.opt_get_default("max-word-length", 16).unwrap();
-
-    let always_accept_new_segmentation = if matches.opt_present("a") {
-        true
-    } else {
-        false
+    let target_directory = config.schedule.target_directory.clone();
+
+    let seed = config.sampler.seed;
+    let epoches = config.schedule.epochs;
+    let split = config.corpus.train_dev_split;
+    let max_word_length = config.model.max_word_length;
+    let whpylm_order = config.model.order;
+    let supervised_proportion = config.corpus.supervised_proportion;
+
+    let num_threads = config.sampler.threads;
+    let batch_size = config.sampler.batch_size;
+
+    let checkpoint_interval = config.schedule.checkpoint_interval;
+    let checkpoint_minutes = config.schedule.checkpoint_minutes;
+    let num_checkpoints_to_keep = config.schedule.keep_checkpoints;
+    let mbr_epochs = config.schedule.mbr_epochs;
+    let word_length_correction = WordLengthCorrection {
+        start_epoch: config.sampler.length_correction_start,
+        exact: config.sampler.exact_length_correction,
+        num_samples: config.sampler.length_correction_samples,
+        early_stopping_threshold: config.sampler.length_correction_early_stop,
+    };
+    let annealing_schedule = AnnealingSchedule::from_spec(&config.sampler.annealing)
+        .unwrap_or_else(|e| exit_with_error("failed to set up the annealing", e));
+    let word_type_scheme = get_word_type_scheme(&config.model.word_types)
+        .unwrap_or_else(|e| exit_with_error("failed to set up the word types", e));
+
+    let with_gold_segmentation = config.corpus.gold;
+    let eval_interval = config.schedule.eval_interval;
+    let report_interval = config.schedule.report_interval;
+    let report_samples = config.schedule.report_samples;
+    let mut report_log = config.schedule.report_log.as_ref().map(|report_log_file| {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(report_log_file)
+            .unwrap_or_else(|e| exit_with_error(&format!("failed to open {}", report_log_file), e))
+    });
+    let eval_dictionary = match config.schedule.eval_dictionary {
+        None => HashSet::new(),
+        Some(ref dictionary_file) => {
+            read_dictionary(Path::new(dictionary_file)).unwrap_or_else(|e| {
+                exit_with_error(
+                    &format!("failed to read the dictionary {}", dictionary_file),
+                    e,
+                )
+            })
+        }
     };
 
-    let corpus = if matches.opt_present("f") {
-        let input_file: String = matches.opt_get("f").unwrap().unwrap();
-        build_corpus(Left(&input_file))
+    let always_accept_new_segmentation = config.sampler.always_accept_new_segmentation;
+
+    let (mut trainer, num_finished_epochs) = if let Some(ref checkpoint_file) = config.resume {
+        let (trainer, epoch) = Trainer::load_checkpoint(Path::new(&checkpoint_file))
+            .unwrap_or_else(|e| {
+                exit_with_error(
+                    &format!("failed to load the checkpoint {}", checkpoint_file),
+                    e,
+                )
+            });
+        println!("Resuming training after epoch {}", epoch);
+        (trainer, epoch)
+    } else if let Some(ref model_file) = config.load_model {
+        let mut model = Model::load(Path::new(&model_file)).unwrap_or_else(|e| {
+            exit_with_error(&format!("failed to load the model from {}", model_file), e)
+        });
+        model.reseed(seed);
+        println!("Normalization: {}", model.normalizer);
+        println!("Hard boundaries: {}", model.boundary_rules);
+        println!("Word types: {}", model.get_word_type_scheme().get_name());
+        println!(
+            "Continuing training with {} saved train sentences",
+            model.train_sentences.len()
+        );
+        (
+            Trainer::new_from_saved_model(model, always_accept_new_segmentation),
+            0,
+        )
     } else {
-        let input_dir: String = matches.opt_get("d").unwrap().unwrap();
-        build_corpus(Right(&input_dir))
+        let input_files = if let Some(ref input_file) = config.corpus.file {
+            list_corpus_files(Left(input_file))
+        } else if let Some(ref input_dir) = config.corpus.dir {
+            list_corpus_files(Right(input_dir))
+        } else {
+            Ok(Vec::new())
+        }
+        .unwrap_or_else(|e| exit_with_error("failed to list the corpus files", e));
+
+        let mut normalizer = Normalizer::from_spec(&config.corpus.normalize)
+            .unwrap_or_else(|e| exit_with_error("failed to set up the normalization", e));
+        for mapping_file in &config.corpus.mapping {
+            normalizer
+                .add_mapping_table(Path::new(&mapping_file))
+                .unwrap_or_else(|e| {
+                    exit_with_error(&format!("failed to read the mapping {}", mapping_file), e)
+                });
+        }
+        println!("Normalization: {}", normalizer);
+
+        let mut boundary_rules = BoundaryRules::new();
+        boundary_rules.whitespace = config.corpus.whitespace_boundaries;
+        if boundary_rules.whitespace && with_gold_segmentation {
+            exit_with_error(
+                "failed to set up the hard boundaries",
+                "--whitespace-boundaries would give away the gold segmentation of -g",
+            );
+        }
+        if let Some(ref symbols) = config.corpus.boundary_symbols {
+            boundary_rules
+                .set_symbols(symbols)
+                .unwrap_or_else(|e| exit_with_error("failed to set up the hard boundaries", e));
+        }
+        println!("Hard boundaries: {}", boundary_rules);
+
+        let mut corpus = Corpus::new();
+        if let Some(ref dictionary_file) = config.corpus.dictionary {
+            let dictionary = Dictionary::read(Path::new(dictionary_file), &normalizer)
+                .unwrap_or_else(|e| {
+                    exit_with_error(
+                        &format!("failed to read the dictionary {}", dictionary_file),
+                        e,
+                    )
+                });
+            println!("Dictionary: {}", dictionary);
+            corpus.dictionary = Some(dictionary);
+        }
+        corpus.normalizer = normalizer.clone();
+        corpus.boundary_rules = boundary_rules.clone();
+        let mut stream_files = Vec::new();
+        if config.corpus.stream {
+            stream_files = input_files;
+        } else {
+            for input_file in &input_files {
+                read_file_into_corpus(input_file, &mut corpus, with_gold_segmentation)
+                    .unwrap_or_else(|e| {
+                        exit_with_error(
+                            &format!("failed to read the corpus {}", input_file.display()),
+                            e,
+                        )
+                    });
+            }
+        }
+        for supervised_file in &config.corpus.supervised {
+            corpus
+                .read_segmented_corpus(Path::new(supervised_file))
+                .unwrap_or_else(|e| {
+                    exit_with_error(&format!("failed to read {}", supervised_file), e)
+                });
+        }
+
+        let dataset_split = DatasetSplit {
+            train_proportion: split,
+            supervised_proportion,
+            seed,
+        };
+        let dataset = Dataset::new_streaming(
+            corpus,
+            &stream_files,
+            with_gold_segmentation,
+            &dataset_split,
+        )
+        .unwrap_or_else(|e| exit_with_error("failed to build the dataset", e));
+        println!(
+            "Number of train sentences {}",
+            dataset.get_num_train_sentences()
+        );
+        println!("Number of supervised words {}", dataset.num_segmented_words);
+        println!(
+            "Number of dev sentences {}",
+            dataset.get_num_dev_sentences()
+        );
+
+        let model = Model::new_with_order(&dataset, whpylm_order, max_word_length, seed)
+            .and_then(|mut model| {
+                // Drawing the Poisson parameters anew would change the training of the same seed.
+                if word_type_scheme.get_name() != model.get_word_type_scheme().get_name() {
+                    model.set_word_type_scheme(word_type_scheme)?;
+                }
+                model.set_initial_a(config.model.lambda_a)?;
+                model.set_initial_b(config.model.lambda_b)?;
+                model.set_chpylm_beta_stop(config.model.beta_stop)?;
+                model.set_chpylm_beta_pass(config.model.beta_pass)?;
+                model.set_chpylm_epsilon(config.model.chpylm_epsilon)?;
+                model.set_whpylm_priors(config.model.whpylm_priors)?;
+                model.set_chpylm_priors(config.model.chpylm_priors)?;
+                model.normalizer = normalizer;
+                model.boundary_rules = boundary_rules;
+                Ok(model)
+            })
+            .unwrap_or_else(|e| exit_with_error("failed to create the model", e));
+        println!("Word types: {}", model.get_word_type_scheme().get_name());
+
+        (
+            Trainer::new(dataset, model, always_accept_new_segmentation),
+            0,
+        )
     };
 
-    let dataset = Dataset::new(corpus, split, seed);
-    println!(
-        "Number of train sentences {}",
-        dataset.get_num_train_sentences()
-    );
-    println!(
-        "Number of dev sentences {}",
-        dataset.get_num_dev_sentences()
-    );
+    trainer.set_num_threads(num_threads, batch_size);
+    trainer.set_word_length_correction(word_length_correction);
 
-    let mut model = Model::new(&dataset, max_word_length);
-    model.set_initial_a(lambda_a);
-    model.set_initial_b(lambda_b);
-    model.set_chpylm_beta_stop(beta_stop);
-    model.set_chpylm_beta_pass(beta_pass);
+    fs::create_dir_all(&target_directory).unwrap_or_else(|e| {
+        exit_with_error(
+            &format!("failed to create the directory {}", target_directory),
+            e,
+        )
+    });
+    let target_directory = Path::new(&target_directory);
+    let config_path = target_directory.join("config.toml");
+    config.save(&config_path).unwrap_or_else(|e| {
+        exit_with_error(
+            &format!("failed to save the configuration to {:?}", config_path),
+            e,
+        )
+    });
+    println!("Saved the configuration to {:?}", config_path);
 
-    let mut trainer = Trainer::new(dataset, model, always_accept_new_segmentation);
+    if annealing_schedule != AnnealingSchedule::Constant {
+        println!("Annealing: {}", annealing_schedule);
+    }
 
-    for epoch in 1..epoches + 1 {
+    let mut last_checkpoint_time = Instant::now();
+    for epoch in num_finished_epochs + 1..epoches + 1 {
         let start_time = SystemTime::now();
-        trainer.blocked_gibbs_sampling();
-        trainer.sample_hyperparameters();
-        trainer.sample_lambda();
-
-        if epoch > 3 {
-            trainer.update_p_k_given_chpylm_default();
+        trainer.set_temperature(annealing_schedule.get_temperature(epoch));
+        let result = trainer
+            .blocked_gibbs_sampling()
+            .and_then(|_| {
+                trainer.sample_hyperparameters();
+                trainer.sample_lambda()
+            })
+            .and_then(|_| trainer.correct_word_lengths(epoch));
+        if let Err(e) = result {
+            exit_with_error(&format!("training failed in epoch {}", epoch), e);
+        }
+        if epoch + mbr_epochs > epoches {
+            trainer.collect_boundary_samples();
         }
 
         let end_time = SystemTime::now();
         let duration = end_time.duration_since(start_time).unwrap();
+        if annealing_schedule == AnnealingSchedule::Constant {
+            println!(
+                "Iteration {}. Elapsed time in this iteration: {}ms",
+                epoch,
+                duration.as_millis()
+            );
+        } else {
+            println!(
+                "Iteration {} at temperature {:.3}. Elapsed time in this iteration: {}ms",
+                epoch,
+                trainer.get_temperature(),
+                duration.as_millis()
+            );
+        }
+
+        if report_interval > 0 && epoch % report_interval == 0 {
+            let report = trainer
+                .report(epoch, report_samples)
+                .unwrap_or_else(|e| exit_with_error("failed to compute the report", e));
+            println!("{}", report);
+            if let Some(ref mut log) = report_log {
+                writeln!(log, "{}", report.to_json())
+                    .unwrap_or_else(|e| exit_with_error("failed to write the report log", e));
+            }
+        }
+
+        if eval_interval > 0 && epoch % eval_interval == 0 {
+            let scores = trainer
+                .evaluate_dev(&eval_dictionary)
+                .unwrap_or_else(|e| exit_with_error("failed to evaluate the dev sentences", e));
+            if let Some(scores) = scores {
+                println!("Evaluation on the dev sentences after epoch {}:", epoch);
+                println!("{}", scores);
+            }
+        }
+
+        if (checkpoint_interval > 0 && epoch % checkpoint_interval == 0)
+            || (checkpoint_minutes > 0
+                && last_checkpoint_time.elapsed() >= Duration::from_secs(checkpoint_minutes * 60))
+        {
+            let path = checkpoint_path(target_directory, epoch);
+            trainer.save_checkpoint(&path, epoch).unwrap_or_else(|e| {
+                exit_with_error(&format!("failed to save the checkpoint {:?}", path), e)
+            });
+            println!("Saved a checkpoint to {:?}", path);
+            last_checkpoint_time = Instant::now();
+            if num_checkpoints_to_keep > 0 {
+                rotate_checkpoints(target_directory, num_checkpoints_to_keep)
+                    .unwrap_or_else(|e| exit_with_error("failed to remove old checkpoints", e));
+            }
+        }
+    }
+
+    let model_path = target_directory.join("npylm.model");
+    trainer.save_model(&model_path).unwrap_or_else(|e| {
+        exit_with_error(&format!("failed to save the model to {:?}", model_path), e)
+    });
+    println!("Saved the model to {:?}", model_path);
+
+    if mbr_epochs > 0 {
+        let mbr_path = target_directory.join("train.mbr.txt");
+        write_segmentations(&mbr_path, &trainer.mbr_segmentations_train())
+            .unwrap_or_else(|e| exit_with_error(&format!("failed to write {:?}", mbr_path), e));
         println!(
-            "Iteration {}. Elapsed time in this iteration: {}ms",
-            epoch,
-            duration.as_millis()
+            "Saved the minimum Bayes risk segmentations over {} epochs to {:?}",
+            trainer.get_num_boundary_samples(),
+            mbr_path
         );
     }
 }
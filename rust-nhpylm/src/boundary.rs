@@ -0,0 +1,103 @@
+use error::*;
+use std::collections::BTreeSet;
+use std::fmt;
+use wtype::is_symbol;
+
+/// Which characters of the input force a word boundary. The whitespace is removed from the text either way, but with these rules it is remembered where it was, and no word is ever built across it, neither in training nor in segmentation.
+///
+/// New rules force no boundaries, so that the text is segmented freely as before.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BoundaryRules {
+    /// Whether the whitespace in the input separates words.
+    pub whitespace: bool,
+    /// Whether every character for which `wtype::is_symbol` holds is a word on its own.
+    pub all_symbols: bool,
+    /// Characters that are words on their own, in addition to `all_symbols`.
+    pub symbols: BTreeSet<char>,
+}
+
+impl Default for BoundaryRules {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BoundaryRules {
+    pub fn new() -> Self {
+        Self {
+            whitespace: false,
+            all_symbols: false,
+            symbols: BTreeSet::new(),
+        }
+    }
+
+    /// Makes the given characters words on their own. `all` stands for every character for which `wtype::is_symbol` holds, anything else is taken as a list of characters, e.g. `，。！？`. Only symbols may be listed, since the others are ordinary parts of words.
+    pub fn set_symbols(&mut self, symbols: &str) -> Result<()> {
+        if symbols == "all" {
+            self.all_symbols = true;
+            return Ok(());
+        }
+        for c in symbols.chars() {
+            if !is_symbol(c) {
+                return Err(Error::InvalidConfig(format!(
+                    "{:?} is not a symbol, so it can't be a forced boundary",
+                    c
+                )));
+            }
+            self.symbols.insert(c);
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.whitespace && !self.all_symbols && self.symbols.is_empty()
+    }
+
+    fn is_boundary_symbol(&self, c: char) -> bool {
+        self.symbols.contains(&c) || (self.all_symbols && is_symbol(c))
+    }
+
+    /// Removes the whitespace from a line and returns its characters, together with the positions before which a word must end according to the rules. The positions are increasing and lie strictly inside the sentence.
+    pub fn split_line(&self, line: &str) -> (Vec<char>, Vec<usize>) {
+        let mut sentence_chars: Vec<char> = Vec::new();
+        let mut hard_boundaries: Vec<usize> = Vec::new();
+        let mut pending_boundary = false;
+        for c in line.chars() {
+            if c.is_whitespace() {
+                pending_boundary = pending_boundary || self.whitespace;
+                continue;
+            }
+            let is_boundary_symbol = self.is_boundary_symbol(c);
+            let position = sentence_chars.len();
+            if (pending_boundary || is_boundary_symbol)
+                && position > 0
+                && hard_boundaries.last() != Some(&position)
+            {
+                hard_boundaries.push(position);
+            }
+            sentence_chars.push(c);
+            pending_boundary = is_boundary_symbol;
+        }
+        (sentence_chars, hard_boundaries)
+    }
+}
+
+impl fmt::Display for BoundaryRules {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rules = Vec::new();
+        if self.whitespace {
+            rules.push("whitespace".to_owned());
+        }
+        if self.all_symbols {
+            rules.push("all symbols".to_owned());
+        }
+        if !self.symbols.is_empty() {
+            rules.push(self.symbols.iter().collect());
+        }
+        if rules.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", rules.join(", "))
+        }
+    }
+}
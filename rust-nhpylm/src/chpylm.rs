@@ -1,14 +1,20 @@
 use def::*;
+use error::*;
 use pyp::*;
 
 use either::*;
-use hpylm::{init_hyperparameters_at_depth_if_needed, sum_auxiliary_variables_recursively, HPYLM};
+use hpylm::{
+    init_hyperparameters_at_depth_if_needed, init_priors_at_depth_if_needed,
+    sum_auxiliary_variables_recursively, HPYLMPriors, HPYLM,
+};
 use rand::distributions::WeightedIndex;
 use rand::distributions::{Beta, Gamma};
 use rand::prelude::*;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CHPYLM {
-    pub root: PYP<char>,
+    pub tree: PYPTree<char>,
     pub depth: usize,
     pub g_0: f64,
     pub d_array: Vec<f64>,
@@ -27,15 +33,19 @@ pub struct CHPYLM {
     pub beta_stop: f64,
     pub beta_pass: f64,
     pub max_depth: usize,
+    pub priors: HPYLMPriors,
+    /// The probability of stopping at a depth below which the deeper depths are ignored when computing a probability.
+    pub epsilon: f64,
+    #[serde(skip)]
     parent_p_w_cache: Vec<f64>,
-    path_nodes: Vec<Option<*mut PYP<char>>>,
+    #[serde(skip)]
+    path_nodes: Vec<Option<NodeId>>,
 }
 
 impl CHPYLM {
     pub fn new(g_0: f64, max_depth: usize, beta_stop: f64, beta_pass: f64) -> Self {
-        let root = PYP::new(BOW);
         Self {
-            root: root,
+            tree: PYPTree::new(BOW),
             depth: 0,
             d_array: Vec::new(),
             theta_array: Vec::new(),
@@ -47,18 +57,55 @@ impl CHPYLM {
             beta_stop: beta_stop,
             beta_pass: beta_pass,
             max_depth: max_depth,
-            parent_p_w_cache: vec![0.0; max_depth],
-            path_nodes: vec![None; max_depth],
+            priors: HPYLMPriors::default(),
+            epsilon: CHPYLM_EPSILON,
+            // A word can be as long as the longest sentence. Wrapped in BOW and EOW, it takes two more entries, and `sample_depth_at_index_n` writes one past its last character.
+            parent_p_w_cache: vec![0.0; max_depth + 3],
+            path_nodes: vec![None; max_depth + 3],
         }
     }
 
-    pub fn add_customer_at_index_n(
+    /// The caches are not serialized. Restore them after the CHPYLM is deserialized.
+    pub fn rebuild_after_deserialization(&mut self) {
+        self.parent_p_w_cache = vec![0.0; self.max_depth + 3];
+        self.path_nodes = vec![None; self.max_depth + 3];
+    }
+
+    /// Starts the hyperparameters of every depth over from the priors. Only meant for a model that hasn't been trained yet.
+    pub fn set_priors(&mut self, priors: HPYLMPriors) {
+        self.priors = priors;
+        let num_depths = self.d_array.len();
+        self.d_array = vec![priors.initial_d; num_depths];
+        self.theta_array = vec![priors.initial_theta; num_depths];
+        self.a_array = vec![priors.a; num_depths];
+        self.b_array = vec![priors.b; num_depths];
+        self.alpha_array = vec![priors.alpha; num_depths];
+        self.beta_array = vec![priors.beta; num_depths];
+    }
+
+    pub fn add_customer_at_index_n<R: Rng>(
         &mut self,
         characters: &Vec<char>,
         n: usize,
         depth: usize,
         with_cache: bool,
+        rng: &mut R,
     ) -> bool {
+        // The restaurants at the depth and the ones above it start from the priors.
+        init_hyperparameters_at_depth_if_needed(
+            &mut self.d_array,
+            &mut self.theta_array,
+            &self.priors,
+            depth,
+        );
+        init_priors_at_depth_if_needed(
+            &mut self.a_array,
+            &mut self.b_array,
+            &mut self.alpha_array,
+            &mut self.beta_array,
+            &self.priors,
+            depth,
+        );
         let char_n = characters[n];
         let mut root_table_index = 0;
         let node = if with_cache {
@@ -68,23 +115,26 @@ impl CHPYLM {
             self.find_node_by_tracing_back_context_parent_p_w(characters, n, depth)
                 .unwrap()
         };
-        unsafe {
-            return (*node).add_customer(
-                char_n,
-                Right(&self.parent_p_w_cache),
-                &mut self.d_array,
-                &mut self.theta_array,
-                true,
-                &mut root_table_index,
-            );
-        }
+        return self.tree.add_customer(
+            node,
+            char_n,
+            &mut SeatingParameters {
+                g0_or_parent_p_ws: Right(&self.parent_p_w_cache),
+                d_array: &mut self.d_array,
+                theta_array: &mut self.theta_array,
+            },
+            true,
+            &mut root_table_index,
+            rng,
+        );
     }
 
-    pub fn remove_customer_at_index_n(
+    pub fn remove_customer_at_index_n<R: Rng>(
         &mut self,
         characters: &Vec<char>,
         n: usize,
         depth: usize,
+        rng: &mut R,
     ) -> bool {
         let char_n = characters[n];
         let mut root_table_index = 0;
@@ -92,12 +142,11 @@ impl CHPYLM {
             .find_node_by_tracing_back_context_removal(characters, n, depth, false, false)
             .unwrap();
 
-        unsafe {
-            (*node).remove_customer(char_n, true, &mut root_table_index);
+        self.tree
+            .remove_customer(node, char_n, true, &mut root_table_index, rng);
 
-            if (*node).need_to_remove_from_parent() {
-                (*node).remove_from_parent();
-            }
+        if self.tree.node(node).need_to_remove_from_parent() {
+            self.tree.remove_from_parent(node);
         }
         return true;
     }
@@ -109,26 +158,26 @@ impl CHPYLM {
         depth_of_n: usize,
         generate_if_not_found: bool,
         return_cur_node_if_not_found: bool,
-    ) -> Option<*mut PYP<char>> {
+    ) -> Option<NodeId> {
         if n < depth_of_n {
             return None;
         }
 
-        let mut cur_node = &mut self.root as *mut PYP<char>;
+        let mut cur_node = ROOT_ID;
         for d in 1..depth_of_n + 1 {
             let context = characters[n - d];
-            unsafe {
-                let child = (*cur_node).find_child_pyp(context, generate_if_not_found);
-                match child {
-                    None => {
-                        if return_cur_node_if_not_found {
-                            return Some(cur_node);
-                        } else {
-                            return None;
-                        }
+            let child = self
+                .tree
+                .find_child_pyp(cur_node, context, generate_if_not_found);
+            match child {
+                None => {
+                    if return_cur_node_if_not_found {
+                        return Some(cur_node);
+                    } else {
+                        return None;
                     }
-                    Some(c) => cur_node = c,
                 }
+                Some(c) => cur_node = c,
             }
         }
         return Some(cur_node);
@@ -139,30 +188,28 @@ impl CHPYLM {
         characters: &Vec<char>,
         n: usize,
         depth_of_n: usize,
-    ) -> Option<*mut PYP<char>> {
+    ) -> Option<NodeId> {
         if n < depth_of_n {
             return None;
         }
 
         let char_n = characters[n];
 
-        let mut cur_node = &mut self.root as *mut PYP<char>;
+        let mut cur_node = ROOT_ID;
         let mut parent_p_w = self.g_0;
         self.parent_p_w_cache[0] = parent_p_w;
 
-        unsafe {
-            for d in 1..depth_of_n + 1 {
-                let p_w = (*cur_node).compute_p_w_with_parent_p_w(
-                    char_n,
-                    parent_p_w,
-                    &mut self.d_array,
-                    &mut self.theta_array,
-                );
-                let context = characters[n - d];
-                let child = (*cur_node).find_child_pyp(context, true);
-                parent_p_w = p_w;
-                cur_node = child.unwrap();
-            }
+        for d in 1..depth_of_n + 1 {
+            let p_w = self.tree.node(cur_node).compute_p_w_with_parent_p_w(
+                char_n,
+                parent_p_w,
+                &self.d_array,
+                &self.theta_array,
+            );
+            let context = characters[n - d];
+            let child = self.tree.find_child_pyp(cur_node, context, true);
+            parent_p_w = p_w;
+            cur_node = child.unwrap();
         }
         return Some(cur_node);
     }
@@ -172,39 +219,37 @@ impl CHPYLM {
         characters: &Vec<char>,
         n: usize,
         depth_of_n: usize,
-    ) -> Option<*mut PYP<char>> {
+    ) -> Option<NodeId> {
         if n < depth_of_n {
             return None;
         }
-        let mut cur_node = &mut self.root as *mut PYP<char>;
-        unsafe {
-            for d in 0..depth_of_n {
-                match self.path_nodes[d + 1] {
-                    None => {
-                        let context = characters[n - d - 1];
-                        let child = (*cur_node).find_child_pyp(context, true);
-                        cur_node = child.unwrap();
-                    }
-                    Some(node) => cur_node = node,
+        let mut cur_node = ROOT_ID;
+        for d in 0..depth_of_n {
+            match self.path_nodes[d + 1] {
+                None => {
+                    let context = characters[n - d - 1];
+                    let child = self.tree.find_child_pyp(cur_node, context, true);
+                    cur_node = child.unwrap();
                 }
+                Some(node) => cur_node = node,
             }
         }
         return Some(cur_node);
     }
 
-    pub fn compute_p_w(&mut self, characters: &Vec<char>) -> f64 {
+    pub fn compute_p_w(&self, characters: &Vec<char>) -> f64 {
         return self.compute_log_p_w(characters).exp();
     }
 
-    pub fn compute_log_p_w(&mut self, characters: &Vec<char>) -> f64 {
+    pub fn compute_log_p_w(&self, characters: &Vec<char>) -> f64 {
         let char = characters[0];
         let mut log_p_w = 0.0 as f64;
 
         // I'm not sure if this scenario ever happens.
         if char != BOW {
             log_p_w += self
-                .root
-                .compute_p_w(char, self.g_0, &mut self.d_array, &mut self.theta_array)
+                .tree
+                .compute_p_w(ROOT_ID, char, self.g_0, &self.d_array, &self.theta_array)
                 .ln();
         }
 
@@ -216,7 +261,7 @@ impl CHPYLM {
     }
 
     pub fn compute_p_w_given_h(
-        &mut self,
+        &self,
         characters: &Vec<char>,
         context_begin: usize,
         context_end: usize,
@@ -231,14 +276,13 @@ impl CHPYLM {
     }
 
     pub fn compute_p_w_given_h_with_target(
-        &mut self,
+        &self,
         target_char: char,
         characters: &Vec<char>,
         context_begin: usize,
         context_end: usize,
     ) -> f64 {
-        let mut cur_node = &mut self.root as *mut PYP<char>;
-        // let cur_node = &mut self.root;
+        let mut cur_node = ROOT_ID;
         let mut parent_pass_probability = 1.0 as f64;
         let mut p = 0.0 as f64;
         let mut parent_p_w = self.g_0;
@@ -246,47 +290,53 @@ impl CHPYLM {
         let mut depth = 0;
         let mut end_reached = false;
 
-        unsafe {
-            while p_stop > CHPYLM_EPSILON {
-                if end_reached {
-                    p_stop = self.beta_stop / (self.beta_pass + self.beta_stop)
-                        * parent_pass_probability;
-                    p += parent_p_w * p_stop;
-                    parent_pass_probability *= self.beta_pass / (self.beta_pass + self.beta_stop);
-                } else {
-                    let p_w = (*cur_node).compute_p_w_with_parent_p_w(
-                        target_char,
-                        parent_p_w,
-                        &mut self.d_array,
-                        &mut self.theta_array,
-                    );
-                    p_stop = (*cur_node).stop_probability(self.beta_stop, self.beta_pass, false)
+        while p_stop > self.epsilon {
+            if end_reached {
+                p_stop =
+                    self.beta_stop / (self.beta_pass + self.beta_stop) * parent_pass_probability;
+                p += parent_p_w * p_stop;
+                parent_pass_probability *= self.beta_pass / (self.beta_pass + self.beta_stop);
+            } else {
+                let p_w = self.tree.node(cur_node).compute_p_w_with_parent_p_w(
+                    target_char,
+                    parent_p_w,
+                    &self.d_array,
+                    &self.theta_array,
+                );
+                p_stop =
+                    self.tree
+                        .stop_probability(cur_node, self.beta_stop, self.beta_pass, false)
                         * parent_pass_probability;
-                    p += p_w * p_stop;
-                    parent_pass_probability *=
-                        (*cur_node).pass_probability(self.beta_stop, self.beta_pass, false);
-                    parent_p_w = p_w;
+                p += p_w * p_stop;
+                parent_pass_probability *=
+                    self.tree
+                        .pass_probability(cur_node, self.beta_stop, self.beta_pass, false);
+                parent_p_w = p_w;
 
-                    if depth + 1 >= context_end - context_begin + 1 {
-                        end_reached = true;
-                    } else {
-                        let cur_context_char = characters[context_end - depth];
-                        match (*cur_node).find_child_pyp(cur_context_char, false) {
-                            None => end_reached = true,
-                            Some(child) => cur_node = child,
-                        }
+                if depth + 1 >= context_end - context_begin + 1 {
+                    end_reached = true;
+                } else {
+                    let cur_context_char = characters[context_end - depth];
+                    match self.tree.get_child(cur_node, cur_context_char) {
+                        None => end_reached = true,
+                        Some(child) => cur_node = child,
                     }
                 }
-                depth += 1;
             }
+            depth += 1;
         }
         p
     }
 
-    pub fn sample_depth_at_index_n(&mut self, characters: &Vec<char>, n: usize) -> usize {
+    pub fn sample_depth_at_index_n<R: Rng>(
+        &mut self,
+        characters: &Vec<char>,
+        n: usize,
+        rng: &mut R,
+    ) -> Result<usize> {
         // The first character is always the BOW, with depth 0.
         if n == 0 {
-            return 0;
+            return Ok(0);
         }
 
         let mut sampling_table = vec![0.0; n + 1];
@@ -296,82 +346,348 @@ impl CHPYLM {
         let mut parent_pass_probability = 1.0 as f64;
         self.parent_p_w_cache[0] = parent_p_w;
         // let mut sampling_table_size = 0;
-        let mut cur_node: Option<*mut PYP<char>> = Some(&mut self.root as *mut PYP<char>);
+        let mut cur_node: Option<NodeId> = Some(ROOT_ID);
 
-        unsafe {
-            for index in 0..n + 1 {
-                match cur_node {
-                    None => {
-                        let p_stop = self.beta_stop / (self.beta_pass + self.beta_stop)
-                            * parent_pass_probability;
-                        let p = parent_p_w * p_stop;
-                        self.parent_p_w_cache[index + 1] = parent_p_w;
-                        sampling_table[index] = p;
-                        self.path_nodes[index] = None;
-                        // sampling_table_size += 1;
-                        // sum += p;
-                        parent_pass_probability *=
-                            self.beta_pass / (self.beta_pass + self.beta_stop);
-                        if p_stop < CHPYLM_EPSILON {
-                            break;
-                        }
+        for index in 0..n + 1 {
+            match cur_node {
+                None => {
+                    let p_stop = self.beta_stop / (self.beta_pass + self.beta_stop)
+                        * parent_pass_probability;
+                    let p = parent_p_w * p_stop;
+                    self.parent_p_w_cache[index + 1] = parent_p_w;
+                    sampling_table[index] = p;
+                    self.path_nodes[index] = None;
+                    // sampling_table_size += 1;
+                    // sum += p;
+                    parent_pass_probability *= self.beta_pass / (self.beta_pass + self.beta_stop);
+                    if p_stop < self.epsilon {
+                        break;
+                    }
+                }
+                Some(node) => {
+                    let p_w = self.tree.node(node).compute_p_w_with_parent_p_w(
+                        char_n,
+                        parent_p_w,
+                        &self.d_array,
+                        &self.theta_array,
+                    );
+                    let p_stop = self.beta_stop / (self.beta_pass + self.beta_stop)
+                        * parent_pass_probability;
+                    let p = p_w * p_stop * parent_pass_probability;
+                    let parent_p_w = p_w;
+                    self.parent_p_w_cache[index + 1] = parent_p_w;
+                    sampling_table[index] = p;
+                    self.path_nodes[index] = cur_node;
+                    // sampling_table_size += 1;
+                    parent_pass_probability *= self.beta_pass / (self.beta_pass + self.beta_stop);
+                    // sum += p;
+                    if p_stop < self.epsilon {
+                        break;
                     }
-                    Some(node) => {
-                        let p_w = (*node).compute_p_w_with_parent_p_w(
-                            char_n,
-                            parent_p_w,
-                            &mut self.d_array,
-                            &mut self.theta_array,
-                        );
-                        let p_stop = self.beta_stop / (self.beta_pass + self.beta_stop)
-                            * parent_pass_probability;
-                        let p = p_w * p_stop * parent_pass_probability;
-                        let parent_p_w = p_w;
-                        self.parent_p_w_cache[index + 1] = parent_p_w;
-                        sampling_table[index] = p;
-                        self.path_nodes[index] = cur_node;
-                        // sampling_table_size += 1;
-                        parent_pass_probability *=
-                            self.beta_pass / (self.beta_pass + self.beta_stop);
-                        // sum += p;
-                        if p_stop < CHPYLM_EPSILON {
-                            break;
-                        }
-                        if index < n {
-                            let context_char = characters[n - index - 1];
-                            // TODO: This is a bit awkward. Might refactor this function.
-                            cur_node = match (*node).find_child_pyp(context_char, false) {
-                                None => None,
-                                Some(n) => Some(n as *mut PYP<char>),
-                            };
-                        }
+                    if index < n {
+                        let context_char = characters[n - index - 1];
+                        cur_node = self.tree.get_child(node, context_char);
                     }
                 }
             }
         }
-        let dist = WeightedIndex::new(&sampling_table).unwrap();
-        dist.sample(&mut rand::thread_rng())
+        let dist = WeightedIndex::new(&sampling_table).map_err(|e| {
+            Error::NumericalUnderflow(format!(
+                "Cannot sample the depth of character {:?}: {}",
+                char_n, e
+            ))
+        })?;
+        Ok(dist.sample(rng))
+    }
+
+    /// The probabilities of the depths of the nodes on `path`, which leads from the root to the node that predicts the next character, computed just like in `compute_p_w_given_h_with_target`. The depths beyond the end of the path, which share the prediction of its last node, are added to the last one.
+    fn compute_depth_weights(&self, path: &[NodeId]) -> Vec<f64> {
+        let mut weights = vec![0.0; path.len()];
+        let mut parent_pass_probability: f64 = 1.0;
+        let mut p_stop: f64 = 1.0;
+        let mut depth = 0;
+        while p_stop > self.epsilon {
+            if depth >= path.len() {
+                p_stop =
+                    self.beta_stop / (self.beta_pass + self.beta_stop) * parent_pass_probability;
+                parent_pass_probability *= self.beta_pass / (self.beta_pass + self.beta_stop);
+                weights[path.len() - 1] += p_stop;
+            } else {
+                let node = path[depth];
+                p_stop = self
+                    .tree
+                    .stop_probability(node, self.beta_stop, self.beta_pass, false)
+                    * parent_pass_probability;
+                parent_pass_probability *=
+                    self.tree
+                        .pass_probability(node, self.beta_stop, self.beta_pass, false);
+                weights[depth] += p_stop;
+            }
+            depth += 1;
+        }
+        weights
+    }
+
+    fn compute_p_w_on_path(&self, target_char: char, path: &[NodeId], weights: &[f64]) -> f64 {
+        let mut parent_p_w = self.g_0;
+        let mut p = 0.0;
+        for (&node, weight) in path.iter().zip(weights) {
+            parent_p_w = self.tree.node(node).compute_p_w_with_parent_p_w(
+                target_char,
+                parent_p_w,
+                &self.d_array,
+                &self.theta_array,
+            );
+            p += weight * parent_p_w;
+        }
+        p
+    }
+
+    fn compute_context_prediction(
+        &self,
+        automaton: &ContextAutomaton,
+        state: usize,
+        character_indices: &HashMap<char, usize, FixedState>,
+        p_w_at_root: &[f64],
+        sum_p_w_at_root: f64,
+    ) -> ContextPrediction {
+        let mut path = vec![automaton.nodes[state]];
+        while let Some(parent) = self.tree.node(path[path.len() - 1]).parent {
+            path.push(parent);
+        }
+        path.reverse();
+        let weights = self.compute_depth_weights(&path);
+
+        // A character without customers below the root gets the prediction of the root scaled by the same factor as any other such character. BOW is never a customer, so it stands for them.
+        let mut scale = weights[0];
+        let mut coefficient = 1.0;
+        for (&node, weight) in path[1..].iter().zip(&weights[1..]) {
+            coefficient = self.tree.node(node).compute_p_w_with_parent_p_w(
+                BOW,
+                coefficient,
+                &self.d_array,
+                &self.theta_array,
+            );
+            scale += weight * coefficient;
+        }
+
+        // The other characters are either served below the root or lead to another state than they would from the root.
+        let mut exceptions = BTreeSet::new();
+        for &node in &path[1..] {
+            exceptions.extend(self.tree.node(node).tablegroups.keys().cloned());
+        }
+        let mut s = state;
+        while s != 0 {
+            exceptions.extend(automaton.transitions[s].keys().cloned());
+            s = automaton.failures[s];
+        }
+
+        let mut sum_p_w = scale * sum_p_w_at_root;
+        let mut exceptional_transitions = Vec::new();
+        for c in exceptions {
+            if let Some(&index) = character_indices.get(&c) {
+                let p_w = self.compute_p_w_on_path(c, &path, &weights);
+                sum_p_w += p_w - scale * p_w_at_root[index];
+                exceptional_transitions.push((index, p_w, automaton.next_state(state, c)));
+            }
+        }
+        ContextPrediction {
+            scale,
+            exceptional_transitions,
+            sum_p_w,
+            p_eow: self.compute_p_w_on_path(EOW, &path, &weights),
+        }
+    }
+
+    /// The exact distribution of the length of a word drawn from the CHPYLM, an alternative to estimating it by sampling words as `Trainer::update_p_k_given_chpylm` does. The words are drawn the same way: each character out of `characters` and EOW, the first one never being EOW, and a word that reaches `max_word_length` characters is cut off there. Element k of the result is the probability of length k, element 0 is always 0.
+    ///
+    /// The distribution is summed up over the lengths by dynamic programming. The prediction of the next character only depends on the longest suffix of the characters drawn so far that is the context of a node of the tree, so the words are grouped by the states of an Aho-Corasick automaton over those contexts.
+    pub fn compute_word_length_distribution(
+        &self,
+        characters: &[char],
+        max_word_length: usize,
+    ) -> Vec<f64> {
+        let automaton = ContextAutomaton::new(&self.tree);
+        let num_states = automaton.nodes.len();
+        let mut character_indices: HashMap<char, usize, FixedState> = HashMap::default();
+        for (index, &c) in characters.iter().enumerate() {
+            character_indices.insert(c, index);
+        }
+        let p_w_at_root: Vec<f64> = characters
+            .iter()
+            .map(|&c| {
+                self.tree.root().compute_p_w_with_parent_p_w(
+                    c,
+                    self.g_0,
+                    &self.d_array,
+                    &self.theta_array,
+                )
+            })
+            .collect();
+        let sum_p_w_at_root = p_w_at_root.iter().sum();
+        let states_from_root: Vec<usize> = characters
+            .iter()
+            .map(|&c| automaton.next_state(0, c))
+            .collect();
+
+        let mut predictions: Vec<Option<ContextPrediction>> =
+            (0..num_states).map(|_| None).collect();
+        let mut distribution = vec![0.0; max_word_length + 1];
+        let mut probabilities = vec![0.0; num_states];
+        probabilities[0] = 1.0;
+        for length in 0..max_word_length {
+            let mut next_probabilities = vec![0.0; num_states];
+            // The probability that flows into the state each character leads to from the root, weighted by the prediction of the root for the character.
+            let mut unexceptional = 0.0;
+            let mut excluded = vec![0.0; characters.len()];
+            for (state, &p_state) in probabilities.iter().enumerate() {
+                if p_state == 0.0 {
+                    continue;
+                }
+                if predictions[state].is_none() {
+                    predictions[state] = Some(self.compute_context_prediction(
+                        &automaton,
+                        state,
+                        &character_indices,
+                        &p_w_at_root,
+                        sum_p_w_at_root,
+                    ));
+                }
+                let prediction = predictions[state].as_ref().unwrap();
+                let normalizer = if length == 0 {
+                    prediction.sum_p_w
+                } else {
+                    let normalizer = prediction.sum_p_w + prediction.p_eow;
+                    distribution[length] += p_state * prediction.p_eow / normalizer;
+                    normalizer
+                };
+                unexceptional += p_state * prediction.scale / normalizer;
+                for &(index, p_w, next_state) in &prediction.exceptional_transitions {
+                    excluded[index] += p_state * prediction.scale / normalizer;
+                    next_probabilities[next_state] += p_state * p_w / normalizer;
+                }
+            }
+            for (index, &next_state) in states_from_root.iter().enumerate() {
+                next_probabilities[next_state] +=
+                    (unexceptional - excluded[index]).max(0.0) * p_w_at_root[index];
+            }
+            probabilities = next_probabilities;
+        }
+        distribution[max_word_length] += probabilities.iter().sum::<f64>();
+        distribution
+    }
+}
+
+/// An Aho-Corasick automaton over the contexts of the nodes of a CHPYLM, read from the oldest character to the newest. A state stands for the longest suffix of the text read so far that is a prefix of a context, which is all that the prediction of the following characters depends on.
+struct ContextAutomaton {
+    transitions: Vec<HashMap<char, usize, FixedState>>,
+    failures: Vec<usize>,
+    // The deepest node whose context is a suffix of each state.
+    nodes: Vec<NodeId>,
+}
+
+impl ContextAutomaton {
+    fn new(tree: &PYPTree<char>) -> Self {
+        let mut transitions: Vec<HashMap<char, usize, FixedState>> = vec![HashMap::default()];
+        let mut exact_nodes = vec![Some(ROOT_ID)];
+        // The contexts never include BOW when a word is drawn, since the trace stops before the first character.
+        let mut stack: Vec<(NodeId, Vec<char>)> = vec![(ROOT_ID, Vec::new())];
+        while let Some((node, context)) = stack.pop() {
+            let mut state = 0;
+            for &c in context.iter().rev() {
+                state = match transitions[state].get(&c) {
+                    Some(&next_state) => next_state,
+                    None => {
+                        transitions.push(HashMap::default());
+                        exact_nodes.push(None);
+                        let next_state = transitions.len() - 1;
+                        transitions[state].insert(c, next_state);
+                        next_state
+                    }
+                };
+            }
+            exact_nodes[state] = Some(node);
+            for child in tree.get_sorted_child_ids(node) {
+                let c = tree.node(child).context;
+                if c != BOW {
+                    let mut child_context = context.clone();
+                    child_context.push(c);
+                    stack.push((child, child_context));
+                }
+            }
+        }
+
+        let num_states = transitions.len();
+        let mut automaton = Self {
+            transitions,
+            failures: vec![0; num_states],
+            nodes: vec![ROOT_ID; num_states],
+        };
+        let mut queue = VecDeque::new();
+        queue.push_back(0);
+        while let Some(state) = queue.pop_front() {
+            if let Some(node) = exact_nodes[state] {
+                automaton.nodes[state] = node;
+            } else {
+                automaton.nodes[state] = automaton.nodes[automaton.failures[state]];
+            }
+            let mut children: Vec<(char, usize)> = automaton.transitions[state]
+                .iter()
+                .map(|(&c, &next_state)| (c, next_state))
+                .collect();
+            children.sort();
+            for (c, next_state) in children {
+                automaton.failures[next_state] = if state == 0 {
+                    0
+                } else {
+                    automaton.next_state(automaton.failures[state], c)
+                };
+                queue.push_back(next_state);
+            }
+        }
+        automaton
+    }
+
+    fn next_state(&self, mut state: usize, c: char) -> usize {
+        loop {
+            if let Some(&next_state) = self.transitions[state].get(&c) {
+                return next_state;
+            }
+            if state == 0 {
+                return 0;
+            }
+            state = self.failures[state];
+        }
     }
 }
 
+/// How the next character of a word is drawn in a state of a `ContextAutomaton`. Most characters are drawn with `scale` times their probability at the root and lead to the same state as from the root, the others are listed in `exceptional_transitions` as the index of the character, its probability and the next state.
+struct ContextPrediction {
+    scale: f64,
+    exceptional_transitions: Vec<(usize, f64, usize)>,
+    // The sum of the probabilities of all the characters but EOW.
+    sum_p_w: f64,
+    p_eow: f64,
+}
+
 impl HPYLM<char> for CHPYLM {
     fn get_num_nodes(&self) -> usize {
-        return self.root.get_num_nodes() + 1;
+        return self.tree.get_num_nodes(ROOT_ID) + 1;
     }
     fn get_num_tables(&self) -> usize {
-        return self.root.get_num_tables() + 1;
+        return self.tree.get_num_tables(ROOT_ID) + 1;
     }
     fn get_num_customers(&self) -> usize {
-        return self.root.get_num_customers() + 1;
+        return self.tree.get_num_customers(ROOT_ID) + 1;
     }
     fn get_pass_counts(&self) -> usize {
-        return self.root.get_pass_counts() + 1;
+        return self.tree.get_pass_counts(ROOT_ID) + 1;
     }
     fn get_stop_counts(&self) -> usize {
-        return self.root.get_stop_counts() + 1;
+        return self.tree.get_stop_counts(ROOT_ID) + 1;
     }
 
-    fn sample_hyperparameters(&mut self) {
+    fn sample_hyperparameters<R: Rng>(&mut self, rng: &mut R) {
         let max_depth: usize = self.d_array.len() - 1;
         let mut sum_log_x_u_array = vec![0.0; max_depth + 1];
         let mut sum_y_ui_array = vec![0.0; max_depth + 1];
@@ -380,7 +696,8 @@ impl HPYLM<char> for CHPYLM {
 
         self.depth = 0;
         sum_auxiliary_variables_recursively(
-            &self.root,
+            &self.tree,
+            ROOT_ID,
             &mut sum_log_x_u_array,
             &mut sum_y_ui_array,
             &mut sum_one_minus_y_ui_array,
@@ -391,16 +708,23 @@ impl HPYLM<char> for CHPYLM {
             &mut self.b_array,
             &mut self.alpha_array,
             &mut self.beta_array,
+            &self.priors,
             &mut self.depth,
+            rng,
         );
 
         init_hyperparameters_at_depth_if_needed(
             &mut self.d_array,
             &mut self.theta_array,
+            &self.priors,
+            self.depth,
+        );
+        init_priors_at_depth_if_needed(
             &mut self.a_array,
             &mut self.b_array,
             &mut self.alpha_array,
             &mut self.beta_array,
+            &self.priors,
             self.depth,
         );
 
@@ -409,13 +733,13 @@ impl HPYLM<char> for CHPYLM {
                 self.a_array[u] + sum_one_minus_y_ui_array[u],
                 self.b_array[u] + sum_one_minus_z_uwkj_array[u],
             );
-            self.d_array[u] = dist1.sample(&mut rand::thread_rng());
+            self.d_array[u] = dist1.sample(rng);
 
             let dist2 = Gamma::new(
                 self.alpha_array[u] + sum_y_ui_array[u],
                 1.0 / (self.beta_array[u] - sum_log_x_u_array[u]),
             );
-            self.theta_array[u] = dist2.sample(&mut rand::thread_rng());
+            self.theta_array[u] = dist2.sample(rng);
         }
 
         let excessive_length = max_depth - self.depth;
@@ -0,0 +1,218 @@
+use def::*;
+use error::*;
+use hpylm::HPYLMPriors;
+use serde_json;
+use std::fs;
+use std::path::Path;
+use toml;
+
+/// Every setting of a training run, read from a TOML or JSON file with `train --config`. The keys are the names of the options of `train`, grouped into sections, and every key that is left out has the default of its option. `train` writes the configuration it ends up with to `config.toml` in the target directory.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TrainingConfig {
+    /// A saved model to continue training from. The corpus and model settings are then ignored.
+    pub load_model: Option<String>,
+    /// A checkpoint to continue an interrupted training from. The corpus and model settings are then ignored.
+    pub resume: Option<String>,
+    pub corpus: CorpusConfig,
+    pub model: ModelConfig,
+    pub sampler: SamplerConfig,
+    pub schedule: ScheduleConfig,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CorpusConfig {
+    pub file: Option<String>,
+    pub dir: Option<String>,
+    pub gold: bool,
+    pub stream: bool,
+    pub train_dev_split: f64,
+    pub supervised: Vec<String>,
+    pub supervised_proportion: f64,
+    pub normalize: String,
+    pub mapping: Vec<String>,
+    pub whitespace_boundaries: bool,
+    pub boundary_symbols: Option<String>,
+    pub dictionary: Option<String>,
+}
+
+impl Default for CorpusConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            dir: None,
+            gold: false,
+            stream: false,
+            train_dev_split: 0.9,
+            supervised: Vec::new(),
+            supervised_proportion: 1.0,
+            normalize: "none".to_owned(),
+            mapping: Vec::new(),
+            whitespace_boundaries: false,
+            boundary_symbols: None,
+            dictionary: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ModelConfig {
+    pub order: usize,
+    pub max_word_length: usize,
+    pub word_types: String,
+    pub lambda_a: f64,
+    pub lambda_b: f64,
+    pub beta_stop: f64,
+    pub beta_pass: f64,
+    pub chpylm_epsilon: f64,
+    pub whpylm_priors: HPYLMPriors,
+    pub chpylm_priors: HPYLMPriors,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            order: WHPYLM_ORDER,
+            max_word_length: MAX_WORD_LENGTH,
+            word_types: "japanese".to_owned(),
+            lambda_a: INITIAL_LAMBDA_A,
+            lambda_b: INITIAL_LAMBDA_B,
+            beta_stop: CHPYLM_BETA_STOP,
+            beta_pass: CHPYLM_BETA_PASS,
+            chpylm_epsilon: CHPYLM_EPSILON,
+            whpylm_priors: HPYLMPriors::default(),
+            chpylm_priors: HPYLMPriors::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SamplerConfig {
+    pub seed: u64,
+    pub threads: usize,
+    pub batch_size: usize,
+    pub always_accept_new_segmentation: bool,
+    pub annealing: String,
+    pub length_correction_start: usize,
+    pub length_correction_samples: usize,
+    pub length_correction_early_stop: usize,
+    pub exact_length_correction: bool,
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        Self {
+            seed: 1,
+            threads: 1,
+            batch_size: 256,
+            always_accept_new_segmentation: false,
+            annealing: "none".to_owned(),
+            length_correction_start: WORD_LENGTH_CORRECTION_START_EPOCH,
+            length_correction_samples: NUM_WORD_LENGTH_SAMPLES,
+            length_correction_early_stop: WORD_LENGTH_EARLY_STOPPING_THRESHOLD,
+            exact_length_correction: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ScheduleConfig {
+    pub epochs: usize,
+    pub target_directory: String,
+    pub checkpoint_interval: usize,
+    pub checkpoint_minutes: u64,
+    pub keep_checkpoints: usize,
+    pub mbr_epochs: usize,
+    pub eval_interval: usize,
+    pub eval_dictionary: Option<String>,
+    pub report_interval: usize,
+    pub report_samples: usize,
+    pub report_log: Option<String>,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            epochs: 100000,
+            target_directory: "out".to_owned(),
+            checkpoint_interval: 0,
+            checkpoint_minutes: 0,
+            keep_checkpoints: 3,
+            mbr_epochs: 0,
+            eval_interval: 10,
+            eval_dictionary: None,
+            report_interval: 10,
+            report_samples: 5,
+            report_log: None,
+        }
+    }
+}
+
+impl TrainingConfig {
+    /// Reads a file ending in `.json` as JSON, and any other file as TOML.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let is_json = path.extension() == Some("json".as_ref());
+        if is_json {
+            serde_json::from_str(&text).map_err(|e| Error::InvalidConfig(e.to_string()))
+        } else {
+            toml::from_str(&text).map_err(|e| Error::InvalidConfig(e.to_string()))
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap()
+    }
+
+    /// The settings that take effect, as TOML. A saved model or a checkpoint brings its own corpus and model settings, so these are left out, and so are the seed and `always-accept-new-segmentation` when resuming, which the checkpoint restores as well.
+    fn to_effective_toml(&self) -> String {
+        let mut value = toml::Value::try_from(self).unwrap();
+        if let Some(table) = value.as_table_mut() {
+            if self.load_model.is_some() || self.resume.is_some() {
+                table.remove("corpus");
+                table.remove("model");
+            }
+            if self.resume.is_some() {
+                if let Some(sampler) = table.get_mut("sampler").and_then(|s| s.as_table_mut()) {
+                    sampler.remove("seed");
+                    sampler.remove("always-accept-new-segmentation");
+                }
+            }
+        }
+        toml::to_string_pretty(&value).unwrap()
+    }
+
+    /// Writes the settings that take effect, so that loading the file again repeats the run.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_effective_toml())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_of_a_loaded_model_are_not_saved() {
+        let mut config = TrainingConfig::default();
+        config.model.max_word_length = 8;
+        let saved: TrainingConfig = toml::from_str(&config.to_effective_toml()).unwrap();
+        assert_eq!(saved, config);
+
+        config.load_model = Some("out/npylm.model".to_owned());
+        config.sampler.seed = 2;
+        let saved: TrainingConfig = toml::from_str(&config.to_effective_toml()).unwrap();
+        assert_eq!(saved.model, ModelConfig::default());
+        assert_eq!(saved.sampler.seed, 2);
+
+        config.resume = Some("out/checkpoint.bin".to_owned());
+        let saved: TrainingConfig = toml::from_str(&config.to_effective_toml()).unwrap();
+        assert_eq!(saved.sampler.seed, SamplerConfig::default().seed);
+        assert_eq!(saved.resume, config.resume);
+    }
+}
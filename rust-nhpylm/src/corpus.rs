@@ -2,23 +2,27 @@ use rand::prelude::*;
 use rand::seq::SliceRandom;
 use rand::SeedableRng;
 
+use boundary::BoundaryRules;
+use def::FixedState;
+use dictionary::Dictionary;
+use error::*;
+use normalizer::Normalizer;
 use sentence::*;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::path::Path;
-
-use regex::Regex;
+use std::path::{Path, PathBuf};
 
 pub struct Vocabulary {
-    pub all_characters: HashSet<char>,
+    pub all_characters: HashSet<char, FixedState>,
 }
 
 impl Vocabulary {
     fn new() -> Self {
         Self {
-            all_characters: HashSet::new(),
+            all_characters: HashSet::default(),
         }
     }
 
@@ -31,68 +35,526 @@ impl Vocabulary {
     }
 }
 
+/// Sentences stored back to back in a single character buffer, so that each sentence costs its characters and one offset instead of an allocation of its own. A segmentation is kept as a bitset over the same buffer, in which the bit of a character is set if a word ends with it. The hard boundaries of the sentences are kept the same way.
+#[derive(Serialize, Deserialize)]
+pub struct SentenceStore {
+    characters: Vec<char>,
+    // Sentence i occupies `characters[offsets[i]..offsets[i + 1]]`.
+    offsets: Vec<usize>,
+    word_ends: Vec<u64>,
+    // The bit of a character is set if a word must end before it.
+    hard_boundaries: Vec<u64>,
+    // Whether sentence i came with a segmentation. If not, its bits in `word_ends` are all 0.
+    segmented: Vec<bool>,
+}
+
+impl Default for SentenceStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SentenceStore {
+    pub fn new() -> Self {
+        Self {
+            characters: Vec::new(),
+            offsets: vec![0],
+            word_ends: Vec::new(),
+            hard_boundaries: Vec::new(),
+            segmented: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, sentence_chars: &[char]) {
+        self.characters.extend_from_slice(sentence_chars);
+        self.offsets.push(self.characters.len());
+        self.segmented.push(false);
+        self.word_ends.resize(self.characters.len().div_ceil(64), 0);
+        self.hard_boundaries
+            .resize(self.characters.len().div_ceil(64), 0);
+    }
+
+    /// Marks positions of the sentence added last before which a word must end. See `SegmentationConstraints::boundaries`.
+    pub fn add_hard_boundaries(&mut self, hard_boundaries: &[usize]) {
+        let begin = self.offsets[self.offsets.len() - 2];
+        for boundary in hard_boundaries {
+            let position = begin + boundary;
+            assert!(position < self.characters.len());
+            self.hard_boundaries[position / 64] |= 1 << (position % 64);
+        }
+    }
+
+    /// Adds a sentence together with its segmentation, given as word lengths that add up to the length of the sentence. A segmentation that doesn't fit the sentence is an error, and the sentence isn't added then.
+    pub fn push_segmented(
+        &mut self,
+        sentence_chars: &[char],
+        segment_lengths: &[usize],
+    ) -> Result<()> {
+        if segment_lengths.contains(&0) {
+            return Err(Error::InvalidConfig(
+                "The segmentation contains an empty word".to_owned(),
+            ));
+        }
+        let num_segmented_characters: usize = segment_lengths.iter().sum();
+        if num_segmented_characters != sentence_chars.len() {
+            return Err(Error::InvalidConfig(format!(
+                "The words of the segmentation have {} characters, but the sentence has {}",
+                num_segmented_characters,
+                sentence_chars.len()
+            )));
+        }
+        self.push(sentence_chars);
+        self.set_segment_lengths(self.get_num_sentences() - 1, segment_lengths);
+        Ok(())
+    }
+
+    /// Replaces the segmentation of a sentence, given as word lengths that must add up to the length of the sentence.
+    pub fn set_segment_lengths(&mut self, index: usize, segment_lengths: &[usize]) {
+        let begin = self.offsets[index];
+        let end = self.offsets[index + 1];
+        for position in begin..end {
+            self.word_ends[position / 64] &= !(1 << (position % 64));
+        }
+        let mut word_end = begin;
+        for length in segment_lengths {
+            word_end += length;
+            self.word_ends[(word_end - 1) / 64] |= 1 << ((word_end - 1) % 64);
+        }
+        assert!(word_end == end);
+        self.segmented[index] = true;
+    }
+
+    pub fn get_num_sentences(&self) -> usize {
+        self.segmented.len()
+    }
+
+    pub fn get_num_characters(&self) -> usize {
+        self.characters.len()
+    }
+
+    pub fn get_sentence(&self, index: usize) -> &[char] {
+        &self.characters[self.offsets[index]..self.offsets[index + 1]]
+    }
+
+    /// The segmentation of the sentence as word lengths, or `None` if it was added without one.
+    pub fn get_segment_lengths(&self, index: usize) -> Option<Vec<usize>> {
+        if !self.segmented[index] {
+            return None;
+        }
+        let mut segment_lengths = Vec::new();
+        let mut word_begin = self.offsets[index];
+        for position in self.offsets[index]..self.offsets[index + 1] {
+            if self.word_ends[position / 64] & (1 << (position % 64)) != 0 {
+                segment_lengths.push(position + 1 - word_begin);
+                word_begin = position + 1;
+            }
+        }
+        Some(segment_lengths)
+    }
+
+    pub fn get_hard_boundaries(&self, index: usize) -> Vec<usize> {
+        let begin = self.offsets[index];
+        (begin..self.offsets[index + 1])
+            .filter(|position| self.hard_boundaries[position / 64] & (1 << (position % 64)) != 0)
+            .map(|position| position - begin)
+            .collect()
+    }
+}
+
+/// Sentences with their current segmentations, kept in a `SentenceStore` rather than as a `Sentence` each, which needs two words per character for its segmentation. A `Sentence` is only built by `get_sentence` while a sentence is being sampled or decoded, and its new segmentation is written back with `set_segmentation`.
+#[derive(Serialize, Deserialize)]
+pub struct SegmentedSentences {
+    store: SentenceStore,
+    supervised: Vec<bool>,
+    // Only the few sentences with constraints have an entry.
+    constraints: BTreeMap<usize, SegmentationConstraints>,
+}
+
+impl Default for SegmentedSentences {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SegmentedSentences {
+    pub fn new() -> Self {
+        Self {
+            store: SentenceStore::new(),
+            supervised: Vec::new(),
+            constraints: BTreeMap::new(),
+        }
+    }
+
+    /// Adds an unsupervised sentence. Its initial segmentation splits it at the split positions of the constraints, see `Sentence::set_constraints`.
+    pub fn push_unsegmented(
+        &mut self,
+        sentence_chars: &[char],
+        constraints: SegmentationConstraints,
+    ) {
+        let segment_lengths = constraints.get_initial_segment_lengths(sentence_chars.len());
+        self.push(sentence_chars, &segment_lengths, false);
+        if !constraints.is_empty() {
+            self.constraints.insert(self.len() - 1, constraints);
+        }
+    }
+
+    /// Adds a supervised sentence, whose segmentation is kept fixed. The word lengths must add up to the length of the sentence.
+    pub fn push_supervised(&mut self, sentence_chars: &[char], segment_lengths: &[usize]) {
+        self.push(sentence_chars, segment_lengths, true);
+    }
+
+    fn push(&mut self, sentence_chars: &[char], segment_lengths: &[usize], supervised: bool) {
+        let index = self.len();
+        self.store.push(sentence_chars);
+        self.store.set_segment_lengths(index, segment_lengths);
+        self.supervised.push(supervised);
+    }
+
+    pub fn len(&self) -> usize {
+        self.supervised.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.supervised.is_empty()
+    }
+
+    pub fn get_characters(&self, index: usize) -> &[char] {
+        self.store.get_sentence(index)
+    }
+
+    pub fn get_num_characters(&self) -> usize {
+        self.store.get_num_characters()
+    }
+
+    pub fn is_supervised(&self, index: usize) -> bool {
+        self.supervised[index]
+    }
+
+    /// The current segmentation of the sentence as word lengths.
+    pub fn get_segment_lengths(&self, index: usize) -> Vec<usize> {
+        self.store.get_segment_lengths(index).unwrap()
+    }
+
+    /// Builds the sentence with its current segmentation and its constraints.
+    pub fn get_sentence(&self, index: usize) -> Sentence {
+        let mut sentence =
+            Sentence::new(self.get_characters(index).to_vec(), self.supervised[index]);
+        sentence.split_sentence(self.get_segment_lengths(index));
+        // The segmentation already takes the constraints into account, so they are set without splitting the sentence again.
+        if let Some(constraints) = self.constraints.get(&index) {
+            sentence.constraints = constraints.clone();
+        }
+        sentence
+    }
+
+    /// Stores the segmentation of a sentence built by `get_sentence` and sampled anew.
+    pub fn set_segmentation(&mut self, index: usize, sentence: &Sentence) {
+        self.store
+            .set_segment_lengths(index, get_segment_lengths(sentence));
+    }
+
+    /// Builds every sentence in turn, see `get_sentence`.
+    pub fn iter(&self) -> impl Iterator<Item = Sentence> + '_ {
+        (0..self.len()).map(move |index| self.get_sentence(index))
+    }
+}
+
+fn get_segment_lengths(sentence: &Sentence) -> &[usize] {
+    &sentence.segment_lengths[2..sentence.num_segments - 1]
+}
+
 pub struct Corpus {
-    sentence_list: Vec<Vec<char>>,
-    segmented_word_list: Vec<Vec<String>>,
+    // The sentences to be segmented. If a sentence was read as a gold standard, the segmentation it had in the input file is stored with it. This is only used for evaluation and never seen by the model.
+    sentences: SentenceStore,
+    // The sentences whose segmentation is known and kept fixed during training.
+    segmented_sentences: SentenceStore,
+    /// Applied to every line read from a file before its characters are stored.
+    pub normalizer: Normalizer,
+    /// Decides which positions of the lines read from a file become hard boundaries of their sentences.
+    pub boundary_rules: BoundaryRules,
+    /// If set, the sentences put into a `Dataset` are constrained by the entries of the dictionary they contain.
+    pub dictionary: Option<Dictionary>,
+}
+
+impl Default for Corpus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Corpus {
     pub fn new() -> Self {
         Self {
-            sentence_list: Vec::new(),
-            segmented_word_list: Vec::new(),
+            sentences: SentenceStore::new(),
+            segmented_sentences: SentenceStore::new(),
+            normalizer: Normalizer::new(),
+            boundary_rules: BoundaryRules::new(),
+            dictionary: None,
         }
     }
 
     pub fn add_sentence(&mut self, sentence_chars: Vec<char>) {
-        self.sentence_list.push(sentence_chars);
+        self.sentences.push(&sentence_chars);
+    }
+
+    /// Adds an unsegmented sentence whose correct segmentation is known, so that the segmentation found by the model can be evaluated against it. See `SentenceStore::push_segmented` for the errors.
+    pub fn add_sentence_with_gold_segmentation(
+        &mut self,
+        sentence_chars: Vec<char>,
+        gold_segment_lengths: Vec<usize>,
+    ) -> Result<()> {
+        self.sentences
+            .push_segmented(&sentence_chars, &gold_segment_lengths)
+    }
+
+    pub fn read_corpus(&mut self, input_file_path: &Path) -> Result<()> {
+        self.read_lines(input_file_path, false)
+    }
+
+    /// Reads a file that is already segmented with whitespace, such as `icwb2-data/gold/pku_test_gold.utf8`. The model still only sees the unsegmented sentences, the segmentation in the file is kept as the gold standard for evaluation.
+    pub fn read_corpus_with_gold_segmentation(&mut self, input_file_path: &Path) -> Result<()> {
+        self.read_lines(input_file_path, true)
     }
 
-    // Don't think this function was ever used???
-    pub fn read_corpus(&mut self, input_file_path: &Path) {
-        let input_file = File::open(input_file_path).unwrap();
+    fn read_lines(&mut self, input_file_path: &Path, with_gold_segmentation: bool) -> Result<()> {
+        let mut lines = LineReader::open(input_file_path, self, with_gold_segmentation)?;
+        while let Some(sentence) = lines.next_sentence()? {
+            match sentence.gold_segment_lengths {
+                Some(gold_segment_lengths) => self
+                    .add_sentence_with_gold_segmentation(sentence.characters, gold_segment_lengths)
+                    .map_err(|e| locate_error(e, &lines.path, lines.line_index))?,
+                None => self.add_sentence(sentence.characters),
+            }
+            self.sentences
+                .add_hard_boundaries(&sentence.hard_boundaries);
+        }
+        Ok(())
+    }
+
+    /// Adds a sentence whose segmentation is known and should be kept fixed during training. Empty words are an error.
+    pub fn add_segmented_sentence(&mut self, words: Vec<String>) -> Result<()> {
+        let mut sentence_chars: Vec<char> = Vec::new();
+        let mut segment_lengths: Vec<usize> = Vec::new();
+        for word in &words {
+            sentence_chars.extend(word.chars());
+            segment_lengths.push(word.chars().count());
+        }
+        self.segmented_sentences
+            .push_segmented(&sentence_chars, &segment_lengths)
+    }
+
+    /// Reads a file that is segmented with whitespace, such as `icwb2-data/gold/pku_training_words.utf8`. Unlike `read_corpus_with_gold_segmentation`, the sentences are used as supervised examples during training.
+    pub fn read_segmented_corpus(&mut self, input_file_path: &Path) -> Result<()> {
+        let input_file = File::open(input_file_path)?;
         let reader = &mut BufReader::new(input_file);
-        let spaces = Regex::new(r"\s").unwrap();
-        // let mut line = String::new();
-        for line in reader.lines() {
-            let l = line.unwrap();
-            if l.is_empty() {
+        for (line_index, line) in reader.lines().enumerate() {
+            let l = self
+                .normalizer
+                .normalize(&check_line(line, input_file_path, line_index)?);
+            let words: Vec<String> = l.split_whitespace().map(|w| w.to_owned()).collect();
+            if words.is_empty() {
                 continue;
             }
-            let no_whitespaces = spaces.replace_all(&l, "");
-            // self.add_sentence(no_whitespaces.to_string());
-            self.add_sentence(no_whitespaces.chars().collect());
+            self.add_segmented_sentence(words)
+                .map_err(|e| locate_error(e, input_file_path, line_index + 1))?;
         }
+        Ok(())
     }
 
     pub fn get_num_sentences(&self) -> usize {
-        self.sentence_list.len()
+        self.sentences.get_num_sentences()
     }
 
     pub fn get_num_already_segmented_sentences(&self) -> usize {
-        self.segmented_word_list.len()
+        self.segmented_sentences.get_num_sentences()
+    }
+
+    fn find_constraints(
+        &self,
+        sentence_chars: &[char],
+        hard_boundaries: Vec<usize>,
+    ) -> SegmentationConstraints {
+        let mut constraints = SegmentationConstraints::from_boundaries(hard_boundaries);
+        if let Some(ref dictionary) = self.dictionary {
+            dictionary.add_constraints(sentence_chars, &mut constraints);
+        }
+        constraints
     }
 }
 
+/// A sentence as read from a line of a corpus file.
+struct LineSentence {
+    characters: Vec<char>,
+    // The word lengths the line was segmented into, if it is read as a gold standard.
+    gold_segment_lengths: Option<Vec<usize>>,
+    hard_boundaries: Vec<usize>,
+}
+
+/// Reads a corpus file one sentence at a time. Every line is normalized and stripped of its whitespace, and lines left empty are skipped.
+struct LineReader {
+    path: PathBuf,
+    normalizer: Normalizer,
+    boundary_rules: BoundaryRules,
+    reader: BufReader<File>,
+    line: String,
+    line_index: usize,
+    with_gold_segmentation: bool,
+}
+
+impl LineReader {
+    /// Reads the lines with the normalizer and boundary rules of `corpus`.
+    fn open(path: &Path, corpus: &Corpus, with_gold_segmentation: bool) -> Result<Self> {
+        let input_file = File::open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            normalizer: corpus.normalizer.clone(),
+            boundary_rules: corpus.boundary_rules.clone(),
+            reader: BufReader::new(input_file),
+            line: String::new(),
+            line_index: 0,
+            with_gold_segmentation,
+        })
+    }
+
+    /// Returns the next sentence, or `None` at the end of the file.
+    fn next_sentence(&mut self) -> Result<Option<LineSentence>> {
+        loop {
+            self.line.clear();
+            let num_bytes = self.reader.read_line(&mut self.line);
+            check_line(num_bytes, &self.path, self.line_index)?;
+            if self.line.is_empty() {
+                return Ok(None);
+            }
+            self.line_index += 1;
+
+            let line = self.normalizer.normalize(&self.line);
+            let (sentence_chars, hard_boundaries) = self.boundary_rules.split_line(&line);
+            if sentence_chars.is_empty() {
+                continue;
+            }
+            let gold_segment_lengths = if self.with_gold_segmentation {
+                Some(line.split_whitespace().map(|w| w.chars().count()).collect())
+            } else {
+                None
+            };
+            return Ok(Some(LineSentence {
+                characters: sentence_chars,
+                gold_segment_lengths,
+                hard_boundaries,
+            }));
+        }
+    }
+}
+
+/// Prefixes the message of an error about the contents of a line with the file and the line, counted from 1.
+fn locate_error(error: Error, path: &Path, line: usize) -> Error {
+    match error {
+        Error::InvalidConfig(message) => {
+            Error::InvalidConfig(format!("{:?}, line {}: {}", path, line, message))
+        }
+        other => other,
+    }
+}
+
+/// `BufRead` reports invalid UTF-8 as an `InvalidData` I/O error. Turn it into an error that says where the problem is.
+pub fn check_line<T>(line: io::Result<T>, path: &Path, line_index: usize) -> Result<T> {
+    line.map_err(|e| {
+        if e.kind() == io::ErrorKind::InvalidData {
+            Error::InvalidEncoding {
+                path: path.to_path_buf(),
+                line: line_index + 1,
+            }
+        } else {
+            Error::Io(e)
+        }
+    })
+}
+
+/// How `Dataset::new_streaming` divides the sentences into training and dev sentences.
+#[derive(Clone, Debug)]
+pub struct DatasetSplit {
+    /// The share of the unsegmented sentences used for training.
+    pub train_proportion: f64,
+    /// The largest share of supervised sentences among all the training sentences.
+    pub supervised_proportion: f64,
+    /// Seeds the shuffling of the sentences before they are divided.
+    pub seed: u64,
+}
+
 pub struct Dataset {
     pub vocabulary: Vocabulary,
-    pub corpus: Corpus,
     pub max_sentence_length: usize,
     pub avg_sentence_length: f64,
     pub num_segmented_words: usize,
-    pub train_sentences: Vec<Sentence>,
-    pub dev_sentences: Vec<Sentence>,
+    pub train_sentences: SegmentedSentences,
+    pub dev_sentences: SegmentedSentences,
+    /// The gold segmentation of each dev sentence, if the corpus provided one.
+    pub dev_gold_segmentations: Vec<Option<Vec<usize>>>,
 }
 
 impl Dataset {
-    pub fn new(corpus: Corpus, train_proportion: f64, seed: u64) -> Self {
-        let mut corpus_length = 0;
-        let num_sentences = corpus.get_num_sentences();
-        let mut vocabulary = Vocabulary::new();
-        let mut train_sentences: Vec<Sentence> = Vec::new();
-        let mut dev_sentences: Vec<Sentence> = Vec::new();
-        let mut max_sentence_length = 0;
+    pub fn new(corpus: Corpus, train_proportion: f64, seed: u64) -> Result<Self> {
+        Self::new_with_supervised_proportion(corpus, train_proportion, 1.0, seed)
+    }
+
+    /// The already segmented sentences in the corpus are all put into the training set as supervised sentences, while the unsegmented ones are split into training and dev sentences according to `train_proportion`.
+    ///
+    /// `supervised_proportion` is the largest share of supervised sentences among all the training sentences. If the corpus contains more segmented sentences than that, a random subset of them is used. With 1.0, all of them are used.
+    ///
+    /// Both proportions must lie in [0, 1], and there must be at least one training sentence.
+    pub fn new_with_supervised_proportion(
+        corpus: Corpus,
+        train_proportion: f64,
+        supervised_proportion: f64,
+        seed: u64,
+    ) -> Result<Self> {
+        let split = DatasetSplit {
+            train_proportion,
+            supervised_proportion,
+            seed,
+        };
+        Self::new_streaming(corpus, &[], false, &split)
+    }
+
+    /// Builds the dataset like `new_with_supervised_proportion`, and additionally reads the files in `paths` one line at a time, adding each line to the sentences right away. Neither the files nor a `Corpus` of them are ever held in memory as a whole, so corpora much larger than the memory can be trained on, as long as their characters and segmentations fit.
+    ///
+    /// Since the number of lines isn't known in advance, each line read from `paths` goes into the training set with probability `train_proportion` instead of an exact share of a shuffled list. The lines are read as gold standards if `with_gold_segmentation` is set.
+    pub fn new_streaming(
+        corpus: Corpus,
+        paths: &[PathBuf],
+        with_gold_segmentation: bool,
+        split: &DatasetSplit,
+    ) -> Result<Self> {
+        let DatasetSplit {
+            train_proportion,
+            supervised_proportion,
+            seed,
+        } = *split;
+        if !(0.0..=1.0).contains(&train_proportion) {
+            return Err(Error::InvalidConfig(format!(
+                "The train proportion must be between 0 and 1, got {}",
+                train_proportion
+            )));
+        }
+        if !(0.0..=1.0).contains(&supervised_proportion) {
+            return Err(Error::InvalidConfig(format!(
+                "The supervised proportion must be between 0 and 1, got {}",
+                supervised_proportion
+            )));
+        }
+
+        let mut dataset = Self {
+            vocabulary: Vocabulary::new(),
+            max_sentence_length: 0,
+            avg_sentence_length: 0.0,
+            // Will be 0 if we don't provide any supervised examples to the training.
+            num_segmented_words: 0,
+            train_sentences: SegmentedSentences::new(),
+            dev_sentences: SegmentedSentences::new(),
+            dev_gold_segmentations: Vec::new(),
+        };
 
+        let num_sentences = corpus.get_num_sentences();
         let mut sentence_indices = vec![0; num_sentences];
         for i in 0..num_sentences {
             sentence_indices[i] = i;
@@ -101,46 +563,118 @@ impl Dataset {
         let mut rng: StdRng = SeedableRng::seed_from_u64(seed);
         sentence_indices.shuffle(&mut rng);
 
-        let train_proportion = (1.0 as f64).min((0.0 as f64).max(train_proportion));
-        // let train_proportion = train_proportion.max(0.0);
-        let num_train_sentences =
-            (corpus.get_num_sentences() as f64 * train_proportion).floor() as usize;
+        let num_train_sentences = (num_sentences as f64 * train_proportion).floor() as usize;
 
         for i in 0..num_sentences {
             // It is actually a reference to something stored in the Corpus struct.
-            let sentence_chars = &corpus.sentence_list[sentence_indices[i]];
+            let sentence_chars = corpus.sentences.get_sentence(sentence_indices[i]);
+            let gold_segment_lengths = corpus.sentences.get_segment_lengths(sentence_indices[i]);
+            let constraints = corpus.find_constraints(
+                sentence_chars,
+                corpus.sentences.get_hard_boundaries(sentence_indices[i]),
+            );
+            dataset.add_sentence(
+                sentence_chars,
+                gold_segment_lengths,
+                constraints,
+                i < num_train_sentences,
+            );
+        }
 
-            if i < num_train_sentences {
-                add_sentence(&mut vocabulary, &mut train_sentences, sentence_chars);
-            } else {
-                add_sentence(&mut vocabulary, &mut dev_sentences, sentence_chars);
+        for path in paths {
+            let mut lines = LineReader::open(path, &corpus, with_gold_segmentation)?;
+            while let Some(sentence) = lines.next_sentence()? {
+                let is_train_sentence = rng.gen::<f64>() < train_proportion;
+                let constraints =
+                    corpus.find_constraints(&sentence.characters, sentence.hard_boundaries);
+                dataset.add_sentence(
+                    &sentence.characters,
+                    sentence.gold_segment_lengths,
+                    constraints,
+                    is_train_sentence,
+                );
             }
+        }
+
+        let num_unsupervised_train_sentences = dataset.train_sentences.len();
+        let num_available_supervised_sentences = corpus.get_num_already_segmented_sentences();
+        let num_supervised_sentences = if supervised_proportion >= 1.0 {
+            num_available_supervised_sentences
+        } else {
+            let max_num_supervised_sentences = (num_unsupervised_train_sentences as f64
+                * supervised_proportion
+                / (1.0 - supervised_proportion))
+                .floor() as usize;
+            max_num_supervised_sentences.min(num_available_supervised_sentences)
+        };
+
+        let mut supervised_sentence_indices: Vec<usize> =
+            (0..num_available_supervised_sentences).collect();
+        supervised_sentence_indices.shuffle(&mut rng);
 
-            if sentence_chars.len() > max_sentence_length {
-                max_sentence_length = sentence_chars.len();
+        for &index in supervised_sentence_indices
+            .iter()
+            .take(num_supervised_sentences)
+        {
+            let sentence_chars = corpus.segmented_sentences.get_sentence(index);
+            let segment_lengths = corpus
+                .segmented_sentences
+                .get_segment_lengths(index)
+                .unwrap();
+            for c in sentence_chars {
+                dataset.vocabulary.add_character(*c);
             }
-            corpus_length += sentence_chars.len();
-        }
+            dataset.max_sentence_length = dataset.max_sentence_length.max(sentence_chars.len());
+            dataset.num_segmented_words += segment_lengths.len();
 
-        let avg_sentence_length = corpus_length as f64 / num_sentences as f64;
+            dataset
+                .train_sentences
+                .push_supervised(sentence_chars, &segment_lengths);
+        }
 
-        // let num_supervised_sentences = corpus.get_num_already_segmented_sentences();
+        if dataset.train_sentences.is_empty() {
+            return Err(Error::InvalidConfig(
+                "The corpus doesn't contain any training sentences".to_owned(),
+            ));
+        }
 
-        // for i in 0..num_supervised_sentences {
-        //     words = corpus.segmented_word_list[i];
-        //     segment
-        // }
+        dataset.avg_sentence_length = dataset.compute_avg_sentence_length();
+        Ok(dataset)
+    }
 
-        Self {
-            vocabulary: vocabulary,
-            corpus: corpus,
-            max_sentence_length: max_sentence_length,
-            avg_sentence_length: avg_sentence_length,
-            train_sentences: train_sentences,
-            dev_sentences: dev_sentences,
-            // Will be 0 if we don't provide any supervised examples to the training.
-            num_segmented_words: 0,
+    /// Builds a dataset directly out of sentences that already carry a segmentation, e.g. the training sentences restored together with a saved model.
+    pub fn new_from_sentences(
+        train_sentences: SegmentedSentences,
+        dev_sentences: SegmentedSentences,
+    ) -> Self {
+        let mut vocabulary = Vocabulary::new();
+        let mut max_sentence_length = 0;
+        let mut num_segmented_words = 0;
+        for sentences in &[&train_sentences, &dev_sentences] {
+            for index in 0..sentences.len() {
+                let sentence_chars = sentences.get_characters(index);
+                for c in sentence_chars {
+                    vocabulary.add_character(*c);
+                }
+                max_sentence_length = max_sentence_length.max(sentence_chars.len());
+                if sentences.is_supervised(index) {
+                    num_segmented_words += sentences.get_segment_lengths(index).len();
+                }
+            }
         }
+        let dev_gold_segmentations = vec![None; dev_sentences.len()];
+
+        let mut dataset = Self {
+            vocabulary,
+            max_sentence_length,
+            avg_sentence_length: 0.0,
+            train_sentences,
+            dev_sentences,
+            dev_gold_segmentations,
+            num_segmented_words,
+        };
+        dataset.avg_sentence_length = dataset.compute_avg_sentence_length();
+        dataset
     }
 
     pub fn get_num_train_sentences(&self) -> usize {
@@ -150,16 +684,31 @@ impl Dataset {
     pub fn get_num_dev_sentences(&self) -> usize {
         self.dev_sentences.len()
     }
-}
 
-fn add_sentence(
-    vocabulary: &mut Vocabulary,
-    sentences: &mut Vec<Sentence>,
-    sentence_chars: &Vec<char>,
-) {
-    for c in sentence_chars {
-        vocabulary.add_character(*c);
+    fn add_sentence(
+        &mut self,
+        sentence_chars: &[char],
+        gold_segment_lengths: Option<Vec<usize>>,
+        constraints: SegmentationConstraints,
+        is_train_sentence: bool,
+    ) {
+        for c in sentence_chars {
+            self.vocabulary.add_character(*c);
+        }
+        self.max_sentence_length = self.max_sentence_length.max(sentence_chars.len());
+        if is_train_sentence {
+            self.train_sentences
+                .push_unsegmented(sentence_chars, constraints);
+        } else {
+            self.dev_sentences
+                .push_unsegmented(sentence_chars, constraints);
+            self.dev_gold_segmentations.push(gold_segment_lengths);
+        }
+    }
+
+    fn compute_avg_sentence_length(&self) -> f64 {
+        let corpus_length =
+            self.train_sentences.get_num_characters() + self.dev_sentences.get_num_characters();
+        corpus_length as f64 / (self.train_sentences.len() + self.dev_sentences.len()) as f64
     }
-    let s = Sentence::new(sentence_chars.clone(), false);
-    sentences.push(s);
 }
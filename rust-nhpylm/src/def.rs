@@ -1,11 +1,12 @@
+use rand_pcg::Pcg64Mcg;
 use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::hash::BuildHasherDefault;
 
 pub const BOS_CHAR: char = 'Α';
 pub const EOS_CHAR: char = 'Ω';
-// It's awkward but Rust doesn't support calculating a const yet...
-pub const BOS: u64 = 3902801122696021506;
-pub const EOS: u64 = 13786557304133791402;
+/// The word IDs of the sentence boundaries, which every `WordInterner` reserves.
+pub const BOS: u64 = 0;
+pub const EOS: u64 = 1;
 pub const HPYLM_INITIAL_D: f64 = 0.5;
 pub const HPYLM_INITIAL_THETA: f64 = 2.0;
 pub const BOW: char = 'α';
@@ -19,9 +20,17 @@ pub const CHPYLM_BETA_PASS: f64 = 0.85;
 pub const CHPYLM_EPSILON: f64 = 1e-12;
 pub const INITIAL_LAMBDA_A: f64 = 4.0;
 pub const INITIAL_LAMBDA_B: f64 = 1.0;
+/// The order of the word n-gram model unless another one is chosen. Only bigram (2) and trigram (3) models are supported.
+pub const WHPYLM_ORDER: usize = 3;
+/// The maximum word length unless another one is chosen.
+pub const MAX_WORD_LENGTH: usize = 16;
+/// The first epoch after which the word length distribution of the CHPYLM is re-estimated, see `WordLengthCorrection`.
+pub const WORD_LENGTH_CORRECTION_START_EPOCH: usize = 4;
+pub const NUM_WORD_LENGTH_SAMPLES: usize = 20000;
+pub const WORD_LENGTH_EARLY_STOPPING_THRESHOLD: usize = 10;
 
-pub fn calculate_hash<T: Hash>(t: &T) -> u64 {
-    let mut s = DefaultHasher::new();
-    t.hash(&mut s);
-    s.finish()
-}
+/// The random number generator used throughout training. It is seeded once, so that two runs with the same seed and corpus give identical results.
+pub type NpylmRng = Pcg64Mcg;
+
+/// A hasher without random state. The restaurants are iterated over while sampling the hyperparameters, and a HashMap with the default random state would visit them in a different order in every run, breaking reproducibility.
+pub type FixedState = BuildHasherDefault<DefaultHasher>;
@@ -0,0 +1,170 @@
+use aho_corasick::{AhoCorasick, MatchKind};
+use corpus::check_line;
+use error::*;
+use normalizer::Normalizer;
+use sentence::SegmentationConstraints;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+
+enum PatternKind {
+    Forbidden,
+    // The offsets, within the pattern, before which a word must end.
+    Boundaries(Vec<usize>),
+}
+
+/// A list of words that turns into `SegmentationConstraints` for every sentence in which they occur, e.g. a lexicon of entity names that must never be split.
+///
+/// Each line of a dictionary file holds one entry, of which only the part before the first tab is read:
+/// - `word` must be a single word wherever it occurs. Where such words overlap, the leftmost and then the longest one is taken.
+/// - `!word` must never be a word.
+/// - `wo|rd` must have a word boundary at each `|` wherever it occurs.
+///
+/// Empty lines and lines starting with `#` are skipped. The entries go through the same normalizer as the text they are matched against.
+pub struct Dictionary {
+    words: Option<AhoCorasick>,
+    num_words: usize,
+    // Unlike the words, all the occurrences of these are used, even overlapping ones.
+    patterns: Option<AhoCorasick>,
+    pattern_kinds: Vec<PatternKind>,
+}
+
+impl Dictionary {
+    pub fn read(path: &Path, normalizer: &Normalizer) -> Result<Self> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+        let mut words: Vec<String> = Vec::new();
+        let mut patterns: Vec<String> = Vec::new();
+        let mut pattern_kinds = Vec::new();
+        for (line_index, line) in reader.lines().enumerate() {
+            let l = check_line(line, path, line_index)?;
+            let entry = l.split('\t').next().unwrap().trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            let (forbidden, entry) = match entry.strip_prefix('!') {
+                Some(word) => (true, word),
+                None => (false, entry),
+            };
+
+            // The model never sees any whitespace, so it can't be part of a word either.
+            let mut word = String::new();
+            let mut word_length = 0;
+            let mut boundaries = Vec::new();
+            for c in normalizer.normalize(entry).chars() {
+                if c == '|' {
+                    boundaries.push(word_length);
+                } else if !c.is_whitespace() {
+                    word.push(c);
+                    word_length += 1;
+                }
+            }
+            boundaries.retain(|&boundary| boundary > 0 && boundary < word_length);
+            boundaries.dedup();
+            if word.is_empty() {
+                continue;
+            }
+
+            if forbidden {
+                if !boundaries.is_empty() {
+                    return Err(Error::InvalidConfig(format!(
+                        "{:?}, line {}: a forbidden word can't have boundaries",
+                        path,
+                        line_index + 1
+                    )));
+                }
+                patterns.push(word);
+                pattern_kinds.push(PatternKind::Forbidden);
+            } else if !boundaries.is_empty() {
+                patterns.push(word);
+                pattern_kinds.push(PatternKind::Boundaries(boundaries));
+            } else {
+                words.push(word);
+            }
+        }
+
+        let num_words = words.len();
+        let words = if words.is_empty() {
+            None
+        } else {
+            Some(
+                AhoCorasick::builder()
+                    .match_kind(MatchKind::LeftmostLongest)
+                    .build(&words)
+                    .map_err(|e| Error::InvalidConfig(e.to_string()))?,
+            )
+        };
+        let patterns = if patterns.is_empty() {
+            None
+        } else {
+            Some(AhoCorasick::new(&patterns).map_err(|e| Error::InvalidConfig(e.to_string()))?)
+        };
+        Ok(Self {
+            words,
+            num_words,
+            patterns,
+            pattern_kinds,
+        })
+    }
+
+    /// Adds the constraints that the entries occurring in the sentence impose to `constraints`.
+    pub fn add_constraints(
+        &self,
+        sentence_chars: &[char],
+        constraints: &mut SegmentationConstraints,
+    ) {
+        let text: String = sentence_chars.iter().collect();
+        // The matches are reported in bytes, while the constraints are in characters.
+        let mut char_positions = vec![0; text.len() + 1];
+        for (char_index, (byte_index, _)) in text.char_indices().enumerate() {
+            char_positions[byte_index] = char_index;
+        }
+        char_positions[text.len()] = sentence_chars.len();
+
+        if let Some(ref words) = self.words {
+            for m in words.find_iter(&text) {
+                constraints
+                    .words
+                    .push((char_positions[m.start()], char_positions[m.end()]));
+            }
+        }
+        if let Some(ref patterns) = self.patterns {
+            for m in patterns.find_overlapping_iter(&text) {
+                let begin = char_positions[m.start()];
+                match self.pattern_kinds[m.pattern().as_usize()] {
+                    PatternKind::Forbidden => {
+                        constraints
+                            .forbidden_words
+                            .push((begin, char_positions[m.end()]));
+                    }
+                    PatternKind::Boundaries(ref boundaries) => {
+                        for boundary in boundaries {
+                            constraints.boundaries.push(begin + boundary);
+                        }
+                    }
+                }
+            }
+        }
+        constraints.boundaries.sort();
+        constraints.boundaries.dedup();
+    }
+}
+
+impl fmt::Display for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let num_forbidden_words = self
+            .pattern_kinds
+            .iter()
+            .filter(|kind| matches!(kind, PatternKind::Forbidden))
+            .count();
+        write!(
+            f,
+            "{} words, {} forbidden words, {} words with boundaries",
+            self.num_words,
+            num_forbidden_words,
+            self.pattern_kinds.len() - num_forbidden_words
+        )
+    }
+}
@@ -0,0 +1,61 @@
+use bincode;
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::result;
+
+/// The errors returned by the library instead of panicking, so that a program embedding it can report them and decide what to do.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed.
+    Io(io::Error),
+    /// A line of an input file is not valid UTF-8. Lines are counted from 1.
+    InvalidEncoding { path: PathBuf, line: usize },
+    /// A probability underflowed to zero or became NaN, so that nothing could be sampled from it.
+    NumericalUnderflow(String),
+    /// A parameter is out of its valid range, or the input leaves the model nothing to work with.
+    InvalidConfig(String),
+    /// The file is not a model saved by `Model::save`, or was saved by an incompatible version.
+    InvalidModelFile(String),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::InvalidEncoding { path, line } => {
+                write!(f, "{:?}, line {}: not valid UTF-8", path, line)
+            }
+            Error::NumericalUnderflow(message) => write!(f, "Numerical underflow: {}", message),
+            Error::InvalidConfig(message) => write!(f, "Invalid configuration: {}", message),
+            Error::InvalidModelFile(message) => write!(f, "Invalid model file: {}", message),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        match *e {
+            bincode::ErrorKind::Io(e) => Error::Io(e),
+            other => Error::InvalidModelFile(other.to_string()),
+        }
+    }
+}
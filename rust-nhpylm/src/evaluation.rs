@@ -0,0 +1,373 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+
+/// Reads a word list such as `icwb2-data/gold/pku_training_words.utf8`, one word per line. Words in this list are "in vocabulary" (IV) for the OOV and IV recall.
+pub fn read_dictionary(path: &Path) -> io::Result<HashSet<String>> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+    let mut dictionary = HashSet::new();
+    for line in reader.lines() {
+        let line = line?;
+        let word = line.trim();
+        if !word.is_empty() {
+            dictionary.insert(word.to_owned());
+        }
+    }
+    Ok(dictionary)
+}
+
+/// Splits a line of segmented text into words. Any whitespace separates words, including the full-width space used in the AS gold files.
+pub fn split_segmented_line(line: &str) -> Vec<String> {
+    line.split_whitespace().map(|w| w.to_owned()).collect()
+}
+
+/// The scores reported by the SIGHAN `score.pl` script, plus the precision, recall and F-measure of the word boundaries.
+#[derive(Clone, Debug)]
+pub struct SegmentationScores {
+    pub num_true_words: usize,
+    pub num_test_words: usize,
+    pub num_correct_words: usize,
+    pub recall: f64,
+    pub precision: f64,
+    pub f_measure: f64,
+    pub oov_rate: f64,
+    /// `None` if there are no OOV words in the gold data.
+    pub oov_recall: Option<f64>,
+    /// `None` if there are no IV words in the gold data.
+    pub iv_recall: Option<f64>,
+    pub boundary_recall: f64,
+    pub boundary_precision: f64,
+    pub boundary_f_measure: f64,
+}
+
+/// Formats a rate with three decimals, or `--` if it is undefined.
+pub fn format_optional_rate(rate: Option<f64>) -> String {
+    match rate {
+        None => "--".to_owned(),
+        Some(r) => format!("{:.3}", r),
+    }
+}
+
+impl fmt::Display for SegmentationScores {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "=== TOTAL TRUE WORD COUNT:\t{}", self.num_true_words)?;
+        writeln!(f, "=== TOTAL TEST WORD COUNT:\t{}", self.num_test_words)?;
+        writeln!(f, "=== TOTAL TRUE WORDS RECALL:\t{:.3}", self.recall)?;
+        writeln!(f, "=== TOTAL TEST WORDS PRECISION:\t{:.3}", self.precision)?;
+        writeln!(f, "=== F MEASURE:\t{:.3}", self.f_measure)?;
+        writeln!(f, "=== OOV Rate:\t{:.3}", self.oov_rate)?;
+        writeln!(
+            f,
+            "=== OOV Recall Rate:\t{}",
+            format_optional_rate(self.oov_recall)
+        )?;
+        writeln!(
+            f,
+            "=== IV Recall Rate:\t{}",
+            format_optional_rate(self.iv_recall)
+        )?;
+        writeln!(f, "=== BOUNDARY RECALL:\t{:.3}", self.boundary_recall)?;
+        writeln!(f, "=== BOUNDARY PRECISION:\t{:.3}", self.boundary_precision)?;
+        write!(f, "=== BOUNDARY F MEASURE:\t{:.3}", self.boundary_f_measure)
+    }
+}
+
+fn f_measure(precision: f64, recall: f64) -> f64 {
+    if precision + recall == 0.0 {
+        0.0
+    } else {
+        2.0 * precision * recall / (precision + recall)
+    }
+}
+
+/// Returns, for each gold word, whether it is part of the longest common subsequence of the two word sequences.
+///
+/// This is what `score.pl` gets out of `diff -y`: a gold word counts as correctly segmented if diff doesn't mark it as deleted or substituted.
+fn align_words(gold: &[String], test: &[String]) -> Vec<bool> {
+    let n = gold.len();
+    let m = test.len();
+    // lcs[i][j] is the length of the LCS of gold[i..] and test[j..].
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if gold[i] == test[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut matched = vec![false; n];
+    let mut i = 0;
+    let mut j = 0;
+    while i < n && j < m {
+        if gold[i] == test[j] {
+            matched[i] = true;
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    matched
+}
+
+/// The character offsets at which a new word starts, excluding the beginning of the sentence.
+fn word_boundaries(words: &[String]) -> HashSet<usize> {
+    let mut boundaries = HashSet::new();
+    let mut offset = 0;
+    for (index, word) in words.iter().enumerate() {
+        if index > 0 {
+            boundaries.insert(offset);
+        }
+        offset += word.chars().count();
+    }
+    boundaries
+}
+
+/// Turns a segmentation given as word lengths back into words.
+pub fn words_from_segment_lengths(characters: &[char], segment_lengths: &[usize]) -> Vec<String> {
+    let mut words = Vec::with_capacity(segment_lengths.len());
+    let mut start = 0;
+    for length in segment_lengths {
+        words.push(characters[start..start + length].iter().collect());
+        start += length;
+    }
+    words
+}
+
+/// Accumulates the counts needed for the scores over a whole test set, one sentence at a time.
+pub struct Evaluator<'a> {
+    dictionary: &'a HashSet<String>,
+    num_true_words: usize,
+    num_test_words: usize,
+    num_correct_words: usize,
+    num_oov: usize,
+    num_oov_missed: usize,
+    num_iv: usize,
+    num_iv_missed: usize,
+    num_true_boundaries: usize,
+    num_test_boundaries: usize,
+    num_correct_boundaries: usize,
+}
+
+impl<'a> Evaluator<'a> {
+    /// The dictionary contains the words seen in training. Pass an empty set if there is no such list, in which case every gold word is counted as OOV.
+    pub fn new(dictionary: &'a HashSet<String>) -> Self {
+        Self {
+            dictionary,
+            num_true_words: 0,
+            num_test_words: 0,
+            num_correct_words: 0,
+            num_oov: 0,
+            num_oov_missed: 0,
+            num_iv: 0,
+            num_iv_missed: 0,
+            num_true_boundaries: 0,
+            num_test_boundaries: 0,
+            num_correct_boundaries: 0,
+        }
+    }
+
+    /// Adds one sentence. As in `score.pl`, sentences with an empty gold segmentation are skipped.
+    pub fn add_sentence(&mut self, gold: &[String], test: &[String]) {
+        if gold.is_empty() {
+            return;
+        }
+
+        let matched = align_words(gold, test);
+        for (word, is_matched) in gold.iter().zip(matched.iter()) {
+            if self.dictionary.contains(word) {
+                self.num_iv += 1;
+                if !is_matched {
+                    self.num_iv_missed += 1;
+                }
+            } else {
+                self.num_oov += 1;
+                if !is_matched {
+                    self.num_oov_missed += 1;
+                }
+            }
+            if *is_matched {
+                self.num_correct_words += 1;
+            }
+        }
+        self.num_true_words += gold.len();
+        self.num_test_words += test.len();
+
+        let true_boundaries = word_boundaries(gold);
+        let test_boundaries = word_boundaries(test);
+        self.num_true_boundaries += true_boundaries.len();
+        self.num_test_boundaries += test_boundaries.len();
+        self.num_correct_boundaries += true_boundaries.intersection(&test_boundaries).count();
+    }
+
+    pub fn get_scores(&self) -> SegmentationScores {
+        let recall = if self.num_true_words == 0 {
+            0.0
+        } else {
+            self.num_correct_words as f64 / self.num_true_words as f64
+        };
+        let precision = if self.num_test_words == 0 {
+            0.0
+        } else {
+            self.num_correct_words as f64 / self.num_test_words as f64
+        };
+        let oov_rate = if self.num_true_words == 0 {
+            0.0
+        } else {
+            self.num_oov as f64 / self.num_true_words as f64
+        };
+        let oov_recall = if self.num_oov == 0 {
+            None
+        } else {
+            Some(1.0 - self.num_oov_missed as f64 / self.num_oov as f64)
+        };
+        let iv_recall = if self.num_iv == 0 {
+            None
+        } else {
+            Some(1.0 - self.num_iv_missed as f64 / self.num_iv as f64)
+        };
+
+        // A sentence of a single word has no boundaries at all. If neither side has any, the boundaries are trivially all correct.
+        let boundary_recall = if self.num_true_boundaries == 0 {
+            1.0
+        } else {
+            self.num_correct_boundaries as f64 / self.num_true_boundaries as f64
+        };
+        let boundary_precision = if self.num_test_boundaries == 0 {
+            if self.num_true_boundaries == 0 {
+                1.0
+            } else {
+                0.0
+            }
+        } else {
+            self.num_correct_boundaries as f64 / self.num_test_boundaries as f64
+        };
+
+        SegmentationScores {
+            num_true_words: self.num_true_words,
+            num_test_words: self.num_test_words,
+            num_correct_words: self.num_correct_words,
+            recall,
+            precision,
+            f_measure: f_measure(precision, recall),
+            oov_rate,
+            oov_recall,
+            iv_recall,
+            boundary_recall,
+            boundary_precision,
+            boundary_f_measure: f_measure(boundary_precision, boundary_recall),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<String> {
+        split_segmented_line(line)
+    }
+
+    fn score(dictionary: &[&str], gold: &[&str], test: &[&str]) -> SegmentationScores {
+        let dictionary: HashSet<String> = dictionary.iter().map(|w| w.to_string()).collect();
+        let mut evaluator = Evaluator::new(&dictionary);
+        for (gold_line, test_line) in gold.iter().zip(test.iter()) {
+            evaluator.add_sentence(&words(gold_line), &words(test_line));
+        }
+        evaluator.get_scores()
+    }
+
+    fn rate(rate: f64) -> String {
+        format!("{:.3}", rate)
+    }
+
+    // The expected values below are what `icwb2-data/scripts/score.pl` prints for the same files.
+
+    #[test]
+    fn test_identical_segmentation() {
+        let scores = score(&[], &["a b c"], &["a b c"]);
+        assert_eq!(scores.num_true_words, 3);
+        assert_eq!(scores.num_test_words, 3);
+        assert_eq!(rate(scores.recall), "1.000");
+        assert_eq!(rate(scores.precision), "1.000");
+        assert_eq!(rate(scores.f_measure), "1.000");
+        assert_eq!(rate(scores.oov_rate), "1.000");
+        assert_eq!(format_optional_rate(scores.oov_recall), "1.000");
+        assert_eq!(format_optional_rate(scores.iv_recall), "--");
+    }
+
+    #[test]
+    fn test_merged_words() {
+        let scores = score(&[], &["a b c d"], &["a bc d"]);
+        assert_eq!(scores.num_true_words, 4);
+        assert_eq!(scores.num_test_words, 3);
+        assert_eq!(rate(scores.recall), "0.500");
+        assert_eq!(rate(scores.precision), "0.667");
+        assert_eq!(rate(scores.f_measure), "0.571");
+        assert_eq!(format_optional_rate(scores.oov_recall), "0.500");
+    }
+
+    #[test]
+    fn test_inserted_word() {
+        let scores = score(&[], &["x y z"], &["x y z w"]);
+        assert_eq!(scores.num_true_words, 3);
+        assert_eq!(scores.num_test_words, 4);
+        assert_eq!(rate(scores.recall), "1.000");
+        assert_eq!(rate(scores.precision), "0.750");
+        assert_eq!(rate(scores.f_measure), "0.857");
+    }
+
+    #[test]
+    fn test_substitutions_insertions_deletions_and_empty_lines() {
+        // The third line is empty on both sides, and the fifth only in the gold file. score.pl skips both.
+        let gold = ["中国 人民 银行", "北京大学 学生", "", "今天 天气 很 好", ""];
+        let test = [
+            "中国人民 银行",
+            "北京 大学 学生",
+            "",
+            "今天 天气 很好",
+            "我 爱 北京",
+        ];
+        let scores = score(&["中国", "人民", "学生"], &gold, &test);
+        assert_eq!(scores.num_true_words, 9);
+        assert_eq!(scores.num_test_words, 8);
+        assert_eq!(scores.num_correct_words, 4);
+        assert_eq!(rate(scores.recall), "0.444");
+        assert_eq!(rate(scores.precision), "0.500");
+        assert_eq!(rate(scores.f_measure), "0.471");
+        assert_eq!(rate(scores.oov_rate), "0.667");
+        assert_eq!(format_optional_rate(scores.oov_recall), "0.500");
+        assert_eq!(format_optional_rate(scores.iv_recall), "0.333");
+    }
+
+    #[test]
+    fn test_empty_test_line() {
+        // score.pl divides by zero on such a line, so these values are counted by hand: every gold word is missed.
+        let scores = score(&["北京"], &["我 爱 北京", "a b"], &["", "a b"]);
+        assert_eq!(scores.num_true_words, 5);
+        assert_eq!(scores.num_test_words, 2);
+        assert_eq!(scores.num_correct_words, 2);
+        assert_eq!(rate(scores.recall), "0.400");
+        assert_eq!(rate(scores.precision), "1.000");
+        assert_eq!(format_optional_rate(scores.iv_recall), "0.000");
+        assert_eq!(format_optional_rate(scores.oov_recall), "0.500");
+    }
+
+    #[test]
+    fn test_word_boundaries() {
+        let scores = score(&[], &["ab c d"], &["a bc d"]);
+        // Gold boundaries at 2 and 3, test boundaries at 1 and 3.
+        assert_eq!(rate(scores.boundary_recall), "0.500");
+        assert_eq!(rate(scores.boundary_precision), "0.500");
+    }
+}
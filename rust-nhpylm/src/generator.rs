@@ -0,0 +1,72 @@
+use error::*;
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
+use serde_json;
+
+/// The number of times a new word is spelled again when the character model comes up with a word the word model already knows, see `NPYLM::generate_sentence`.
+pub const MAX_NEW_WORD_ATTEMPTS: usize = 100;
+
+/// A sentence sampled from a trained model, see `Model::generate_sentence`.
+#[derive(Clone, Debug, Serialize)]
+pub struct GeneratedSentence {
+    pub words: Vec<String>,
+    /// The words that the word model doesn't know, and which were spelled by the character model.
+    pub num_new_words: usize,
+    /// The log probability of the words and the EOS after them under the model at temperature 1.
+    pub log_probability: f64,
+    /// Whether the sentence was cut off at the maximum number of words before the model drew EOS.
+    pub truncated: bool,
+}
+
+impl GeneratedSentence {
+    pub fn get_text(&self) -> String {
+        self.words.concat()
+    }
+
+    /// The words separated by two spaces, the same as in the PKU and MSR gold files.
+    pub fn get_segmented_text(&self) -> String {
+        self.words.join("  ")
+    }
+
+    /// The sentence as a single line of JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+pub fn check_temperature(temperature: f64) -> Result<f64> {
+    if temperature > 0.0 && temperature.is_finite() {
+        Ok(temperature)
+    } else {
+        Err(Error::InvalidConfig(format!(
+            "The temperature must be positive, got {}",
+            temperature
+        )))
+    }
+}
+
+/// Draws an index with a probability proportional to `probabilities[i]` raised to the power of 1/T. Below 1, the temperature sharpens the distribution towards its most probable entries, and above 1 it flattens it. The powers are taken in log space, so that a low temperature doesn't underflow.
+pub fn sample_index_with_temperature<R: Rng>(
+    probabilities: &[f64],
+    temperature: f64,
+    rng: &mut R,
+) -> Result<usize> {
+    let max_log_p = probabilities
+        .iter()
+        .filter(|&&p| p > 0.0)
+        .map(|p| p.ln())
+        .fold(f64::NEG_INFINITY, f64::max);
+    let weights: Vec<f64> = probabilities
+        .iter()
+        .map(|&p| {
+            if p > 0.0 {
+                ((p.ln() - max_log_p) / temperature).exp()
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    let dist = WeightedIndex::new(&weights)
+        .map_err(|e| Error::NumericalUnderflow(format!("Cannot sample from the model: {}", e)))?;
+    Ok(dist.sample(rng))
+}
@@ -1,5 +1,7 @@
 use def::*;
+use error::*;
 use pyp::*;
+use rand::Rng;
 
 pub trait HPYLM<T> {
   fn get_num_nodes(&self) -> usize;
@@ -7,52 +9,114 @@ pub trait HPYLM<T> {
   fn get_num_customers(&self) -> usize;
   fn get_pass_counts(&self) -> usize;
   fn get_stop_counts(&self) -> usize;
-  fn sample_hyperparameters(&mut self);
+  fn sample_hyperparameters<R: Rng>(&mut self, rng: &mut R);
+}
+
+/// The hyperparameters every restaurant of a depth starts with, and the priors they are sampled from. d is drawn from Beta(a + ..., b + ...) and θ from Gamma(alpha + ..., 1 / (beta - ...)), see expressions (40) and (41) of the Teh technical report.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HPYLMPriors {
+  pub initial_d: f64,
+  pub initial_theta: f64,
+  pub a: f64,
+  pub b: f64,
+  pub alpha: f64,
+  pub beta: f64,
+}
+
+impl Default for HPYLMPriors {
+  fn default() -> Self {
+    Self {
+      initial_d: HPYLM_INITIAL_D,
+      initial_theta: HPYLM_INITIAL_THETA,
+      a: HPYLM_A,
+      b: HPYLM_B,
+      alpha: HPYLM_ALPHA,
+      beta: HPYLM_BETA,
+    }
+  }
+}
+
+impl HPYLMPriors {
+  /// `name` is the prefix of the parameters in the error, e.g. the section of the configuration they come from.
+  pub fn check(&self, name: &str) -> Result<()> {
+    if !(self.initial_d > 0.0 && self.initial_d < 1.0) {
+      return Err(Error::InvalidConfig(format!(
+        "{}.initial-d must be between 0 and 1, got {}",
+        name, self.initial_d
+      )));
+    }
+    let parameters = [
+      ("initial-theta", self.initial_theta),
+      ("a", self.a),
+      ("b", self.b),
+      ("alpha", self.alpha),
+      ("beta", self.beta),
+    ];
+    for &(parameter, value) in parameters.iter() {
+      if !(value > 0.0 && value.is_finite()) {
+        return Err(Error::InvalidConfig(format!(
+          "{}.{} must be a positive number, got {}",
+          name, parameter, value
+        )));
+      }
+    }
+    Ok(())
+  }
 }
 
 pub fn init_hyperparameters_at_depth_if_needed(
   d_array: &mut Vec<f64>,
   theta_array: &mut Vec<f64>,
-  a_array: &mut Vec<f64>,
-  b_array: &mut Vec<f64>,
-  alpha_array: &mut Vec<f64>,
-  beta_array: &mut Vec<f64>,
+  priors: &HPYLMPriors,
   depth: usize,
 ) {
   if d_array.len() <= depth {
     while d_array.len() <= depth {
-      d_array.push(HPYLM_INITIAL_D);
+      d_array.push(priors.initial_d);
     }
   }
   if theta_array.len() <= depth {
     while theta_array.len() <= depth {
-      theta_array.push(HPYLM_INITIAL_THETA);
+      theta_array.push(priors.initial_theta);
     }
   }
+}
+
+// The parameters of the Beta and Gamma priors over d and theta at each depth.
+pub fn init_priors_at_depth_if_needed(
+  a_array: &mut Vec<f64>,
+  b_array: &mut Vec<f64>,
+  alpha_array: &mut Vec<f64>,
+  beta_array: &mut Vec<f64>,
+  priors: &HPYLMPriors,
+  depth: usize,
+) {
   if a_array.len() <= depth {
     while a_array.len() <= depth {
-      a_array.push(HPYLM_A);
+      a_array.push(priors.a);
     }
   }
   if b_array.len() <= depth {
     while b_array.len() <= depth {
-      b_array.push(HPYLM_B);
+      b_array.push(priors.b);
     }
   }
   if alpha_array.len() <= depth {
     while alpha_array.len() <= depth {
-      alpha_array.push(HPYLM_ALPHA);
+      alpha_array.push(priors.alpha);
     }
   }
   if beta_array.len() <= depth {
     while beta_array.len() <= depth {
-      beta_array.push(HPYLM_BETA);
+      beta_array.push(priors.beta);
     }
   }
 }
 
-pub fn sum_auxiliary_variables_recursively<T>(
-  node: &PYP<T>,
+pub fn sum_auxiliary_variables_recursively<T, R>(
+  tree: &PYPTree<T>,
+  node_id: NodeId,
   sum_log_x_u_array: &mut Vec<f64>,
   sum_y_ui_array: &mut Vec<f64>,
   sum_one_minus_y_ui_array: &mut Vec<f64>,
@@ -63,37 +127,35 @@ pub fn sum_auxiliary_variables_recursively<T>(
   b_array: &mut Vec<f64>,
   alpha_array: &mut Vec<f64>,
   beta_array: &mut Vec<f64>,
+  priors: &HPYLMPriors,
   bottom: &mut usize,
+  rng: &mut R,
 ) where
   T: std::cmp::Eq,
   T: std::hash::Hash,
   T: Copy,
+  R: Rng,
 {
-  for child in node.children.values() {
-    let depth = child.depth;
+  let node = tree.node(node_id);
+  for child_id in tree.get_sorted_child_ids(node_id) {
+    let depth = tree.node(child_id).depth;
     if depth > *bottom {
       *bottom = depth;
     }
 
-    init_hyperparameters_at_depth_if_needed(
-      d_array,
-      theta_array,
-      a_array,
-      b_array,
-      alpha_array,
-      beta_array,
-      depth,
-    );
+    init_hyperparameters_at_depth_if_needed(d_array, theta_array, priors, depth);
+    init_priors_at_depth_if_needed(a_array, b_array, alpha_array, beta_array, priors, depth);
 
     let d = d_array[depth];
     let theta = theta_array[depth];
-    sum_log_x_u_array[depth] += node.sample_log_x_u(theta);
-    sum_y_ui_array[depth] += node.sample_summed_y_ui(d, theta, false);
-    sum_one_minus_y_ui_array[depth] += node.sample_summed_y_ui(d, theta, true);
-    sum_one_minus_z_uwkj_array[depth] += node.sample_summed_one_minus_z_uwkj(d);
+    sum_log_x_u_array[depth] += node.sample_log_x_u(theta, rng);
+    sum_y_ui_array[depth] += node.sample_summed_y_ui(d, theta, false, rng);
+    sum_one_minus_y_ui_array[depth] += node.sample_summed_y_ui(d, theta, true, rng);
+    sum_one_minus_z_uwkj_array[depth] += node.sample_summed_one_minus_z_uwkj(d, rng);
 
     sum_auxiliary_variables_recursively(
-      child,
+      tree,
+      child_id,
       sum_log_x_u_array,
       sum_y_ui_array,
       sum_one_minus_y_ui_array,
@@ -104,7 +166,9 @@ pub fn sum_auxiliary_variables_recursively<T>(
       b_array,
       alpha_array,
       beta_array,
+      priors,
       bottom,
+      rng,
     );
   }
 }
@@ -0,0 +1,231 @@
+use def::*;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+/// The IDs from here on are never handed out by a `WordInterner`. They stand for substrings of the sentence at hand that are not words of the model yet, see `transient_word_id`.
+pub const TRANSIENT_WORD_ID_BASE: u64 = 1 << 63;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// 64-bit FNV-1a over the code points of the word. Unlike `DefaultHasher`, it gives the same result with every Rust release.
+fn stable_hash(word: &[char]) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    for &c in word {
+        for byte in (c as u32).to_le_bytes().iter() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+    hash
+}
+
+/// An ID that stands for the `length` characters of the current sentence beginning at `begin`, for words that are not in the interner. Different substrings of a sentence of the given length always get different IDs, so they may be used as keys of caches that are cleared for every sentence.
+pub fn transient_word_id(sentence_length: usize, begin: usize, length: usize) -> u64 {
+    TRANSIENT_WORD_ID_BASE + (begin * (sentence_length + 1) + length) as u64
+}
+
+pub fn is_transient_word_id(word_id: u64) -> bool {
+    word_id >= TRANSIENT_WORD_ID_BASE
+}
+
+/// Maps the words of the model to dense IDs and back. `BOS` and `EOS` are reserved as the first two IDs, and every other word gets the smallest free ID when it is first interned.
+///
+/// Only the words seated in the model are interned, but every segmentation the sampler accepts seats new ones, so the IDs of the words that are no longer seated have to be given back with `release_unused` from time to time. Between two such calls the interner holds at most the words seated in the meantime, which are all substrings of the training sentences.
+///
+/// Words are found by a stable hash of their characters, and two different words whose hashes collide still get different IDs. Only the words are serialized, in the order of their IDs, so a saved interner reads back with the same IDs and the same free ones.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WordInterner {
+    // The characters of all the words one after another.
+    characters: Vec<char>,
+    // Word `id` is `characters[word_ranges[id].0..word_ranges[id].1]`. The range of a released ID is empty.
+    word_ranges: Vec<(usize, usize)>,
+    // The released IDs, largest first, so that the smallest one is handed out next.
+    #[serde(skip)]
+    free_ids: Vec<u64>,
+    #[serde(skip)]
+    ids: HashMap<u64, u64, FixedState>,
+    // The IDs of the words whose hash is already taken by the one in `ids`.
+    #[serde(skip)]
+    colliding_ids: HashMap<u64, Vec<u64>, FixedState>,
+}
+
+impl Default for WordInterner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WordInterner {
+    pub fn new() -> Self {
+        let mut interner = Self {
+            characters: Vec::new(),
+            word_ranges: Vec::new(),
+            free_ids: Vec::new(),
+            ids: HashMap::default(),
+            colliding_ids: HashMap::default(),
+        };
+        // These can't be looked up, so that they never clash with a word of the text.
+        interner.push_word(&['<', 'B', 'O', 'S', '>']);
+        interner.push_word(&['<', 'E', 'O', 'S', '>']);
+        interner
+    }
+
+    /// Restores the lookup tables, which are not serialized.
+    pub fn rebuild_after_deserialization(&mut self) {
+        self.ids = HashMap::default();
+        self.colliding_ids = HashMap::default();
+        self.free_ids = Vec::new();
+        for id in (EOS + 1..self.word_ranges.len() as u64).rev() {
+            if self.is_free(id) {
+                self.free_ids.push(id);
+            }
+        }
+        for id in EOS + 1..self.word_ranges.len() as u64 {
+            if !self.is_free(id) {
+                let hash = stable_hash(self.get_word(id));
+                self.index_word(hash, id);
+            }
+        }
+    }
+
+    /// The number of words interned, including `BOS` and `EOS`.
+    pub fn get_num_words(&self) -> usize {
+        self.word_ranges.len() - self.free_ids.len()
+    }
+
+    /// The number of words whose hash collides with that of an earlier word.
+    pub fn get_num_collisions(&self) -> usize {
+        self.colliding_ids.values().map(|ids| ids.len()).sum()
+    }
+
+    pub fn get(&self, word: &[char]) -> Option<u64> {
+        let hash = stable_hash(word);
+        let id = *self.ids.get(&hash)?;
+        if self.get_word(id) == word {
+            return Some(id);
+        }
+        self.colliding_ids.get(&hash).and_then(|ids| {
+            ids.iter()
+                .cloned()
+                .find(|&colliding_id| self.get_word(colliding_id) == word)
+        })
+    }
+
+    pub fn get_or_intern(&mut self, word: &[char]) -> u64 {
+        if let Some(id) = self.get(word) {
+            return id;
+        }
+        let id = self.push_word(word);
+        self.index_word(stable_hash(word), id);
+        id
+    }
+
+    /// The characters of a word handed out by this interner. Panics for any other ID, including the transient ones, and is empty for a released one.
+    pub fn get_word(&self, id: u64) -> &[char] {
+        let (begin, end) = self.word_ranges[id as usize];
+        &self.characters[begin..end]
+    }
+
+    /// Gives back the ID of every word other than `BOS` and `EOS` for which `is_used` is false, so that it can be handed out to another word, and drops the characters of these words. Returns the number of IDs released.
+    ///
+    /// Whatever still refers to a released ID, e.g. a cache keyed by the IDs, has to forget it, since the ID will stand for another word.
+    pub fn release_unused<F: Fn(u64) -> bool>(&mut self, is_used: F) -> usize {
+        let mut characters = Vec::with_capacity(self.characters.len());
+        let mut num_released = 0;
+        for id in 0..self.word_ranges.len() as u64 {
+            if self.is_free(id) {
+                continue;
+            }
+            if id > EOS && !is_used(id) {
+                self.word_ranges[id as usize] = (0, 0);
+                num_released += 1;
+                continue;
+            }
+            let begin = characters.len();
+            characters.extend_from_slice(self.get_word(id));
+            self.word_ranges[id as usize] = (begin, characters.len());
+        }
+        self.characters = characters;
+        self.rebuild_after_deserialization();
+        num_released
+    }
+
+    fn is_free(&self, id: u64) -> bool {
+        let (begin, end) = self.word_ranges[id as usize];
+        begin == end
+    }
+
+    fn push_word(&mut self, word: &[char]) -> u64 {
+        let range = (self.characters.len(), self.characters.len() + word.len());
+        self.characters.extend_from_slice(word);
+        match self.free_ids.pop() {
+            Some(id) => {
+                self.word_ranges[id as usize] = range;
+                id
+            }
+            None => {
+                self.word_ranges.push(range);
+                self.word_ranges.len() as u64 - 1
+            }
+        }
+    }
+
+    fn index_word(&mut self, hash: u64, id: u64) {
+        match self.ids.entry(hash) {
+            Entry::Occupied(_) => self.colliding_ids.entry(hash).or_default().push(id),
+            Entry::Vacant(e) => {
+                e.insert(id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode;
+
+    fn chars(word: &str) -> Vec<char> {
+        word.chars().collect()
+    }
+
+    #[test]
+    fn test_released_ids_are_handed_out_again() {
+        let mut interner = WordInterner::new();
+        let a = interner.get_or_intern(&chars("a"));
+        let bc = interner.get_or_intern(&chars("bc"));
+        let d = interner.get_or_intern(&chars("d"));
+        assert_eq!(interner.get_num_words(), 5);
+
+        assert_eq!(interner.release_unused(|id| id != bc), 1);
+        assert_eq!(interner.get_num_words(), 4);
+        assert_eq!(interner.get(&chars("bc")), None);
+        assert_eq!(interner.get(&chars("a")), Some(a));
+        assert_eq!(interner.get_word(d), &chars("d")[..]);
+
+        let efg = interner.get_or_intern(&chars("efg"));
+        assert_eq!(efg, bc);
+        assert_eq!(interner.get_word(efg), &chars("efg")[..]);
+        assert_eq!(interner.get_or_intern(&chars("h")), d + 1);
+    }
+
+    #[test]
+    fn test_free_ids_survive_serialization() {
+        let mut interner = WordInterner::new();
+        for word in &["a", "b", "c", "d"] {
+            interner.get_or_intern(&chars(word));
+        }
+        let b = interner.get(&chars("b")).unwrap();
+        let c = interner.get(&chars("c")).unwrap();
+        interner.release_unused(|id| id != b && id != c);
+
+        let mut restored: WordInterner =
+            bincode::deserialize(&bincode::serialize(&interner).unwrap()).unwrap();
+        restored.rebuild_after_deserialization();
+        assert_eq!(restored.get_num_words(), interner.get_num_words());
+        assert_eq!(restored.get(&chars("d")), interner.get(&chars("d")));
+        assert_eq!(restored.get_or_intern(&chars("x")), b);
+        assert_eq!(restored.get_or_intern(&chars("y")), c);
+    }
+}
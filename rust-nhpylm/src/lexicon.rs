@@ -0,0 +1,123 @@
+use error::*;
+use serde_json;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::Write;
+
+/// A word of the training segmentation, with what the model has learned about it.
+#[derive(Clone, Debug, Serialize)]
+pub struct LexiconEntry {
+    pub word: String,
+    pub length: usize,
+    /// The type of the word under the word type scheme of the model, numbered from 1.
+    pub word_type: usize,
+    /// The number of times the word occurs in the segmentation of the training sentences.
+    pub token_count: usize,
+    /// The number of tables serving the word in the unigram restaurant of the WHPYLM.
+    pub table_count: usize,
+    /// The unigram probability p(w) of the WHPYLM.
+    pub probability: f64,
+    /// The probability of the word under the base measure of the WHPYLM, i.e. the CHPYLM with its word length distribution replaced by the Poisson distribution of the word type.
+    pub base_probability: f64,
+}
+
+/// The order of the entries of a `Lexicon`. The counts, probabilities and lengths are sorted from the highest down, and ties are broken by the word.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LexiconOrder {
+    TokenCount,
+    TableCount,
+    Probability,
+    BaseProbability,
+    Length,
+    Word,
+}
+
+impl LexiconOrder {
+    /// Parses one of `count`, `tables`, `probability`, `base-probability`, `length` and `word`.
+    pub fn from_spec(spec: &str) -> Result<Self> {
+        match spec {
+            "count" => Ok(LexiconOrder::TokenCount),
+            "tables" => Ok(LexiconOrder::TableCount),
+            "probability" => Ok(LexiconOrder::Probability),
+            "base-probability" => Ok(LexiconOrder::BaseProbability),
+            "length" => Ok(LexiconOrder::Length),
+            "word" => Ok(LexiconOrder::Word),
+            _ => Err(Error::InvalidConfig(format!(
+                "Unknown lexicon order {:?}, expected count, tables, probability, base-probability, length or word",
+                spec
+            ))),
+        }
+    }
+
+    fn compare(&self, a: &LexiconEntry, b: &LexiconEntry) -> Ordering {
+        let ordering = match self {
+            LexiconOrder::TokenCount => b.token_count.cmp(&a.token_count),
+            LexiconOrder::TableCount => b.table_count.cmp(&a.table_count),
+            LexiconOrder::Probability => b
+                .probability
+                .partial_cmp(&a.probability)
+                .unwrap_or(Ordering::Equal),
+            LexiconOrder::BaseProbability => b
+                .base_probability
+                .partial_cmp(&a.base_probability)
+                .unwrap_or(Ordering::Equal),
+            LexiconOrder::Length => b.length.cmp(&a.length),
+            LexiconOrder::Word => Ordering::Equal,
+        };
+        ordering.then_with(|| a.word.cmp(&b.word))
+    }
+}
+
+impl fmt::Display for LexiconOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LexiconOrder::TokenCount => write!(f, "count"),
+            LexiconOrder::TableCount => write!(f, "tables"),
+            LexiconOrder::Probability => write!(f, "probability"),
+            LexiconOrder::BaseProbability => write!(f, "base-probability"),
+            LexiconOrder::Length => write!(f, "length"),
+            LexiconOrder::Word => write!(f, "word"),
+        }
+    }
+}
+
+/// The vocabulary induced by the training, one entry per word type of the training segmentation, see `Model::lexicon`.
+pub struct Lexicon {
+    pub entries: Vec<LexiconEntry>,
+}
+
+impl Lexicon {
+    pub fn sort(&mut self, order: LexiconOrder) {
+        self.entries.sort_by(|a, b| order.compare(a, b));
+    }
+
+    /// Writes a header line and then one line per entry, with the fields separated by tabs.
+    pub fn write_tsv<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(
+            writer,
+            "word\tlength\tword_type\ttoken_count\ttable_count\tprobability\tbase_probability"
+        )?;
+        for entry in &self.entries {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{}\t{:e}\t{:e}",
+                entry.word,
+                entry.length,
+                entry.word_type,
+                entry.token_count,
+                entry.table_count,
+                entry.probability,
+                entry.base_probability
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes each entry as a line of JSON.
+    pub fn write_json<W: Write>(&self, writer: &mut W) -> Result<()> {
+        for entry in &self.entries {
+            writeln!(writer, "{}", serde_json::to_string(entry).unwrap())?;
+        }
+        Ok(())
+    }
+}
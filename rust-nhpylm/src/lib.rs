@@ -1,31 +1,77 @@
+extern crate aho_corasick;
+extern crate bincode;
 extern crate either;
 extern crate ndarray;
 extern crate rand;
+extern crate rand_pcg;
 extern crate regex;
+extern crate serde;
+extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
 extern crate statrs;
+extern crate toml;
+extern crate unicode_normalization;
+
+mod error;
+pub use error::{Error, Result};
 
 mod sentence;
-pub use sentence::Sentence;
+pub use sentence::{SegmentationConstraints, Sentence};
 
 pub mod def;
 
 mod ctype;
 mod wtype;
+pub use wtype::{
+    get_word_type_scheme, ChineseWordTypes, JapaneseWordTypes, KoreanWordTypes,
+    SoutheastAsianWordTypes, WordTypeScheme, WORD_TYPE_SCHEME_NAMES,
+};
+
+mod boundary;
+pub use boundary::BoundaryRules;
+
+mod dictionary;
+pub use dictionary::Dictionary;
+
+mod normalizer;
+pub use normalizer::{NormalizationStep, Normalizer};
 
 mod corpus;
-pub use corpus::{Corpus, Dataset};
+pub use corpus::{Corpus, Dataset, DatasetSplit, SentenceStore};
 
 mod pyp;
 
 mod chpylm;
 mod hpylm;
+pub use hpylm::HPYLMPriors;
+mod interner;
+pub use interner::WordInterner;
 mod npylm;
+pub use npylm::NPYLMParams;
 mod whpylm;
 
+mod lexicon;
+pub use lexicon::{Lexicon, LexiconEntry, LexiconOrder};
+
+mod generator;
+pub use generator::GeneratedSentence;
+
+mod mbr;
+pub use mbr::BoundaryCounts;
+
+mod annealing;
+pub use annealing::AnnealingSchedule;
+
 mod sampler;
 
 mod model;
-pub use model::Model;
+pub use model::{Model, ScoredSegmentation, SegmentationAnalysis, SentenceScore};
+
+mod config;
+pub use config::{CorpusConfig, ModelConfig, SamplerConfig, ScheduleConfig, TrainingConfig};
 
 mod trainer;
-pub use trainer::Trainer;
+pub use trainer::{Trainer, TrainingReport, WordLengthCorrection};
+
+pub mod evaluation;
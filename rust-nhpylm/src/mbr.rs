@@ -0,0 +1,85 @@
+/// Counts how often a word ends after each character of a sentence over a number of sampled segmentations, from which the segmentation with the minimum Bayes risk under the boundary F-measure is derived.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BoundaryCounts {
+    sentence_length: usize,
+    num_samples: usize,
+    // The number of samples with a boundary after each character but the last.
+    counts: Vec<usize>,
+    num_boundaries: usize,
+}
+
+impl BoundaryCounts {
+    pub fn new(sentence_length: usize) -> Self {
+        Self {
+            sentence_length,
+            num_samples: 0,
+            counts: vec![0; sentence_length.saturating_sub(1)],
+            num_boundaries: 0,
+        }
+    }
+
+    pub fn add_sample(&mut self, segment_lengths: &[usize]) {
+        let mut position = 0;
+        for segment_length in &segment_lengths[..segment_lengths.len().saturating_sub(1)] {
+            position += segment_length;
+            self.counts[position - 1] += 1;
+            self.num_boundaries += 1;
+        }
+        self.num_samples += 1;
+    }
+
+    pub fn get_num_samples(&self) -> usize {
+        self.num_samples
+    }
+
+    /// The share of the samples with a boundary after each character but the last.
+    pub fn get_boundary_probabilities(&self) -> Vec<f64> {
+        self.counts
+            .iter()
+            .map(|&count| count as f64 / self.num_samples.max(1) as f64)
+            .collect()
+    }
+
+    /// The segmentation that maximizes the expected boundary F-measure against the samples, as word lengths.
+    ///
+    /// The expected F-measure of a set of boundaries is approximated by the ratio of the expectations, `2 * (sum of the probabilities of its boundaries) / (its size + the expected number of boundaries)`, as in Jansche (2005). For a given size, the boundaries with the highest probabilities are the best, so only the sizes have to be searched.
+    pub fn mbr_segment_lengths(&self) -> Vec<usize> {
+        if self.sentence_length == 0 {
+            return Vec::new();
+        }
+        let boundary_probabilities = self.get_boundary_probabilities();
+        let expected_num_boundaries = self.num_boundaries as f64 / self.num_samples.max(1) as f64;
+        let mut positions: Vec<usize> = (0..self.counts.len()).collect();
+        // The earlier position wins a tie, so that the result doesn't depend on the sort.
+        positions.sort_by(|&a, &b| self.counts[b].cmp(&self.counts[a]).then(a.cmp(&b)));
+
+        let mut best_num_boundaries = 0;
+        let mut best_f_measure = if expected_num_boundaries == 0.0 {
+            1.0
+        } else {
+            0.0
+        };
+        let mut sum_probabilities = 0.0;
+        for (i, &position) in positions.iter().enumerate() {
+            sum_probabilities += boundary_probabilities[position];
+            let f_measure = 2.0 * sum_probabilities / ((i + 1) as f64 + expected_num_boundaries);
+            if f_measure > best_f_measure {
+                best_f_measure = f_measure;
+                best_num_boundaries = i + 1;
+            }
+        }
+
+        let mut boundaries: Vec<usize> = positions[..best_num_boundaries]
+            .iter()
+            .map(|position| position + 1)
+            .collect();
+        boundaries.sort();
+        let mut segment_lengths = Vec::with_capacity(boundaries.len() + 1);
+        let mut word_begin = 0;
+        for boundary in boundaries.into_iter().chain(Some(self.sentence_length)) {
+            segment_lengths.push(boundary - word_begin);
+            word_begin = boundary;
+        }
+        segment_lengths
+    }
+}
@@ -1,114 +1,543 @@
+use bincode;
+use boundary::BoundaryRules;
 use corpus::*;
 use def::*;
-use npylm::NPYLM;
+use dictionary::Dictionary;
+use error::*;
+use evaluation::words_from_segment_lengths;
+use generator::GeneratedSentence;
+use hpylm::HPYLMPriors;
+use interner::WordInterner;
+use lexicon::Lexicon;
+use mbr::BoundaryCounts;
+use normalizer::Normalizer;
+use npylm::{NPYLMParams, NPYLM};
+use rand::SeedableRng;
 use sampler::*;
 use sentence::*;
+use serde_json;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use wtype::WordTypeScheme;
+
+/// Written at the very beginning of every saved model, so that we can tell whether a file is a model at all.
+const MODEL_FILE_MAGIC: [u8; 8] = *b"NPYLMMDL";
+/// Bump this whenever the serialized layout of the model changes. Files with a different version are rejected instead of being misread.
+pub const MODEL_FILE_VERSION: u32 = 11;
+
+#[derive(Serialize, Deserialize)]
+struct FileHeader {
+    magic: [u8; 8],
+    version: u32,
+}
+
+/// One of the segmentations returned by `Model::nbest_segmentations`.
+#[derive(Serialize)]
+pub struct ScoredSegmentation {
+    pub words: Vec<String>,
+    /// The joint log probability of the sentence and this segmentation.
+    pub log_probability: f64,
+}
+
+/// Everything known about the segmentation of a sentence: the best segmentation, and optionally the runners-up and the probability of each boundary. Serialized to JSON by the segment program.
+#[derive(Serialize)]
+pub struct SegmentationAnalysis {
+    pub words: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nbest: Option<Vec<ScoredSegmentation>>,
+    /// The probability that a word ends after each character but the last.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boundary_probabilities: Option<Vec<f64>>,
+}
+
+impl SegmentationAnalysis {
+    /// The analysis as a single line of JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// How well the model predicts a sentence, see `Model::score_sentence_with_constraints`. An empty sentence scores 0 throughout.
+#[derive(Clone, Debug, Serialize)]
+pub struct SentenceScore {
+    pub num_characters: usize,
+    /// The characters that never occurred in the training text. The character model still gives them the probability of its uniform base measure.
+    pub num_unknown_characters: usize,
+    /// The marginal log probability of the sentence, summed over all the segmentations that satisfy the constraints.
+    pub log_likelihood: f64,
+    /// `-log_likelihood` in bits per character.
+    pub cross_entropy: f64,
+    /// The most probable segmentation, and the joint log probability of the sentence and it.
+    pub words: Vec<String>,
+    pub viterbi_log_probability: f64,
+}
+
+impl SentenceScore {
+    /// The score as a single line of JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Writes the magic bytes and the version that identify a file written by this crate, e.g. a model or a training checkpoint.
+pub fn write_file_header<W: Write>(writer: &mut W, magic: [u8; 8], version: u32) -> Result<()> {
+    let header = FileHeader { magic, version };
+    bincode::serialize_into(writer, &header)?;
+    Ok(())
+}
+
+/// Reads the header written by `write_file_header` and checks that it has the expected magic bytes and version. `description` names the kind of file in the error.
+pub fn read_file_header<R: Read>(
+    reader: &mut R,
+    magic: [u8; 8],
+    version: u32,
+    description: &str,
+) -> Result<()> {
+    let header: FileHeader = bincode::deserialize_from(reader)?;
+    if header.magic != magic {
+        return Err(Error::InvalidModelFile(format!(
+            "The file is not a {}",
+            description
+        )));
+    }
+    if header.version != version {
+        return Err(Error::InvalidModelFile(format!(
+            "Unsupported {} version {}, expected {}",
+            description, header.version, version
+        )));
+    }
+    Ok(())
+}
+
+fn check_positive(name: &str, value: f64) -> Result<()> {
+    if value > 0.0 && value.is_finite() {
+        Ok(())
+    } else {
+        Err(Error::InvalidConfig(format!(
+            "{} must be a positive number, got {}",
+            name, value
+        )))
+    }
+}
 
 pub struct Model {
     // I'm not sure if this struct is actually meaningful... Anyways let's refactor it later.
+    pub npylm: NPYLM,
+    /// The working memory for sampling and decoding the segmentations of a sentence with `npylm`.
     pub sampler: Sampler,
+    /// The training sentences restored by `Model::load`, with the segmentations they had when the model was saved. Empty for a freshly created model.
+    pub train_sentences: SegmentedSentences,
+    /// The single source of randomness for everything that is sampled with this model.
+    pub rng: NpylmRng,
+    /// The normalization the training text went through. The text to be segmented has to go through the same one, so it is saved with the model.
+    pub normalizer: Normalizer,
+    /// The hard boundaries the training text was read with. Like the normalizer, they are saved with the model and apply to the text to be segmented as well.
+    pub boundary_rules: BoundaryRules,
 }
 
 impl Model {
-    pub fn new(dataset: &Dataset, max_word_length: usize) -> Self {
-        let max_sentence_length = dataset.max_sentence_length;
-        let chpylm_g_0 = 1.0 / dataset.vocabulary.get_num_characters() as f64;
-        let npylm = NPYLM::new(
-            max_word_length,
-            max_sentence_length,
-            chpylm_g_0,
-            4.0,
-            1.0,
-            CHPYLM_BETA_STOP,
-            CHPYLM_BETA_PASS,
-        );
-        let sampler = Sampler::new(npylm, max_word_length, max_sentence_length);
-        Self { sampler: sampler }
+    pub fn new(dataset: &Dataset, max_word_length: usize, seed: u64) -> Result<Self> {
+        Self::new_with_order(dataset, WHPYLM_ORDER, max_word_length, seed)
     }
 
-    pub fn new_with_explicit_params(
+    /// Creates a model whose word n-gram model has the given order, 2 for a bigram model or 3 for a trigram model. The order can't be changed afterwards, since the restaurants are arranged by it.
+    pub fn new_with_order(
         dataset: &Dataset,
+        whpylm_order: usize,
         max_word_length: usize,
-        initial_a: f64,
-        initial_b: f64,
-        chpylm_beta_stop: f64,
-        chpylm_beta_pass: f64,
-    ) -> Self {
+        seed: u64,
+    ) -> Result<Self> {
+        Self::new_with_explicit_params(
+            dataset,
+            &NPYLMParams::new(whpylm_order, max_word_length),
+            seed,
+        )
+    }
+
+    pub fn new_with_explicit_params(
+        dataset: &Dataset,
+        params: &NPYLMParams,
+        seed: u64,
+    ) -> Result<Self> {
+        if params.max_word_length == 0 {
+            return Err(Error::InvalidConfig(
+                "The maximum word length must be at least 1".to_owned(),
+            ));
+        }
+        if dataset.vocabulary.get_num_characters() == 0 {
+            return Err(Error::InvalidConfig(
+                "The dataset doesn't contain any characters".to_owned(),
+            ));
+        }
+        check_positive("lambda-a", params.initial_a)?;
+        check_positive("lambda-b", params.initial_b)?;
+        check_positive("beta-stop", params.chpylm_beta_stop)?;
+        check_positive("beta-pass", params.chpylm_beta_pass)?;
+
+        let mut rng = NpylmRng::seed_from_u64(seed);
         let max_sentence_length = dataset.max_sentence_length;
         let chpylm_g_0 = 1.0 / dataset.vocabulary.get_num_characters() as f64;
-        let npylm = NPYLM::new(
-            max_word_length,
-            max_sentence_length,
-            chpylm_g_0,
-            initial_a,
-            initial_b,
-            chpylm_beta_stop,
-            chpylm_beta_pass,
-        );
-        let sampler = Sampler::new(npylm, max_word_length, max_sentence_length);
-        Self { sampler: sampler }
+        let npylm = NPYLM::new(params, max_sentence_length, chpylm_g_0, &mut rng)?;
+        let sampler = Sampler::new(&npylm, params.max_word_length, max_sentence_length);
+        Ok(Self {
+            npylm,
+            sampler,
+            train_sentences: SegmentedSentences::new(),
+            rng,
+            normalizer: Normalizer::new(),
+            boundary_rules: BoundaryRules::new(),
+        })
+    }
+
+    /// Saves the full state of the NPYLM, i.e. both restaurant trees, the hyperparameters and the word length distribution, together with the current segmentation of the training sentences, the normalizer and the boundary rules.
+    ///
+    /// The training sentences are needed to continue training the model later, since the customers in the restaurants have to be removed based on them.
+    pub fn save(&self, path: &Path, train_sentences: &SegmentedSentences) -> Result<()> {
+        let file = File::create(path)?;
+        let mut writer = BufWriter::new(file);
+        write_file_header(&mut writer, MODEL_FILE_MAGIC, MODEL_FILE_VERSION)?;
+        bincode::serialize_into(
+            &mut writer,
+            &(
+                &self.npylm,
+                train_sentences,
+                &self.normalizer,
+                &self.boundary_rules,
+            ),
+        )?;
+        Ok(())
+    }
+
+    /// Loads a model saved by `Model::save`. The training sentences stored in the file are put into `train_sentences`.
+    ///
+    /// The random number generator is seeded with 0. Call `reseed` before continuing the training if another seed is wanted.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let mut reader = BufReader::new(file);
+        read_file_header(
+            &mut reader,
+            MODEL_FILE_MAGIC,
+            MODEL_FILE_VERSION,
+            "saved NPYLM model",
+        )?;
+
+        let (mut npylm, train_sentences, normalizer, boundary_rules): (
+            NPYLM,
+            SegmentedSentences,
+            Normalizer,
+            BoundaryRules,
+        ) = bincode::deserialize_from(&mut reader)?;
+        npylm.rebuild_after_deserialization();
+        let mut model = Self::from_npylm(npylm, train_sentences, NpylmRng::seed_from_u64(0));
+        model.normalizer = normalizer;
+        model.boundary_rules = boundary_rules;
+        Ok(model)
+    }
+
+    /// Wraps an NPYLM restored from a file. Its caches must already be rebuilt. The normalizer and the boundary rules are the ones that do nothing.
+    pub fn from_npylm(npylm: NPYLM, train_sentences: SegmentedSentences, rng: NpylmRng) -> Self {
+        let max_word_length = npylm.max_word_length;
+        let max_sentence_length = npylm.max_sentence_length;
+        let sampler = Sampler::new(&npylm, max_word_length, max_sentence_length);
+        Self {
+            npylm,
+            sampler,
+            train_sentences,
+            rng,
+            normalizer: Normalizer::new(),
+            boundary_rules: BoundaryRules::new(),
+        }
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = NpylmRng::seed_from_u64(seed);
     }
 
     pub fn get_max_word_length(&self) -> usize {
-        return self.sampler.npylm.max_word_length;
+        self.npylm.max_word_length
+    }
+
+    pub fn get_whpylm_order(&self) -> usize {
+        self.npylm.whpylm.order
+    }
+
+    pub fn set_initial_a(&mut self, initial_a: f64) -> Result<()> {
+        check_positive("lambda-a", initial_a)?;
+        self.npylm.lambda_a = initial_a;
+        self.npylm.sample_lambda_with_initial_params(&mut self.rng)
+    }
+
+    /// Selects the word types with their own word length distributions, e.g. one of `get_word_type_scheme`. Only the schemes known to `get_word_type_scheme` can be saved.
+    pub fn set_word_type_scheme(
+        &mut self,
+        word_type_scheme: Arc<dyn WordTypeScheme>,
+    ) -> Result<()> {
+        self.npylm
+            .set_word_type_scheme(word_type_scheme, &mut self.rng)
+    }
+
+    pub fn get_word_type_scheme(&self) -> &dyn WordTypeScheme {
+        &*self.npylm.word_type_scheme
+    }
+
+    /// The words of the segmentation of `train_sentences`, i.e. of a saved model, with their counts and probabilities.
+    pub fn lexicon(&mut self) -> Result<Lexicon> {
+        self.npylm.build_lexicon(&self.train_sentences)
+    }
+
+    /// Samples a sentence from the model, see `NPYLM::generate_sentence`. Draws on the random number generator of the model, so `reseed` it to get the same sentences again.
+    pub fn generate_sentence(
+        &mut self,
+        temperature: f64,
+        max_words: usize,
+    ) -> Result<GeneratedSentence> {
+        self.npylm
+            .generate_sentence(temperature, max_words, &mut self.rng)
+    }
+
+    /// The IDs of the words of the model, which also turns them back into characters.
+    pub fn get_interner(&self) -> &WordInterner {
+        &self.npylm.interner
+    }
+
+    pub fn set_initial_b(&mut self, initial_b: f64) -> Result<()> {
+        check_positive("lambda-b", initial_b)?;
+        self.npylm.lambda_b = initial_b;
+        self.npylm.sample_lambda_with_initial_params(&mut self.rng)
     }
 
-    pub fn set_initial_a(&mut self, initial_a: f64) {
-        self.sampler.npylm.lambda_a = initial_a;
-        self.sampler.npylm.sample_lambda_with_initial_params();
+    pub fn set_chpylm_beta_stop(&mut self, stop: f64) -> Result<()> {
+        check_positive("beta-stop", stop)?;
+        self.npylm.chpylm.beta_stop = stop;
+        Ok(())
     }
 
-    pub fn set_initial_b(&mut self, initial_b: f64) {
-        self.sampler.npylm.lambda_b = initial_b;
-        self.sampler.npylm.sample_lambda_with_initial_params();
+    pub fn set_chpylm_beta_pass(&mut self, pass: f64) -> Result<()> {
+        check_positive("beta-pass", pass)?;
+        self.npylm.chpylm.beta_pass = pass;
+        Ok(())
     }
 
-    pub fn set_chpylm_beta_stop(&mut self, stop: f64) {
-        self.sampler.npylm.chpylm.beta_stop = stop;
+    /// Replaces the priors of the hyperparameters of the word n-gram model. Only meant for a model that hasn't been trained yet, since the hyperparameters start over from them.
+    pub fn set_whpylm_priors(&mut self, priors: HPYLMPriors) -> Result<()> {
+        priors.check("whpylm-priors")?;
+        self.npylm.whpylm.set_priors(priors);
+        Ok(())
     }
 
-    pub fn set_chpylm_beta_pass(&mut self, pass: f64) {
-        self.sampler.npylm.chpylm.beta_pass = pass;
+    /// Replaces the priors of the hyperparameters of the character n-gram model, see `set_whpylm_priors`.
+    pub fn set_chpylm_priors(&mut self, priors: HPYLMPriors) -> Result<()> {
+        priors.check("chpylm-priors")?;
+        self.npylm.chpylm.set_priors(priors);
+        self.npylm.clear_g_0_cache();
+        Ok(())
     }
 
-    // Well this method doesn't seem to be used anyways...
-    // fn segment_sentence(&mut self, sentence_chars: Vec<char>) -> Vec<String> {
-    //     // This is a bit silly...
-    //     let max_word_length = self.sampler.npylm.max_word_length.clone();
-    //     self.sampler
-    //         .extend_capacity(max_word_length, sentence_chars.len());
+    /// The character n-gram model ignores the depths below the one at which the probability of stopping falls under `epsilon`.
+    pub fn set_chpylm_epsilon(&mut self, epsilon: f64) -> Result<()> {
+        if !(epsilon > 0.0 && epsilon < 1.0) {
+            return Err(Error::InvalidConfig(format!(
+                "chpylm-epsilon must be between 0 and 1, got {}",
+                epsilon
+            )));
+        }
+        self.npylm.chpylm.epsilon = epsilon;
+        self.npylm.clear_g_0_cache();
+        Ok(())
+    }
+
+    /// Segments an unseen sentence with the Viterbi algorithm. The buffers of the sampler and the NPYLM grow if the sentence is longer than any sentence seen so far.
+    pub fn segment_sentence(&mut self, sentence_chars: Vec<char>) -> Result<Vec<String>> {
+        self.segment_sentence_with_constraints(sentence_chars, SegmentationConstraints::new())
+    }
 
-    //     self.sampler.npylm.extend_capacity(sentence_chars.len());
+    /// Normalizes a line of raw text and segments it, respecting the hard boundaries the boundary rules find in it and, if given, the constraints of the dictionary. This treats the text exactly like the training text was treated.
+    pub fn segment_line(
+        &mut self,
+        line: &str,
+        dictionary: Option<&Dictionary>,
+    ) -> Result<Vec<String>> {
+        let (sentence_chars, constraints) = self.read_line(line, dictionary);
+        self.segment_sentence_with_constraints(sentence_chars, constraints)
+    }
 
-    //     let mut segmented_sentence: Vec<String> = Vec::new();
+    /// Turns a line of raw text into the characters of a sentence and the constraints on their segmentation, the same way as `segment_line`.
+    pub fn read_line(
+        &self,
+        line: &str,
+        dictionary: Option<&Dictionary>,
+    ) -> (Vec<char>, SegmentationConstraints) {
+        let line = self.normalizer.normalize(line);
+        let (sentence_chars, hard_boundaries) = self.boundary_rules.split_line(&line);
+        let mut constraints = SegmentationConstraints::from_boundaries(hard_boundaries);
+        if let Some(dictionary) = dictionary {
+            dictionary.add_constraints(&sentence_chars, &mut constraints);
+        }
+        (sentence_chars, constraints)
+    }
 
-    //     let mut sentence = Sentence::new(sentence_chars, false);
-    //     let segment_lengths = self.sampler.viterbi_decode(&sentence);
+    /// Makes room for an unseen sentence in the buffers of the sampler and the NPYLM.
+    fn new_sentence(
+        &mut self,
+        sentence_chars: Vec<char>,
+        constraints: SegmentationConstraints,
+    ) -> Sentence {
+        let max_word_length = self.npylm.max_word_length;
+        self.sampler
+            .extend_capacity(max_word_length, sentence_chars.len());
+        self.npylm.extend_capacity(sentence_chars.len());
 
-    //     // This is really convoluted. Let's see if we can do better.
-    //     sentence.split_sentence(segment_lengths);
-    //     for i in 0..sentence.get_num_segments_without_special_tokens() {
-    //         segmented_sentence.push(sentence.get_nth_word_chars(i + 2));
-    //     }
+        let mut sentence = Sentence::new(sentence_chars, false);
+        sentence.set_constraints(constraints);
+        sentence
+    }
 
-    //     segmented_sentence
-    // }
+    /// The `n` most probable segmentations of a sentence that satisfy the constraints, best first.
+    pub fn nbest_segmentations(
+        &mut self,
+        sentence_chars: Vec<char>,
+        constraints: SegmentationConstraints,
+        n: usize,
+    ) -> Result<Vec<ScoredSegmentation>> {
+        if sentence_chars.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sentence = self.new_sentence(sentence_chars, constraints);
+        Ok(self
+            .sampler
+            .nbest_decode(&self.npylm, &sentence, n)?
+            .into_iter()
+            .map(|(segment_lengths, log_probability)| ScoredSegmentation {
+                words: words_from_segment_lengths(&sentence.characters, &segment_lengths),
+                log_probability,
+            })
+            .collect())
+    }
+
+    /// Segments a sentence with the minimum Bayes risk under the boundary F-measure, estimated from `num_samples` segmentations drawn from the posterior. This is more stable than the single best segmentation of `segment_sentence_with_constraints`, but draws on the random number generator of the model.
+    pub fn mbr_segment_sentence_with_constraints(
+        &mut self,
+        sentence_chars: Vec<char>,
+        constraints: SegmentationConstraints,
+        num_samples: usize,
+    ) -> Result<Vec<String>> {
+        if num_samples == 0 {
+            return Err(Error::InvalidConfig(
+                "The minimum Bayes risk segmentation needs at least one sample".to_owned(),
+            ));
+        }
+        if sentence_chars.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sentence = self.new_sentence(sentence_chars, constraints);
+        let mut boundary_counts = BoundaryCounts::new(sentence.length());
+        for segment_lengths in
+            self.sampler
+                .sample_segmentations(&self.npylm, &sentence, num_samples, &mut self.rng)?
+        {
+            boundary_counts.add_sample(&segment_lengths);
+        }
+        Ok(words_from_segment_lengths(
+            &sentence.characters,
+            &boundary_counts.mbr_segment_lengths(),
+        ))
+    }
+
+    /// Scores a sentence as a language model would: the marginal log probability over all its segmentations, the cross-entropy per character derived from it, and the log probability of the Viterbi segmentation.
+    pub fn score_sentence_with_constraints(
+        &mut self,
+        sentence_chars: Vec<char>,
+        constraints: SegmentationConstraints,
+    ) -> Result<SentenceScore> {
+        let num_characters = sentence_chars.len();
+        if num_characters == 0 {
+            return Ok(SentenceScore {
+                num_characters: 0,
+                num_unknown_characters: 0,
+                log_likelihood: 0.0,
+                cross_entropy: 0.0,
+                words: Vec::new(),
+                viterbi_log_probability: 0.0,
+            });
+        }
+        let num_unknown_characters = {
+            let chpylm_root = self.npylm.chpylm.tree.root();
+            sentence_chars
+                .iter()
+                .filter(|c| !chpylm_root.tablegroups.contains_key(c))
+                .count()
+        };
+
+        let sentence = self.new_sentence(sentence_chars, constraints);
+        let log_likelihood =
+            self.sampler
+                .compute_log_forward_probability(&self.npylm, &sentence, true)?;
+        let (segment_lengths, viterbi_log_probability) = self
+            .sampler
+            .nbest_decode(&self.npylm, &sentence, 1)?
+            .pop()
+            .ok_or_else(|| {
+                Error::NumericalUnderflow("The sentence has no segmentation".to_owned())
+            })?;
+        Ok(SentenceScore {
+            num_characters,
+            num_unknown_characters,
+            log_likelihood,
+            cross_entropy: -log_likelihood / num_characters as f64 / ::std::f64::consts::LN_2,
+            words: words_from_segment_lengths(&sentence.characters, &segment_lengths),
+            viterbi_log_probability,
+        })
+    }
+
+    /// The posterior probability that a word ends after each character of a sentence but the last, summed over all the segmentations that satisfy the constraints.
+    pub fn boundary_probabilities(
+        &mut self,
+        sentence_chars: Vec<char>,
+        constraints: SegmentationConstraints,
+    ) -> Result<Vec<f64>> {
+        if sentence_chars.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sentence = self.new_sentence(sentence_chars, constraints);
+        self.sampler
+            .compute_boundary_probabilities(&self.npylm, &sentence)
+    }
+
+    /// Segments a sentence into the most probable words that satisfy the constraints.
+    pub fn segment_sentence_with_constraints(
+        &mut self,
+        sentence_chars: Vec<char>,
+        constraints: SegmentationConstraints,
+    ) -> Result<Vec<String>> {
+        if sentence_chars.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let sentence = self.new_sentence(sentence_chars, constraints);
+        let segment_lengths = self.sampler.viterbi_decode(&self.npylm, &sentence)?;
+        Ok(words_from_segment_lengths(
+            &sentence.characters,
+            &segment_lengths,
+        ))
+    }
 
     pub fn compute_log_forward_probability(
         &mut self,
         sentence_chars: Vec<char>,
         with_scaling: bool,
-    ) -> f64 {
-        let max_word_length = self.sampler.npylm.max_word_length.clone();
+    ) -> Result<f64> {
+        let max_word_length = self.npylm.max_word_length.clone();
         self.sampler
             .extend_capacity(max_word_length, sentence_chars.len());
 
-        self.sampler.npylm.extend_capacity(sentence_chars.len());
+        self.npylm.extend_capacity(sentence_chars.len());
 
         let sentence = Sentence::new(sentence_chars, false);
 
         return self
             .sampler
-            .compute_log_forward_probability(&sentence, with_scaling);
+            .compute_log_forward_probability(&self.npylm, &sentence, with_scaling);
     }
 }
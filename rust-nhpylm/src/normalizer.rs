@@ -0,0 +1,176 @@
+use corpus::check_line;
+use error::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+
+/// A single stage of a `Normalizer`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum NormalizationStep {
+    /// Unicode compatibility composition, which among others folds full-width ASCII, ligatures and circled numbers into their plain forms.
+    Nfkc,
+    /// Folds the full-width ASCII block and the ideographic space into ASCII, but leaves everything else untouched, unlike NFKC.
+    FullWidthToHalfWidth,
+    /// Removes control characters and the invisible format characters that often sneak into web text, such as zero-width spaces and joiners, directional marks, soft hyphens and byte order marks.
+    StripControlCharacters,
+    /// Replaces characters according to a table supplied by the user, e.g. to map traditional characters to simplified ones. A character may be replaced by several characters, or by none to delete it.
+    Mapping(BTreeMap<char, String>),
+}
+
+impl NormalizationStep {
+    fn apply(&self, text: &str) -> String {
+        match self {
+            NormalizationStep::Nfkc => text.nfkc().collect(),
+            NormalizationStep::FullWidthToHalfWidth => text.chars().map(to_half_width).collect(),
+            NormalizationStep::StripControlCharacters => {
+                // Keep the whitespace, which still separates the words of a segmented line.
+                text.chars()
+                    .filter(|c| c.is_whitespace() || !is_control_or_format(*c))
+                    .collect()
+            }
+            NormalizationStep::Mapping(table) => {
+                let mut result = String::with_capacity(text.len());
+                for c in text.chars() {
+                    match table.get(&c) {
+                        Some(replacement) => result.push_str(replacement),
+                        None => result.push(c),
+                    }
+                }
+                result
+            }
+        }
+    }
+}
+
+impl fmt::Display for NormalizationStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NormalizationStep::Nfkc => write!(f, "nfkc"),
+            NormalizationStep::FullWidthToHalfWidth => write!(f, "halfwidth"),
+            NormalizationStep::StripControlCharacters => write!(f, "strip-control"),
+            NormalizationStep::Mapping(table) => write!(f, "mapping({} characters)", table.len()),
+        }
+    }
+}
+
+fn to_half_width(c: char) -> char {
+    match c {
+        '\u{3000}' => ' ',
+        '\u{FF01}'..='\u{FF5E}' => std::char::from_u32(c as u32 - 0xFEE0).unwrap(),
+        _ => c,
+    }
+}
+
+fn is_control_or_format(c: char) -> bool {
+    c.is_control()
+        || matches!(
+            c,
+            '\u{00AD}'
+                | '\u{061C}'
+                | '\u{180E}'
+                | '\u{FEFF}'
+                | '\u{200B}'..='\u{200F}'
+                | '\u{202A}'..='\u{202E}'
+                | '\u{2060}'..='\u{206F}'
+                | '\u{FFF9}'..='\u{FFFB}'
+        )
+}
+
+/// The stages every line of text goes through before the model sees its characters, applied in order. The same normalizer has to be used for training and segmentation, which is why a trained model records the one it was trained with.
+///
+/// A new normalizer does nothing, so that the text is used exactly as it is.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Normalizer {
+    pub steps: Vec<NormalizationStep>,
+}
+
+impl Default for Normalizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Normalizer {
+    pub fn new() -> Self {
+        Self { steps: Vec::new() }
+    }
+
+    /// Builds a normalizer out of a comma separated list of the steps `nfkc`, `halfwidth` and `strip-control`, e.g. `strip-control,nfkc`. `none` or an empty list gives a normalizer that does nothing.
+    pub fn from_spec(spec: &str) -> Result<Self> {
+        let mut normalizer = Self::new();
+        for name in spec.split(',').map(|name| name.trim()) {
+            let step = match name {
+                "" | "none" => continue,
+                "nfkc" => NormalizationStep::Nfkc,
+                "halfwidth" => NormalizationStep::FullWidthToHalfWidth,
+                "strip-control" => NormalizationStep::StripControlCharacters,
+                _ => {
+                    return Err(Error::InvalidConfig(format!(
+                    "Unknown normalization step {:?}, expected nfkc, halfwidth or strip-control",
+                    name
+                )))
+                }
+            };
+            normalizer.steps.push(step);
+        }
+        Ok(normalizer)
+    }
+
+    /// Appends a step that maps characters according to the table in the file. Each line of the file holds a character, a tab and its replacement, which may be empty. Empty lines and lines starting with `#` are skipped.
+    pub fn add_mapping_table(&mut self, path: &Path) -> Result<()> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+        let mut table = BTreeMap::new();
+        for (line_index, line) in reader.lines().enumerate() {
+            let l = check_line(line, path, line_index)?;
+            if l.is_empty() || l.starts_with('#') {
+                continue;
+            }
+            let mut fields = l.splitn(2, '\t');
+            let from = fields.next().unwrap();
+            let to = fields.next();
+            let mut from_chars = from.chars();
+            match (from_chars.next(), from_chars.next(), to) {
+                (Some(c), None, Some(to)) => {
+                    table.insert(c, to.to_owned());
+                }
+                _ => {
+                    return Err(Error::InvalidConfig(format!(
+                        "{:?}, line {}: expected a single character, a tab and its replacement",
+                        path,
+                        line_index + 1
+                    )))
+                }
+            }
+        }
+        self.steps.push(NormalizationStep::Mapping(table));
+        Ok(())
+    }
+
+    pub fn normalize(&self, text: &str) -> String {
+        let mut result = text.to_owned();
+        for step in &self.steps {
+            result = step.apply(&result);
+        }
+        result
+    }
+}
+
+impl fmt::Display for Normalizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.steps.is_empty() {
+            return write!(f, "none");
+        }
+        for (i, step) in self.steps.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", step)?;
+        }
+        Ok(())
+    }
+}
@@ -1,14 +1,22 @@
 use chpylm::*;
+use corpus::SegmentedSentences;
 use def::*;
 use either::*;
+use error::*;
+use generator::*;
 use hpylm::HPYLM;
+use interner::*;
+use lexicon::*;
 use pyp::*;
 use rand::distributions::{Distribution, Gamma};
 use rand::prelude::*;
 use sentence::*;
 use statrs::distribution::{Discrete, Poisson};
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use whpylm::*;
 use wtype::*;
 
@@ -28,32 +36,119 @@ fn produce_word_with_bow_and_eow(
     word
 }
 
+static NEXT_G_0_GENERATION: AtomicUsize = AtomicUsize::new(1);
+
+// A generation no NPYLM has had yet, see `ProbabilityCache`. The caches start at 0, so they are always filled anew the first time.
+fn next_g_0_generation() -> usize {
+    NEXT_G_0_GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The base measures of the words that `NPYLM::compute_p_w_of_nth_word_as_chars` computes along the way. Computing probabilities only reads the model, so several threads can share one NPYLM as long as each has its own cache.
+#[derive(Clone, Default)]
+pub struct ProbabilityCache {
+    // The `g_0_generation` of the NPYLM the base measures were computed from.
+    generation: usize,
+    whpylm_g_0: HashMap<u64, f64>,
+    // The base measure of the words of the current sentence that only have transient IDs. Cleared for every sentence.
+    transient_g_0: HashMap<u64, f64>,
+}
+
+impl ProbabilityCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forgets the base measure of the words with transient IDs. Call this before working on another sentence, whose substrings reuse the same transient IDs.
+    pub fn clear_transient(&mut self) {
+        self.transient_g_0.clear();
+    }
+
+    // Drops the base measures if the model has changed since they were computed.
+    fn refresh(&mut self, generation: usize) {
+        if self.generation != generation {
+            self.whpylm_g_0.clear();
+            self.transient_g_0.clear();
+            self.generation = generation;
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct NPYLM {
     pub whpylm: WHPYLM,
     pub chpylm: CHPYLM,
-    recorded_depth_arrays_for_tablegroups_of_token: HashMap<u64, Vec<Vec<usize>>>,
-    whpylm_g_0_cache: HashMap<u64, f64>,
+    recorded_depth_arrays_for_tablegroups_of_token: HashMap<u64, Vec<Vec<usize>>, FixedState>,
+    // The cache of the methods that have the NPYLM to themselves.
+    #[serde(skip)]
+    cache: ProbabilityCache,
+    // Changes whenever the base measures of the words may have changed, see `clear_g_0_cache`.
+    #[serde(skip)]
+    g_0_generation: usize,
+    pub interner: WordInterner,
     // chpylm_g_0_cache: HashMap<usize, f64>,
+    #[serde(with = "word_type_scheme_by_name")]
+    pub word_type_scheme: Arc<dyn WordTypeScheme>,
+    /// One Poisson parameter for each type of `word_type_scheme`, indexed from 1.
     pub lambda_for_types: Vec<f64>,
     pub p_k_chpylm: Vec<f64>,
     pub max_word_length: usize,
     pub max_sentence_length: usize,
     pub lambda_a: f64,
     pub lambda_b: f64,
+    #[serde(skip)]
     whpylm_parent_p_w_cache: Vec<f64>,
+    #[serde(skip)]
     most_recent_word: Vec<char>,
 }
 
+/// The parameters a new NPYLM is created with, apart from the ones that follow from the training corpus.
+#[derive(Clone, Debug)]
+pub struct NPYLMParams {
+    /// The order of the word n-gram model, 2 for a bigram model or 3 for a trigram model.
+    pub whpylm_order: usize,
+    pub max_word_length: usize,
+    /// The initial shape and rate of the gamma prior on the Poisson parameters of the word lengths.
+    pub initial_a: f64,
+    pub initial_b: f64,
+    /// The beta prior on the probability of stopping at each depth of the CHPYLM.
+    pub chpylm_beta_stop: f64,
+    pub chpylm_beta_pass: f64,
+}
+
+impl NPYLMParams {
+    pub fn new(whpylm_order: usize, max_word_length: usize) -> Self {
+        Self {
+            whpylm_order,
+            max_word_length,
+            initial_a: INITIAL_LAMBDA_A,
+            initial_b: INITIAL_LAMBDA_B,
+            chpylm_beta_stop: CHPYLM_BETA_STOP,
+            chpylm_beta_pass: CHPYLM_BETA_PASS,
+        }
+    }
+}
+
 impl NPYLM {
-    pub fn new(
-        max_word_length: usize,
+    pub fn new<R: Rng>(
+        params: &NPYLMParams,
         max_sentence_length: usize,
         g_0: f64,
-        initial_lambda_a: f64,
-        initial_lambda_b: f64,
-        chpylm_beta_stop: f64,
-        chpylm_beta_pass: f64,
-    ) -> Self {
+        rng: &mut R,
+    ) -> Result<Self> {
+        let NPYLMParams {
+            whpylm_order,
+            max_word_length,
+            initial_a: initial_lambda_a,
+            initial_b: initial_lambda_b,
+            chpylm_beta_stop,
+            chpylm_beta_pass,
+        } = *params;
+        if whpylm_order != 2 && whpylm_order != 3 {
+            return Err(Error::InvalidConfig(format!(
+                "The order of the word n-gram model must be 2 or 3, got {}",
+                whpylm_order
+            )));
+        }
         let mut npylm = Self {
             // whpylm: Box::new(WHPYLM::new(3)),
             // chpylm: Box::new(CHPYLM::new(
@@ -62,13 +157,16 @@ impl NPYLM {
             //     CHPYLM_BETA_STOP,
             //     chpylm_beta_pass,
             // )),
-            whpylm: WHPYLM::new(3),
+            whpylm: WHPYLM::new(whpylm_order),
             chpylm: CHPYLM::new(g_0, max_sentence_length, chpylm_beta_stop, chpylm_beta_pass),
-            recorded_depth_arrays_for_tablegroups_of_token: HashMap::new(),
-            whpylm_g_0_cache: HashMap::new(),
+            recorded_depth_arrays_for_tablegroups_of_token: HashMap::default(),
+            cache: ProbabilityCache::new(),
+            g_0_generation: next_g_0_generation(),
+            interner: WordInterner::new(),
             // chpylm_g_0_cache: HashMap::new(),
+            word_type_scheme: Arc::new(JapaneseWordTypes),
             lambda_for_types: vec![0.0; WORDTYPE_NUM_TYPES + 1],
-            whpylm_parent_p_w_cache: vec![0.0; 3],
+            whpylm_parent_p_w_cache: vec![0.0; whpylm_order],
             lambda_a: initial_lambda_a,
             lambda_b: initial_lambda_b,
             max_sentence_length: max_sentence_length,
@@ -76,15 +174,73 @@ impl NPYLM {
             p_k_chpylm: vec![1.0 / (max_word_length + 2) as f64; max_word_length + 2],
             most_recent_word: Vec::new(),
         };
-        npylm.sample_lambda_with_initial_params();
-        npylm
+        npylm.sample_lambda_with_initial_params(rng)?;
+        Ok(npylm)
+    }
+
+    /// Restores the caches, which are not serialized.
+    pub fn rebuild_after_deserialization(&mut self) {
+        self.chpylm.rebuild_after_deserialization();
+        self.interner.rebuild_after_deserialization();
+        self.cache = ProbabilityCache::new();
+        self.g_0_generation = next_g_0_generation();
+        self.whpylm_parent_p_w_cache = vec![0.0; self.whpylm.order];
+        self.most_recent_word = vec![' '; self.max_sentence_length + 2];
     }
 
-    pub fn sample_lambda_with_initial_params(&mut self) {
-        for i in 1..WORDTYPE_NUM_TYPES + 1 {
+    /// Replaces the word types, whose Poisson parameters are then drawn anew from the prior.
+    pub fn set_word_type_scheme<R: Rng>(
+        &mut self,
+        word_type_scheme: Arc<dyn WordTypeScheme>,
+        rng: &mut R,
+    ) -> Result<()> {
+        self.lambda_for_types = vec![0.0; word_type_scheme.get_num_types() + 1];
+        self.word_type_scheme = word_type_scheme;
+        self.sample_lambda_with_initial_params(rng)
+    }
+
+    pub fn sample_lambda_with_initial_params<R: Rng>(&mut self, rng: &mut R) -> Result<()> {
+        for i in 1..self.word_type_scheme.get_num_types() + 1 {
             let dist = Gamma::new(self.lambda_a, 1.0 / self.lambda_b);
-            self.lambda_for_types[i] = dist.sample(&mut thread_rng());
+            self.lambda_for_types[i] = check_lambda(i, dist.sample(rng))?;
         }
+        self.clear_g_0_cache();
+        Ok(())
+    }
+
+    /// The cached base probabilities of the words depend on the CHPYLM, the Poisson parameters and the word length distribution. Call this whenever any of them changes. Every `ProbabilityCache` then drops them the next time it is used with this NPYLM.
+    pub fn clear_g_0_cache(&mut self) {
+        self.g_0_generation = next_g_0_generation();
+    }
+
+    /// Forgets the base measure of the words with transient IDs, see `ProbabilityCache::clear_transient`.
+    pub fn clear_transient_g_0_cache(&mut self) {
+        self.cache.clear_transient();
+    }
+
+    // Runs a computation that only reads the model with the cache of the NPYLM itself.
+    fn with_own_cache<T, F: FnOnce(&Self, &mut ProbabilityCache) -> T>(&mut self, f: F) -> T {
+        let mut cache = mem::replace(&mut self.cache, ProbabilityCache::new());
+        let result = f(self, &mut cache);
+        self.cache = cache;
+        result
+    }
+
+    // The IDs of the nth word of the sentence and the two words before it, in this order, so that the nth word is at index 2. Words the interner doesn't know yet are added to it.
+    fn intern_word_ids_up_to_nth_word(&mut self, sentence: &Sentence, n: usize) -> [u64; 3] {
+        [
+            sentence.get_nth_word_id(n - 2, &mut self.interner),
+            sentence.get_nth_word_id(n - 1, &mut self.interner),
+            sentence.get_nth_word_id(n, &mut self.interner),
+        ]
+    }
+
+    // The IDs of all the words of the sentence, including the BOS and EOS around them. Words the interner doesn't know get transient IDs.
+    fn find_word_ids(&self, sentence: &Sentence, cache: &mut ProbabilityCache) -> Vec<u64> {
+        cache.clear_transient();
+        (0..sentence.num_segments)
+            .map(|n| sentence.find_nth_word_id(n, &self.interner))
+            .collect()
     }
 
     pub fn extend_capacity(&mut self, max_sentence_length: usize) {
@@ -100,73 +256,88 @@ impl NPYLM {
         self.most_recent_word = vec![' '; max_sentence_length + 2];
     }
 
-    pub fn add_customer_at_index_n(&mut self, sentence: &Sentence, n: usize) -> bool {
+    pub fn add_customer_at_index_n<R: Rng>(
+        &mut self,
+        sentence: &Sentence,
+        n: usize,
+        rng: &mut R,
+    ) -> Result<()> {
         assert!(n >= 2);
-        let token_n = sentence.get_nth_word_id(n);
+        self.whpylm.extend_hyperparameters(self.whpylm.order - 1);
+        let word_ids = self.intern_word_ids_up_to_nth_word(sentence, n);
+        let token_n = word_ids[2];
         let pyp = self
-            .find_node_with_sentence(sentence, n, true, false)
+            .find_node_with_sentence(sentence, &word_ids, n, true, false)?
             .unwrap();
-        let num_tables_before_addition = self.whpylm.root.ntables;
+        let num_tables_before_addition = self.whpylm.tree.root().ntables;
         let mut index_of_table_added_to_in_root = 0;
-        unsafe {
-            (*pyp).add_customer(
-                token_n,
-                Right(&mut self.whpylm_parent_p_w_cache),
-                &mut self.whpylm.d_array,
-                &mut self.whpylm.theta_array,
-                true,
-                &mut index_of_table_added_to_in_root,
-            );
-            let num_tables_after_addition = self.whpylm.root.ntables;
-            let word_begin_index = sentence.segment_begin_positions[n];
-            let word_end_index = word_begin_index + sentence.segment_lengths[n] - 1;
-
-            if num_tables_before_addition < num_tables_after_addition {
-                self.whpylm_g_0_cache = HashMap::new();
-                if token_n == EOS {
-                    self.chpylm.root.add_customer(
-                        EOS_CHAR,
-                        Left(self.chpylm.g_0),
-                        &mut self.chpylm.d_array,
-                        &mut self.chpylm.theta_array,
-                        true,
-                        &mut index_of_table_added_to_in_root,
-                    );
-                    return true;
-                }
-
-                // assert!(index_of_table_added_to_in_root != 0);
+        self.whpylm.tree.add_customer(
+            pyp,
+            token_n,
+            &mut SeatingParameters {
+                g0_or_parent_p_ws: Right(&self.whpylm_parent_p_w_cache),
+                d_array: &mut self.whpylm.d_array,
+                theta_array: &mut self.whpylm.theta_array,
+            },
+            true,
+            &mut index_of_table_added_to_in_root,
+            rng,
+        );
+        let num_tables_after_addition = self.whpylm.tree.root().ntables;
+        let word_begin_index = sentence.segment_begin_positions[n];
+        let word_end_index = word_begin_index + sentence.segment_lengths[n] - 1;
 
-                let mut recorded_depth_array = vec![0; word_end_index - word_begin_index + 3];
-                self.add_word_to_chpylm(
-                    &sentence.characters,
-                    word_begin_index,
-                    word_end_index,
-                    &mut recorded_depth_array,
+        if num_tables_before_addition < num_tables_after_addition {
+            self.clear_g_0_cache();
+            if token_n == EOS {
+                self.chpylm.tree.add_customer(
+                    ROOT_ID,
+                    EOS_CHAR,
+                    &mut SeatingParameters {
+                        g0_or_parent_p_ws: Left(self.chpylm.g_0),
+                        d_array: &mut self.chpylm.d_array,
+                        theta_array: &mut self.chpylm.theta_array,
+                    },
+                    true,
+                    &mut index_of_table_added_to_in_root,
+                    rng,
                 );
+                return Ok(());
+            }
+
+            // assert!(index_of_table_added_to_in_root != 0);
 
-                assert!(recorded_depth_array.len() == word_end_index - word_begin_index + 3);
+            let mut recorded_depth_array = vec![0; word_end_index - word_begin_index + 3];
+            self.add_word_to_chpylm(
+                &sentence.characters,
+                word_begin_index,
+                word_end_index,
+                &mut recorded_depth_array,
+                rng,
+            )?;
 
-                let depth_arrays_for_the_tablegroup = self
-                    .recorded_depth_arrays_for_tablegroups_of_token
-                    .entry(token_n)
-                    .or_insert(Vec::new());
+            assert!(recorded_depth_array.len() == word_end_index - word_begin_index + 3);
 
-                assert!(depth_arrays_for_the_tablegroup.len() <= index_of_table_added_to_in_root);
+            let depth_arrays_for_the_tablegroup = self
+                .recorded_depth_arrays_for_tablegroups_of_token
+                .entry(token_n)
+                .or_insert(Vec::new());
 
-                depth_arrays_for_the_tablegroup.push(recorded_depth_array);
-            }
+            assert!(depth_arrays_for_the_tablegroup.len() <= index_of_table_added_to_in_root);
+
+            depth_arrays_for_the_tablegroup.push(recorded_depth_array);
         }
-        return true;
+        Ok(())
     }
 
-    fn add_word_to_chpylm(
+    fn add_word_to_chpylm<R: Rng>(
         &mut self,
         sentence_as_chars: &Vec<char>,
         word_begin_index: usize,
         word_end_index: usize,
         recorded_depth_array: &mut Vec<usize>,
-    ) {
+        rng: &mut R,
+    ) -> Result<()> {
         assert!(word_end_index >= word_begin_index);
         assert!(word_end_index < self.max_sentence_length);
         self.most_recent_word =
@@ -175,36 +346,47 @@ impl NPYLM {
         for n in 0..word_length_with_symbols {
             let depth_n = self
                 .chpylm
-                .sample_depth_at_index_n(&self.most_recent_word, n);
+                .sample_depth_at_index_n(&self.most_recent_word, n, rng)?;
             self.chpylm
-                .add_customer_at_index_n(&self.most_recent_word, n, depth_n, true);
+                .add_customer_at_index_n(&self.most_recent_word, n, depth_n, true, rng);
             recorded_depth_array[n] = depth_n;
         }
+        Ok(())
     }
 
-    pub fn remove_customer_at_index_n(&mut self, sentence: &Sentence, n: usize) -> bool {
+    pub fn remove_customer_at_index_n<R: Rng>(
+        &mut self,
+        sentence: &Sentence,
+        n: usize,
+        rng: &mut R,
+    ) -> bool {
         assert!(n >= 2);
-        let token_n = sentence.get_nth_word_id(n);
+        let word_ids = self.intern_word_ids_up_to_nth_word(sentence, n);
+        let token_n = word_ids[2];
         let pyp = self
-            .find_node_with_word_ids(&sentence.word_ids, n, false, false)
+            .find_node_with_word_ids(&word_ids, 2, false, false)
             .unwrap();
-        let num_tables_before_removal = self.whpylm.root.ntables;
+        let num_tables_before_removal = self.whpylm.tree.root().ntables;
         let mut index_of_table_removed_from = 0;
         let word_begin_index = sentence.segment_begin_positions[n];
         let word_end_index = word_begin_index + sentence.segment_lengths[n] - 1;
 
-        unsafe {
-            (*pyp).remove_customer(token_n, true, &mut index_of_table_removed_from);
-        }
+        self.whpylm
+            .tree
+            .remove_customer(pyp, token_n, true, &mut index_of_table_removed_from, rng);
 
-        let num_tables_after_removal = self.whpylm.root.ntables;
+        let num_tables_after_removal = self.whpylm.tree.root().ntables;
 
         if num_tables_before_removal > num_tables_after_removal {
-            self.whpylm_g_0_cache = HashMap::new();
+            self.clear_g_0_cache();
             if token_n == EOS {
-                self.chpylm
-                    .root
-                    .remove_customer(EOS_CHAR, true, &mut index_of_table_removed_from);
+                self.chpylm.tree.remove_customer(
+                    ROOT_ID,
+                    EOS_CHAR,
+                    true,
+                    &mut index_of_table_removed_from,
+                    rng,
+                );
                 return true;
             }
 
@@ -229,6 +411,7 @@ impl NPYLM {
                 word_begin_index,
                 word_end_index,
                 &recorded_depths,
+                rng,
             );
 
             self.recorded_depth_arrays_for_tablegroups_of_token
@@ -239,21 +422,20 @@ impl NPYLM {
                 .remove(index_of_table_removed_from);
         }
 
-        unsafe {
-            if (*pyp).need_to_remove_from_parent() {
-                (*pyp).remove_from_parent();
-            }
+        if self.whpylm.tree.node(pyp).need_to_remove_from_parent() {
+            self.whpylm.tree.remove_from_parent(pyp);
         }
 
         true
     }
 
-    fn remove_word_from_chpylm(
+    fn remove_word_from_chpylm<R: Rng>(
         &mut self,
         sentence_as_chars: &Vec<char>,
         word_begin_index: usize,
         word_end_index: usize,
         recorded_depths: &Vec<usize>,
+        rng: &mut R,
     ) {
         assert!(recorded_depths.len() > 0);
         assert!(word_end_index >= word_begin_index);
@@ -267,54 +449,61 @@ impl NPYLM {
         assert!(recorded_depths.len() == word_length_with_symbols);
 
         for n in 0..word_length_with_symbols {
-            self.chpylm
-                .remove_customer_at_index_n(&self.most_recent_word, n, recorded_depths[n]);
+            self.chpylm.remove_customer_at_index_n(
+                &self.most_recent_word,
+                n,
+                recorded_depths[n],
+                rng,
+            );
         }
     }
 
     fn find_node_with_word_ids(
         &mut self,
-        word_ids: &Vec<u64>,
+        word_ids: &[u64],
         n: usize,
         generate_if_not_found: bool,
         return_middle_node: bool,
-    ) -> Option<*mut PYP<u64>> {
+    ) -> Option<NodeId> {
         assert!(n >= 2);
         assert!(n < word_ids.len());
 
-        let mut cur_node = &mut self.whpylm.root as *mut PYP<u64>;
+        let mut cur_node = ROOT_ID;
 
-        unsafe {
-            for depth in 1..3 {
-                let mut context = BOS;
-                if n >= depth {
-                    context = word_ids[n - depth];
-                }
-                let child = (*cur_node).find_child_pyp(context, generate_if_not_found);
-                match child {
-                    None => {
-                        if return_middle_node {
-                            return Some(cur_node);
-                        } else {
-                            return None;
-                        }
+        for depth in 1..self.whpylm.order {
+            let mut context = BOS;
+            if n >= depth {
+                context = word_ids[n - depth];
+            }
+            let child = self
+                .whpylm
+                .tree
+                .find_child_pyp(cur_node, context, generate_if_not_found);
+            match child {
+                None => {
+                    if return_middle_node {
+                        return Some(cur_node);
+                    } else {
+                        return None;
                     }
-                    Some(c) => cur_node = c,
                 }
+                Some(c) => cur_node = c,
             }
-            assert!((*cur_node).depth == 2);
         }
+        assert!(self.whpylm.tree.node(cur_node).depth == self.whpylm.order - 1);
 
         return Some(cur_node);
     }
 
+    /// `word_ids` ends with the IDs of the words up to the nth one, see `intern_word_ids_up_to_nth_word`.
     fn find_node_with_sentence(
         &mut self,
         sentence: &Sentence,
+        word_ids: &[u64],
         n: usize,
         generate_if_not_found: bool,
         return_middle_node: bool,
-    ) -> Option<*mut PYP<u64>> {
+    ) -> Result<Option<NodeId>> {
         assert!(n >= 2);
         assert!(n < sentence.num_segments);
         assert!(sentence.segment_lengths[n] > 0);
@@ -323,8 +512,8 @@ impl NPYLM {
         let word_end_index = word_begin_index + sentence.segment_lengths[n] - 1;
         return self.find_node_with_sentence_as_chars(
             &sentence.characters,
-            &sentence.word_ids,
-            n,
+            word_ids,
+            word_ids.len() - 1,
             word_begin_index,
             word_end_index,
             generate_if_not_found,
@@ -335,61 +524,66 @@ impl NPYLM {
     fn find_node_with_sentence_as_chars(
         &mut self,
         sentence_as_chars: &Vec<char>,
-        word_ids: &Vec<u64>,
+        word_ids: &[u64],
         n: usize,
         word_begin_index: usize,
         word_end_index: usize,
         generate_if_not_found: bool,
         return_middle_node: bool,
-    ) -> Option<*mut PYP<u64>> {
+    ) -> Result<Option<NodeId>> {
         assert!(n >= 2);
         assert!(n < word_ids.len());
         assert!(word_begin_index >= 0);
         assert!(word_end_index >= word_begin_index);
 
-        let mut cur_node = &mut self.whpylm.root as *mut PYP<u64>;
+        let mut cur_node = ROOT_ID;
         let word_n_id = word_ids[n];
-        let mut parent_p_w = self.compute_g_0_of_word_at_index_n(
-            sentence_as_chars,
-            word_begin_index,
-            word_end_index,
-            word_n_id,
-        );
+        let mut parent_p_w = self.with_own_cache(|npylm, cache| {
+            npylm.compute_g_0_of_word_at_index_n(
+                cache,
+                sentence_as_chars,
+                word_begin_index,
+                word_end_index,
+                word_n_id,
+            )
+        })?;
         self.whpylm_parent_p_w_cache[0] = parent_p_w;
-        unsafe {
-            for depth in 1..3 {
-                let mut context = BOS;
-                if n >= depth {
-                    context = word_ids[n - depth];
-                }
-                let p_w = (*cur_node).compute_p_w_with_parent_p_w(
-                    word_n_id,
-                    parent_p_w,
-                    &mut self.whpylm.d_array,
-                    &mut self.whpylm.theta_array,
-                );
-                self.whpylm_parent_p_w_cache[depth] = p_w;
-                let child = (*cur_node).find_child_pyp(context, generate_if_not_found);
-                if child.is_none() && return_middle_node == true {
-                    return Some(cur_node);
-                }
-                parent_p_w = p_w;
-                cur_node = child.unwrap();
+        for depth in 1..self.whpylm.order {
+            let mut context = BOS;
+            if n >= depth {
+                context = word_ids[n - depth];
+            }
+            let p_w = self.whpylm.tree.node(cur_node).compute_p_w_with_parent_p_w(
+                word_n_id,
+                parent_p_w,
+                &self.whpylm.d_array,
+                &self.whpylm.theta_array,
+            );
+            self.whpylm_parent_p_w_cache[depth] = p_w;
+            let child = self
+                .whpylm
+                .tree
+                .find_child_pyp(cur_node, context, generate_if_not_found);
+            if child.is_none() && return_middle_node == true {
+                return Ok(Some(cur_node));
             }
-            assert!((*cur_node).depth == 2);
+            parent_p_w = p_w;
+            cur_node = child.unwrap();
         }
-        return Some(cur_node);
+        assert!(self.whpylm.tree.node(cur_node).depth == self.whpylm.order - 1);
+        return Ok(Some(cur_node));
     }
 
     fn compute_g_0_of_word_at_index_n(
-        &mut self,
+        &self,
+        cache: &mut ProbabilityCache,
         sentence_as_chars: &Vec<char>,
         word_begin_index: usize,
         word_end_index: usize,
         word_n_id: u64,
-    ) -> f64 {
+    ) -> Result<f64> {
         if word_n_id == EOS {
-            return self.chpylm.g_0;
+            return Ok(self.chpylm.g_0);
         }
 
         assert!(word_end_index < self.max_sentence_length);
@@ -397,20 +591,26 @@ impl NPYLM {
         assert!(word_end_index >= word_begin_index);
 
         let word_length = word_end_index - word_begin_index + 1;
-        match self.whpylm_g_0_cache.entry(word_n_id) {
+        cache.refresh(self.g_0_generation);
+        let g_0_cache = if is_transient_word_id(word_n_id) {
+            &mut cache.transient_g_0
+        } else {
+            &mut cache.whpylm_g_0
+        };
+        match g_0_cache.entry(word_n_id) {
             Entry::Vacant(e) => {
-                self.most_recent_word = produce_word_with_bow_and_eow(
+                let word = produce_word_with_bow_and_eow(
                     sentence_as_chars,
                     word_begin_index,
                     word_end_index,
                 );
                 // let word_length_with_symbols = word_length + 2;
-                let p_w = self.chpylm.compute_p_w(&self.most_recent_word);
+                let p_w = self.chpylm.compute_p_w(&word);
                 // println!("p_w is {}", p_w);
                 if word_length > self.max_word_length {
                     // self.whpylm_g_0_cache[&word_n_id] = p_w;
                     e.insert(p_w);
-                    return p_w;
+                    return Ok(p_w);
                 } else {
                     // let p_k_given_chpylm = self.compute_p_k_given_chpylm(word_length);
                     // Unfortunately borrow checker doesn't allow me to call the function... Inlining the function directly like this seems to work though.
@@ -419,31 +619,29 @@ impl NPYLM {
                     } else {
                         self.p_k_chpylm[word_length]
                     };
-                    let t = detect_word_type_substr(
+                    let t = self.word_type_scheme.detect_word_type_substr(
                         sentence_as_chars,
                         word_begin_index,
                         word_end_index,
                     );
                     let lambda = self.lambda_for_types[t];
-                    let poisson_sample = sample_poisson_k_lambda(word_length, lambda);
-                    assert!(poisson_sample > 0.0);
+                    let poisson_sample = sample_poisson_k_lambda(word_length, lambda)?;
                     let g_0 = p_w / p_k_given_chpylm * poisson_sample;
-
-                    // Very rarely the result will exceed 1.
                     if !(0.0 < g_0 && g_0 < 1.0) {
-                        println!("The result exceeds 1!");
-                        for i in word_begin_index..word_end_index + 1 {
-                            print!("{}", sentence_as_chars[i]);
-                        }
-                        print!("\n");
-                        println!("p_w: {}, poisson_sample: {}, p_k_given_chpylm: {}, g_0: {}, word_length: {}", p_w, poisson_sample, p_k_given_chpylm, g_0, word_length);
+                        let word: String = sentence_as_chars[word_begin_index..word_end_index + 1]
+                            .iter()
+                            .collect();
+                        return Err(Error::NumericalUnderflow(format!(
+                            "The base measure of the word {} is {}, from p_w {}, the Poisson probability {} and the length probability {}",
+                            word, g_0, p_w, poisson_sample, p_k_given_chpylm
+                        )));
                     }
 
                     e.insert(g_0);
-                    return g_0;
+                    return Ok(g_0);
                 }
             }
-            Entry::Occupied(e) => e.get().clone(),
+            Entry::Occupied(e) => Ok(e.get().clone()),
         }
     }
 
@@ -456,76 +654,491 @@ impl NPYLM {
     //     }
     // }
 
-    pub fn sample_hyperparameters(&mut self) {
-        self.whpylm.sample_hyperparameters();
-        self.chpylm.sample_hyperparameters();
+    /// Lists the words of the segmentation of the sentences, in the order in which they first occur.
+    pub fn build_lexicon(&mut self, sentences: &SegmentedSentences) -> Result<Lexicon> {
+        let mut word_ids = Vec::new();
+        let mut token_counts: HashMap<u64, usize> = HashMap::new();
+        for sentence in sentences.iter() {
+            for n in 2..sentence.num_segments - 1 {
+                let word_id = sentence.get_nth_word_id(n, &mut self.interner);
+                let token_count = token_counts.entry(word_id).or_insert(0);
+                if *token_count == 0 {
+                    word_ids.push(word_id);
+                }
+                *token_count += 1;
+            }
+        }
+
+        let mut entries = Vec::with_capacity(word_ids.len());
+        for word_id in word_ids {
+            let word = self.interner.get_word(word_id).to_vec();
+            let base_probability = self.with_own_cache(|npylm, cache| {
+                npylm.compute_g_0_of_word_at_index_n(cache, &word, 0, word.len() - 1, word_id)
+            })?;
+            let root = self.whpylm.tree.root();
+            let table_count = root
+                .tablegroups
+                .get(&word_id)
+                .map_or(0, |tablegroups| tablegroups.len());
+            let probability = root.compute_p_w_with_parent_p_w(
+                word_id,
+                base_probability,
+                &self.whpylm.d_array,
+                &self.whpylm.theta_array,
+            );
+            entries.push(LexiconEntry {
+                word: word.iter().collect(),
+                length: word.len(),
+                word_type: self.word_type_scheme.detect_word_type(&word),
+                token_count: token_counts[&word_id],
+                table_count,
+                probability,
+                base_probability,
+            });
+        }
+        Ok(Lexicon { entries })
     }
 
-    fn compute_probability_of_sentence(&mut self, sentence: &Sentence) -> f64 {
-        let mut prod = 1.0 as f64;
-        for n in 2..sentence.num_segments {
-            prod *= self.compute_p_w_of_nth_word(sentence, n);
+    /// Samples a sentence word by word from the word n-gram model, until it draws EOS or has drawn `max_words` words. Each word is either one the model knows or a new one, which is then spelled by the character model. New words are spelled without the Poisson correction of the word lengths, and a spelling that turns out to be a known word is drawn again. All the draws are made at `temperature`, see `sample_index_with_temperature`.
+    pub fn generate_sentence<R: Rng>(
+        &mut self,
+        temperature: f64,
+        max_words: usize,
+        rng: &mut R,
+    ) -> Result<GeneratedSentence> {
+        check_temperature(temperature)?;
+        // Sorted, so that the same seed always gives the same sentence.
+        let mut candidate_ids: Vec<u64> = self
+            .whpylm
+            .tree
+            .root()
+            .tablegroups
+            .keys()
+            .cloned()
+            .filter(|&word_id| word_id != EOS)
+            .collect();
+        candidate_ids.sort();
+        let mut candidate_g_0s = Vec::with_capacity(candidate_ids.len() + 1);
+        let mut new_word_g_0 = 1.0 - self.chpylm.g_0;
+        for &word_id in &candidate_ids {
+            let word = self.interner.get_word(word_id).to_vec();
+            let g_0 = self.with_own_cache(|npylm, cache| {
+                npylm.compute_g_0_of_word_at_index_n(cache, &word, 0, word.len() - 1, word_id)
+            })?;
+            candidate_g_0s.push(g_0);
+            new_word_g_0 -= g_0;
+        }
+        candidate_ids.push(EOS);
+        candidate_g_0s.push(self.chpylm.g_0);
+        // The rest of the base measure goes to the words the model doesn't know.
+        let new_word_g_0 = new_word_g_0.max(0.0);
+
+        self.clear_transient_g_0_cache();
+        let mut word_ids = vec![BOS, BOS];
+        let mut words = Vec::new();
+        let mut num_new_words = 0;
+        let mut num_characters = 0;
+        let mut log_probability = 0.0;
+        let mut truncated = false;
+        // The last entry is for a new word.
+        let mut probabilities = vec![0.0; candidate_ids.len() + 1];
+        loop {
+            if words.len() == max_words {
+                truncated = true;
+                break;
+            }
+            let n = word_ids.len();
+            // The word to be drawn, which `find_node_with_word_ids` doesn't look at.
+            word_ids.push(EOS);
+            let node = self
+                .find_node_with_word_ids(&word_ids, n, false, true)
+                .unwrap();
+            for (i, (&word_id, &g_0)) in candidate_ids.iter().zip(&candidate_g_0s).enumerate() {
+                probabilities[i] = self.whpylm.tree.compute_p_w(
+                    node,
+                    word_id,
+                    g_0,
+                    &self.whpylm.d_array,
+                    &self.whpylm.theta_array,
+                );
+            }
+            // No restaurant serves the transient IDs, so this is the probability of backing off to the base measure times its share for new words.
+            probabilities[candidate_ids.len()] = self.whpylm.tree.compute_p_w(
+                node,
+                TRANSIENT_WORD_ID_BASE,
+                new_word_g_0,
+                &self.whpylm.d_array,
+                &self.whpylm.theta_array,
+            );
+
+            let index = sample_index_with_temperature(&probabilities, temperature, rng)?;
+            let (word_id, word) = if index < candidate_ids.len() {
+                log_probability += probabilities[index].ln();
+                let word_id = candidate_ids[index];
+                if word_id == EOS {
+                    break;
+                }
+                (word_id, self.interner.get_word(word_id).to_vec())
+            } else {
+                let word = self.spell_new_word(temperature, rng)?;
+                // The words are never longer than the maximum word length, so the IDs of new words at different positions differ.
+                let word_id = transient_word_id(self.max_word_length, num_characters, word.len());
+                let g_0 = self.with_own_cache(|npylm, cache| {
+                    npylm.compute_g_0_of_word_at_index_n(cache, &word, 0, word.len() - 1, word_id)
+                })?;
+                log_probability += self
+                    .whpylm
+                    .tree
+                    .compute_p_w(
+                        node,
+                        word_id,
+                        g_0,
+                        &self.whpylm.d_array,
+                        &self.whpylm.theta_array,
+                    )
+                    .ln();
+                num_new_words += 1;
+                (word_id, word)
+            };
+            word_ids[n] = word_id;
+            num_characters += word.len();
+            words.push(word.iter().collect());
+        }
+        self.clear_transient_g_0_cache();
+
+        Ok(GeneratedSentence {
+            words,
+            num_new_words,
+            log_probability,
+            truncated,
+        })
+    }
+
+    /// Spells a word the word model doesn't know character by character, see `generate_sentence`.
+    fn spell_new_word<R: Rng>(&mut self, temperature: f64, rng: &mut R) -> Result<Vec<char>> {
+        let mut characters: Vec<char> = self
+            .chpylm
+            .tree
+            .root()
+            .tablegroups
+            .keys()
+            .cloned()
+            .filter(|&c| c != BOW && c != EOW)
+            .collect();
+        characters.sort();
+        characters.push(EOW);
+        let mut probabilities = vec![0.0; characters.len()];
+        for _ in 0..MAX_NEW_WORD_ATTEMPTS {
+            let mut word = vec![BOW];
+            while word.len() <= self.max_word_length {
+                for (i, &c) in characters.iter().enumerate() {
+                    probabilities[i] =
+                        self.chpylm
+                            .compute_p_w_given_h_with_target(c, &word, 0, word.len() - 1);
+                }
+                // Every word has at least one character.
+                if word.len() == 1 {
+                    probabilities[characters.len() - 1] = 0.0;
+                }
+                let c =
+                    characters[sample_index_with_temperature(&probabilities, temperature, rng)?];
+                if c == EOW {
+                    break;
+                }
+                word.push(c);
+            }
+            word.remove(0);
+            let is_known = match self.interner.get(&word) {
+                Some(word_id) => self.whpylm.tree.root().tablegroups.contains_key(&word_id),
+                None => false,
+            };
+            if !is_known {
+                return Ok(word);
+            }
+        }
+        Err(Error::NumericalUnderflow(format!(
+            "The character model spelled only known words in {} attempts",
+            MAX_NEW_WORD_ATTEMPTS
+        )))
+    }
+
+    /// Gives the IDs of the words that are no longer seated in the WHPYLM, neither as a word nor as the context of one, back to the interner, which hands them out again to the next new words. Returns the number of words dropped.
+    pub fn release_unused_word_ids(&mut self) -> usize {
+        let tree = &self.whpylm.tree;
+        let mut used_word_ids: HashSet<u64> = tree.root().tablegroups.keys().cloned().collect();
+        // A restaurant whose last customer has left stays in the tree, but it stands for no context anymore. If its word is given to another one, it simply serves as the empty restaurant of that word.
+        for node in tree.nodes() {
+            for (&word_id, &child_id) in &node.children {
+                if tree.node(child_id).ncustomers > 0 {
+                    used_word_ids.insert(word_id);
+                }
+            }
+        }
+        let num_released = self
+            .interner
+            .release_unused(|word_id| used_word_ids.contains(&word_id));
+        if num_released > 0 {
+            self.recorded_depth_arrays_for_tablegroups_of_token
+                .retain(|word_id, _| used_word_ids.contains(word_id));
+            // The cached base measures are keyed by the IDs, some of which will stand for other words.
+            self.clear_g_0_cache();
         }
-        prod
+        num_released
     }
 
-    pub fn compute_log_probability_of_sentence(&mut self, sentence: &Sentence) -> f64 {
+    pub fn sample_hyperparameters<R: Rng>(&mut self, rng: &mut R) {
+        self.whpylm.sample_hyperparameters(rng);
+        self.chpylm.sample_hyperparameters(rng);
+        self.clear_g_0_cache();
+    }
+
+    fn compute_probability_of_sentence(&mut self, sentence: &Sentence) -> Result<f64> {
+        self.with_own_cache(|npylm, cache| {
+            let word_ids = npylm.find_word_ids(sentence, cache);
+            let mut prod = 1.0 as f64;
+            for n in 2..sentence.num_segments {
+                prod *= npylm.compute_p_w_of_nth_word(cache, sentence, &word_ids, n)?;
+            }
+            Ok(prod)
+        })
+    }
+
+    pub fn compute_log_probability_of_sentence(&mut self, sentence: &Sentence) -> Result<f64> {
+        self.with_own_cache(|npylm, cache| {
+            npylm.compute_log_probability_of_sentence_with_cache(sentence, cache)
+        })
+    }
+
+    /// The same as `compute_log_probability_of_sentence`, for the threads that share the NPYLM.
+    pub fn compute_log_probability_of_sentence_with_cache(
+        &self,
+        sentence: &Sentence,
+        cache: &mut ProbabilityCache,
+    ) -> Result<f64> {
+        let word_ids = self.find_word_ids(sentence, cache);
         let mut sum = 0.0 as f64;
         for n in 2..sentence.num_segments {
-            sum += self.compute_p_w_of_nth_word(sentence, n).ln();
+            sum += self
+                .compute_p_w_of_nth_word(cache, sentence, &word_ids, n)?
+                .ln();
         }
-        sum
+        Ok(sum)
     }
 
-    pub fn compute_p_w_of_nth_word(&mut self, sentence: &Sentence, n: usize) -> f64 {
+    /// `word_ids` holds the IDs of all the words of the sentence, see `find_word_ids`.
+    fn compute_p_w_of_nth_word(
+        &self,
+        cache: &mut ProbabilityCache,
+        sentence: &Sentence,
+        word_ids: &[u64],
+        n: usize,
+    ) -> Result<f64> {
         assert!(n >= 2);
         assert!(n < sentence.num_segments);
         assert!(sentence.segment_lengths[n] > 0);
-        let word_begin_index = sentence.segment_begin_positions[0];
+        let word_begin_index = sentence.segment_begin_positions[n];
         let word_end_index = word_begin_index + sentence.segment_lengths[n] - 1;
         return self.compute_p_w_of_nth_word_as_chars(
+            cache,
             &sentence.characters,
-            &sentence.word_ids,
+            word_ids,
             n,
             word_begin_index,
             word_end_index,
         );
     }
 
+    /// The probability of the nth word given the words before it, backing off from the node of the longest context the WHPYLM knows.
     pub fn compute_p_w_of_nth_word_as_chars(
-        &mut self,
+        &self,
+        cache: &mut ProbabilityCache,
         sentence_as_chars: &Vec<char>,
-        word_ids: &Vec<u64>,
+        word_ids: &[u64],
         n: usize,
         word_begin_position: usize,
         word_end_position: usize,
-    ) -> f64 {
+    ) -> Result<f64> {
+        assert!(n >= 2);
+        assert!(n < word_ids.len());
         let word_id = word_ids[n];
-        let node = self
-            .find_node_with_sentence_as_chars(
-                sentence_as_chars,
-                word_ids,
-                n,
-                word_begin_position,
-                word_end_position,
-                false,
-                true,
-            )
+        let tree = &self.whpylm.tree;
+        let g_0 = self.compute_g_0_of_word_at_index_n(
+            cache,
+            sentence_as_chars,
+            word_begin_position,
+            word_end_position,
+            word_id,
+        )?;
+        let mut cur_node = ROOT_ID;
+        let mut p_w = tree.root().compute_p_w_with_parent_p_w(
+            word_id,
+            g_0,
+            &self.whpylm.d_array,
+            &self.whpylm.theta_array,
+        );
+        for depth in 1..self.whpylm.order {
+            let context = word_ids[n - depth];
+            match tree.get_child(cur_node, context) {
+                None => break,
+                Some(child) => {
+                    p_w = tree.node(child).compute_p_w_with_parent_p_w(
+                        word_id,
+                        p_w,
+                        &self.whpylm.d_array,
+                        &self.whpylm.theta_array,
+                    );
+                    cur_node = child;
+                }
+            }
+        }
+        Ok(p_w)
+    }
+}
+
+/// The Poisson parameters are sampled from a gamma distribution, which can underflow to 0 if its shape is tiny. A word length distribution built from such a parameter would be degenerate, so report it instead.
+pub fn check_lambda(word_type: usize, lambda: f64) -> Result<f64> {
+    if lambda > 0.0 && lambda.is_finite() {
+        Ok(lambda)
+    } else {
+        Err(Error::NumericalUnderflow(format!(
+            "The Poisson parameter sampled for word type {} is {}",
+            word_type, lambda
+        )))
+    }
+}
+
+// A length the Poisson distribution gives no probability at all, because the parameter is tiny or wasn't valid in the first place, would make every word of that length impossible.
+fn sample_poisson_k_lambda(k: usize, lambda: f64) -> Result<f64> {
+    let p = match Poisson::new(lambda) {
+        Ok(dist) => dist.pmf(k as u64),
+        Err(_) => 0.0,
+    };
+    if p > 0.0 {
+        Ok(p)
+    } else {
+        Err(Error::NumericalUnderflow(format!(
+            "The probability of the word length {} under the Poisson parameter {} is {}",
+            k, lambda, p
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_npylm(rng: &mut NpylmRng) -> NPYLM {
+        NPYLM::new(&NPYLMParams::new(3, 4), 10, 1.0 / 8.0, rng).unwrap()
+    }
+
+    fn segmented_sentence(words: &[&str]) -> Sentence {
+        let mut sentence = Sentence::new_from_string(words.concat(), false);
+        sentence.split_sentence(words.iter().map(|w| w.chars().count()).collect());
+        sentence
+    }
+
+    fn add_sentence(npylm: &mut NPYLM, sentence: &Sentence, rng: &mut NpylmRng) {
+        for n in 2..sentence.num_segments {
+            npylm.add_customer_at_index_n(sentence, n, rng).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_probability_of_sentence_uses_the_characters_of_each_word() {
+        let mut rng = NpylmRng::seed_from_u64(0);
+        let mut npylm = new_npylm(&mut rng);
+        add_sentence(
+            &mut npylm,
+            &segmented_sentence(&["ab", "ab", "c"]),
+            &mut rng,
+        );
+
+        // The model has never seen "xyz", so its base measure has to be computed from its own characters, not from the "abx" at the beginning of the sentence.
+        let sentence = segmented_sentence(&["ab", "xyz"]);
+        let mut cache = ProbabilityCache::new();
+        let word_ids = npylm.find_word_ids(&sentence, &mut cache);
+        let mut expected_log_p = 0.0;
+        for n in 2..sentence.num_segments {
+            let begin = sentence.segment_begin_positions[n];
+            let end = begin + sentence.segment_lengths[n] - 1;
+            expected_log_p += npylm
+                .compute_p_w_of_nth_word_as_chars(
+                    &mut cache,
+                    &sentence.characters,
+                    &word_ids,
+                    n,
+                    begin,
+                    end,
+                )
+                .unwrap()
+                .ln();
+        }
+        let log_p = npylm
+            .compute_log_probability_of_sentence(&sentence)
             .unwrap();
+        assert!((log_p - expected_log_p).abs() < 1e-12);
+    }
 
-        unsafe {
-            let parent_p_w = self.whpylm_parent_p_w_cache[(*node).depth];
-            return (*node).compute_p_w_with_parent_p_w(
-                word_id,
-                parent_p_w,
-                &mut self.whpylm.d_array,
-                &mut self.whpylm.theta_array,
+    #[test]
+    fn test_base_measure_of_a_word_length_without_poisson_probability_is_an_error() {
+        let mut rng = NpylmRng::seed_from_u64(0);
+        let sentence = segmented_sentence(&["ab", "cde"]);
+        // The first parameter is no valid parameter of a Poisson distribution at all, the second one leaves the probability of every length but 0 underflowing to 0.
+        for &lambda in &[0.0, 1e-300] {
+            let mut npylm = new_npylm(&mut rng);
+            for l in npylm.lambda_for_types.iter_mut() {
+                *l = lambda;
+            }
+            let mut cache = ProbabilityCache::new();
+            let word_ids = npylm.find_word_ids(&sentence, &mut cache);
+            let begin = sentence.segment_begin_positions[3];
+            let end = begin + sentence.segment_lengths[3] - 1;
+            let g_0 = npylm.compute_g_0_of_word_at_index_n(
+                &mut cache,
+                &sentence.characters,
+                begin,
+                end,
+                word_ids[3],
             );
+            match g_0 {
+                Err(Error::NumericalUnderflow(_)) => {}
+                other => panic!("expected a numerical underflow, got {:?}", other),
+            }
+            assert!(npylm
+                .compute_log_probability_of_sentence(&sentence)
+                .is_err());
         }
     }
-}
 
-fn sample_poisson_k_lambda(k: usize, lambda: f64) -> f64 {
-    let dist = Poisson::new(lambda).unwrap();
-    dist.pmf(k as u64)
+    #[test]
+    fn test_words_no_longer_seated_are_released() {
+        let mut rng = NpylmRng::seed_from_u64(0);
+        let mut npylm = new_npylm(&mut rng);
+        let kept = segmented_sentence(&["ab", "c"]);
+        let dropped = segmented_sentence(&["de", "fg"]);
+        add_sentence(&mut npylm, &kept, &mut rng);
+        add_sentence(&mut npylm, &dropped, &mut rng);
+        let de = npylm.interner.get(&['d', 'e']).unwrap();
+        npylm.compute_log_probability_of_sentence(&dropped).unwrap();
+        for n in 2..dropped.num_segments {
+            npylm.remove_customer_at_index_n(&dropped, n, &mut rng);
+        }
+        assert_eq!(npylm.interner.get_num_words(), 6);
+
+        assert_eq!(npylm.release_unused_word_ids(), 2);
+        assert_eq!(npylm.interner.get_num_words(), 4);
+        assert!(npylm.interner.get(&['d', 'e']).is_none());
+        assert!(npylm.interner.get(&['a', 'b']).is_some());
+
+        // The next new word gets the ID of "de", and nothing cached for "de" may leak into it.
+        let added = segmented_sentence(&["xyz", "c"]);
+        add_sentence(&mut npylm, &added, &mut rng);
+        assert_eq!(npylm.interner.get(&['x', 'y', 'z']), Some(de));
+        let log_p = npylm.compute_log_probability_of_sentence(&added).unwrap();
+        npylm.clear_g_0_cache();
+        assert_eq!(
+            npylm.compute_log_probability_of_sentence(&added).unwrap(),
+            log_p
+        );
+    }
 }
@@ -3,6 +3,7 @@ use def::*;
 use either::*;
 use rand::distributions::{Bernoulli, Beta, Distribution, WeightedIndex};
 use rand::Rng;
+use serde::Deserialize;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 
@@ -21,12 +22,26 @@ fn init_hyperparameters_at_depth_if_needed(
   }
 }
 
-#[derive(Clone)]
+/// What seating a customer takes besides the tree: the base measure `g0`, or the probabilities of the dish at each depth of the path if they are computed already, and the d and theta of each depth, which grow when a deeper node gets its first customer.
+pub struct SeatingParameters<'a> {
+  pub g0_or_parent_p_ws: Either<f64, &'a Vec<f64>>,
+  pub d_array: &'a mut Vec<f64>,
+  pub theta_array: &'a mut Vec<f64>,
+}
+
+/// The index of a node in a `PYPTree`.
+pub type NodeId = usize;
+
+/// The root is always the first node of a `PYPTree`.
+pub const ROOT_ID: NodeId = 0;
+
+/// A single restaurant. The links to the parent and the children are indices into the `PYPTree` the node belongs to, so the operations that have to walk up or down the tree are methods of `PYPTree`.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "T: Deserialize<'de> + std::cmp::Eq + std::hash::Hash"))]
 pub struct PYP<T> {
-  pub children: HashMap<T, PYP<T>>,
-  // https://stackoverflow.com/questions/36167160/how-do-i-express-mutually-recursive-data-structures-in-safe-rust
-  parent: Option<*mut PYP<T>>,
-  pub tablegroups: HashMap<T, Vec<usize>>,
+  pub children: HashMap<T, NodeId, FixedState>,
+  pub parent: Option<NodeId>,
+  pub tablegroups: HashMap<T, Vec<usize>, FixedState>,
   pub ntables: usize,
   pub ncustomers: usize,
   pub stop_count: usize,
@@ -43,9 +58,9 @@ where
 {
   pub fn new(context: T) -> Self {
     Self {
-      children: HashMap::new(),
+      children: HashMap::default(),
       parent: None,
-      tablegroups: HashMap::new(),
+      tablegroups: HashMap::default(),
       ntables: 0,
       ncustomers: 0,
       stop_count: 0,
@@ -73,9 +88,196 @@ where
     }
   }
 
-  pub fn find_child_pyp(&mut self, dish: T, generate_if_not_found: bool) -> Option<&mut PYP<T>> {
-    if self.children.contains_key(&dish) {
-      return self.children.get_mut(&dish);
+  pub fn _add_customer_to_new_table(&mut self, dish: T) {
+    match self.tablegroups.entry(dish) {
+      Entry::Vacant(e) => {
+        e.insert(vec![1]);
+      }
+      Entry::Occupied(mut e) => {
+        e.get_mut().push(1);
+      }
+    }
+
+    self.ntables += 1;
+    self.ncustomers += 1;
+  }
+
+  pub fn compute_p_w_with_parent_p_w(
+    &self,
+    dish: T,
+    parent_p_w: f64,
+    d_array: &[f64],
+    theta_array: &[f64],
+  ) -> f64 {
+    // The hyperparameters of a depth no customer has reached yet keep their initial values.
+    let d_u = d_array.get(self.depth).cloned().unwrap_or(HPYLM_INITIAL_D);
+    let theta_u = theta_array.get(self.depth).cloned().unwrap_or(HPYLM_INITIAL_THETA);
+    let t_u = self.ntables as f64;
+    let c_u = self.ncustomers as f64;
+    match self.tablegroups.get(&dish) {
+      None => {
+        let coeff: f64 = (theta_u + d_u * t_u) / (theta_u + c_u);
+        return parent_p_w * coeff;
+      }
+      Some(tablegroup) => {
+        let c_uw: usize = tablegroup.into_iter().sum();
+        let t_uw = tablegroup.len() as f64;
+        let first_term: f64 = (c_uw as f64 - d_u * t_uw).max(0.0) / (theta_u + c_u);
+        let second_coeff: f64 = (theta_u + d_u * t_u) / (theta_u + c_u);
+        return first_term + second_coeff * parent_p_w;
+      }
+    }
+  }
+
+  /* The functions below are related to hyperparameter (d, θ) sampling, based on the algorithm given in the Teh Technical Report
+
+  There are 3 auxiliary variables defined, x_**u**, y_**u**i, z**u**wkj.
+
+  The following methods sample them. */
+
+  pub fn sample_log_x_u<R: Rng>(&self, theta_u: f64, rng: &mut R) -> f64 {
+    if self.ncustomers >= 2 {
+      let dist = Beta::new(theta_u + 1.0, self.ncustomers as f64 - 1.0);
+      // Prevent underflow.
+      let sample = dist.sample(rng) + 1e-8;
+      return sample.ln();
+    } else {
+      return 0.0;
+    }
+  }
+
+  pub fn sample_summed_y_ui<R: Rng>(
+    &self,
+    d_u: f64,
+    theta_u: f64,
+    is_one_minus: bool,
+    rng: &mut R,
+  ) -> f64 {
+    if self.ntables >= 2 {
+      let mut sum = 0;
+      for i in 1..self.ntables - 1 {
+        let denom = theta_u + d_u * i as f64;
+        assert!(denom > 0.0);
+        let prob = theta_u / denom;
+        let dist = Bernoulli::new(prob);
+        let mut y_ui = dist.sample(rng);
+        if is_one_minus {
+          y_ui = !y_ui;
+        }
+        match y_ui {
+          true => sum += 1,
+          false => sum += 0,
+        }
+      }
+      return sum as f64;
+    } else {
+      return 0.0;
+    }
+  }
+
+  pub fn sample_summed_one_minus_z_uwkj<R: Rng>(&self, d_u: f64, rng: &mut R) -> f64 {
+    let mut sum = 0;
+    // Visit the tablegroups in an order that doesn't depend on the HashMap, see `PYPTree::get_sorted_child_ids`.
+    let mut tablegroups: Vec<&Vec<usize>> = self.tablegroups.values().collect();
+    tablegroups.sort();
+    for tablegroup in tablegroups {
+      for customercount in tablegroup {
+        if customercount >= &2 {
+          for j in 1..customercount - 1 {
+            assert!(j as f64 - d_u > 0.0);
+            let prob = (j - 1) as f64 / (j as f64 - d_u);
+            let dist = Bernoulli::new(prob);
+            let result = if dist.sample(rng) { 1 } else { 0 };
+            sum += 1 - result;
+          }
+        }
+      }
+    }
+    return sum as f64;
+  }
+}
+
+/// All the restaurants of an HPYLM, stored in one arena and linked by their indices. Since there are no pointers between the nodes, the tree can be moved, cloned and serialized as it is.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "T: Deserialize<'de> + std::cmp::Eq + std::hash::Hash"))]
+pub struct PYPTree<T> {
+  nodes: Vec<PYP<T>>,
+  // The slots of removed nodes, which are reused for new ones.
+  free_node_ids: Vec<NodeId>,
+}
+
+impl<T> PYPTree<T>
+where
+  T: std::cmp::Eq,
+  T: std::hash::Hash,
+  T: Copy,
+{
+  pub fn new(root_context: T) -> Self {
+    Self {
+      nodes: vec![PYP::new(root_context)],
+      free_node_ids: Vec::new(),
+    }
+  }
+
+  pub fn root(&self) -> &PYP<T> {
+    &self.nodes[ROOT_ID]
+  }
+
+  pub fn node(&self, id: NodeId) -> &PYP<T> {
+    &self.nodes[id]
+  }
+
+  pub fn node_mut(&mut self, id: NodeId) -> &mut PYP<T> {
+    &mut self.nodes[id]
+  }
+
+  /// All the nodes, including the slots of removed nodes, which have neither children nor tables.
+  pub fn nodes(&self) -> &[PYP<T>] {
+    &self.nodes
+  }
+
+  fn allocate_node(&mut self, node: PYP<T>) -> NodeId {
+    match self.free_node_ids.pop() {
+      Some(id) => {
+        self.nodes[id] = node;
+        id
+      }
+      None => {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+      }
+    }
+  }
+
+  /// The children of a node ordered by their ids. Unlike the iteration order of the `HashMap`, this order is the same after the tree is deserialized, so a training restored from a checkpoint draws the same random numbers.
+  pub fn get_sorted_child_ids(&self, id: NodeId) -> Vec<NodeId> {
+    let mut child_ids: Vec<NodeId> = self.nodes[id].children.values().cloned().collect();
+    child_ids.sort();
+    child_ids
+  }
+
+  fn free_subtree(&mut self, id: NodeId) {
+    for child_id in self.get_sorted_child_ids(id) {
+      self.free_subtree(child_id);
+    }
+    // Drop the tables and the children of the node right away instead of waiting for the slot to be reused.
+    let context = self.nodes[id].context;
+    self.nodes[id] = PYP::new(context);
+    self.free_node_ids.push(id);
+  }
+
+  pub fn get_child(&self, id: NodeId, dish: T) -> Option<NodeId> {
+    self.nodes[id].children.get(&dish).cloned()
+  }
+
+  pub fn find_child_pyp(
+    &mut self,
+    id: NodeId,
+    dish: T,
+    generate_if_not_found: bool,
+  ) -> Option<NodeId> {
+    if let Some(child_id) = self.get_child(id, dish) {
+      return Some(child_id);
     }
 
     if !generate_if_not_found {
@@ -83,164 +285,137 @@ where
     }
 
     let mut child = PYP::new(dish);
-    child.parent = Some(self);
-    child.depth = self.depth + 1;
-    self.children.insert(dish, child);
-    // Fucking hell this actually worked!
-    // return Some(self.children.get(&dish).unwrap());
-    return self.children.get_mut(&dish);
+    child.parent = Some(id);
+    child.depth = self.nodes[id].depth + 1;
+    let child_id = self.allocate_node(child);
+    self.nodes[id].children.insert(dish, child_id);
+    return Some(child_id);
   }
 
-  pub fn add_customer_to_table(
+  pub fn add_customer_to_table<R: Rng>(
     &mut self,
+    id: NodeId,
     dish: T,
     table_index: usize,
-    g0_or_parent_p_ws: Either<f64, &Vec<f64>>,
-    d_array: &mut Vec<f64>,
-    theta_array: &mut Vec<f64>,
+    params: &mut SeatingParameters,
     table_index_in_root: &mut usize,
+    rng: &mut R,
   ) -> bool {
-    if !self.tablegroups.contains_key(&dish) {
-      return PYP::add_customer_to_new_table(
-        self,
-        dish,
-        g0_or_parent_p_ws,
-        d_array,
-        theta_array,
-        table_index_in_root,
-      );
+    if !self.nodes[id].tablegroups.contains_key(&dish) {
+      return self.add_customer_to_new_table(id, dish, params, table_index_in_root, rng);
     } else {
-      let tablegroup = self.tablegroups.get_mut(&dish).unwrap();
+      let node = &mut self.nodes[id];
+      let tablegroup = node.tablegroups.get_mut(&dish).unwrap();
       tablegroup[table_index] += 1;
-      self.ncustomers += 1;
+      node.ncustomers += 1;
       return true;
     }
   }
 
-  pub fn add_customer_to_new_table(
+  pub fn add_customer_to_new_table<R: Rng>(
     &mut self,
+    id: NodeId,
     dish: T,
-    g0_or_parent_p_ws: Either<f64, &Vec<f64>>,
-    d_array: &mut Vec<f64>,
-    theta_array: &mut Vec<f64>,
+    params: &mut SeatingParameters,
     table_index_in_root: &mut usize,
+    rng: &mut R,
   ) -> bool {
-    self._add_customer_to_new_table(dish);
+    self.nodes[id]._add_customer_to_new_table(dish);
 
-    match self.parent {
+    match self.nodes[id].parent {
       None => return true,
-      Some(p) => unsafe {
-        let success = (*p).add_customer(
-          dish,
-          g0_or_parent_p_ws,
-          d_array,
-          theta_array,
-          false,
-          table_index_in_root,
-        );
+      Some(parent_id) => {
+        let success = self.add_customer(parent_id, dish, params, false, table_index_in_root, rng);
         assert!(success == true);
         return success;
-      },
-    }
-  }
-
-  pub fn _add_customer_to_new_table(&mut self, dish: T) {
-    match self.tablegroups.entry(dish) {
-      Entry::Vacant(e) => {
-        e.insert(vec![1]);
-      }
-      Entry::Occupied(mut e) => {
-        e.get_mut().push(1);
       }
     }
-
-    self.ntables += 1;
-    self.ncustomers += 1;
   }
 
-  pub fn remove_customer_from_table(
+  pub fn remove_customer_from_table<R: Rng>(
     &mut self,
+    id: NodeId,
     dish: T,
     table_index: usize,
     table_index_in_root: &mut usize,
+    rng: &mut R,
   ) -> bool {
-    let is_empty = {
-      let mut tablegroup = self.tablegroups.get_mut(&dish).unwrap();
+    let (table_is_empty, parent) = {
+      let node = &mut self.nodes[id];
+      let tablegroup = node.tablegroups.get_mut(&dish).unwrap();
       assert!(table_index < tablegroup.len());
       tablegroup[table_index] -= 1;
-      self.ncustomers -= 1;
-      assert!(tablegroup[table_index] >= 0);
-
-      // If there are no customers anymore at this table, we need to remove this table.
-      if tablegroup[table_index] == 0 {
-        match self.parent {
-          None => {}
-          Some(p) => unsafe {
-            let success = (*p).remove_customer(dish, false, table_index_in_root);
-            assert!(success == true);
-          },
-        }
+      node.ncustomers -= 1;
+      (tablegroup[table_index] == 0, node.parent)
+    };
 
-        tablegroup.remove(table_index);
-        self.ntables -= 1;
+    // If there are no customers anymore at this table, we need to remove this table.
+    if table_is_empty {
+      match parent {
+        None => {}
+        Some(parent_id) => {
+          let success = self.remove_customer(parent_id, dish, false, table_index_in_root, rng);
+          assert!(success == true);
+        }
       }
 
-      tablegroup.is_empty()
-    };
+      let node = &mut self.nodes[id];
+      let tablegroup_is_empty = {
+        let tablegroup = node.tablegroups.get_mut(&dish).unwrap();
+        tablegroup.remove(table_index);
+        tablegroup.is_empty()
+      };
+      node.ntables -= 1;
 
-    if is_empty {
-      // Does this work if I'm still borrowing the value? I guess so since the borrowed value only goes out scope when the function itself finishes. Let's see then.
-      self.tablegroups.remove(&dish);
+      if tablegroup_is_empty {
+        node.tablegroups.remove(&dish);
+      }
     }
 
     return true;
   }
 
-  pub fn add_customer(
+  pub fn add_customer<R: Rng>(
     &mut self,
+    id: NodeId,
     dish: T,
-    g0_or_parent_p_ws: Either<f64, &Vec<f64>>,
-    d_array: &mut Vec<f64>,
-    theta_array: &mut Vec<f64>,
+    params: &mut SeatingParameters,
     update_beta_count: bool,
     index_of_table_in_root: &mut usize,
+    rng: &mut R,
   ) -> bool {
-    init_hyperparameters_at_depth_if_needed(self.depth, d_array, theta_array);
-    let d_u = d_array[self.depth];
-    let theta_u = theta_array[self.depth];
-    let parent_p_w: f64 = match g0_or_parent_p_ws {
-      Left(g0) => match self.parent {
+    let depth = self.nodes[id].depth;
+    init_hyperparameters_at_depth_if_needed(depth, params.d_array, params.theta_array);
+    let d_u = params.d_array[depth];
+    let theta_u = params.theta_array[depth];
+    let parent_p_w: f64 = match params.g0_or_parent_p_ws {
+      Left(g0) => match self.nodes[id].parent {
         None => g0,
-        Some(p) => unsafe { (*p).compute_p_w(dish, g0, d_array, theta_array) },
+        Some(parent_id) => {
+          self.compute_p_w(parent_id, dish, g0, params.d_array, params.theta_array)
+        }
       },
-      Right(parent_p_ws) => parent_p_ws[self.depth],
+      Right(parent_p_ws) => parent_p_ws[depth],
     };
 
-    if !self.tablegroups.contains_key(&dish) {
-      self.add_customer_to_new_table(
-        dish,
-        g0_or_parent_p_ws,
-        d_array,
-        theta_array,
-        index_of_table_in_root,
-      );
+    if !self.nodes[id].tablegroups.contains_key(&dish) {
+      self.add_customer_to_new_table(id, dish, params, index_of_table_in_root, rng);
       if update_beta_count == true {
-        self.increment_stop_count();
+        self.increment_stop_count(id);
       }
       return true;
     } else {
       let mut sum: f64 = 0.0;
       // Apparently we'll have to make a clone here otherwise we'd be borrowing `self` as a whole.
-      let tablegroup = self.tablegroups.get(&dish).unwrap().clone();
+      let tablegroup = self.nodes[id].tablegroups.get(&dish).unwrap().clone();
       for k in 0..tablegroup.len() {
         // Man comparing two floats surely is convoluted...
         let temp: f64 = tablegroup[k] as f64 - d_u;
         sum += temp.max(0.0);
       }
-      let t_u = self.ntables as f64;
+      let t_u = self.nodes[id].ntables as f64;
       sum += (theta_u + d_u * t_u) * parent_p_w;
 
-      let mut rng = rand::thread_rng();
       let normalizer = 1.0 / sum;
       let bernoulli: f64 = rng.gen();
       let mut stack = 0.0;
@@ -248,18 +423,11 @@ where
         let temp: f64 = tablegroup[k] as f64 - d_u;
         stack += temp.max(0.0) * normalizer;
         if bernoulli <= stack {
-          self.add_customer_to_table(
-            dish,
-            k,
-            g0_or_parent_p_ws,
-            d_array,
-            theta_array,
-            index_of_table_in_root,
-          );
+          self.add_customer_to_table(id, dish, k, params, index_of_table_in_root, rng);
           if update_beta_count {
-            self.increment_stop_count();
+            self.increment_stop_count(id);
           }
-          if self.depth == 0 {
+          if depth == 0 {
             *index_of_table_in_root = k;
           }
 
@@ -267,18 +435,12 @@ where
         }
       }
 
-      self.add_customer_to_new_table(
-        dish,
-        g0_or_parent_p_ws,
-        d_array,
-        theta_array,
-        index_of_table_in_root,
-      );
+      self.add_customer_to_new_table(id, dish, params, index_of_table_in_root, rng);
 
       if update_beta_count {
-        self.increment_stop_count();
+        self.increment_stop_count(id);
       }
-      if self.depth == 0 {
+      if depth == 0 {
         *index_of_table_in_root = tablegroup.len();
       }
 
@@ -286,22 +448,23 @@ where
     }
   }
 
-  pub fn remove_customer(
+  pub fn remove_customer<R: Rng>(
     &mut self,
+    id: NodeId,
     dish: T,
     update_beta_count: bool,
     index_of_table_in_root: &mut usize,
+    rng: &mut R,
   ) -> bool {
-    let tablegroup = self.tablegroups.get(&dish).unwrap().clone();
+    let tablegroup = self.nodes[id].tablegroups.get(&dish).unwrap().clone();
 
-    let mut rng = rand::thread_rng();
     let dist = WeightedIndex::new(&tablegroup).unwrap();
-    let index_to_remove = dist.sample(&mut rng);
-    self.remove_customer_from_table(dish, index_to_remove, index_of_table_in_root);
+    let index_to_remove = dist.sample(rng);
+    self.remove_customer_from_table(id, dish, index_to_remove, index_of_table_in_root, rng);
     if update_beta_count == true {
-      self.decrement_stop_count();
+      self.decrement_stop_count(id);
     }
-    if self.depth == 0 {
+    if self.nodes[id].depth == 0 {
       *index_of_table_in_root = index_to_remove;
     }
 
@@ -330,159 +493,125 @@ where
   }
 
   pub fn compute_p_w(
-    &mut self,
+    &self,
+    id: NodeId,
     dish: T,
     g_0: f64,
-    d_array: &mut Vec<f64>,
-    theta_array: &mut Vec<f64>,
-  ) -> f64 {
-    init_hyperparameters_at_depth_if_needed(self.depth, d_array, theta_array);
-    let d_u = d_array[self.depth];
-    let theta_u = theta_array[self.depth];
-    let t_u = self.ntables as f64;
-    let c_u = self.ncustomers as f64;
-    match self.tablegroups.entry(dish) {
-      Entry::Vacant(_) => {
-        let coeff: f64 = (theta_u + d_u * t_u) / (theta_u + c_u);
-        match self.parent {
-          None => return g_0 * coeff,
-          Some(p) => unsafe { return (*p).compute_p_w(dish, g_0, d_array, theta_array) * coeff },
-        }
-      }
-      Entry::Occupied(e) => {
-        let parent_p_w = match self.parent {
-          None => g_0,
-          Some(p) => unsafe { (*p).compute_p_w(dish, g_0, d_array, theta_array) },
-        };
-        let tablegroup = e.get();
-        let c_uw: usize = tablegroup.into_iter().sum();
-        let t_uw = tablegroup.len() as f64;
-        let first_term: f64 = (c_uw as f64 - d_u * t_uw).max(0.0) / (theta_u + c_u);
-        let second_coeff: f64 = (theta_u + d_u * t_u) / (theta_u + c_u);
-        return first_term + second_coeff * parent_p_w;
-      }
-    }
-  }
-
-  pub fn compute_p_w_with_parent_p_w(
-    &mut self,
-    dish: T,
-    parent_p_w: f64,
-    d_array: &mut Vec<f64>,
-    theta_array: &mut Vec<f64>,
+    d_array: &[f64],
+    theta_array: &[f64],
   ) -> f64 {
-    init_hyperparameters_at_depth_if_needed(self.depth, d_array, theta_array);
-    let d_u = d_array[self.depth];
-    let theta_u = theta_array[self.depth];
-    let t_u = self.ntables as f64;
-    let c_u = self.ncustomers as f64;
-    match self.tablegroups.entry(dish) {
-      Entry::Vacant(_) => {
-        let coeff: f64 = (theta_u + d_u * t_u) / (theta_u + c_u);
-        return parent_p_w * coeff;
-      }
-      Entry::Occupied(e) => {
-        let tablegroup = e.get();
-        let c_uw: usize = tablegroup.into_iter().sum();
-        let t_uw = tablegroup.len() as f64;
-        let first_term: f64 = (c_uw as f64 - d_u * t_uw).max(0.0) / (theta_u + c_u);
-        let second_coeff: f64 = (theta_u + d_u * t_u) / (theta_u + c_u);
-        return first_term + second_coeff * parent_p_w;
-      }
-    }
+    let node = &self.nodes[id];
+    let parent_p_w = match node.parent {
+      None => g_0,
+      Some(parent_id) => self.compute_p_w(parent_id, dish, g_0, d_array, theta_array),
+    };
+    node.compute_p_w_with_parent_p_w(dish, parent_p_w, d_array, theta_array)
   }
 
   /* The following methods are specifically related to the character variant of PYP */
 
-  pub fn stop_probability(&self, beta_stop: f64, beta_pass: f64, recursive: bool) -> f64 {
-    let prob = (self.stop_count as f64 + beta_stop)
-      / (self.stop_count as f64 + self.pass_count as f64 + beta_stop + beta_pass);
+  pub fn stop_probability(
+    &self,
+    id: NodeId,
+    beta_stop: f64,
+    beta_pass: f64,
+    recursive: bool,
+  ) -> f64 {
+    let node = &self.nodes[id];
+    let prob = (node.stop_count as f64 + beta_stop)
+      / (node.stop_count as f64 + node.pass_count as f64 + beta_stop + beta_pass);
     if !recursive {
       return prob;
     } else {
-      match self.parent {
+      match node.parent {
         None => return prob,
-        Some(p) => unsafe {
-          return prob * (*p).pass_probability(beta_stop, beta_pass, recursive);
-        },
+        Some(parent_id) => {
+          return prob * self.pass_probability(parent_id, beta_stop, beta_pass, recursive);
+        }
       }
     }
   }
 
-  pub fn pass_probability(&self, beta_stop: f64, beta_pass: f64, recursive: bool) -> f64 {
-    let prob = (self.stop_count as f64 + beta_pass)
-      / (self.stop_count as f64 + self.pass_count as f64 + beta_stop + beta_pass);
+  pub fn pass_probability(
+    &self,
+    id: NodeId,
+    beta_stop: f64,
+    beta_pass: f64,
+    recursive: bool,
+  ) -> f64 {
+    let node = &self.nodes[id];
+    let prob = (node.stop_count as f64 + beta_pass)
+      / (node.stop_count as f64 + node.pass_count as f64 + beta_stop + beta_pass);
     if !recursive {
       return prob;
     } else {
-      match self.parent {
+      match node.parent {
         None => return prob,
-        Some(p) => unsafe {
-          return prob * (*p).pass_probability(beta_stop, beta_pass, recursive);
-        },
+        Some(parent_id) => {
+          return prob * self.pass_probability(parent_id, beta_stop, beta_pass, recursive);
+        }
       }
     }
   }
 
-  pub fn increment_stop_count(&mut self) {
-    self.stop_count += 1;
-    match self.parent {
+  pub fn increment_stop_count(&mut self, id: NodeId) {
+    self.nodes[id].stop_count += 1;
+    match self.nodes[id].parent {
       None => {}
-      Some(p) => unsafe { (*p).increment_pass_count() },
+      Some(parent_id) => self.increment_pass_count(parent_id),
     }
   }
 
-  pub fn decrement_stop_count(&mut self) {
-    self.stop_count -= 1;
-    match self.parent {
+  pub fn decrement_stop_count(&mut self, id: NodeId) {
+    self.nodes[id].stop_count -= 1;
+    match self.nodes[id].parent {
       None => {}
-      Some(p) => unsafe { (*p).decrement_pass_count() },
+      Some(parent_id) => self.decrement_pass_count(parent_id),
     }
   }
 
-  pub fn increment_pass_count(&mut self) {
-    self.pass_count += 1;
-    match self.parent {
+  pub fn increment_pass_count(&mut self, id: NodeId) {
+    self.nodes[id].pass_count += 1;
+    match self.nodes[id].parent {
       None => {}
-      Some(p) => unsafe { (*p).increment_pass_count() },
+      Some(parent_id) => self.increment_pass_count(parent_id),
     }
   }
 
-  pub fn decrement_pass_count(&mut self) {
-    println!("Pass count {}", self.pass_count);
-    self.pass_count -= 1;
-    match self.parent {
+  pub fn decrement_pass_count(&mut self, id: NodeId) {
+    self.nodes[id].pass_count -= 1;
+    match self.nodes[id].parent {
       None => {}
-      Some(p) => unsafe { (*p).decrement_pass_count() },
+      Some(parent_id) => self.decrement_pass_count(parent_id),
     }
   }
 
-  pub fn remove_from_parent(&self) -> bool {
-    match self.parent {
+  pub fn remove_from_parent(&mut self, id: NodeId) -> bool {
+    match self.nodes[id].parent {
       None => false,
-      Some(p) => unsafe {
-        (*p).delete_child_node(self.context);
+      Some(parent_id) => {
+        let context = self.nodes[id].context;
+        self.delete_child_node(parent_id, context);
         true
-      },
+      }
     }
   }
 
-  pub fn delete_child_node(&mut self, dish: T) {
-    // let child = { self.find_child_pyp(dish, false) };
-    // Yeah this is great. Think through the perspectives then. Why you need the variables in the first place, etc.
-    // The thing about Rust then is that you won't need manual memory management. In C++ you'd need to write a line `delete child`, which here is not necessary. Thus the system like this.
-    let has_this_child = { self.find_child_pyp(dish, false).is_some() };
-    if has_this_child {
-      self.children.remove(&dish);
-    } else if self.children.len() == 0 && self.tablegroups.len() == 0 {
-      self.remove_from_parent();
+  pub fn delete_child_node(&mut self, id: NodeId, dish: T) {
+    match self.nodes[id].children.remove(&dish) {
+      Some(child_id) => self.free_subtree(child_id),
+      None => {
+        if self.nodes[id].children.len() == 0 && self.nodes[id].tablegroups.len() == 0 {
+          self.remove_from_parent(id);
+        }
+      }
     }
   }
 
-  pub fn get_max_depth(&self, base: usize) -> usize {
+  pub fn get_max_depth(&self, id: NodeId, base: usize) -> usize {
     let mut max_depth = base;
-    for child in self.children.values() {
-      let depth = child.get_max_depth(base + 1);
+    for &child_id in self.nodes[id].children.values() {
+      let depth = self.get_max_depth(child_id, base + 1);
       if depth > max_depth {
         max_depth = depth;
       }
@@ -490,115 +619,43 @@ where
     max_depth
   }
 
-  pub fn get_num_nodes(&self) -> usize {
-    let mut count = self.children.len();
-    for child in self.children.values() {
-      count += child.get_num_nodes();
+  pub fn get_num_nodes(&self, id: NodeId) -> usize {
+    let mut count = self.nodes[id].children.len();
+    for &child_id in self.nodes[id].children.values() {
+      count += self.get_num_nodes(child_id);
     }
     count
   }
 
-  pub fn get_num_tables(&self) -> usize {
-    let mut count = self.ntables;
-    // for tablegroup in self.tablegroups.values() {
-    //   count += tablegroup.len();
-    // }
-    for child in self.children.values() {
-      count += child.get_num_tables();
+  pub fn get_num_tables(&self, id: NodeId) -> usize {
+    let mut count = self.nodes[id].ntables;
+    for &child_id in self.nodes[id].children.values() {
+      count += self.get_num_tables(child_id);
     }
     count
   }
 
-  pub fn get_num_customers(&self) -> usize {
-    let mut count = self.ncustomers;
-    for child in self.children.values() {
-      count += child.get_num_customers();
+  pub fn get_num_customers(&self, id: NodeId) -> usize {
+    let mut count = self.nodes[id].ncustomers;
+    for &child_id in self.nodes[id].children.values() {
+      count += self.get_num_customers(child_id);
     }
     count
   }
 
-  pub fn get_pass_counts(&self) -> usize {
-    let mut count = self.pass_count;
-    for child in self.children.values() {
-      count += child.get_pass_counts();
+  pub fn get_pass_counts(&self, id: NodeId) -> usize {
+    let mut count = self.nodes[id].pass_count;
+    for &child_id in self.nodes[id].children.values() {
+      count += self.get_pass_counts(child_id);
     }
     count
   }
 
-  pub fn get_stop_counts(&self) -> usize {
-    let mut count = self.stop_count;
-    for child in self.children.values() {
-      count += child.get_stop_counts();
+  pub fn get_stop_counts(&self, id: NodeId) -> usize {
+    let mut count = self.nodes[id].stop_count;
+    for &child_id in self.nodes[id].children.values() {
+      count += self.get_stop_counts(child_id);
     }
     count
   }
-
-  // Don't think this is actually used.
-  // pub fn get_all_pyps_at_depth(&self, depth: usize, acc: &mut Vec<&PYP<T>>) {
-  //   if self.depth == depth {
-  //     acc.push(self);
-  //   }
-  // }
-
-  /* The functions below are related to hyperparameter (d, θ) sampling, based on the algorithm given in the Teh Technical Report
-
-  There are 3 auxiliary variables defined, x_**u**, y_**u**i, z**u**wkj.
-
-  The following methods sample them. */
-
-  pub fn sample_log_x_u(&self, theta_u: f64) -> f64 {
-    if self.ncustomers >= 2 {
-      let dist = Beta::new(theta_u + 1.0, self.ncustomers as f64 - 1.0);
-      // Prevent underflow.
-      let sample = dist.sample(&mut rand::thread_rng()) + 1e-8;
-      return sample.ln();
-    } else {
-      return 0.0;
-    }
-  }
-
-  pub fn sample_summed_y_ui(&self, d_u: f64, theta_u: f64, is_one_minus: bool) -> f64 {
-    if self.ntables >= 2 {
-      let mut sum = 0;
-      for i in 1..self.ntables - 1 {
-        let denom = theta_u + d_u * i as f64;
-        assert!(denom > 0.0);
-        let prob = theta_u / denom;
-        let dist = Bernoulli::new(prob);
-        let mut y_ui = dist.sample(&mut rand::thread_rng());
-        if is_one_minus {
-          y_ui = !y_ui;
-        }
-        match y_ui {
-          true => sum += 1,
-          false => sum += 0,
-        }
-      }
-      return sum as f64;
-    } else {
-      return 0.0;
-    }
-  }
-
-  pub fn sample_summed_one_minus_z_uwkj(&self, d_u: f64) -> f64 {
-    let mut sum = 0;
-    for tablegroup in self.tablegroups.values() {
-      for customercount in tablegroup {
-        if customercount >= &2 {
-          for j in 1..customercount - 1 {
-            assert!(j as f64 - d_u > 0.0);
-            let prob = (j - 1) as f64 / (j as f64 - d_u);
-            let dist = Bernoulli::new(prob);
-            let result = if dist.sample(&mut rand::thread_rng()) {
-              1
-            } else {
-              0
-            };
-            sum += 1 - result;
-          }
-        }
-      }
-    }
-    return sum as f64;
-  }
 }
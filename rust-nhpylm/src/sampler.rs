@@ -1,29 +1,85 @@
 use ndarray::{Array2, Array3, Array4};
 
 use def::*;
+use error::*;
 use npylm::*;
 use rand::Rng;
 use sentence::*;
+use std::cmp::Ordering;
 
+/// The size of the last dimension of the forward variables, which indexes the length of the word two positions back. The bigram model never looks that far, so it only keeps index 0 there and its tensors shrink by a factor of the maximum word length.
+fn second_context_size(whpylm_order: usize, max_word_length: usize) -> usize {
+    if whpylm_order == 2 {
+        1
+    } else {
+        max_word_length + 1
+    }
+}
+
+/// Raises a probability to the power of `exponent`, which is the inverse of the sampling temperature.
+fn anneal(p: f64, exponent: f64) -> f64 {
+    if exponent == 1.0 {
+        p
+    } else {
+        p.powf(exponent)
+    }
+}
+
+/// A partial path of the N-best search. It ends with the words of lengths k and j whose forward state holds it, and continues with the `rank`-th best partial path of the state that ends with the words of lengths j and `previous_length`.
+#[derive(Clone)]
+struct NBestEntry {
+    log_p: f64,
+    previous_length: usize,
+    rank: usize,
+}
+
+/// Sorts the entries from the most to the least probable. A NaN log probability can't be ranked, so it is reported instead.
+fn sort_by_log_p_descending<T, F: Fn(&T) -> f64>(entries: &mut [T], log_p: F) -> Result<()> {
+    if let Some(entry) = entries.iter().find(|entry| log_p(entry).is_nan()) {
+        return Err(Error::NumericalUnderflow(format!(
+            "The N-best search came across the log probability {}",
+            log_p(entry)
+        )));
+    }
+    entries.sort_by(|a, b| log_p(b).partial_cmp(&log_p(a)).unwrap_or(Ordering::Equal));
+    Ok(())
+}
+
+/// The lattice of the segmentations of a sentence and the other working memory for sampling and decoding them. The NPYLM is passed to every method instead, so that several samplers can work with the same model at once.
 pub struct Sampler {
-    pub npylm: NPYLM,
+    whpylm_order: usize,
+    cache: ProbabilityCache,
     word_ids: Vec<u64>,
     substring_word_id_cache: Array2<u64>,
     alpha_tensor: Array3<f64>,
+    // The backward variables matching `alpha_tensor`: the probability of the rest of the sentence after position t, given that the last two words end there with lengths k and j. Scaled with the same coefficients.
+    beta_tensor: Array3<f64>,
     p_w_h_cache: Array4<f64>,
     log_z: Vec<f64>,
     scaling_coefficients: Vec<f64>,
     backward_sampling_table: Vec<f64>,
     viterbi_backward_indices: Array3<usize>,
+    // The longest word that may end at each position of the current sentence. This is `max_word_length` unless a boundary of the sentence comes closer.
+    max_word_length_ending_at: Vec<usize>,
+    // Whether the constraints of the current sentence rule out the word of length k ending at position t. The forward filtering gives such a word probability 0 by leaving it out of the lattice altogether.
+    word_forbidden: Array2<bool>,
+    // Whether a position of the current sentence lies strictly inside a required word, so that no word may begin or end there.
+    is_inside_required_word: Vec<bool>,
     max_word_length: usize,
     max_sentence_length: usize,
+    // The temperature at which `blocked_gibbs_segment` samples, see `set_temperature`.
+    temperature: f64,
+    // The power to which the word probabilities of the current forward variables are raised. The backward sampling has to use the same one.
+    exponent: f64,
 }
 
 impl Sampler {
-    pub fn new(npylm: NPYLM, max_word_length: usize, max_sentence_length: usize) -> Self {
+    pub fn new(npylm: &NPYLM, max_word_length: usize, max_sentence_length: usize) -> Self {
         let size = max_sentence_length + 1;
+        let context_size = second_context_size(npylm.whpylm.order, max_word_length);
         Self {
-            npylm: npylm,
+            whpylm_order: npylm.whpylm.order,
+            cache: ProbabilityCache::new(),
             word_ids: vec![0; 3],
             max_word_length: max_word_length,
             max_sentence_length: max_sentence_length,
@@ -33,20 +89,39 @@ impl Sampler {
             viterbi_backward_indices: Array3::zeros((
                 max_sentence_length + 1,
                 max_word_length + 1,
-                max_word_length + 1,
+                context_size,
             )),
             backward_sampling_table: vec![0.0; max_word_length * max_word_length],
-            alpha_tensor: Array3::zeros((size + 1, max_word_length + 1, max_word_length + 1)),
+            alpha_tensor: Array3::zeros((size + 1, max_word_length + 1, context_size)),
+            beta_tensor: Array3::zeros((size + 1, max_word_length + 1, context_size)),
             p_w_h_cache: Array4::zeros((
                 max_sentence_length + 1,
                 max_word_length + 1,
                 max_word_length + 1,
-                max_word_length + 1,
+                context_size,
             )),
             substring_word_id_cache: Array2::zeros((max_sentence_length + 1, max_word_length + 1)),
+            max_word_length_ending_at: vec![0; max_sentence_length + 1],
+            word_forbidden: Array2::from_elem(
+                (max_sentence_length + 1, max_word_length + 1),
+                false,
+            ),
+            is_inside_required_word: vec![false; max_sentence_length + 1],
+            temperature: 1.0,
+            exponent: 1.0,
         }
     }
 
+    /// Makes `blocked_gibbs_segment` sample the segmentation from the posterior raised to the power of 1/T, where T is the temperature. A temperature above 1 flattens the distribution, which lets the sampler escape from poor segmentations early in the training. Everything else, e.g. the likelihood, the Viterbi decoding and `sample_segmentations`, always uses the plain model.
+    pub fn set_temperature(&mut self, temperature: f64) {
+        assert!(temperature > 0.0);
+        self.temperature = temperature;
+    }
+
+    pub fn get_temperature(&self) -> f64 {
+        self.temperature
+    }
+
     pub fn extend_capacity(&mut self, max_word_length: usize, max_sentence_length: usize) {
         if max_word_length <= self.max_word_length
             && max_sentence_length <= self.max_sentence_length
@@ -59,135 +134,254 @@ impl Sampler {
 
     fn allocate_capacity(&mut self, max_word_length: usize, max_sentence_length: usize) {
         let size = max_sentence_length + 1;
+        let context_size = second_context_size(self.whpylm_order, max_word_length);
         self.max_word_length = max_word_length;
         self.max_sentence_length = max_sentence_length;
         // TODO = Not sure if using 0.0 instead of something like undefined is the right choice. Let's see.
         self.log_z = vec![0.0; max_sentence_length + 1];
         self.scaling_coefficients = vec![0.0; size + 1];
-        self.viterbi_backward_indices = Array3::zeros((
-            max_sentence_length + 1,
-            max_word_length + 1,
-            max_word_length + 1,
-        ));
+        self.viterbi_backward_indices =
+            Array3::zeros((max_sentence_length + 1, max_word_length + 1, context_size));
         self.backward_sampling_table = vec![0.0; max_word_length * max_word_length];
-        self.alpha_tensor = Array3::zeros((size + 1, max_word_length + 1, max_word_length + 1));
+        self.alpha_tensor = Array3::zeros((size + 1, max_word_length + 1, context_size));
+        self.beta_tensor = Array3::zeros((size + 1, max_word_length + 1, context_size));
         self.p_w_h_cache = Array4::zeros((
             max_sentence_length + 1,
             max_word_length + 1,
             max_word_length + 1,
-            max_word_length + 1,
+            context_size,
         ));
         self.substring_word_id_cache =
             Array2::zeros((max_sentence_length + 1, max_word_length + 1));
+        self.max_word_length_ending_at = vec![0; max_sentence_length + 1];
+        self.word_forbidden =
+            Array2::from_elem((max_sentence_length + 1, max_word_length + 1), false);
+        self.is_inside_required_word = vec![false; max_sentence_length + 1];
+    }
+
+    /// Builds the lattice of the words the current sentence may be segmented into according to its constraints. Required words that are impossible under the other constraints are dropped, which leaves the segmentation into single characters outside of the required words always possible.
+    fn set_constraints(&mut self, sentence: &Sentence) {
+        let sentence_length = sentence.length();
+        let constraints = &sentence.constraints;
+        let mut is_boundary = vec![false; sentence_length + 1];
+        for &boundary in &constraints.boundaries {
+            is_boundary[boundary] = true;
+        }
+        for t in 0..sentence_length + 1 {
+            self.is_inside_required_word[t] = false;
+        }
+        let mut last_word_end = 0;
+        for &(begin, end) in &constraints.words {
+            if begin < last_word_end
+                || end - begin > self.max_word_length
+                || is_boundary[begin + 1..end].iter().any(|&b| b)
+                || constraints.forbidden_words.contains(&(begin, end))
+            {
+                continue;
+            }
+            is_boundary[begin] = true;
+            is_boundary[end] = true;
+            for position in begin + 1..end {
+                self.is_inside_required_word[position] = true;
+            }
+            last_word_end = end;
+        }
+
+        let mut last_boundary = 0;
+        for (t, &boundary) in is_boundary.iter().enumerate() {
+            self.max_word_length_ending_at[t] = (t - last_boundary).min(self.max_word_length);
+            for k in 0..self.max_word_length + 1 {
+                self.word_forbidden[[t, k]] = k > 0
+                    && k <= t
+                    && (self.is_inside_required_word[t] || self.is_inside_required_word[t - k]);
+            }
+            if boundary {
+                last_boundary = t;
+            }
+        }
+        for &(begin, end) in &constraints.forbidden_words {
+            if end - begin > 1 && end - begin <= self.max_word_length {
+                self.word_forbidden[[end, end - begin]] = true;
+            }
+        }
     }
 
-    fn get_substring_word_id_at_t_k(&mut self, sentence: &Sentence, t: usize, k: usize) -> u64 {
+    /// Prepares the IDs of the substrings for a new sentence.
+    fn clear_substring_word_ids(&mut self, sentence: &Sentence) {
+        for t in 0..sentence.length() + 1 {
+            for k in 0..self.max_word_length + 1 {
+                self.substring_word_id_cache[[t, k]] = 0;
+            }
+        }
+        self.cache.clear_transient();
+    }
+
+    fn get_substring_word_id_at_t_k(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+        t: usize,
+        k: usize,
+    ) -> u64 {
         let mut word_id = self.substring_word_id_cache[[t, k]];
         if word_id == 0 {
-            word_id = sentence.get_substr_word_id(t - k, t - 1);
+            word_id = sentence.get_substr_word_id(t - k, t - 1, &npylm.interner);
             self.substring_word_id_cache[[t, k]] = word_id;
         }
         word_id
     }
 
-    fn forward_filtering(&mut self, sentence: &Sentence, with_scaling: bool) {
+    fn forward_filtering(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+        with_scaling: bool,
+        exponent: f64,
+    ) -> Result<()> {
+        self.exponent = exponent;
+        if npylm.whpylm.order == 2 {
+            return self.bigram_forward_filtering(npylm, sentence, with_scaling);
+        }
         self.alpha_tensor[[0, 0, 0]] = 1.0;
         for t in 1..sentence.length() + 1 {
             let mut prod_scaling = 1.0;
-            for k in 1..t.min(self.max_word_length) + 1 {
+            for k in 1..self.max_word_length_ending_at[t] + 1 {
                 if with_scaling && k > 1 {
                     prod_scaling *= self.scaling_coefficients[t - k + 1];
                 }
+                if self.word_forbidden[[t, k]] {
+                    continue;
+                }
 
-                for j in if t == k { 0 } else { 1 }..(t - k).min(self.max_word_length) + 1 {
+                for j in if t == k { 0 } else { 1 }..self.max_word_length_ending_at[t - k] + 1 {
+                    if self.word_forbidden[[t - k, j]] {
+                        continue;
+                    }
                     self.alpha_tensor[[t, k, j]] = 0.0;
-                    self.calculate_alpha_t_k_j(sentence, t, k, j, prod_scaling);
+                    self.calculate_alpha_t_k_j(npylm, sentence, t, k, j, prod_scaling)?;
                 }
             }
 
-            if with_scaling {
+            // Inside a required word, no word ends at all, and there is nothing to scale.
+            if with_scaling && self.is_inside_required_word[t] {
+                self.scaling_coefficients[t] = 1.0;
+            } else if with_scaling {
                 let mut sum_alpha = 0.0;
-                for k in 1..t.min(self.max_word_length) + 1 {
-                    for j in if t == k { 0 } else { 1 }..(t - k).min(self.max_word_length) + 1 {
+                for k in 1..self.max_word_length_ending_at[t] + 1 {
+                    if self.word_forbidden[[t, k]] {
+                        continue;
+                    }
+                    for j in if t == k { 0 } else { 1 }..self.max_word_length_ending_at[t - k] + 1 {
+                        if self.word_forbidden[[t - k, j]] {
+                            continue;
+                        }
                         sum_alpha += self.alpha_tensor[[t, k, j]];
                     }
                 }
 
-                assert!(sum_alpha > 0.0);
+                if !(sum_alpha > 0.0 && sum_alpha.is_finite()) {
+                    return Err(Error::NumericalUnderflow(format!(
+                        "The forward variables at position {} sum up to {}",
+                        t, sum_alpha
+                    )));
+                }
                 self.scaling_coefficients[t] = 1.0 / sum_alpha;
 
-                for k in 1..t.min(self.max_word_length) + 1 {
-                    for j in if t == k { 0 } else { 1 }..(t - k).min(self.max_word_length) + 1 {
+                for k in 1..self.max_word_length_ending_at[t] + 1 {
+                    if self.word_forbidden[[t, k]] {
+                        continue;
+                    }
+                    for j in if t == k { 0 } else { 1 }..self.max_word_length_ending_at[t - k] + 1 {
+                        if self.word_forbidden[[t - k, j]] {
+                            continue;
+                        }
                         assert!(self.alpha_tensor[[t, k, j]] > 0.0);
                         self.alpha_tensor[[t, k, j]] *= self.scaling_coefficients[t];
                     }
                 }
             }
         }
+        Ok(())
     }
 
     fn calculate_alpha_t_k_j(
         &mut self,
+        npylm: &NPYLM,
         sentence: &Sentence,
         t: usize,
         k: usize,
         j: usize,
         prod_scaling: f64,
-    ) {
+    ) -> Result<()> {
         assert!(t <= self.max_sentence_length + 1);
         assert!(k <= self.max_word_length);
         assert!(j <= self.max_word_length);
         assert!(t - k >= 0);
-        let word_k_id = self.get_substring_word_id_at_t_k(sentence, t, k);
+        let word_k_id = self.get_substring_word_id_at_t_k(npylm, sentence, t, k);
         // let sentence_as_chars = &sentence.characters;
 
         if j == 0 {
             self.word_ids[0] = BOS;
             self.word_ids[1] = BOS;
             self.word_ids[2] = word_k_id;
-            let p_w_h = self.npylm.compute_p_w_of_nth_word_as_chars(
-                &sentence.characters,
-                &self.word_ids,
-                2,
-                t - k,
-                t - 1,
+            let p_w_h = anneal(
+                npylm.compute_p_w_of_nth_word_as_chars(
+                    &mut self.cache,
+                    &sentence.characters,
+                    &self.word_ids,
+                    2,
+                    t - k,
+                    t - 1,
+                )?,
+                self.exponent,
             );
             assert!(p_w_h > 0.0);
             self.alpha_tensor[[t, k, 0]] = p_w_h * prod_scaling;
             self.p_w_h_cache[[t, k, 0, 0]] = p_w_h;
-            return;
+            return Ok(());
         } else if t - k - j == 0 {
-            let word_j_id = self.get_substring_word_id_at_t_k(sentence, t - k, j);
+            let word_j_id = self.get_substring_word_id_at_t_k(npylm, sentence, t - k, j);
             self.word_ids[0] = BOS;
             self.word_ids[1] = word_j_id;
             self.word_ids[2] = word_k_id;
-            let p_w_h = self.npylm.compute_p_w_of_nth_word_as_chars(
-                &sentence.characters,
-                &self.word_ids,
-                2,
-                t - k,
-                t - 1,
+            let p_w_h = anneal(
+                npylm.compute_p_w_of_nth_word_as_chars(
+                    &mut self.cache,
+                    &sentence.characters,
+                    &self.word_ids,
+                    2,
+                    t - k,
+                    t - 1,
+                )?,
+                self.exponent,
             );
             assert!(p_w_h > 0.0);
             assert!(self.alpha_tensor[[t - k, j, 0]] > 0.0);
             self.alpha_tensor[[t, k, j]] = p_w_h * self.alpha_tensor[[t - k, j, 0]] * prod_scaling;
             self.p_w_h_cache[[t, k, j, 0]] = p_w_h;
-            return;
+            return Ok(());
         } else {
             let mut sum = 0.0;
-            for i in 1..self.max_word_length.min(t - k - j) + 1 {
-                let word_i_id = self.get_substring_word_id_at_t_k(sentence, t - k - j, i);
-                let word_j_id = self.get_substring_word_id_at_t_k(sentence, t - k, j);
+            for i in 1..self.max_word_length_ending_at[t - k - j] + 1 {
+                if self.word_forbidden[[t - k - j, i]] {
+                    continue;
+                }
+                let word_i_id = self.get_substring_word_id_at_t_k(npylm, sentence, t - k - j, i);
+                let word_j_id = self.get_substring_word_id_at_t_k(npylm, sentence, t - k, j);
                 self.word_ids[0] = word_i_id;
                 self.word_ids[1] = word_j_id;
                 self.word_ids[2] = word_k_id;
 
-                let p_w_h = self.npylm.compute_p_w_of_nth_word_as_chars(
-                    &sentence.characters,
-                    &self.word_ids,
-                    2,
-                    t - k,
-                    t - 1,
+                let p_w_h = anneal(
+                    npylm.compute_p_w_of_nth_word_as_chars(
+                        &mut self.cache,
+                        &sentence.characters,
+                        &self.word_ids,
+                        2,
+                        t - k,
+                        t - 1,
+                    )?,
+                    self.exponent,
                 );
                 assert!(p_w_h > 0.0);
                 assert!(i <= self.max_word_length);
@@ -198,21 +392,27 @@ impl Sampler {
 
             assert!(sum > 0.0);
             self.alpha_tensor[[t, k, j]] = sum * prod_scaling;
-            return;
+            return Ok(());
         }
     }
 
-    fn backward_sampling(&mut self, sentence: &Sentence) -> Vec<usize> {
+    fn backward_sampling<R: Rng>(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+        rng: &mut R,
+    ) -> Result<Vec<usize>> {
+        if npylm.whpylm.order == 2 {
+            return self.bigram_backward_sampling(npylm, sentence, rng);
+        }
         let mut t = sentence.length();
-        let mut k = 0;
-        let mut j = 0;
         let mut sum_length = 0;
-        self.backward_sample_k_and_j(sentence, t, 1, &mut k, &mut j);
+        let (mut k, mut j) = self.backward_sample_k_and_j(npylm, sentence, t, 1, rng)?;
 
         let mut segment_lengths: Vec<usize> = Vec::new();
         segment_lengths.push(k);
         if j == 0 && k == t {
-            return segment_lengths;
+            return Ok(segment_lengths);
         }
 
         assert!(k > 0 && j > 0);
@@ -228,7 +428,10 @@ impl Sampler {
                 k = 1;
                 j = 0;
             } else {
-                self.backward_sample_k_and_j(sentence, t, next_word_length, &mut k, &mut j);
+                let (sampled_k, sampled_j) =
+                    self.backward_sample_k_and_j(npylm, sentence, t, next_word_length, rng)?;
+                k = sampled_k;
+                j = sampled_j;
             }
             segment_lengths.push(k);
             t -= k;
@@ -247,29 +450,36 @@ impl Sampler {
         assert!(sum_length == sentence.length());
 
         segment_lengths.reverse();
-        return segment_lengths;
+        return Ok(segment_lengths);
     }
 
-    fn backward_sample_k_and_j(
+    fn backward_sample_k_and_j<R: Rng>(
         &mut self,
+        npylm: &NPYLM,
         sentence: &Sentence,
         t: usize,
         third_gram_length: usize,
-        sampled_k: &mut usize,
-        sampled_j: &mut usize,
-    ) {
+        rng: &mut R,
+    ) -> Result<(usize, usize)> {
         let mut table_index = 0;
         let sentence_length = sentence.length();
         let mut sum_p = 0.0;
-        for k in 1..t.min(self.max_word_length) + 1 {
-            for j in 1..(t - k).min(self.max_word_length) + 1 {
-                let mut word_j_id = self.get_substring_word_id_at_t_k(sentence, t - k, j);
-                let mut word_k_id = self.get_substring_word_id_at_t_k(sentence, t, k);
+        for k in 1..self.max_word_length_ending_at[t] + 1 {
+            if self.word_forbidden[[t, k]] {
+                continue;
+            }
+            for j in 1..self.max_word_length_ending_at[t - k] + 1 {
+                if self.word_forbidden[[t - k, j]] {
+                    continue;
+                }
+                let mut word_j_id = self.get_substring_word_id_at_t_k(npylm, sentence, t - k, j);
+                let mut word_k_id = self.get_substring_word_id_at_t_k(npylm, sentence, t, k);
                 let mut word_t_id = EOS;
                 if t < sentence_length {
                     assert!(t + third_gram_length <= sentence_length);
                     assert!(third_gram_length > 0);
                     word_t_id = self.get_substring_word_id_at_t_k(
+                        npylm,
                         sentence,
                         t + third_gram_length,
                         third_gram_length,
@@ -279,12 +489,16 @@ impl Sampler {
                 self.word_ids[1] = word_k_id;
                 self.word_ids[2] = word_t_id;
                 let mut p_w_h = if t == sentence_length {
-                    self.npylm.compute_p_w_of_nth_word_as_chars(
-                        &sentence.characters,
-                        &self.word_ids,
-                        2,
-                        t,
-                        t,
+                    anneal(
+                        npylm.compute_p_w_of_nth_word_as_chars(
+                            &mut self.cache,
+                            &sentence.characters,
+                            &self.word_ids,
+                            2,
+                            t,
+                            t,
+                        )?,
+                        self.exponent,
                     )
                 } else {
                     self.p_w_h_cache[[t + third_gram_length, third_gram_length, k, j]]
@@ -301,12 +515,13 @@ impl Sampler {
             if t == k {
                 let mut j = 0;
                 let mut word_j_id = BOS;
-                let mut word_k_id = self.get_substring_word_id_at_t_k(sentence, t, k);
+                let mut word_k_id = self.get_substring_word_id_at_t_k(npylm, sentence, t, k);
                 let mut word_t_id = EOS;
                 if t < sentence_length {
                     assert!(t + third_gram_length <= sentence_length);
                     assert!(third_gram_length > 0);
                     word_t_id = self.get_substring_word_id_at_t_k(
+                        npylm,
                         sentence,
                         t + third_gram_length,
                         third_gram_length,
@@ -316,12 +531,16 @@ impl Sampler {
                 self.word_ids[1] = word_k_id;
                 self.word_ids[2] = word_t_id;
                 let mut p_w_h = if t == sentence_length {
-                    self.npylm.compute_p_w_of_nth_word_as_chars(
-                        &sentence.characters,
-                        &self.word_ids,
-                        2,
-                        t,
-                        t,
+                    anneal(
+                        npylm.compute_p_w_of_nth_word_as_chars(
+                            &mut self.cache,
+                            &sentence.characters,
+                            &self.word_ids,
+                            2,
+                            t,
+                            t,
+                        )?,
+                        self.exponent,
                     )
                 } else {
                     self.p_w_h_cache[[t + third_gram_length, third_gram_length, k, j]]
@@ -339,19 +558,25 @@ impl Sampler {
         assert!(table_index <= self.max_word_length * self.max_word_length);
 
         let normalizer = 1.0 / sum_p;
-        let randnum: f64 = rand::thread_rng().gen();
+        let randnum: f64 = rng.gen();
         let mut index = 0;
         let mut stack = 0.0;
-        for k in 1..t.min(self.max_word_length) + 1 {
-            for j in 1..(t - k).min(self.max_word_length) + 1 {
+        let mut last_allowed = (0, 0);
+        for k in 1..self.max_word_length_ending_at[t] + 1 {
+            if self.word_forbidden[[t, k]] {
+                continue;
+            }
+            for j in 1..self.max_word_length_ending_at[t - k] + 1 {
+                if self.word_forbidden[[t - k, j]] {
+                    continue;
+                }
                 assert!(index < table_index);
                 assert!(self.backward_sampling_table[index] > 0.0);
                 stack += self.backward_sampling_table[index] * normalizer;
                 if randnum < stack {
-                    *sampled_k = k;
-                    *sampled_j = j;
-                    return;
+                    return Ok((k, j));
                 }
+                last_allowed = (k, j);
                 index += 1;
             }
 
@@ -360,91 +585,119 @@ impl Sampler {
                 assert!(self.backward_sampling_table[index] > 0.0);
                 stack += self.backward_sampling_table[index] * normalizer;
                 if randnum < stack {
-                    *sampled_k = k;
-                    *sampled_j = 0;
-                    return;
+                    return Ok((k, 0));
                 }
+                last_allowed = (k, 0);
                 index += 1;
             }
         }
-        print!("Fell through?");
+        // Only reachable through rounding errors in the normalization.
+        Ok(last_allowed)
     }
 
-    pub fn blocked_gibbs_segment(&mut self, sentence: &Sentence, with_scaling: bool) -> Vec<usize> {
-        for i in 0..sentence.length() + 1 {
-            for j in 0..self.max_word_length + 1 {
-                self.substring_word_id_cache[[i, j]] = 0;
-            }
-        }
+    pub fn blocked_gibbs_segment<R: Rng>(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+        with_scaling: bool,
+        rng: &mut R,
+    ) -> Result<Vec<usize>> {
+        self.clear_substring_word_ids(sentence);
+
+        self.set_constraints(sentence);
+        let exponent = 1.0 / self.temperature;
+        self.forward_filtering(npylm, sentence, with_scaling, exponent)?;
+        self.backward_sampling(npylm, sentence, rng)
+    }
+
+    /// Draws `num_samples` segmentations of the sentence independently from the posterior of the current model, the same way as `blocked_gibbs_segment`. The forward variables are computed once and shared by all the draws.
+    pub fn sample_segmentations<R: Rng>(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+        num_samples: usize,
+        rng: &mut R,
+    ) -> Result<Vec<Vec<usize>>> {
+        self.clear_substring_word_ids(sentence);
 
-        self.forward_filtering(sentence, with_scaling);
-        return self.backward_sampling(sentence);
+        self.set_constraints(sentence);
+        self.forward_filtering(npylm, sentence, true, 1.0)?;
+        (0..num_samples)
+            .map(|_| self.backward_sampling(npylm, sentence, rng))
+            .collect()
     }
 
     fn viterbi_argmax_calculate_alpha_t_k_j(
         &mut self,
+        npylm: &NPYLM,
         sentence: &Sentence,
         t: usize,
         k: usize,
         j: usize,
-    ) {
+    ) -> Result<()> {
         assert!(t <= self.max_sentence_length + 1);
         assert!(k <= self.max_word_length);
         assert!(j <= self.max_word_length);
-        let word_k_id = self.get_substring_word_id_at_t_k(sentence, t, k);
+        let word_k_id = self.get_substring_word_id_at_t_k(npylm, sentence, t, k);
 
         if j == 0 {
             self.word_ids[0] = BOS;
             self.word_ids[1] = BOS;
             self.word_ids[2] = word_k_id;
-            let p_w_h = self.npylm.compute_p_w_of_nth_word_as_chars(
+            let p_w_h = npylm.compute_p_w_of_nth_word_as_chars(
+                &mut self.cache,
                 &sentence.characters,
                 &self.word_ids,
                 2,
                 t - k,
                 t - 1,
-            );
+            )?;
             assert!(p_w_h > 0.0);
             // Here two are the differences compared with the non viterbi method.
             self.alpha_tensor[[t, k, 0]] = p_w_h.ln();
             self.viterbi_backward_indices[[t, k, 0]] = 0;
-            return;
+            return Ok(());
         } else if t - k - j == 0 {
-            let word_j_id = self.get_substring_word_id_at_t_k(sentence, t - k, j);
+            let word_j_id = self.get_substring_word_id_at_t_k(npylm, sentence, t - k, j);
             self.word_ids[0] = BOS;
             self.word_ids[1] = word_j_id;
             self.word_ids[2] = word_k_id;
-            let p_w_h = self.npylm.compute_p_w_of_nth_word_as_chars(
+            let p_w_h = npylm.compute_p_w_of_nth_word_as_chars(
+                &mut self.cache,
                 &sentence.characters,
                 &self.word_ids,
                 2,
                 t - k,
                 t - 1,
-            );
+            )?;
             assert!(p_w_h > 0.0);
             assert!(self.alpha_tensor[[t - k, j, 0]] != 0.0);
             // Here two are the differences compared with the non viterbi method.
             self.alpha_tensor[[t, k, j]] = p_w_h.ln() + self.alpha_tensor[[t - k, j, 0]];
             self.viterbi_backward_indices[[t, k, j]] = 0;
-            return;
+            return Ok(());
         } else {
             // Here two are the differences compared with the non viterbi method.
             let mut max_log_p = 0.0;
             let mut argmax = 0;
-            for i in 1..self.max_word_length.min(t - k - j) + 1 {
-                let word_i_id = self.get_substring_word_id_at_t_k(sentence, t - k - j, i);
-                let word_j_id = self.get_substring_word_id_at_t_k(sentence, t - k, j);
+            for i in 1..self.max_word_length_ending_at[t - k - j] + 1 {
+                if self.word_forbidden[[t - k - j, i]] {
+                    continue;
+                }
+                let word_i_id = self.get_substring_word_id_at_t_k(npylm, sentence, t - k - j, i);
+                let word_j_id = self.get_substring_word_id_at_t_k(npylm, sentence, t - k, j);
                 self.word_ids[0] = word_i_id;
                 self.word_ids[1] = word_j_id;
                 self.word_ids[2] = word_k_id;
 
-                let p_w_h = self.npylm.compute_p_w_of_nth_word_as_chars(
+                let p_w_h = npylm.compute_p_w_of_nth_word_as_chars(
+                    &mut self.cache,
                     &sentence.characters,
                     &self.word_ids,
                     2,
                     t - k,
                     t - 1,
-                );
+                )?;
                 assert!(p_w_h > 0.0);
                 assert!(i <= self.max_word_length);
                 assert!(self.alpha_tensor[[t - k, j, i]] <= 0.0);
@@ -461,53 +714,68 @@ impl Sampler {
             self.alpha_tensor[[t, k, j]] = max_log_p;
             // We use the viterbi_backward_indices matrix to store the i value that maximizes the possibility of the trigram.
             self.viterbi_backward_indices[[t, k, j]] = argmax;
-            return;
+            return Ok(());
         }
     }
 
-    fn viterbi_forward_filtering(&mut self, sentence: &Sentence) {
+    fn viterbi_forward_filtering(&mut self, npylm: &NPYLM, sentence: &Sentence) -> Result<()> {
         for t in 1..sentence.length() + 1 {
-            for k in 1..t.min(self.max_word_length) + 1 {
+            for k in 1..self.max_word_length_ending_at[t] + 1 {
+                if self.word_forbidden[[t, k]] {
+                    continue;
+                }
                 // There is no j, i.e. the second gram is also BOS.
                 if t == k {
-                    self.viterbi_argmax_calculate_alpha_t_k_j(sentence, t, k, 0);
+                    self.viterbi_argmax_calculate_alpha_t_k_j(npylm, sentence, t, k, 0)?;
                 }
 
                 // Note that in the t==k case, we will have range 1:0 which is automatically empty, so the following code will not be run.
-                for j in 1..(t - k).min(self.max_word_length) + 1 {
-                    self.viterbi_argmax_calculate_alpha_t_k_j(sentence, t, k, j);
+                for j in 1..self.max_word_length_ending_at[t - k] + 1 {
+                    if self.word_forbidden[[t - k, j]] {
+                        continue;
+                    }
+                    self.viterbi_argmax_calculate_alpha_t_k_j(npylm, sentence, t, k, j)?;
                 }
             }
         }
+        Ok(())
     }
 
     fn viterbi_argmax_backward_sample_k_and_j_to_eos(
         &mut self,
+        npylm: &NPYLM,
         sentence: &Sentence,
         t: usize,
         // This is actually not used in this function.
         _third_gram_length: usize,
         argmax_k: &mut usize,
         argmax_j: &mut usize,
-    ) {
+    ) -> Result<()> {
         assert!(t == sentence.length());
         let mut max_log_p = 0.0;
         *argmax_k = 0;
         *argmax_j = 0;
-        for k in 1..t.min(self.max_word_length) + 1 {
-            for j in 1..(t - k).min(self.max_word_length) + 1 {
-                let mut word_j_id = self.get_substring_word_id_at_t_k(sentence, t - k, j);
-                let mut word_k_id = self.get_substring_word_id_at_t_k(sentence, t, k);
+        for k in 1..self.max_word_length_ending_at[t] + 1 {
+            if self.word_forbidden[[t, k]] {
+                continue;
+            }
+            for j in 1..self.max_word_length_ending_at[t - k] + 1 {
+                if self.word_forbidden[[t - k, j]] {
+                    continue;
+                }
+                let mut word_j_id = self.get_substring_word_id_at_t_k(npylm, sentence, t - k, j);
+                let mut word_k_id = self.get_substring_word_id_at_t_k(npylm, sentence, t, k);
                 self.word_ids[0] = word_j_id;
                 self.word_ids[1] = word_k_id;
                 self.word_ids[2] = EOS;
-                let p_w_h = self.npylm.compute_p_w_of_nth_word_as_chars(
+                let p_w_h = npylm.compute_p_w_of_nth_word_as_chars(
+                    &mut self.cache,
                     &sentence.characters,
                     &self.word_ids,
                     2,
                     t,
                     t,
-                );
+                )?;
                 assert!(self.alpha_tensor[[t, k, j]] <= 0.0);
                 let temp = p_w_h.ln() + self.alpha_tensor[[t, k, j]];
                 assert!(temp <= 0.0);
@@ -520,18 +788,19 @@ impl Sampler {
 
             if t == k {
                 let mut word_j_id = BOS;
-                let mut word_k_id = self.get_substring_word_id_at_t_k(sentence, t, k);
+                let mut word_k_id = self.get_substring_word_id_at_t_k(npylm, sentence, t, k);
                 let mut word_t_id = EOS;
                 self.word_ids[0] = word_j_id;
                 self.word_ids[1] = word_k_id;
                 self.word_ids[2] = word_t_id;
-                let p_w_h = self.npylm.compute_p_w_of_nth_word_as_chars(
+                let p_w_h = npylm.compute_p_w_of_nth_word_as_chars(
+                    &mut self.cache,
                     &sentence.characters,
                     &self.word_ids,
                     2,
                     t,
                     t,
-                );
+                )?;
                 assert!(self.alpha_tensor[[t, k, 0]] <= 0.0);
                 let temp = p_w_h.ln() + self.alpha_tensor[[t, k, 0]];
                 assert!(temp <= 0.0);
@@ -542,15 +811,20 @@ impl Sampler {
                 }
             }
         }
+        Ok(())
     }
 
-    fn viterbi_backward_sampling(&mut self, sentence: &Sentence) -> Vec<usize> {
+    fn viterbi_backward_sampling(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+    ) -> Result<Vec<usize>> {
         let mut segment_lengths: Vec<usize> = Vec::new();
         let mut t = sentence.length();
         let mut sum_length = 0;
         let mut k = 0;
         let mut j = 0;
-        self.viterbi_argmax_backward_sample_k_and_j_to_eos(sentence, t, 1, &mut k, &mut j);
+        self.viterbi_argmax_backward_sample_k_and_j_to_eos(npylm, sentence, t, 1, &mut k, &mut j)?;
 
         assert!(k <= self.max_word_length);
 
@@ -559,7 +833,7 @@ impl Sampler {
 
         // There's only one word in total for the sentence.
         if j == 0 && k == t {
-            return segment_lengths;
+            return Ok(segment_lengths);
         }
 
         assert!(k > 0 && j > 0);
@@ -581,7 +855,7 @@ impl Sampler {
         if i == 0 {
             assert!(sum_length == sentence.length());
             segment_lengths.reverse();
-            return segment_lengths;
+            return Ok(segment_lengths);
         }
 
         segment_lengths.push(i);
@@ -604,73 +878,444 @@ impl Sampler {
         assert!(segment_lengths.len() > 0);
 
         segment_lengths.reverse();
-        return segment_lengths;
+        return Ok(segment_lengths);
     }
 
     /// This function uses viterbi algorithm to sample the segmentation of a sentence, instead of the approach in the `blocked_gibbs_segment` function above. They should both be valid approaches.
-    pub fn viterbi_decode(&mut self, sentence: &Sentence) -> Vec<usize> {
+    pub fn viterbi_decode(&mut self, npylm: &NPYLM, sentence: &Sentence) -> Result<Vec<usize>> {
         self.alpha_tensor[[0, 0, 0]] = 0.0;
         self.log_z[0] = 0.0;
-        for t in 0..sentence.length() + 1 {
-            for k in 0..self.max_word_length + 1 {
-                self.substring_word_id_cache[[t, k]] = 0;
+        self.clear_substring_word_ids(sentence);
+        self.set_constraints(sentence);
+        if npylm.whpylm.order == 2 {
+            self.bigram_viterbi_forward_filtering(npylm, sentence)?;
+            return self.bigram_viterbi_backward_sampling(npylm, sentence);
+        }
+        self.viterbi_forward_filtering(npylm, sentence)?;
+        self.viterbi_backward_sampling(npylm, sentence)
+    }
+
+    fn bigram_forward_filtering(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+        with_scaling: bool,
+    ) -> Result<()> {
+        self.alpha_tensor[[0, 0, 0]] = 1.0;
+        for t in 1..sentence.length() + 1 {
+            let mut prod_scaling = 1.0;
+            for k in 1..self.max_word_length_ending_at[t] + 1 {
+                if with_scaling && k > 1 {
+                    prod_scaling *= self.scaling_coefficients[t - k + 1];
+                }
+                if self.word_forbidden[[t, k]] {
+                    continue;
+                }
+                self.bigram_calculate_alpha_t_k(npylm, sentence, t, k, prod_scaling)?;
+            }
+
+            // Inside a required word, no word ends at all, and there is nothing to scale.
+            if with_scaling && self.is_inside_required_word[t] {
+                self.scaling_coefficients[t] = 1.0;
+            } else if with_scaling {
+                let mut sum_alpha = 0.0;
+                for k in 1..self.max_word_length_ending_at[t] + 1 {
+                    if self.word_forbidden[[t, k]] {
+                        continue;
+                    }
+                    sum_alpha += self.alpha_tensor[[t, k, 0]];
+                }
+
+                if !(sum_alpha > 0.0 && sum_alpha.is_finite()) {
+                    return Err(Error::NumericalUnderflow(format!(
+                        "The forward variables at position {} sum up to {}",
+                        t, sum_alpha
+                    )));
+                }
+                self.scaling_coefficients[t] = 1.0 / sum_alpha;
+
+                for k in 1..self.max_word_length_ending_at[t] + 1 {
+                    if self.word_forbidden[[t, k]] {
+                        continue;
+                    }
+                    assert!(self.alpha_tensor[[t, k, 0]] > 0.0);
+                    self.alpha_tensor[[t, k, 0]] *= self.scaling_coefficients[t];
+                }
             }
         }
-        self.viterbi_forward_filtering(sentence);
-        return self.viterbi_backward_sampling(sentence);
+        Ok(())
+    }
+
+    /// The bigram counterpart of `calculate_alpha_t_k_j`: the probability that the first t characters end with a word of length k, summed over the length j of the word before it.
+    fn bigram_calculate_alpha_t_k(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+        t: usize,
+        k: usize,
+        prod_scaling: f64,
+    ) -> Result<()> {
+        assert!(t <= self.max_sentence_length + 1);
+        assert!(k <= self.max_word_length);
+        let word_k_id = self.get_substring_word_id_at_t_k(npylm, sentence, t, k);
+
+        if t == k {
+            self.word_ids[0] = BOS;
+            self.word_ids[1] = BOS;
+            self.word_ids[2] = word_k_id;
+            let p_w_h = anneal(
+                npylm.compute_p_w_of_nth_word_as_chars(
+                    &mut self.cache,
+                    &sentence.characters,
+                    &self.word_ids,
+                    2,
+                    t - k,
+                    t - 1,
+                )?,
+                self.exponent,
+            );
+            assert!(p_w_h > 0.0);
+            self.alpha_tensor[[t, k, 0]] = p_w_h * prod_scaling;
+            self.p_w_h_cache[[t, k, 0, 0]] = p_w_h;
+        } else {
+            let mut sum = 0.0;
+            for j in 1..self.max_word_length_ending_at[t - k] + 1 {
+                if self.word_forbidden[[t - k, j]] {
+                    continue;
+                }
+                let word_j_id = self.get_substring_word_id_at_t_k(npylm, sentence, t - k, j);
+                self.word_ids[0] = BOS;
+                self.word_ids[1] = word_j_id;
+                self.word_ids[2] = word_k_id;
+                let p_w_h = anneal(
+                    npylm.compute_p_w_of_nth_word_as_chars(
+                        &mut self.cache,
+                        &sentence.characters,
+                        &self.word_ids,
+                        2,
+                        t - k,
+                        t - 1,
+                    )?,
+                    self.exponent,
+                );
+                assert!(p_w_h > 0.0);
+                assert!(self.alpha_tensor[[t - k, j, 0]] > 0.0);
+                self.p_w_h_cache[[t, k, j, 0]] = p_w_h;
+                sum += p_w_h * self.alpha_tensor[[t - k, j, 0]];
+            }
+
+            assert!(sum > 0.0);
+            self.alpha_tensor[[t, k, 0]] = sum * prod_scaling;
+        }
+        Ok(())
+    }
+
+    fn bigram_backward_sampling<R: Rng>(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+        rng: &mut R,
+    ) -> Result<Vec<usize>> {
+        let mut segment_lengths: Vec<usize> = Vec::new();
+        let mut t = sentence.length();
+        // The word after the last one is EOS, which has no length.
+        let mut next_word_length = 0;
+        while t > 0 {
+            let k = self.bigram_backward_sample_k(npylm, sentence, t, next_word_length, rng)?;
+            segment_lengths.push(k);
+            t -= k;
+            next_word_length = k;
+        }
+
+        segment_lengths.reverse();
+        return Ok(segment_lengths);
+    }
+
+    fn bigram_backward_sample_k<R: Rng>(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+        t: usize,
+        next_word_length: usize,
+        rng: &mut R,
+    ) -> Result<usize> {
+        let sentence_length = sentence.length();
+        let max_k = self.max_word_length_ending_at[t];
+        let mut sum_p = 0.0;
+        let mut last_allowed_k = 0;
+        for k in 1..max_k + 1 {
+            if self.word_forbidden[[t, k]] {
+                self.backward_sampling_table[k - 1] = 0.0;
+                continue;
+            }
+            last_allowed_k = k;
+            let p_w_h = if t == sentence_length {
+                self.word_ids[0] = BOS;
+                self.word_ids[1] = self.get_substring_word_id_at_t_k(npylm, sentence, t, k);
+                self.word_ids[2] = EOS;
+                anneal(
+                    npylm.compute_p_w_of_nth_word_as_chars(
+                        &mut self.cache,
+                        &sentence.characters,
+                        &self.word_ids,
+                        2,
+                        t,
+                        t,
+                    )?,
+                    self.exponent,
+                )
+            } else {
+                assert!(next_word_length > 0);
+                self.p_w_h_cache[[t + next_word_length, next_word_length, k, 0]]
+            };
+            assert!(self.alpha_tensor[[t, k, 0]] > 0.0);
+            let p = p_w_h * self.alpha_tensor[[t, k, 0]];
+            assert!(p > 0.0);
+            self.backward_sampling_table[k - 1] = p;
+            sum_p += p;
+        }
+
+        let normalizer = 1.0 / sum_p;
+        let randnum: f64 = rng.gen();
+        let mut stack = 0.0;
+        for k in 1..max_k + 1 {
+            stack += self.backward_sampling_table[k - 1] * normalizer;
+            if randnum < stack {
+                return Ok(k);
+            }
+        }
+        // Only reachable through rounding errors in the normalization.
+        Ok(last_allowed_k)
+    }
+
+    fn bigram_viterbi_argmax_calculate_alpha_t_k(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+        t: usize,
+        k: usize,
+    ) -> Result<()> {
+        assert!(t <= self.max_sentence_length + 1);
+        assert!(k <= self.max_word_length);
+        let word_k_id = self.get_substring_word_id_at_t_k(npylm, sentence, t, k);
+
+        if t == k {
+            self.word_ids[0] = BOS;
+            self.word_ids[1] = BOS;
+            self.word_ids[2] = word_k_id;
+            let p_w_h = npylm.compute_p_w_of_nth_word_as_chars(
+                &mut self.cache,
+                &sentence.characters,
+                &self.word_ids,
+                2,
+                t - k,
+                t - 1,
+            )?;
+            assert!(p_w_h > 0.0);
+            self.alpha_tensor[[t, k, 0]] = p_w_h.ln();
+            self.viterbi_backward_indices[[t, k, 0]] = 0;
+        } else {
+            let mut max_log_p = 0.0;
+            let mut argmax = 0;
+            for j in 1..self.max_word_length_ending_at[t - k] + 1 {
+                if self.word_forbidden[[t - k, j]] {
+                    continue;
+                }
+                let word_j_id = self.get_substring_word_id_at_t_k(npylm, sentence, t - k, j);
+                self.word_ids[0] = BOS;
+                self.word_ids[1] = word_j_id;
+                self.word_ids[2] = word_k_id;
+                let p_w_h = npylm.compute_p_w_of_nth_word_as_chars(
+                    &mut self.cache,
+                    &sentence.characters,
+                    &self.word_ids,
+                    2,
+                    t - k,
+                    t - 1,
+                )?;
+                assert!(p_w_h > 0.0);
+                assert!(self.alpha_tensor[[t - k, j, 0]] <= 0.0);
+                let temp = p_w_h.ln() + self.alpha_tensor[[t - k, j, 0]];
+                if argmax == 0 || temp > max_log_p {
+                    argmax = j;
+                    max_log_p = temp;
+                }
+            }
+            assert!(argmax > 0);
+
+            self.alpha_tensor[[t, k, 0]] = max_log_p;
+            // Here the matrix stores the length j of the previous word.
+            self.viterbi_backward_indices[[t, k, 0]] = argmax;
+        }
+        Ok(())
+    }
+
+    fn bigram_viterbi_forward_filtering(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+    ) -> Result<()> {
+        for t in 1..sentence.length() + 1 {
+            for k in 1..self.max_word_length_ending_at[t] + 1 {
+                if self.word_forbidden[[t, k]] {
+                    continue;
+                }
+                self.bigram_viterbi_argmax_calculate_alpha_t_k(npylm, sentence, t, k)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn bigram_viterbi_backward_sampling(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+    ) -> Result<Vec<usize>> {
+        let mut t = sentence.length();
+        let mut max_log_p = 0.0;
+        let mut k = 0;
+        for candidate_k in 1..self.max_word_length_ending_at[t] + 1 {
+            if self.word_forbidden[[t, candidate_k]] {
+                continue;
+            }
+            self.word_ids[0] = BOS;
+            self.word_ids[1] = self.get_substring_word_id_at_t_k(npylm, sentence, t, candidate_k);
+            self.word_ids[2] = EOS;
+            let p_w_h = npylm.compute_p_w_of_nth_word_as_chars(
+                &mut self.cache,
+                &sentence.characters,
+                &self.word_ids,
+                2,
+                t,
+                t,
+            )?;
+            assert!(self.alpha_tensor[[t, candidate_k, 0]] <= 0.0);
+            let temp = p_w_h.ln() + self.alpha_tensor[[t, candidate_k, 0]];
+            if k == 0 || temp > max_log_p {
+                max_log_p = temp;
+                k = candidate_k;
+            }
+        }
+
+        let mut segment_lengths: Vec<usize> = Vec::new();
+        while t > 0 {
+            assert!(k > 0 && k <= t);
+            segment_lengths.push(k);
+            let j = self.viterbi_backward_indices[[t, k, 0]];
+            t -= k;
+            k = j;
+        }
+        assert!(k == 0);
+
+        segment_lengths.reverse();
+        return Ok(segment_lengths);
     }
 
     pub fn compute_log_forward_probability(
         &mut self,
+        npylm: &NPYLM,
         sentence: &Sentence,
         with_scaling: bool,
-    ) -> f64 {
-        self.enumerate_forward_variables(sentence, with_scaling);
+    ) -> Result<f64> {
+        self.enumerate_forward_variables(npylm, sentence, with_scaling)?;
         let t = sentence.length() + 1;
         if !with_scaling {
             let k = 1;
             let mut alpha_eos = 0.0;
-            for j in 1..self.max_word_length.min(t - k) {
-                assert!(self.alpha_tensor[[t, k, j]] > 0.0);
-                alpha_eos += self.alpha_tensor[[t, k, j]];
+            if npylm.whpylm.order == 2 {
+                alpha_eos = self.alpha_tensor[[t, k, 0]];
+            } else {
+                for j in 1..self.max_word_length_ending_at[t - k] + 1 {
+                    if self.word_forbidden[[t - k, j]] {
+                        continue;
+                    }
+                    assert!(self.alpha_tensor[[t, k, j]] > 0.0);
+                    alpha_eos += self.alpha_tensor[[t, k, j]];
+                }
+            }
+            if alpha_eos.is_nan() || alpha_eos <= 0.0 {
+                return Err(Error::NumericalUnderflow(
+                    "The probability of the sentence is 0 without scaling".to_owned(),
+                ));
             }
-            assert!(alpha_eos > 0.0);
-            return alpha_eos.ln();
+            return Ok(alpha_eos.ln());
         } else {
             let mut log_p_x = 0.0;
             for i in 1..t + 1 {
                 log_p_x += (1.0 / self.scaling_coefficients[i]).ln();
             }
-            return log_p_x;
+            return Ok(log_p_x);
         }
     }
 
     // TODO: This function is a duplicate of some of the functionalities that we already performed above. Should be able to put it somewhere.
-    fn enumerate_forward_variables(&mut self, sentence: &Sentence, with_scaling: bool) {
-        for i in 0..sentence.length() + 1 {
-            for j in 0..self.max_word_length + 1 {
-                self.substring_word_id_cache[[i, j]] = 0;
-            }
-        }
+    fn enumerate_forward_variables(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+        with_scaling: bool,
+    ) -> Result<()> {
+        self.clear_substring_word_ids(sentence);
 
-        self.forward_filtering(sentence, with_scaling);
+        self.set_constraints(sentence);
+        self.forward_filtering(npylm, sentence, with_scaling, 1.0)?;
 
         let mut alpha_eos = 0.0;
         let t = sentence.length() + 1;
         let k = 1;
-        for j in 1..self.max_word_length.min(t - k) + 1 {
+        if npylm.whpylm.order == 2 {
+            for j in 1..self.max_word_length_ending_at[t - k] + 1 {
+                if self.word_forbidden[[t - k, j]] {
+                    continue;
+                }
+                self.word_ids[0] = BOS;
+                self.word_ids[1] = self.get_substring_word_id_at_t_k(npylm, sentence, t - k, j);
+                self.word_ids[2] = EOS;
+                let p_w_h = npylm.compute_p_w_of_nth_word_as_chars(
+                    &mut self.cache,
+                    &sentence.characters,
+                    &self.word_ids,
+                    2,
+                    t,
+                    t,
+                )?;
+                assert!(p_w_h > 0.0);
+                alpha_eos += p_w_h * self.alpha_tensor[[t - k, j, 0]];
+            }
+            // The bigram tensors only have index 0 in their last dimension, so the probability of EOS is stored there.
+            self.alpha_tensor[[t, k, 0]] = alpha_eos;
+            if with_scaling {
+                self.scaling_coefficients[t] = 1.0 / alpha_eos;
+            }
+            return Ok(());
+        }
+        for j in 1..self.max_word_length_ending_at[t - k] + 1 {
+            if self.word_forbidden[[t - k, j]] {
+                continue;
+            }
             let mut prob_sum = 0.0;
-            for i in if t - k - j == 0 { 0 } else { 1 }..self.max_word_length.min(t - k - j) + 1 {
-                self.word_ids[0] = self.get_substring_word_id_at_t_k(sentence, t - k - j, i);
-                self.word_ids[1] = self.get_substring_word_id_at_t_k(sentence, t - k, j);
+            for i in
+                if t - k - j == 0 { 0 } else { 1 }..self.max_word_length_ending_at[t - k - j] + 1
+            {
+                if self.word_forbidden[[t - k - j, i]] {
+                    continue;
+                }
+                // A sentence that is a single word begins right after BOS.
+                self.word_ids[0] = if i == 0 {
+                    BOS
+                } else {
+                    self.get_substring_word_id_at_t_k(npylm, sentence, t - k - j, i)
+                };
+                self.word_ids[1] = self.get_substring_word_id_at_t_k(npylm, sentence, t - k, j);
                 self.word_ids[2] = EOS;
-                let p_w_h = self.npylm.compute_p_w_of_nth_word_as_chars(
+                let p_w_h = npylm.compute_p_w_of_nth_word_as_chars(
+                    &mut self.cache,
                     &sentence.characters,
                     &self.word_ids,
                     2,
                     t,
                     t,
-                );
+                )?;
                 assert!(p_w_h > 0.0);
                 prob_sum += p_w_h * self.alpha_tensor[[t - k, j, i]];
             }
@@ -680,5 +1325,283 @@ impl Sampler {
         if with_scaling {
             self.scaling_coefficients[t] = 1.0 / alpha_eos;
         }
+        Ok(())
+    }
+
+    /// Computes the backward variables of the sentence, which `enumerate_forward_variables` must have been called for with scaling.
+    fn backward_filtering(&mut self, npylm: &NPYLM, sentence: &Sentence) -> Result<()> {
+        let sentence_length = sentence.length();
+        let t = sentence_length;
+        for k in 1..self.max_word_length_ending_at[t] + 1 {
+            if self.word_forbidden[[t, k]] {
+                continue;
+            }
+            for j in self.second_word_lengths(npylm, t, k) {
+                if self.word_forbidden[[t - k, j]] {
+                    continue;
+                }
+                self.word_ids[0] = if j == 0 {
+                    BOS
+                } else {
+                    self.get_substring_word_id_at_t_k(npylm, sentence, t - k, j)
+                };
+                self.word_ids[1] = self.get_substring_word_id_at_t_k(npylm, sentence, t, k);
+                self.word_ids[2] = EOS;
+                let p_w_h = npylm.compute_p_w_of_nth_word_as_chars(
+                    &mut self.cache,
+                    &sentence.characters,
+                    &self.word_ids,
+                    2,
+                    t,
+                    t,
+                )?;
+                self.beta_tensor[[t, k, j]] = p_w_h * self.scaling_coefficients[t + 1];
+            }
+        }
+
+        for t in (1..sentence_length).rev() {
+            for k in 1..self.max_word_length_ending_at[t] + 1 {
+                if self.word_forbidden[[t, k]] {
+                    continue;
+                }
+                for j in self.second_word_lengths(npylm, t, k) {
+                    if self.word_forbidden[[t - k, j]] {
+                        continue;
+                    }
+                    let mut sum = 0.0;
+                    let mut prod_scaling = 1.0;
+                    for n in 1..self.max_word_length.min(sentence_length - t) + 1 {
+                        prod_scaling *= self.scaling_coefficients[t + n];
+                        if n > self.max_word_length_ending_at[t + n]
+                            || self.word_forbidden[[t + n, n]]
+                        {
+                            continue;
+                        }
+                        // The bigram tensors only have index 0 in their last dimension.
+                        let (p_w_h, beta) = if npylm.whpylm.order == 2 {
+                            (
+                                self.p_w_h_cache[[t + n, n, k, 0]],
+                                self.beta_tensor[[t + n, n, 0]],
+                            )
+                        } else {
+                            (
+                                self.p_w_h_cache[[t + n, n, k, j]],
+                                self.beta_tensor[[t + n, n, k]],
+                            )
+                        };
+                        sum += p_w_h * beta * prod_scaling;
+                    }
+                    self.beta_tensor[[t, k, j]] = sum;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The lengths j of the word before the word of length k ending at t that index the last dimension of the forward variables, 0 standing for BOS. The bigram model only keeps index 0 there.
+    fn second_word_lengths(&self, npylm: &NPYLM, t: usize, k: usize) -> ::std::ops::Range<usize> {
+        if npylm.whpylm.order == 2 || t == k {
+            0..1
+        } else {
+            1..self.max_word_length_ending_at[t - k] + 1
+        }
+    }
+
+    /// The posterior probability, under the current model, that a word ends after each character of the sentence but the last. It is computed from the forward variables and the matching backward variables, so it sums over all the segmentations instead of sampling them.
+    pub fn compute_boundary_probabilities(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+    ) -> Result<Vec<f64>> {
+        let sentence_length = sentence.length();
+        if sentence_length < 2 {
+            return Ok(Vec::new());
+        }
+        self.enumerate_forward_variables(npylm, sentence, true)?;
+        self.backward_filtering(npylm, sentence)?;
+
+        let mut boundary_probabilities = vec![0.0; sentence_length - 1];
+        for t in 1..sentence_length {
+            let mut p = 0.0;
+            for k in 1..self.max_word_length_ending_at[t] + 1 {
+                if self.word_forbidden[[t, k]] {
+                    continue;
+                }
+                for j in self.second_word_lengths(npylm, t, k) {
+                    if self.word_forbidden[[t - k, j]] {
+                        continue;
+                    }
+                    p += self.alpha_tensor[[t, k, j]] * self.beta_tensor[[t, k, j]];
+                }
+            }
+            // Rounding may take it a little above 1.
+            boundary_probabilities[t - 1] = p.min(1.0);
+        }
+        Ok(boundary_probabilities)
+    }
+
+    /// Finds the `n` most probable segmentations of the sentence by keeping the `n` best partial paths in every state of the Viterbi algorithm. Returns their segment lengths together with the joint log probability of the sentence and the segmentation, best first. There may be fewer than `n` if the sentence has fewer segmentations.
+    pub fn nbest_decode(
+        &mut self,
+        npylm: &NPYLM,
+        sentence: &Sentence,
+        n: usize,
+    ) -> Result<Vec<(Vec<usize>, f64)>> {
+        let sentence_length = sentence.length();
+        if n == 0 || sentence_length == 0 {
+            return Ok(Vec::new());
+        }
+        self.clear_substring_word_ids(sentence);
+        self.set_constraints(sentence);
+
+        // The states are the same as those of the trigram forward variables, even for the bigram model, which just ignores the word two positions back.
+        let state_size = self.max_word_length + 1;
+        let index = |t: usize, k: usize, j: usize| (t * state_size + k) * state_size + j;
+        let mut entries: Vec<Vec<NBestEntry>> =
+            vec![Vec::new(); (sentence_length + 1) * state_size * state_size];
+        let mut candidates: Vec<NBestEntry> = Vec::new();
+        for t in 1..sentence_length + 1 {
+            for k in 1..self.max_word_length_ending_at[t] + 1 {
+                if self.word_forbidden[[t, k]] {
+                    continue;
+                }
+                let word_k_id = self.get_substring_word_id_at_t_k(npylm, sentence, t, k);
+                let first_j = if t == k { 0 } else { 1 };
+                for j in first_j..self.max_word_length_ending_at[t - k] + 1 {
+                    if self.word_forbidden[[t - k, j]] {
+                        continue;
+                    }
+                    candidates.clear();
+                    self.word_ids[1] = if j == 0 {
+                        BOS
+                    } else {
+                        self.get_substring_word_id_at_t_k(npylm, sentence, t - k, j)
+                    };
+                    self.word_ids[2] = word_k_id;
+                    if j == 0 || t - k - j == 0 {
+                        self.word_ids[0] = BOS;
+                        let log_p_w_h = npylm
+                            .compute_p_w_of_nth_word_as_chars(
+                                &mut self.cache,
+                                &sentence.characters,
+                                &self.word_ids,
+                                2,
+                                t - k,
+                                t - 1,
+                            )?
+                            .ln();
+                        if j == 0 {
+                            candidates.push(NBestEntry {
+                                log_p: log_p_w_h,
+                                previous_length: 0,
+                                rank: 0,
+                            });
+                        } else {
+                            for (rank, entry) in entries[index(t - k, j, 0)].iter().enumerate() {
+                                candidates.push(NBestEntry {
+                                    log_p: log_p_w_h + entry.log_p,
+                                    previous_length: 0,
+                                    rank,
+                                });
+                            }
+                        }
+                    } else {
+                        for i in 1..self.max_word_length_ending_at[t - k - j] + 1 {
+                            if self.word_forbidden[[t - k - j, i]] {
+                                continue;
+                            }
+                            self.word_ids[0] =
+                                self.get_substring_word_id_at_t_k(npylm, sentence, t - k - j, i);
+                            let log_p_w_h = npylm
+                                .compute_p_w_of_nth_word_as_chars(
+                                    &mut self.cache,
+                                    &sentence.characters,
+                                    &self.word_ids,
+                                    2,
+                                    t - k,
+                                    t - 1,
+                                )?
+                                .ln();
+                            for (rank, entry) in entries[index(t - k, j, i)].iter().enumerate() {
+                                candidates.push(NBestEntry {
+                                    log_p: log_p_w_h + entry.log_p,
+                                    previous_length: i,
+                                    rank,
+                                });
+                            }
+                        }
+                    }
+                    sort_by_log_p_descending(&mut candidates, |entry| entry.log_p)?;
+                    candidates.truncate(n);
+                    entries[index(t, k, j)] = candidates.clone();
+                }
+            }
+        }
+
+        // The complete paths, with the lengths of their last two words.
+        let t = sentence_length;
+        let mut complete_paths: Vec<(NBestEntry, usize, usize)> = Vec::new();
+        for k in 1..self.max_word_length_ending_at[t] + 1 {
+            if self.word_forbidden[[t, k]] {
+                continue;
+            }
+            let first_j = if t == k { 0 } else { 1 };
+            for j in first_j..self.max_word_length_ending_at[t - k] + 1 {
+                if self.word_forbidden[[t - k, j]] {
+                    continue;
+                }
+                self.word_ids[0] = if j == 0 {
+                    BOS
+                } else {
+                    self.get_substring_word_id_at_t_k(npylm, sentence, t - k, j)
+                };
+                self.word_ids[1] = self.get_substring_word_id_at_t_k(npylm, sentence, t, k);
+                self.word_ids[2] = EOS;
+                let log_p_eos = npylm
+                    .compute_p_w_of_nth_word_as_chars(
+                        &mut self.cache,
+                        &sentence.characters,
+                        &self.word_ids,
+                        2,
+                        t,
+                        t,
+                    )?
+                    .ln();
+                for (rank, entry) in entries[index(t, k, j)].iter().enumerate() {
+                    complete_paths.push((
+                        NBestEntry {
+                            log_p: log_p_eos + entry.log_p,
+                            previous_length: 0,
+                            rank,
+                        },
+                        k,
+                        j,
+                    ));
+                }
+            }
+        }
+        sort_by_log_p_descending(&mut complete_paths, |path| path.0.log_p)?;
+        complete_paths.truncate(n);
+
+        let mut segmentations = Vec::new();
+        for (path, last_k, last_j) in complete_paths {
+            let mut segment_lengths = Vec::new();
+            let (mut t, mut k, mut j, mut rank) = (sentence_length, last_k, last_j, path.rank);
+            loop {
+                segment_lengths.push(k);
+                if j == 0 {
+                    break;
+                }
+                let entry = &entries[index(t, k, j)][rank];
+                t -= k;
+                k = j;
+                j = entry.previous_length;
+                rank = entry.rank;
+            }
+            assert!(t == k);
+            segment_lengths.reverse();
+            segmentations.push((segment_lengths, path.log_p));
+        }
+        Ok(segmentations)
     }
 }
@@ -1,19 +1,85 @@
 use def::*;
-use std::collections::hash_map::DefaultHasher;
+use interner::*;
 use std::fmt;
-use std::hash::{Hash, Hasher};
 
-// pub const BOS: u64 = calculate_hash(&BOS_CHAR);
-// pub const EOS: u64 = calculate_hash(&EOS_CHAR);
+/// Restrictions on the segmentations the sampler may choose for a sentence. All positions are character offsets into the sentence, and a span `(begin, end)` stands for the characters `begin..end`.
+///
+/// Constraints that contradict each other are resolved by the sampler so that some segmentation always remains: a required word is dropped if it overlaps an earlier one, spans a boundary, is forbidden or is longer than the maximum word length, and single characters can't be forbidden.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SegmentationConstraints {
+    /// Positions, in increasing order, before which a word must end, e.g. because there was whitespace in the input.
+    pub boundaries: Vec<usize>,
+    /// Spans that must be segmented as a single word, in increasing order.
+    pub words: Vec<(usize, usize)>,
+    /// Spans that must never be a word.
+    pub forbidden_words: Vec<(usize, usize)>,
+}
+
+impl Default for SegmentationConstraints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SegmentationConstraints {
+    pub fn new() -> Self {
+        Self {
+            boundaries: Vec::new(),
+            words: Vec::new(),
+            forbidden_words: Vec::new(),
+        }
+    }
+
+    pub fn from_boundaries(boundaries: Vec<usize>) -> Self {
+        let mut constraints = Self::new();
+        constraints.boundaries = boundaries;
+        constraints
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.boundaries.is_empty() && self.words.is_empty() && self.forbidden_words.is_empty()
+    }
+
+    /// All the positions at which a word must end or begin, including the edges of the required words, in increasing order.
+    pub fn get_split_positions(&self) -> Vec<usize> {
+        let mut positions = self.boundaries.clone();
+        for &(begin, end) in &self.words {
+            positions.push(begin);
+            positions.push(end);
+        }
+        positions.sort();
+        positions.dedup();
+        positions
+    }
+
+    /// The segmentation of an unsupervised sentence of `length` characters before it is first sampled, i.e. the sentence split at all the split positions. See `Sentence::set_constraints`.
+    pub fn get_initial_segment_lengths(&self, length: usize) -> Vec<usize> {
+        let mut segment_lengths = Vec::new();
+        let mut word_begin = 0;
+        for position in self
+            .get_split_positions()
+            .into_iter()
+            .filter(|&position| position > 0 && position < length)
+            .chain(Some(length))
+        {
+            if position > word_begin {
+                segment_lengths.push(position - word_begin);
+                word_begin = position;
+            }
+        }
+        segment_lengths
+    }
+}
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Sentence {
     pub num_segments: usize,
     pub segment_lengths: Vec<usize>,
     pub segment_begin_positions: Vec<usize>,
     pub supervised: bool,
     pub characters: Vec<char>,
-    pub word_ids: Vec<u64>,
+    /// The sampler never builds a segmentation that violates them.
+    pub constraints: SegmentationConstraints,
 }
 
 impl Sentence {
@@ -22,11 +88,6 @@ impl Sentence {
     }
 
     pub fn new(characters: Vec<char>, supervised: bool) -> Self {
-        let mut word_ids: Vec<u64> = vec![0; characters.len() + 3];
-        word_ids[0] = BOS;
-        word_ids[1] = BOS;
-        word_ids[3] = EOS;
-
         let mut segment_lengths: Vec<usize> = vec![0; characters.len() + 3];
         segment_lengths[0] = 1;
         segment_lengths[1] = 1;
@@ -41,11 +102,11 @@ impl Sentence {
 
         Self {
             characters: characters,
-            word_ids: word_ids,
             segment_lengths: segment_lengths,
             segment_begin_positions: segment_begin_positions,
             num_segments: 4,
             supervised: supervised,
+            constraints: SegmentationConstraints::new(),
         }
     }
 
@@ -62,17 +123,46 @@ impl Sentence {
         self.segment_lengths[n]
     }
 
-    pub fn get_nth_word_id(&self, n: usize) -> u64 {
-        assert!(n <= self.num_segments);
-        self.word_ids[n]
+    /// The ID of the nth word, which is added to the interner if it isn't there yet.
+    pub fn get_nth_word_id(&self, n: usize, interner: &mut WordInterner) -> u64 {
+        assert!(n < self.num_segments);
+        if n < 2 {
+            BOS
+        } else if n == self.num_segments - 1 {
+            EOS
+        } else {
+            interner.get_or_intern(self.get_nth_word_chars(n))
+        }
     }
 
-    pub fn get_substr_word_id(&self, start_index: usize, end_index: usize) -> u64 {
-        // It seems that we need to + 1
+    /// Like `get_nth_word_id`, but a word the interner doesn't know gets a transient ID instead.
+    pub fn find_nth_word_id(&self, n: usize, interner: &WordInterner) -> u64 {
+        assert!(n < self.num_segments);
+        if n < 2 {
+            BOS
+        } else if n == self.num_segments - 1 {
+            EOS
+        } else {
+            let start_index = self.segment_begin_positions[n];
+            self.get_substr_word_id(
+                start_index,
+                start_index + self.segment_lengths[n] - 1,
+                interner,
+            )
+        }
+    }
+
+    /// The ID of the word made of `characters[start_index..=end_index]`, or a transient ID if the interner doesn't know it.
+    pub fn get_substr_word_id(
+        &self,
+        start_index: usize,
+        end_index: usize,
+        interner: &WordInterner,
+    ) -> u64 {
         let substr = &self.characters[start_index..end_index + 1];
-        let mut s = DefaultHasher::new();
-        substr.hash(&mut s);
-        s.finish()
+        interner.get(substr).unwrap_or_else(|| {
+            transient_word_id(self.length(), start_index, end_index + 1 - start_index)
+        })
     }
 
     pub fn get_nth_word_chars(&self, n: usize) -> &[char] {
@@ -104,8 +194,6 @@ impl Sentence {
             let cur_length = segment_lengths[index];
 
             self.segment_lengths[index + 2] = cur_length;
-            self.word_ids[index + 2] =
-                self.get_substr_word_id(cur_start, cur_start + cur_length - 1);
             self.segment_begin_positions[index + 2] = cur_start;
             cur_start += cur_length;
             index += 1;
@@ -115,7 +203,6 @@ impl Sentence {
 
         // EOS
         self.segment_lengths[index + 2] = 1;
-        self.word_ids[index + 2] = EOS;
         self.segment_begin_positions[index + 2] = self.segment_begin_positions[index + 1];
         index += 1;
 
@@ -128,6 +215,15 @@ impl Sentence {
         self.num_segments = num_segments_without_special_tokens + 3;
     }
 
+    /// Sets the constraints on the segmentation of the sentence. Unless the sentence is supervised, it is also split at their boundaries and around their required words, so that its initial segmentation is closer to respecting them.
+    pub fn set_constraints(&mut self, constraints: SegmentationConstraints) {
+        let segment_lengths = constraints.get_initial_segment_lengths(self.length());
+        if !self.supervised && segment_lengths.len() > 1 {
+            self.split_sentence(segment_lengths);
+        }
+        self.constraints = constraints;
+    }
+
     pub fn split_sentence(&mut self, segment_lengths: Vec<usize>) {
         let num_segments_without_special_tokens = segment_lengths.len();
         self.split_sentence_with_num_segments(segment_lengths, num_segments_without_special_tokens);
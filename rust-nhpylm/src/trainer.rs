@@ -1,13 +1,224 @@
+use bincode;
+use boundary::BoundaryRules;
 use corpus::*;
 use def::*;
+use error::*;
+use evaluation::*;
+use hpylm::HPYLM;
+use lexicon::Lexicon;
+use mbr::BoundaryCounts;
 use model::*;
+use normalizer::Normalizer;
+use npylm::{check_lambda, ProbabilityCache, NPYLM};
 use rand::distributions::Gamma;
 use rand::distributions::WeightedIndex;
 use rand::prelude::*;
 use rand::seq::SliceRandom;
 use rand::Rng;
+use sampler::*;
+use sentence::*;
+use serde_json;
 use std::collections::HashSet;
-use wtype::*;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::mem;
+use std::path::Path;
+use std::thread;
+
+/// A segmentation drawn by a worker thread of the parallel sampling, together with the log probabilities the snapshot of the model assigns to it and to the current segmentation. These are needed for the Metropolis-Hastings correction.
+struct Proposal {
+    segment_lengths: Vec<usize>,
+    snapshot_log_p_old: f64,
+    snapshot_log_p_new: f64,
+}
+
+fn propose_segmentation<R: Rng>(
+    snapshot: &NPYLM,
+    sampler: &mut Sampler,
+    cache: &mut ProbabilityCache,
+    sentence: &Sentence,
+    rng: &mut R,
+) -> Result<Proposal> {
+    let snapshot_log_p_old =
+        snapshot.compute_log_probability_of_sentence_with_cache(sentence, cache)?;
+    let segment_lengths = sampler.blocked_gibbs_segment(snapshot, sentence, true, rng)?;
+    let mut proposed_sentence = sentence.clone();
+    proposed_sentence.split_sentence(segment_lengths.clone());
+    let snapshot_log_p_new =
+        snapshot.compute_log_probability_of_sentence_with_cache(&proposed_sentence, cache)?;
+    Ok(Proposal {
+        segment_lengths,
+        snapshot_log_p_old,
+        snapshot_log_p_new,
+    })
+}
+
+// Only the initial segmentation of a sentence can contain a word longer than the maximum word length, since the sampler never proposes one. Such a segmentation lies outside the space the chain samples from, and its probability can't be compared with that of a proposal either, because the base measure leaves out the length correction for these words and overrates them. It is therefore replaced by the first proposal without the Metropolis-Hastings test.
+fn has_word_longer_than(sentence: &Sentence, max_word_length: usize) -> bool {
+    (2..sentence.num_segments - 1).any(|n| sentence.get_nth_segment_length(n) > max_word_length)
+}
+
+/// How `Trainer::correct_word_lengths` re-estimates the distribution of the lengths of the words the CHPYLM generates, which the NPYLM divides out of its base measure so that the Poisson distribution over the lengths takes its place.
+#[derive(Clone, Debug)]
+pub struct WordLengthCorrection {
+    /// The first epoch after which the distribution is re-estimated. 0 never does, which leaves it uniform.
+    pub start_epoch: usize,
+    /// Compute the distribution exactly by dynamic programming over the CHPYLM instead of sampling words from it.
+    pub exact: bool,
+    /// The number of words sampled from the CHPYLM.
+    pub num_samples: usize,
+    /// The sampling stops early once every length was sampled at least this many times.
+    pub early_stopping_threshold: usize,
+}
+
+impl Default for WordLengthCorrection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WordLengthCorrection {
+    pub fn new() -> Self {
+        Self {
+            start_epoch: WORD_LENGTH_CORRECTION_START_EPOCH,
+            exact: false,
+            num_samples: NUM_WORD_LENGTH_SAMPLES,
+            early_stopping_threshold: WORD_LENGTH_EARLY_STOPPING_THRESHOLD,
+        }
+    }
+}
+
+/// The diagnostics collected by `Trainer::report`. Printed in a human-readable form with `Display`, and can be serialized to JSON.
+#[derive(Serialize)]
+pub struct TrainingReport {
+    pub epoch: usize,
+    pub train_perplexity: f64,
+    pub dev_perplexity: f64,
+    pub train_log_likelihood: f64,
+    pub dev_log_likelihood: f64,
+    pub num_segmentation_acceptances: usize,
+    pub num_segmentation_rejections: usize,
+    /// The temperature at which the last epoch was sampled.
+    pub temperature: f64,
+    /// The share of the new segmentations accepted by the Metropolis-Hastings test since the training started. `None` if nothing was tested yet, e.g. when new segmentations are always accepted. An initial segmentation with a word longer than the maximum word length is replaced without the test, and isn't counted.
+    pub acceptance_rate: Option<f64>,
+    pub num_characters: usize,
+    /// The probability of each word length under the CHPYLM, from length 1 to the maximum word length, as last estimated by `Trainer::correct_word_lengths`.
+    pub word_length_distribution: Vec<f64>,
+    /// The probability of a word longer than the maximum word length under the CHPYLM, estimated along with `word_length_distribution`.
+    pub word_length_overflow: f64,
+    /// The number of distinct words currently seated in the WHPYLM.
+    pub num_words: usize,
+    pub whpylm_num_nodes: usize,
+    pub whpylm_num_tables: usize,
+    pub whpylm_num_customers: usize,
+    pub chpylm_num_nodes: usize,
+    pub chpylm_num_tables: usize,
+    pub chpylm_num_customers: usize,
+    pub dev_segmentations: Vec<String>,
+}
+
+impl TrainingReport {
+    /// The report as a single line of JSON, to be appended to a JSON-lines log.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+impl fmt::Display for TrainingReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "=== EPOCH:\t{}", self.epoch)?;
+        writeln!(f, "=== TRAIN PERPLEXITY:\t{:.3}", self.train_perplexity)?;
+        writeln!(f, "=== DEV PERPLEXITY:\t{:.3}", self.dev_perplexity)?;
+        writeln!(
+            f,
+            "=== TRAIN LOG-LIKELIHOOD:\t{:.3}",
+            self.train_log_likelihood
+        )?;
+        writeln!(f, "=== DEV LOG-LIKELIHOOD:\t{:.3}", self.dev_log_likelihood)?;
+        writeln!(
+            f,
+            "=== ACCEPTANCE RATE:\t{} ({} accepted, {} rejected)",
+            format_optional_rate(self.acceptance_rate),
+            self.num_segmentation_acceptances,
+            self.num_segmentation_rejections
+        )?;
+        writeln!(f, "=== TEMPERATURE:\t{:.3}", self.temperature)?;
+        writeln!(f, "=== CHARACTERS:\t{}", self.num_characters)?;
+        writeln!(f, "=== WORDS:\t{}", self.num_words)?;
+        write!(f, "=== CHPYLM WORD LENGTHS:\t")?;
+        for (i, p) in self.word_length_distribution.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}:{:.4}", i + 1, p)?;
+        }
+        writeln!(
+            f,
+            " >{}:{:.4}",
+            self.word_length_distribution.len(),
+            self.word_length_overflow
+        )?;
+        writeln!(
+            f,
+            "=== WHPYLM NODES / TABLES / CUSTOMERS:\t{} / {} / {}",
+            self.whpylm_num_nodes, self.whpylm_num_tables, self.whpylm_num_customers
+        )?;
+        write!(
+            f,
+            "=== CHPYLM NODES / TABLES / CUSTOMERS:\t{} / {} / {}",
+            self.chpylm_num_nodes, self.chpylm_num_tables, self.chpylm_num_customers
+        )?;
+        for segmentation in &self.dev_segmentations {
+            write!(f, "\n{}", segmentation)?;
+        }
+        Ok(())
+    }
+}
+
+/// Written at the beginning of every checkpoint, see `MODEL_FILE_MAGIC`.
+const CHECKPOINT_FILE_MAGIC: [u8; 8] = *b"NPYLMCKP";
+/// Bump this whenever the serialized layout of the checkpoint changes.
+pub const CHECKPOINT_FILE_VERSION: u32 = 11;
+
+/// Everything `Trainer::load_checkpoint` needs to continue the training exactly where it stopped. The fields must stay in the same order as in `Checkpoint`.
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    epoch: usize,
+    npylm: &'a NPYLM,
+    rng: &'a NpylmRng,
+    normalizer: &'a Normalizer,
+    boundary_rules: &'a BoundaryRules,
+    train_sentences: &'a SegmentedSentences,
+    dev_sentences: &'a SegmentedSentences,
+    dev_gold_segmentations: &'a [Option<Vec<usize>>],
+    added_to_chpylm_train: &'a [bool],
+    rand_indices_train: &'a [usize],
+    num_segmentation_acceptances: usize,
+    num_segmentation_rejections: usize,
+    always_accept_new_segmentation: bool,
+    train_boundary_counts: &'a [BoundaryCounts],
+}
+
+#[derive(Deserialize)]
+struct Checkpoint {
+    epoch: usize,
+    npylm: NPYLM,
+    rng: NpylmRng,
+    normalizer: Normalizer,
+    boundary_rules: BoundaryRules,
+    train_sentences: SegmentedSentences,
+    dev_sentences: SegmentedSentences,
+    dev_gold_segmentations: Vec<Option<Vec<usize>>>,
+    added_to_chpylm_train: Vec<bool>,
+    rand_indices_train: Vec<usize>,
+    num_segmentation_acceptances: usize,
+    num_segmentation_rejections: usize,
+    always_accept_new_segmentation: bool,
+    train_boundary_counts: Vec<BoundaryCounts>,
+}
 
 pub struct Trainer {
     rand_indices_train: Vec<usize>,
@@ -17,10 +228,19 @@ pub struct Trainer {
     model: Model,
     chpylm_sampling_probability_table: Vec<f64>,
     chpylm_sampling_id_table: Vec<char>,
+    // The characters of the vocabulary in a fixed order, so that the characters sampled from the CHPYLM don't depend on the iteration order of the vocabulary, which changes when a checkpoint is restored.
+    sorted_characters: Vec<char>,
     always_accept_new_segmentation: bool,
     added_to_chpylm_train: Vec<bool>,
     num_segmentation_rejections: usize,
     num_segmentation_acceptances: usize,
+    num_threads: usize,
+    parallel_batch_size: usize,
+    // Each worker thread needs its own sampler and cache, since the buffers of the forward filtering are overwritten for every sentence. The model itself is shared.
+    worker_samplers: Vec<(Sampler, ProbabilityCache)>,
+    // The segmentations of the training sentences collected by `collect_boundary_samples`. Empty until it is first called.
+    train_boundary_counts: Vec<BoundaryCounts>,
+    word_length_correction: WordLengthCorrection,
 }
 
 impl Trainer {
@@ -34,13 +254,19 @@ impl Trainer {
             rand_indices_dev[i] = i;
         }
 
+        let mut sorted_characters: Vec<char> =
+            dataset.vocabulary.all_characters.iter().cloned().collect();
+        sorted_characters.sort();
+
+        // One slot for every character and one for EOW. The slot at index 0 is never used.
         Self {
             model: model,
             chpylm_sampling_probability_table: vec![
                 0.0;
-                dataset.vocabulary.get_num_characters() + 1
+                dataset.vocabulary.get_num_characters() + 2
             ],
-            chpylm_sampling_id_table: vec![' '; dataset.vocabulary.get_num_characters() + 1],
+            chpylm_sampling_id_table: vec![' '; dataset.vocabulary.get_num_characters() + 2],
+            sorted_characters,
             added_to_chpylm_train: vec![false; dataset.train_sentences.len()],
             dataset: dataset,
             rand_indices_train: rand_indices_train,
@@ -48,51 +274,161 @@ impl Trainer {
             always_accept_new_segmentation: always_accept_new_segmentation,
             num_segmentation_acceptances: 0,
             num_segmentation_rejections: 0,
+            num_threads: 1,
+            parallel_batch_size: 0,
+            worker_samplers: Vec::new(),
+            train_boundary_counts: Vec::new(),
+            word_length_correction: WordLengthCorrection::new(),
+        }
+    }
+
+    /// Continues the training of a model restored by `Model::load`. The restored training sentences are already seated in the restaurants with their saved segmentation.
+    pub fn new_from_saved_model(mut model: Model, always_accept_new_segmentation: bool) -> Self {
+        let train_sentences = mem::take(&mut model.train_sentences);
+        let dataset = Dataset::new_from_sentences(train_sentences, SegmentedSentences::new());
+        let mut trainer = Self::new(dataset, model, always_accept_new_segmentation);
+        for added in trainer.added_to_chpylm_train.iter_mut() {
+            *added = true;
+        }
+        trainer
+    }
+
+    /// Saves the model together with the current segmentation of the training sentences.
+    ///
+    /// Should only be called after at least one epoch of `blocked_gibbs_sampling`, since the saved sentences are assumed to be seated in the restaurants when the model is loaded again.
+    pub fn save_model(&self, path: &Path) -> Result<()> {
+        self.model.save(path, &self.dataset.train_sentences)
+    }
+
+    /// Saves the complete state of the training after `epoch` epochs: the model, the segmentation of every sentence, the state of the random number generator and the counters. The file is first written under a temporary name and then renamed, so that a crash while writing never leaves a broken checkpoint behind.
+    pub fn save_checkpoint(&self, path: &Path, epoch: usize) -> Result<()> {
+        let temporary_path = path.with_extension("tmp");
+        {
+            let file = File::create(&temporary_path)?;
+            let mut writer = BufWriter::new(file);
+            write_file_header(&mut writer, CHECKPOINT_FILE_MAGIC, CHECKPOINT_FILE_VERSION)?;
+            let checkpoint = CheckpointRef {
+                epoch,
+                npylm: &self.model.npylm,
+                rng: &self.model.rng,
+                normalizer: &self.model.normalizer,
+                boundary_rules: &self.model.boundary_rules,
+                train_sentences: &self.dataset.train_sentences,
+                dev_sentences: &self.dataset.dev_sentences,
+                dev_gold_segmentations: &self.dataset.dev_gold_segmentations,
+                added_to_chpylm_train: &self.added_to_chpylm_train,
+                rand_indices_train: &self.rand_indices_train,
+                num_segmentation_acceptances: self.num_segmentation_acceptances,
+                num_segmentation_rejections: self.num_segmentation_rejections,
+                always_accept_new_segmentation: self.always_accept_new_segmentation,
+                train_boundary_counts: &self.train_boundary_counts,
+            };
+            bincode::serialize_into(&mut writer, &checkpoint)?;
+            writer.flush()?;
         }
+        fs::rename(&temporary_path, path)?;
+        Ok(())
+    }
+
+    /// Restores a trainer saved by `save_checkpoint`, together with the number of epochs it was trained for. Continuing the training gives the same result as if it had never stopped, as long as the same number of threads is used.
+    pub fn load_checkpoint(path: &Path) -> Result<(Self, usize)> {
+        let file = File::open(path)?;
+        let mut reader = BufReader::new(file);
+        read_file_header(
+            &mut reader,
+            CHECKPOINT_FILE_MAGIC,
+            CHECKPOINT_FILE_VERSION,
+            "training checkpoint",
+        )?;
+        let mut checkpoint: Checkpoint = bincode::deserialize_from(&mut reader)?;
+        checkpoint.npylm.rebuild_after_deserialization();
+
+        let mut dataset =
+            Dataset::new_from_sentences(checkpoint.train_sentences, checkpoint.dev_sentences);
+        dataset.dev_gold_segmentations = checkpoint.dev_gold_segmentations;
+        let mut model =
+            Model::from_npylm(checkpoint.npylm, SegmentedSentences::new(), checkpoint.rng);
+        model.normalizer = checkpoint.normalizer;
+        model.boundary_rules = checkpoint.boundary_rules;
+        let mut trainer = Self::new(dataset, model, checkpoint.always_accept_new_segmentation);
+        trainer.added_to_chpylm_train = checkpoint.added_to_chpylm_train;
+        trainer.rand_indices_train = checkpoint.rand_indices_train;
+        trainer.num_segmentation_acceptances = checkpoint.num_segmentation_acceptances;
+        trainer.num_segmentation_rejections = checkpoint.num_segmentation_rejections;
+        trainer.train_boundary_counts = checkpoint.train_boundary_counts;
+        Ok((trainer, checkpoint.epoch))
+    }
+
+    /// Makes `blocked_gibbs_sampling` segment the sentences on `num_threads` threads, `batch_size` sentences at a time. A single thread (the default) gives the usual sequential sampler.
+    ///
+    /// Larger batches keep the threads busier, but the segmentations within a batch are all drawn from the same snapshot of the model, so more of them are rejected. Either way the chain settles at the same distribution as the sequential one, and with batches of one sentence it is the very same chain.
+    pub fn set_num_threads(&mut self, num_threads: usize, batch_size: usize) {
+        self.num_threads = num_threads.max(1);
+        self.parallel_batch_size = batch_size.max(1);
+    }
+
+    /// Sets the temperature of the blocked Gibbs sampling, see `Sampler::set_temperature`. The Metropolis-Hastings test then targets the posterior raised to the power of 1/T as well.
+    pub fn set_temperature(&mut self, temperature: f64) {
+        self.model.sampler.set_temperature(temperature);
+    }
+
+    pub fn get_temperature(&self) -> f64 {
+        self.model.sampler.get_temperature()
     }
 
     pub fn sample_hyperparameters(&mut self) {
-        self.model.sampler.npylm.sample_hyperparameters();
+        self.model.npylm.sample_hyperparameters(&mut self.model.rng);
     }
 
-    pub fn sample_lambda(&mut self) {
-        let mut a_array = vec![self.model.sampler.npylm.lambda_a; WORDTYPE_NUM_TYPES + 1];
-        let mut b_array = vec![self.model.sampler.npylm.lambda_b; WORDTYPE_NUM_TYPES + 1];
+    pub fn sample_lambda(&mut self) -> Result<()> {
+        let num_types = self.model.npylm.word_type_scheme.get_num_types();
+        let mut a_array = vec![self.model.npylm.lambda_a; num_types + 1];
+        let mut b_array = vec![self.model.npylm.lambda_b; num_types + 1];
         let mut word_ids: HashSet<u64> = HashSet::new();
         // This method of storing the dataset is hugely problematic. Surely we've got some better ways then. Let's go on of course go on.
-        for sentence in &self.dataset.train_sentences {
+        for sentence in self.dataset.train_sentences.iter() {
             for index in 2..sentence.num_segments - 1 {
                 let word = sentence.get_nth_word_chars(index);
-                let word_id = sentence.get_nth_word_id(index);
+                let word_id = sentence.find_nth_word_id(index, &self.model.npylm.interner);
                 let word_length = sentence.get_nth_segment_length(index);
-                if word_length > self.model.sampler.npylm.max_word_length {
+                if word_length > self.model.npylm.max_word_length {
                     continue;
                 }
 
                 if !word_ids.contains(&word_id) {
-                    let tablegroups = self
+                    // Every word of the current segmentations is seated in the word HPYLM, unless the model was trained on other sentences than these.
+                    let tablegroups = match self
                         .model
-                        .sampler
                         .npylm
                         .whpylm
-                        .root
+                        .tree
+                        .root()
                         .tablegroups
                         .get(&word_id)
-                        // This word should always be present. Otehrwise it would be a bug.
-                        .unwrap();
-                    // .unwrap_or(&Vec::new());
+                    {
+                        Some(tablegroups) => tablegroups,
+                        None => {
+                            return Err(Error::InvalidModelFile(format!(
+                                "The word {} of a training sentence has no table in the model",
+                                word.iter().collect::<String>()
+                            )))
+                        }
+                    };
                     let num_tablegroups = tablegroups.len();
-                    let t = detect_word_type(word);
+                    let t = self.model.npylm.word_type_scheme.detect_word_type(word);
                     a_array[t] += (num_tablegroups * word_length) as f64;
                     b_array[t] += num_tablegroups as f64;
                     word_ids.insert(word_id);
                 }
             }
-            for t in 1..WORDTYPE_NUM_TYPES + 1 {
+            for t in 1..num_types + 1 {
                 let dist = Gamma::new(a_array[t], 1.0 / b_array[t]);
-                self.model.sampler.npylm.lambda_for_types[t] = dist.sample(&mut thread_rng());
+                self.model.npylm.lambda_for_types[t] =
+                    check_lambda(t, dist.sample(&mut self.model.rng))?;
             }
         }
+        self.model.npylm.clear_g_0_cache();
+        Ok(())
     }
 
     fn sample_next_char_from_chpylm_given_context(
@@ -102,17 +438,17 @@ impl Trainer {
         // This is apparently unused
         _sample_t: usize,
         skip_eow: bool,
-    ) -> char {
+    ) -> Result<char> {
         // let mut prob_sum = 0.0;
         let mut table_index = 1;
         // let num_characters = self.dataset.vocabulary.all_characters.len();
-        for c in &self.dataset.vocabulary.all_characters {
-            let p_w = self
-                .model
-                .sampler
-                .npylm
-                .chpylm
-                .compute_p_w_given_h_with_target(*c, context_chars, 0, context_length - 1);
+        for c in &self.sorted_characters {
+            let p_w = self.model.npylm.chpylm.compute_p_w_given_h_with_target(
+                *c,
+                context_chars,
+                0,
+                context_length - 1,
+            );
 
             // prob_sum += p_w;
             self.chpylm_sampling_probability_table[table_index] = p_w;
@@ -121,30 +457,83 @@ impl Trainer {
         }
 
         if !skip_eow {
-            let p_w = self
-                .model
-                .sampler
-                .npylm
-                .chpylm
-                .compute_p_w_given_h_with_target(EOW, context_chars, 0, context_length - 1);
+            let p_w = self.model.npylm.chpylm.compute_p_w_given_h_with_target(
+                EOW,
+                context_chars,
+                0,
+                context_length - 1,
+            );
             // prob_sum += p_w;
             self.chpylm_sampling_probability_table[table_index] = p_w;
             self.chpylm_sampling_id_table[table_index] = EOW;
+        } else {
+            // Don't leave the probability of EOW from the previous call in the table.
+            self.chpylm_sampling_probability_table[table_index] = 0.0;
         }
 
-        let dist = WeightedIndex::new(&self.chpylm_sampling_probability_table).unwrap();
-        let index_of_char = dist.sample(&mut rand::thread_rng());
-        return self.chpylm_sampling_id_table[index_of_char];
+        let dist = WeightedIndex::new(&self.chpylm_sampling_probability_table).map_err(|e| {
+            Error::NumericalUnderflow(format!("Cannot sample a character from the CHPYLM: {}", e))
+        })?;
+        let index_of_char = dist.sample(&mut self.model.rng);
+        Ok(self.chpylm_sampling_id_table[index_of_char])
+    }
+
+    pub fn set_word_length_correction(&mut self, word_length_correction: WordLengthCorrection) {
+        self.word_length_correction = word_length_correction;
     }
 
-    pub fn update_p_k_given_chpylm_default(&mut self) {
-        self.update_p_k_given_chpylm(20000, 10);
+    /// Re-estimates the word length distribution of the CHPYLM as configured with `set_word_length_correction`, if `epoch` is late enough.
+    pub fn correct_word_lengths(&mut self, epoch: usize) -> Result<()> {
+        let correction = self.word_length_correction.clone();
+        if correction.start_epoch == 0 || epoch < correction.start_epoch {
+            Ok(())
+        } else if correction.exact {
+            self.update_p_k_given_chpylm_exactly()
+        } else {
+            self.update_p_k_given_chpylm(
+                correction.num_samples,
+                correction.early_stopping_threshold,
+            )
+        }
     }
 
-    pub fn update_p_k_given_chpylm(&mut self, num_samples: usize, early_stopping_threshold: usize) {
+    pub fn update_p_k_given_chpylm_default(&mut self) -> Result<()> {
+        self.update_p_k_given_chpylm(
+            NUM_WORD_LENGTH_SAMPLES,
+            WORD_LENGTH_EARLY_STOPPING_THRESHOLD,
+        )
+    }
+
+    /// Sets the word length distribution of the CHPYLM to the exact one, instead of the estimate from sampled words of `update_p_k_given_chpylm`.
+    pub fn update_p_k_given_chpylm_exactly(&mut self) -> Result<()> {
+        let max_word_length = self.model.get_max_word_length() + 1;
+        let distribution = self
+            .model
+            .npylm
+            .chpylm
+            .compute_word_length_distribution(&self.sorted_characters, max_word_length);
+        // The distribution covers the lengths from 0 to `max_word_length`.
+        for (k, &p_k) in distribution.iter().enumerate().skip(1) {
+            if !(p_k > 0.0 && p_k.is_finite()) {
+                return Err(Error::NumericalUnderflow(format!(
+                    "The CHPYLM gives probability {} to words of length {}",
+                    p_k, k
+                )));
+            }
+            self.model.npylm.p_k_chpylm[k] = p_k;
+        }
+        self.model.npylm.clear_g_0_cache();
+        Ok(())
+    }
+
+    pub fn update_p_k_given_chpylm(
+        &mut self,
+        num_samples: usize,
+        early_stopping_threshold: usize,
+    ) -> Result<()> {
         let max_word_length = self.model.get_max_word_length() + 1;
         for i in 0..max_word_length + 1 {
-            self.model.sampler.npylm.p_k_chpylm[i] = 0.0;
+            self.model.npylm.p_k_chpylm[i] = 0.0;
         }
         let mut num_words_of_length_k = vec![0; max_word_length + 1];
 
@@ -162,7 +551,7 @@ impl Trainer {
                     j + 1,
                     j + 1,
                     skip_eow,
-                );
+                )?;
                 wrapped_chars[j + 1] = next_char;
                 if next_char == EOW {
                     break;
@@ -193,38 +582,58 @@ impl Trainer {
         }
 
         for k in 1..max_word_length + 1 {
-            self.model.sampler.npylm.p_k_chpylm[k] = (num_words_of_length_k[k] + 1) as f64
+            self.model.npylm.p_k_chpylm[k] = (num_words_of_length_k[k] + 1) as f64
                 / (num_words_sampled + max_word_length) as f64;
 
-            assert!(self.model.sampler.npylm.p_k_chpylm[k] > 0.0);
+            if self.model.npylm.p_k_chpylm[k] <= 0.0 {
+                return Err(Error::NumericalUnderflow(format!(
+                    "The probability of the word length {} under the character model is {}",
+                    k, self.model.npylm.p_k_chpylm[k]
+                )));
+            }
+        }
+        self.model.npylm.clear_g_0_cache();
+        Ok(())
+    }
+
+    pub fn blocked_gibbs_sampling(&mut self) -> Result<()> {
+        if self.num_threads > 1 {
+            self.parallel_blocked_gibbs_sampling()?;
+        } else {
+            self.sequential_blocked_gibbs_sampling()?;
         }
+        // Every sentence has been segmented anew, so the words of the old segmentations that are gone can be dropped.
+        self.model.npylm.release_unused_word_ids();
+        Ok(())
     }
 
-    pub fn blocked_gibbs_sampling(&mut self) {
+    fn sequential_blocked_gibbs_sampling(&mut self) -> Result<()> {
         let num_sentences = self.dataset.train_sentences.len();
         let max_sentence_length = self.dataset.max_sentence_length;
+        let max_word_length = self.model.get_max_word_length();
+        let temperature = self.get_temperature();
 
-        self.rand_indices_train.shuffle(&mut thread_rng());
+        self.rand_indices_train.shuffle(&mut self.model.rng);
 
         for step in 1..num_sentences + 1 {
             let sentence_index = self.rand_indices_train[step - 1];
-            let sentence = &mut self.dataset.train_sentences[sentence_index];
+            let mut sentence = self.dataset.train_sentences.get_sentence(sentence_index);
 
             if sentence.supervised {
                 if self.added_to_chpylm_train[sentence_index] == true {
                     for n in 2..sentence.num_segments {
-                        self.model
-                            .sampler
-                            .npylm
-                            .remove_customer_at_index_n(sentence, n);
+                        self.model.npylm.remove_customer_at_index_n(
+                            &sentence,
+                            n,
+                            &mut self.model.rng,
+                        );
                     }
                 }
 
                 for n in 2..sentence.num_segments {
                     self.model
-                        .sampler
                         .npylm
-                        .add_customer_at_index_n(sentence, n);
+                        .add_customer_at_index_n(&sentence, n, &mut self.model.rng)?;
                 }
 
                 self.added_to_chpylm_train[sentence_index] = true;
@@ -233,39 +642,44 @@ impl Trainer {
                     let mut old_segment_lengths = vec![0; max_sentence_length + 3];
                     let mut num_old_segments = 0;
                     let mut old_log_p_s = 0.0;
+                    let test_new_segmentation = !self.always_accept_new_segmentation
+                        && !has_word_longer_than(&sentence, max_word_length);
 
                     for n in 2..sentence.num_segments {
-                        self.model
-                            .sampler
-                            .npylm
-                            .remove_customer_at_index_n(sentence, n);
+                        self.model.npylm.remove_customer_at_index_n(
+                            &sentence,
+                            n,
+                            &mut self.model.rng,
+                        );
                     }
 
-                    if !self.always_accept_new_segmentation {
+                    if test_new_segmentation {
                         num_old_segments = sentence.get_num_segments_without_special_tokens();
                         for i in 0..num_old_segments {
                             old_segment_lengths[i] = sentence.segment_lengths[i + 2];
                         }
                         old_log_p_s = self
                             .model
-                            .sampler
                             .npylm
-                            .compute_log_probability_of_sentence(sentence);
+                            .compute_log_probability_of_sentence(&sentence)?;
                     }
 
-                    let new_segment_lengths =
-                        self.model.sampler.blocked_gibbs_segment(sentence, true);
+                    let new_segment_lengths = self.model.sampler.blocked_gibbs_segment(
+                        &self.model.npylm,
+                        &sentence,
+                        true,
+                        &mut self.model.rng,
+                    )?;
 
                     sentence.split_sentence(new_segment_lengths);
 
-                    if !self.always_accept_new_segmentation {
+                    if test_new_segmentation {
                         let new_log_p_s = self
                             .model
-                            .sampler
                             .npylm
-                            .compute_log_probability_of_sentence(sentence);
-                        let bernoulli = (1.0 as f64).min((new_log_p_s - old_log_p_s).exp());
-                        let r = rand::thread_rng().gen();
+                            .compute_log_probability_of_sentence(&sentence)?;
+                        let bernoulli = ((new_log_p_s - old_log_p_s) / temperature).exp().min(1.0);
+                        let r = self.model.rng.gen();
                         if bernoulli < r {
                             sentence.split_sentence_with_num_segments(
                                 old_segment_lengths,
@@ -276,21 +690,201 @@ impl Trainer {
                             self.num_segmentation_acceptances += 1;
                         }
                     }
+                    self.dataset
+                        .train_sentences
+                        .set_segmentation(sentence_index, &sentence);
                 }
 
                 for n in 2..sentence.num_segments {
                     self.model
-                        .sampler
                         .npylm
-                        .add_customer_at_index_n(sentence, n);
+                        .add_customer_at_index_n(&sentence, n, &mut self.model.rng)?;
                 }
                 self.added_to_chpylm_train[sentence_index] = true;
             }
         }
+        Ok(())
+    }
+
+    fn add_sentence_to_model(&mut self, sentence: &Sentence) -> Result<()> {
+        for n in 2..sentence.num_segments {
+            self.model
+                .npylm
+                .add_customer_at_index_n(sentence, n, &mut self.model.rng)?;
+        }
+        Ok(())
+    }
+
+    fn remove_sentence_from_model(&mut self, sentence: &Sentence) {
+        for n in 2..sentence.num_segments {
+            self.model
+                .npylm
+                .remove_customer_at_index_n(sentence, n, &mut self.model.rng);
+        }
+    }
+
+    /// The parallel version of `blocked_gibbs_sampling`. The shuffled sentences are processed in batches. The new segmentations of a batch are drawn concurrently from a snapshot of the model, and then accepted or rejected one by one against the current model.
+    fn parallel_blocked_gibbs_sampling(&mut self) -> Result<()> {
+        self.rand_indices_train.shuffle(&mut self.model.rng);
+        let rand_indices_train = self.rand_indices_train.clone();
+        for batch in rand_indices_train.chunks(self.parallel_batch_size) {
+            self.sample_batch(batch)?;
+        }
+        Ok(())
+    }
+
+    fn sample_batch(&mut self, batch: &[usize]) -> Result<()> {
+        let mut sentences_to_sample = Vec::new();
+        // The sentences of the batch are only built for the time they are sampled.
+        let mut sentences = Vec::new();
+        for &sentence_index in batch {
+            let sentence = self.dataset.train_sentences.get_sentence(sentence_index);
+            if sentence.supervised || !self.added_to_chpylm_train[sentence_index] {
+                // Supervised sentences keep their segmentation, and sentences not yet seated are added with their initial segmentation, just like in the sequential version.
+                if self.added_to_chpylm_train[sentence_index] {
+                    self.remove_sentence_from_model(&sentence);
+                }
+                self.add_sentence_to_model(&sentence)?;
+                self.added_to_chpylm_train[sentence_index] = true;
+            } else {
+                sentences_to_sample.push(sentence_index);
+                sentences.push(sentence);
+            }
+        }
+
+        if sentences_to_sample.is_empty() {
+            return Ok(());
+        }
+
+        // The proposal distribution of a sentence must not depend on its current segmentation, otherwise the Metropolis-Hastings correction below would be wrong. Therefore the proposals are drawn from the model with all the sentences of the batch removed, which the workers share as the snapshot.
+        for sentence in &sentences {
+            self.remove_sentence_from_model(sentence);
+        }
+
+        let num_workers = self.num_threads.min(sentences_to_sample.len());
+        let max_word_length = self.model.get_max_word_length();
+        let max_sentence_length = self.dataset.max_sentence_length;
+        while self.worker_samplers.len() < num_workers {
+            let sampler = Sampler::new(&self.model.npylm, max_word_length, max_sentence_length);
+            self.worker_samplers
+                .push((sampler, ProbabilityCache::new()));
+        }
+        let temperature = self.get_temperature();
+        for &mut (ref mut sampler, _) in &mut self.worker_samplers[..num_workers] {
+            sampler.set_temperature(temperature);
+        }
+
+        let proposals = if num_workers == 1 {
+            // A lone sentence is proposed on this thread with the main generator, there is nothing to gain from a worker.
+            let (ref mut sampler, ref mut cache) = self.worker_samplers[0];
+            vec![propose_segmentation(
+                &self.model.npylm,
+                sampler,
+                cache,
+                &sentences[0],
+                &mut self.model.rng,
+            )?]
+        } else {
+            self.propose_segmentations_in_parallel(&sentences, num_workers)?
+        };
+        // The first sentence stays removed for its own test below, the others go back until their turn comes.
+        for sentence in &sentences[1..] {
+            self.add_sentence_to_model(sentence)?;
+        }
+
+        // The proposal comes from the snapshot of the model, q(s) = p_snapshot(s)^(1/T), and doesn't depend on the current segmentation. This is an independence sampler, which settles at p^(1/T) if a new segmentation is accepted with probability min(1, (p(new) p_snapshot(old) / (p(old) p_snapshot(new)))^(1/T)). For a batch of one sentence the snapshot is the current model, and every proposal is accepted.
+        for (i, ((&sentence_index, mut sentence), proposal)) in sentences_to_sample
+            .iter()
+            .zip(sentences)
+            .zip(proposals)
+            .enumerate()
+        {
+            if i > 0 {
+                self.remove_sentence_from_model(&sentence);
+            }
+            if self.always_accept_new_segmentation
+                || has_word_longer_than(&sentence, max_word_length)
+            {
+                sentence.split_sentence(proposal.segment_lengths);
+            } else {
+                let num_old_segments = sentence.get_num_segments_without_special_tokens();
+                let old_segment_lengths =
+                    sentence.segment_lengths[2..2 + num_old_segments].to_vec();
+                let old_log_p_s = self
+                    .model
+                    .npylm
+                    .compute_log_probability_of_sentence(&sentence)?;
+                sentence.split_sentence(proposal.segment_lengths);
+                let new_log_p_s = self
+                    .model
+                    .npylm
+                    .compute_log_probability_of_sentence(&sentence)?;
+                let log_acceptance_ratio = (new_log_p_s - old_log_p_s)
+                    - (proposal.snapshot_log_p_new - proposal.snapshot_log_p_old);
+                let bernoulli = (log_acceptance_ratio / temperature).exp().min(1.0);
+                let r = self.model.rng.gen();
+                if bernoulli < r {
+                    sentence
+                        .split_sentence_with_num_segments(old_segment_lengths, num_old_segments);
+                    self.num_segmentation_rejections += 1;
+                } else {
+                    self.num_segmentation_acceptances += 1;
+                }
+            }
+            self.add_sentence_to_model(&sentence)?;
+            self.dataset
+                .train_sentences
+                .set_segmentation(sentence_index, &sentence);
+        }
+        Ok(())
+    }
+
+    /// Draws the proposals for the sentences of a batch on `num_workers` threads, from the model with all of them removed.
+    fn propose_segmentations_in_parallel(
+        &mut self,
+        sentences: &[Sentence],
+        num_workers: usize,
+    ) -> Result<Vec<Proposal>> {
+        // Every worker gets its own generator seeded from the main one, so that the result doesn't depend on how the threads are scheduled.
+        let seeds: Vec<u64> = (0..num_workers).map(|_| self.model.rng.gen()).collect();
+        let chunk_size = sentences.len().div_ceil(num_workers);
+        let workers = &mut self.worker_samplers[..num_workers];
+        let snapshot = &self.model.npylm;
+        thread::scope(|scope| {
+            let handles: Vec<_> = workers
+                .iter_mut()
+                .zip(sentences.chunks(chunk_size))
+                .zip(seeds)
+                .map(|((&mut (ref mut sampler, ref mut cache), chunk), seed)| {
+                    scope.spawn(move || {
+                        let mut rng = NpylmRng::seed_from_u64(seed);
+                        chunk
+                            .iter()
+                            .map(|sentence| {
+                                propose_segmentation(snapshot, sampler, cache, sentence, &mut rng)
+                            })
+                            .collect::<Result<Vec<Proposal>>>()
+                    })
+                })
+                .collect();
+            let mut proposals = Vec::new();
+            for handle in handles {
+                let worker_proposals = handle.join().map_err(|e| {
+                    let message = e
+                        .downcast_ref::<&str>()
+                        .map(|m| m.to_string())
+                        .or_else(|| e.downcast_ref::<String>().cloned())
+                        .unwrap_or_default();
+                    Error::NumericalUnderflow(format!("A sampling thread panicked: {}", message))
+                })?;
+                proposals.extend(worker_proposals?);
+            }
+            Ok(proposals)
+        })
     }
 
     // pub fn compute_perplexity(&mut self, sentences: &Vec<Sentence>) -> f64 {
-    pub fn compute_perplexity(&mut self, train_sentences: bool) -> f64 {
+    pub fn compute_perplexity(&mut self, train_sentences: bool) -> Result<f64> {
         let sentences = if train_sentences {
             &self.dataset.train_sentences
         } else {
@@ -300,36 +894,45 @@ impl Trainer {
         let num_sentences = sentences.len();
 
         if num_sentences == 0 {
-            return 0.0;
+            return Ok(0.0);
         }
 
         let mut sum = 0.0;
 
-        for s in sentences {
-            let mut sentence = s.clone();
-            let segment_lengths = self.model.sampler.viterbi_decode(&sentence);
+        for mut sentence in sentences.iter() {
+            let segment_lengths = self
+                .model
+                .sampler
+                .viterbi_decode(&self.model.npylm, &sentence)?;
             sentence.split_sentence(segment_lengths);
             sum += self
                 .model
-                .sampler
                 .npylm
-                .compute_log_probability_of_sentence(&sentence)
+                .compute_log_probability_of_sentence(&sentence)?
                 / (sentence.num_segments - 2) as f64;
         }
 
         let ppl = (-sum / num_sentences as f64).exp();
-        ppl
+        Ok(ppl)
     }
 
-    fn compute_perplexity_train(&mut self) -> f64 {
+    /// The words of the current segmentation of the training sentences, with their counts and probabilities.
+    pub fn lexicon(&mut self) -> Result<Lexicon> {
+        self.model
+            .npylm
+            .build_lexicon(&self.dataset.train_sentences)
+    }
+
+    pub fn compute_perplexity_train(&mut self) -> Result<f64> {
         self.compute_perplexity(true)
     }
 
-    fn compute_perplexity_dev(&mut self) -> f64 {
+    pub fn compute_perplexity_dev(&mut self) -> Result<f64> {
         self.compute_perplexity(false)
     }
 
-    fn compute_log_likelihood(&mut self, train_sentences: bool) -> f64 {
+    /// The sum of the log marginal likelihoods of the sentences, computed with the forward algorithm over all their segmentations.
+    pub fn compute_log_likelihood(&mut self, train_sentences: bool) -> Result<f64> {
         let sentences = if train_sentences {
             &self.dataset.train_sentences
         } else {
@@ -339,31 +942,113 @@ impl Trainer {
         let num_sentences = sentences.len();
 
         if num_sentences == 0 {
-            return 0.0;
+            return Ok(0.0);
         }
 
         let mut sum = 0.0;
 
-        for sentence in sentences {
-            let log_p_x = self
-                .model
-                .sampler
-                .compute_log_forward_probability(sentence, true);
+        for sentence in sentences.iter() {
+            let log_p_x = self.model.sampler.compute_log_forward_probability(
+                &self.model.npylm,
+                &sentence,
+                true,
+            )?;
             sum += log_p_x;
         }
 
-        sum
+        Ok(sum)
     }
 
-    fn compute_log_likelihood_train(&mut self) -> f64 {
+    pub fn compute_log_likelihood_train(&mut self) -> Result<f64> {
         self.compute_log_likelihood(true)
     }
 
-    fn compute_log_likelihood_dev(&mut self) -> f64 {
+    pub fn compute_log_likelihood_dev(&mut self) -> Result<f64> {
         self.compute_log_likelihood(false)
     }
 
-    fn print_segmentations(&mut self, num_to_print: usize, train_sentences: bool) {
+    /// Collects the diagnostics of the training so far. The perplexities and log-likelihoods go over all the train and dev sentences, so this is about as expensive as an epoch of sampling.
+    ///
+    /// `num_sample_segmentations` dev sentences are segmented with the Viterbi algorithm to show what the model currently does.
+    pub fn report(
+        &mut self,
+        epoch: usize,
+        num_sample_segmentations: usize,
+    ) -> Result<TrainingReport> {
+        let num_proposals = self.num_segmentation_acceptances + self.num_segmentation_rejections;
+        let acceptance_rate = if num_proposals == 0 {
+            None
+        } else {
+            Some(self.num_segmentation_acceptances as f64 / num_proposals as f64)
+        };
+        let train_perplexity = self.compute_perplexity_train()?;
+        let dev_perplexity = self.compute_perplexity_dev()?;
+        let train_log_likelihood = self.compute_log_likelihood_train()?;
+        let dev_log_likelihood = self.compute_log_likelihood_dev()?;
+        let dev_segmentations = self.sample_segmentations_dev(num_sample_segmentations)?;
+
+        let npylm = &self.model.npylm;
+        Ok(TrainingReport {
+            epoch,
+            train_perplexity,
+            dev_perplexity,
+            train_log_likelihood,
+            dev_log_likelihood,
+            num_segmentation_acceptances: self.num_segmentation_acceptances,
+            num_segmentation_rejections: self.num_segmentation_rejections,
+            temperature: self.get_temperature(),
+            acceptance_rate,
+            num_characters: self.dataset.vocabulary.get_num_characters(),
+            word_length_distribution: npylm.p_k_chpylm[1..=npylm.max_word_length].to_vec(),
+            word_length_overflow: npylm.p_k_chpylm[npylm.max_word_length + 1],
+            num_words: npylm.whpylm.tree.root().tablegroups.len(),
+            whpylm_num_nodes: npylm.whpylm.get_num_nodes(),
+            whpylm_num_tables: npylm.whpylm.get_num_tables(),
+            whpylm_num_customers: npylm.whpylm.get_num_customers(),
+            chpylm_num_nodes: npylm.chpylm.get_num_nodes(),
+            chpylm_num_tables: npylm.chpylm.get_num_tables(),
+            chpylm_num_customers: npylm.chpylm.get_num_customers(),
+            dev_segmentations,
+        })
+    }
+
+    /// Segments the dev sentences that have a gold segmentation with the Viterbi algorithm and scores the result against the gold segmentation. Returns `None` if no dev sentence has one.
+    ///
+    /// The dictionary holds the words that count as in-vocabulary for the OOV and IV recall.
+    pub fn evaluate_dev(
+        &mut self,
+        dictionary: &HashSet<String>,
+    ) -> Result<Option<SegmentationScores>> {
+        let mut evaluator = Evaluator::new(dictionary);
+        let mut num_evaluated = 0;
+        for (sentence_index, gold) in self.dataset.dev_gold_segmentations.iter().enumerate() {
+            if let Some(gold_segment_lengths) = gold {
+                let sentence = self.dataset.dev_sentences.get_sentence(sentence_index);
+                let segment_lengths = self
+                    .model
+                    .sampler
+                    .viterbi_decode(&self.model.npylm, &sentence)?;
+                let gold_words =
+                    words_from_segment_lengths(&sentence.characters, gold_segment_lengths);
+                let test_words = words_from_segment_lengths(&sentence.characters, &segment_lengths);
+                evaluator.add_sentence(&gold_words, &test_words);
+                num_evaluated += 1;
+            }
+        }
+
+        if num_evaluated == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(evaluator.get_scores()))
+        }
+    }
+
+    /// Segments the first `num_to_print` sentences in the sampling order with the Viterbi algorithm. The words are separated by two spaces.
+    fn sample_segmentations(
+        &mut self,
+        num_to_print: usize,
+        train_sentences: bool,
+    ) -> Result<Vec<String>> {
         let sentences = if train_sentences {
             &self.dataset.train_sentences
         } else {
@@ -376,22 +1061,124 @@ impl Trainer {
             &self.rand_indices_dev
         };
 
-        let num_to_print = num_to_print.min(sentences.len());
+        let mut segmentations = Vec::new();
+        for &sentence_index in rand_indices.iter().take(num_to_print) {
+            let sentence = sentences.get_sentence(sentence_index);
+            let segment_lengths = self
+                .model
+                .sampler
+                .viterbi_decode(&self.model.npylm, &sentence)?;
+            let words = words_from_segment_lengths(&sentence.characters, &segment_lengths);
+            segmentations.push(words.join("  "));
+        }
+        Ok(segmentations)
+    }
+
+    /// Adds the current segmentation of every training sentence to the samples from which `mbr_segmentations_train` is derived. Call it after each of the last epochs of the training.
+    pub fn collect_boundary_samples(&mut self) {
+        if self.train_boundary_counts.is_empty() {
+            let sentences = &self.dataset.train_sentences;
+            self.train_boundary_counts = (0..sentences.len())
+                .map(|index| BoundaryCounts::new(sentences.get_characters(index).len()))
+                .collect();
+        }
+        for (index, boundary_counts) in self.train_boundary_counts.iter_mut().enumerate() {
+            boundary_counts.add_sample(&self.dataset.train_sentences.get_segment_lengths(index));
+        }
+    }
 
-        for n in 1..num_to_print + 1 {
-            let sentence_index = rand_indices[n];
-            let mut sentence = sentences[sentence_index].clone();
-            let segment_lengths = self.model.sampler.viterbi_decode(&sentence);
-            sentence.split_sentence(segment_lengths);
-            println!("{}\n", sentence);
+    /// The number of epochs collected by `collect_boundary_samples`.
+    pub fn get_num_boundary_samples(&self) -> usize {
+        self.train_boundary_counts
+            .first()
+            .map_or(0, |boundary_counts| boundary_counts.get_num_samples())
+    }
+
+    /// The segmentation of each training sentence with the minimum Bayes risk under the boundary F-measure, estimated from the segmentations collected by `collect_boundary_samples`. The supervised sentences keep their fixed segmentation.
+    pub fn mbr_segmentations_train(&self) -> Vec<Vec<String>> {
+        self.train_boundary_counts
+            .iter()
+            .enumerate()
+            .map(|(index, boundary_counts)| {
+                words_from_segment_lengths(
+                    self.dataset.train_sentences.get_characters(index),
+                    &boundary_counts.mbr_segment_lengths(),
+                )
+            })
+            .collect()
+    }
+
+    pub fn sample_segmentations_train(&mut self, num_to_print: usize) -> Result<Vec<String>> {
+        self.sample_segmentations(num_to_print, true)
+    }
+
+    pub fn sample_segmentations_dev(&mut self, num_to_print: usize) -> Result<Vec<String>> {
+        self.sample_segmentations(num_to_print, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_trainer(max_word_length: usize) -> Trainer {
+        let lines = [
+            "abcabcabd",
+            "abdabcab",
+            "cabcabd",
+            "bcdabcd",
+            "abcdabcdab",
+            "dabcab",
+        ];
+        let mut sentences = SegmentedSentences::new();
+        for line in &lines {
+            let sentence_chars: Vec<char> = line.chars().collect();
+            sentences.push_unsegmented(&sentence_chars, SegmentationConstraints::new());
         }
+        // Every sentence starts out as a single word, so the CHPYLM has to hold a word as long as the longest sentence.
+        let dataset = Dataset::new_from_sentences(sentences, SegmentedSentences::new());
+        let model = Model::new(&dataset, max_word_length, 0).unwrap();
+        Trainer::new(dataset, model, false)
     }
 
-    fn print_segmentations_train(&mut self, num_to_print: usize) {
-        self.print_segmentations(num_to_print, true);
+    // The segmentations, the numbers of accepted and rejected segmentations and the log likelihood after some epochs.
+    fn train(trainer: &mut Trainer, num_epochs: usize) -> (Vec<Vec<usize>>, usize, usize, f64) {
+        for _ in 0..num_epochs {
+            trainer.blocked_gibbs_sampling().unwrap();
+            trainer.sample_hyperparameters();
+            trainer.sample_lambda().unwrap();
+        }
+        let sentences = &trainer.dataset.train_sentences;
+        let segmentations = (0..sentences.len())
+            .map(|index| sentences.get_segment_lengths(index))
+            .collect();
+        (
+            segmentations,
+            trainer.num_segmentation_acceptances,
+            trainer.num_segmentation_rejections,
+            trainer.compute_log_likelihood_train().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_batches_of_one_sentence_accept_every_proposal() {
+        let mut trainer = new_trainer(10);
+        trainer.set_num_threads(2, 1);
+        let (_, acceptances, rejections, _) = train(&mut trainer, 6);
+        // The first epoch only seats the initial segmentations of the 6 sentences.
+        assert_eq!(acceptances, 5 * 6);
+        assert_eq!(rejections, 0);
     }
 
-    fn print_segmentations_dev(&mut self, num_to_print: usize) {
-        self.print_segmentations(num_to_print, false);
+    #[test]
+    fn test_initial_words_longer_than_the_maximum_word_length_are_split() {
+        for &num_threads in &[1, 2] {
+            let mut trainer = new_trainer(4);
+            trainer.set_num_threads(num_threads, 2);
+            let (segmentations, _, _, _) = train(&mut trainer, 2);
+            for segment_lengths in segmentations {
+                assert!(segment_lengths.iter().all(|&length| length <= 4));
+            }
+        }
     }
 }
@@ -1,11 +1,17 @@
 use def::*;
-use hpylm::{init_hyperparameters_at_depth_if_needed, sum_auxiliary_variables_recursively, HPYLM};
+use hpylm::{
+    init_hyperparameters_at_depth_if_needed, init_priors_at_depth_if_needed,
+    sum_auxiliary_variables_recursively, HPYLMPriors, HPYLM,
+};
 use pyp::*;
 use rand::distributions::{Beta, Gamma};
 use rand::prelude::*;
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct WHPYLM {
-    pub root: PYP<u64>,
+    pub tree: PYPTree<u64>,
+    /// 2 for a bigram model, 3 for a trigram model. Unlike `depth`, which follows the deepest node actually in the tree, this never changes.
+    pub order: usize,
     pub depth: usize,
     pub g_0: f64,
     pub d_array: Vec<f64>,
@@ -19,12 +25,14 @@ pub struct WHPYLM {
     b_array: Vec<f64>,
     alpha_array: Vec<f64>,
     beta_array: Vec<f64>,
+    pub priors: HPYLMPriors,
 }
 
 impl WHPYLM {
     pub fn new(order: usize) -> Self {
         Self {
-            root: PYP::new(0),
+            tree: PYPTree::new(0),
+            order,
             depth: 0.max(order - 1),
             d_array: vec![HPYLM_INITIAL_D; order],
             theta_array: vec![HPYLM_INITIAL_THETA; order],
@@ -32,29 +40,60 @@ impl WHPYLM {
             b_array: vec![HPYLM_B; order],
             alpha_array: vec![HPYLM_ALPHA; order],
             beta_array: vec![HPYLM_BETA; order],
+            priors: HPYLMPriors::default(),
             g_0: 0.0,
         }
     }
+
+    /// Starts the hyperparameters of every depth over from the priors. Only meant for a model that hasn't been trained yet.
+    pub fn set_priors(&mut self, priors: HPYLMPriors) {
+        self.priors = priors;
+        let num_depths = self.d_array.len();
+        self.d_array = vec![priors.initial_d; num_depths];
+        self.theta_array = vec![priors.initial_theta; num_depths];
+        self.a_array = vec![priors.a; num_depths];
+        self.b_array = vec![priors.b; num_depths];
+        self.alpha_array = vec![priors.alpha; num_depths];
+        self.beta_array = vec![priors.beta; num_depths];
+    }
+
+    /// Makes sure the hyperparameters cover the depth, so that the restaurants at it start from the priors. Call this before adding a customer at the depth.
+    pub fn extend_hyperparameters(&mut self, depth: usize) {
+        init_hyperparameters_at_depth_if_needed(
+            &mut self.d_array,
+            &mut self.theta_array,
+            &self.priors,
+            depth,
+        );
+        init_priors_at_depth_if_needed(
+            &mut self.a_array,
+            &mut self.b_array,
+            &mut self.alpha_array,
+            &mut self.beta_array,
+            &self.priors,
+            depth,
+        );
+    }
 }
 
 impl HPYLM<u64> for WHPYLM {
     fn get_num_nodes(&self) -> usize {
-        return self.root.get_num_nodes() + 1;
+        return self.tree.get_num_nodes(ROOT_ID) + 1;
     }
     fn get_num_tables(&self) -> usize {
-        return self.root.get_num_tables() + 1;
+        return self.tree.get_num_tables(ROOT_ID) + 1;
     }
     fn get_num_customers(&self) -> usize {
-        return self.root.get_num_customers() + 1;
+        return self.tree.get_num_customers(ROOT_ID) + 1;
     }
     fn get_pass_counts(&self) -> usize {
-        return self.root.get_pass_counts() + 1;
+        return self.tree.get_pass_counts(ROOT_ID) + 1;
     }
     fn get_stop_counts(&self) -> usize {
-        return self.root.get_stop_counts() + 1;
+        return self.tree.get_stop_counts(ROOT_ID) + 1;
     }
 
-    fn sample_hyperparameters(&mut self) {
+    fn sample_hyperparameters<R: Rng>(&mut self, rng: &mut R) {
         let max_depth: usize = self.d_array.len() - 1;
         let mut sum_log_x_u_array = vec![0.0; max_depth + 1];
         let mut sum_y_ui_array = vec![0.0; max_depth + 1];
@@ -63,7 +102,8 @@ impl HPYLM<u64> for WHPYLM {
 
         self.depth = 0;
         sum_auxiliary_variables_recursively(
-            &self.root,
+            &self.tree,
+            ROOT_ID,
             &mut sum_log_x_u_array,
             &mut sum_y_ui_array,
             &mut sum_one_minus_y_ui_array,
@@ -74,16 +114,23 @@ impl HPYLM<u64> for WHPYLM {
             &mut self.b_array,
             &mut self.alpha_array,
             &mut self.beta_array,
+            &self.priors,
             &mut self.depth,
+            rng,
         );
 
         init_hyperparameters_at_depth_if_needed(
             &mut self.d_array,
             &mut self.theta_array,
+            &self.priors,
+            self.depth,
+        );
+        init_priors_at_depth_if_needed(
             &mut self.a_array,
             &mut self.b_array,
             &mut self.alpha_array,
             &mut self.beta_array,
+            &self.priors,
             self.depth,
         );
 
@@ -92,13 +139,13 @@ impl HPYLM<u64> for WHPYLM {
                 self.a_array[u] + sum_one_minus_y_ui_array[u],
                 self.b_array[u] + sum_one_minus_z_uwkj_array[u],
             );
-            self.d_array[u] = dist1.sample(&mut rand::thread_rng());
+            self.d_array[u] = dist1.sample(rng);
 
             let dist2 = Gamma::new(
                 self.alpha_array[u] + sum_y_ui_array[u],
                 1.0 / (self.beta_array[u] - sum_log_x_u_array[u]),
             );
-            self.theta_array[u] = dist2.sample(&mut rand::thread_rng());
+            self.theta_array[u] = dist2.sample(rng);
         }
 
         let excessive_length = max_depth - self.depth;
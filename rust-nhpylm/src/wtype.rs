@@ -1,4 +1,8 @@
 use ctype::*;
+use error::*;
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer, Serializer};
+use std::sync::Arc;
 
 pub const WORDTYPE_NUM_TYPES: usize = 9;
 
@@ -129,11 +133,142 @@ pub fn is_symbol(c: char) -> bool {
   return true;
 }
 
-pub fn detect_word_type(word: &[char]) -> usize {
-  return detect_word_type_substr(word, 0, word.len() - 1);
+/// Sorts words into types, each of which gets its own Poisson word length distribution in the NPYLM. The types are numbered from 1 to `get_num_types()`.
+pub trait WordTypeScheme: Send + Sync {
+  /// The name under which the scheme is selected and stored with a model, see `get_word_type_scheme`.
+  fn get_name(&self) -> &'static str;
+
+  fn get_num_types(&self) -> usize;
+
+  /// The type of the word made of `chars[start..=end]`.
+  fn detect_word_type_substr(&self, chars: &[char], start: usize, end: usize) -> usize;
+
+  fn detect_word_type(&self, word: &[char]) -> usize {
+    self.detect_word_type_substr(word, 0, word.len() - 1)
+  }
+}
+
+pub const WORD_TYPE_SCHEME_NAMES: [&str; 4] = ["japanese", "chinese", "korean", "southeast-asian"];
+
+/// Looks up one of the schemes in `WORD_TYPE_SCHEME_NAMES`.
+pub fn get_word_type_scheme(name: &str) -> Result<Arc<dyn WordTypeScheme>> {
+  let scheme: Arc<dyn WordTypeScheme> = match name {
+    "japanese" => Arc::new(JapaneseWordTypes),
+    "chinese" => Arc::new(ChineseWordTypes),
+    "korean" => Arc::new(KoreanWordTypes),
+    "southeast-asian" => Arc::new(SoutheastAsianWordTypes),
+    _ => {
+      return Err(Error::InvalidConfig(format!(
+        "Unknown word type scheme {:?}, expected one of {}",
+        name,
+        WORD_TYPE_SCHEME_NAMES.join(", ")
+      )))
+    }
+  };
+  Ok(scheme)
+}
+
+/// Stores a scheme by its name, so that only the schemes known to `get_word_type_scheme` can be read back.
+pub mod word_type_scheme_by_name {
+  use super::*;
+
+  pub fn serialize<S: Serializer>(
+    scheme: &Arc<dyn WordTypeScheme>,
+    serializer: S,
+  ) -> ::std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(scheme.get_name())
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+  ) -> ::std::result::Result<Arc<dyn WordTypeScheme>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    get_word_type_scheme(&name).map_err(D::Error::custom)
+  }
+}
+
+// Counts the characters of `chars[start..=end]` in each of the classes that `classify` assigns, which are numbered from 1 to `num_classes`.
+fn count_classes<F: Fn(char) -> usize>(
+  chars: &[char],
+  start: usize,
+  end: usize,
+  num_classes: usize,
+  classify: F,
+) -> Vec<usize> {
+  let mut counts = vec![0; num_classes + 1];
+  for i in start..end + 1 {
+    counts[classify(chars[i])] += 1;
+  }
+  counts
+}
+
+// ASCII letters, and their full-width forms, which are common in Chinese and Korean text.
+fn is_latin_letter(c: char) -> bool {
+  if is_alphabet(c) {
+    return true;
+  }
+  let character = c as u32;
+  if (0xFF21..=0xFF3A).contains(&character) {
+    return true;
+  }
+  if (0xFF41..=0xFF5A).contains(&character) {
+    return true;
+  }
+  false
+}
+
+fn is_han(c: char) -> bool {
+  if is_kanji(c) {
+    return true;
+  }
+  let t = detect_ctype(c);
+  if t == CTYPE_CJK_COMPATIBILITY_IDEOGRAPHS {
+    return true;
+  }
+  if t == CTYPE_CJK_COMPATIBILITY_IDEOGRAPHS_SUPPLEMENT {
+    return true;
+  }
+  false
+}
+
+pub fn is_hangul(c: char) -> bool {
+  let t = detect_ctype(c);
+  if t == CTYPE_HANGUL_SYLLABLES {
+    return true;
+  }
+  if t == CTYPE_HANGUL_JAMO {
+    return true;
+  }
+  if t == CTYPE_HANGUL_COMPATIBILITY_JAMO {
+    return true;
+  }
+  if t == CTYPE_HANGUL_JAMO_EXTENDED_A {
+    return true;
+  }
+  if t == CTYPE_HANGUL_JAMO_EXTENDED_B {
+    return true;
+  }
+  false
+}
+
+/// The original scheme of the NPYLM paper, which tells hiragana, katakana and kanji apart, and is the default.
+pub struct JapaneseWordTypes;
+
+impl WordTypeScheme for JapaneseWordTypes {
+  fn get_name(&self) -> &'static str {
+    "japanese"
+  }
+
+  fn get_num_types(&self) -> usize {
+    WORDTYPE_NUM_TYPES
+  }
+
+  fn detect_word_type_substr(&self, chars: &[char], start: usize, end: usize) -> usize {
+    detect_japanese_word_type_substr(chars, start, end)
+  }
 }
 
-pub fn detect_word_type_substr(chars: &[char], start: usize, end: usize) -> usize {
+fn detect_japanese_word_type_substr(chars: &[char], start: usize, end: usize) -> usize {
   let mut num_alphabet = 0;
   let mut num_number = 0;
   let mut num_symbol = 0;
@@ -210,3 +345,183 @@ pub fn detect_word_type_substr(chars: &[char], start: usize, end: usize) -> usiz
   }
   return WORDTYPE_OTHER;
 }
+
+const CHINESE_NUM_TYPES: usize = 6;
+
+const CHINESE_ALPHABET: usize = 1;
+const CHINESE_NUMBER: usize = 2;
+const CHINESE_SYMBOL: usize = 3;
+const CHINESE_HANZI: usize = 4;
+const CHINESE_HANZI_ALPHANUMERIC: usize = 5;
+const CHINESE_OTHER: usize = 6;
+
+/// Hanzi, Latin letters, numbers and symbols, and hanzi mixed with letters or numbers, as in `3月` or `B超`.
+pub struct ChineseWordTypes;
+
+impl WordTypeScheme for ChineseWordTypes {
+  fn get_name(&self) -> &'static str {
+    "chinese"
+  }
+
+  fn get_num_types(&self) -> usize {
+    CHINESE_NUM_TYPES
+  }
+
+  fn detect_word_type_substr(&self, chars: &[char], start: usize, end: usize) -> usize {
+    let counts = count_classes(chars, start, end, CHINESE_NUM_TYPES, |c| {
+      if is_latin_letter(c) {
+        return CHINESE_ALPHABET;
+      }
+      if c.is_numeric() {
+        return CHINESE_NUMBER;
+      }
+      if is_han(c) {
+        return CHINESE_HANZI;
+      }
+      CHINESE_SYMBOL
+    });
+    let size = end - start + 1;
+    if let Some(t) = (1..CHINESE_NUM_TYPES + 1).find(|&t| counts[t] == size) {
+      return t;
+    }
+    if counts[CHINESE_HANZI] > 0
+      && counts[CHINESE_HANZI] + counts[CHINESE_ALPHABET] + counts[CHINESE_NUMBER] == size
+    {
+      return CHINESE_HANZI_ALPHANUMERIC;
+    }
+    CHINESE_OTHER
+  }
+}
+
+const KOREAN_NUM_TYPES: usize = 8;
+
+const KOREAN_ALPHABET: usize = 1;
+const KOREAN_NUMBER: usize = 2;
+const KOREAN_SYMBOL: usize = 3;
+const KOREAN_HANGUL: usize = 4;
+const KOREAN_HANJA: usize = 5;
+const KOREAN_HANGUL_HANJA: usize = 6;
+const KOREAN_HANGUL_ALPHANUMERIC: usize = 7;
+const KOREAN_OTHER: usize = 8;
+
+/// Hangul, hanja, Latin letters, numbers and symbols, and hangul mixed with hanja or with letters and numbers, as in `3월` or `PC방`.
+pub struct KoreanWordTypes;
+
+impl WordTypeScheme for KoreanWordTypes {
+  fn get_name(&self) -> &'static str {
+    "korean"
+  }
+
+  fn get_num_types(&self) -> usize {
+    KOREAN_NUM_TYPES
+  }
+
+  fn detect_word_type_substr(&self, chars: &[char], start: usize, end: usize) -> usize {
+    let counts = count_classes(chars, start, end, KOREAN_NUM_TYPES, |c| {
+      if is_latin_letter(c) {
+        return KOREAN_ALPHABET;
+      }
+      if c.is_numeric() {
+        return KOREAN_NUMBER;
+      }
+      if is_hangul(c) {
+        return KOREAN_HANGUL;
+      }
+      if is_han(c) {
+        return KOREAN_HANJA;
+      }
+      KOREAN_SYMBOL
+    });
+    let size = end - start + 1;
+    if let Some(t) = (1..KOREAN_NUM_TYPES + 1).find(|&t| counts[t] == size) {
+      return t;
+    }
+    if counts[KOREAN_HANGUL] > 0 {
+      if counts[KOREAN_HANJA] > 0 && counts[KOREAN_HANGUL] + counts[KOREAN_HANJA] == size {
+        return KOREAN_HANGUL_HANJA;
+      }
+      if counts[KOREAN_HANGUL] + counts[KOREAN_ALPHABET] + counts[KOREAN_NUMBER] == size {
+        return KOREAN_HANGUL_ALPHANUMERIC;
+      }
+    }
+    KOREAN_OTHER
+  }
+}
+
+const SOUTHEAST_ASIAN_NUM_TYPES: usize = 10;
+
+const SOUTHEAST_ASIAN_ALPHABET: usize = 1;
+const SOUTHEAST_ASIAN_NUMBER: usize = 2;
+const SOUTHEAST_ASIAN_SYMBOL: usize = 3;
+const SOUTHEAST_ASIAN_THAI: usize = 4;
+const SOUTHEAST_ASIAN_LAO: usize = 5;
+const SOUTHEAST_ASIAN_KHMER: usize = 6;
+const SOUTHEAST_ASIAN_MYANMAR: usize = 7;
+const SOUTHEAST_ASIAN_TIBETAN: usize = 8;
+const SOUTHEAST_ASIAN_OTHER_SCRIPT: usize = 9;
+const SOUTHEAST_ASIAN_OTHER: usize = 10;
+
+/// One type for each of the scripts that are written without spaces between the words: Thai, Lao, Khmer, Myanmar and Tibetan, with the smaller scripts of the region (Tai Le, New Tai Lue, Tai Tham, Tai Viet, Buginese, Balinese, Javanese, Sundanese, Batak, Cham and Kayah Li) sharing one. The digits of these scripts count as numbers, and the combining vowel signs and tone marks as part of their script.
+pub struct SoutheastAsianWordTypes;
+
+fn detect_southeast_asian_script(c: char) -> usize {
+  let t = detect_ctype(c);
+  if t == CTYPE_THAI {
+    return SOUTHEAST_ASIAN_THAI;
+  }
+  if t == CTYPE_LAO {
+    return SOUTHEAST_ASIAN_LAO;
+  }
+  if t == CTYPE_KHMER || t == CTYPE_KHMER_SYMBOLS {
+    return SOUTHEAST_ASIAN_KHMER;
+  }
+  if t == CTYPE_MYANMAR || t == CTYPE_MYANMAR_EXTENDED_A || t == CTYPE_MYANMAR_EXTENDED_B {
+    return SOUTHEAST_ASIAN_MYANMAR;
+  }
+  if t == CTYPE_TIBETAN {
+    return SOUTHEAST_ASIAN_TIBETAN;
+  }
+  if t == CTYPE_TAI_LE
+    || t == CTYPE_NEW_TAI_LUE
+    || t == CTYPE_TAI_THAM
+    || t == CTYPE_TAI_VIET
+    || t == CTYPE_BUGINESE
+    || t == CTYPE_BALINESE
+    || t == CTYPE_JAVANESE
+    || t == CTYPE_SUNDANESE
+    || t == CTYPE_SUNDANESE_SUPPLEMENT
+    || t == CTYPE_BATAK
+    || t == CTYPE_CHAM
+    || t == CTYPE_KAYAH_LI
+  {
+    return SOUTHEAST_ASIAN_OTHER_SCRIPT;
+  }
+  SOUTHEAST_ASIAN_SYMBOL
+}
+
+impl WordTypeScheme for SoutheastAsianWordTypes {
+  fn get_name(&self) -> &'static str {
+    "southeast-asian"
+  }
+
+  fn get_num_types(&self) -> usize {
+    SOUTHEAST_ASIAN_NUM_TYPES
+  }
+
+  fn detect_word_type_substr(&self, chars: &[char], start: usize, end: usize) -> usize {
+    let counts = count_classes(chars, start, end, SOUTHEAST_ASIAN_NUM_TYPES, |c| {
+      if is_latin_letter(c) {
+        return SOUTHEAST_ASIAN_ALPHABET;
+      }
+      if c.is_numeric() {
+        return SOUTHEAST_ASIAN_NUMBER;
+      }
+      detect_southeast_asian_script(c)
+    });
+    let size = end - start + 1;
+    if let Some(t) = (1..SOUTHEAST_ASIAN_NUM_TYPES + 1).find(|&t| counts[t] == size) {
+      return t;
+    }
+    SOUTHEAST_ASIAN_OTHER
+  }
+}